@@ -0,0 +1,253 @@
+use std::{io::Write, sync::Arc};
+
+use arrow_array::{ArrayRef, Float32Array, Float64Array, Int32Array, RecordBatch, UInt32Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{
+    arrow::ArrowWriter, basic::Compression, errors::Result, file::properties::WriterProperties,
+};
+
+use crate::{diagnostic::DiagnositcLog, Simulator};
+
+/// Number of rows buffered before they are written as a row group
+const ROW_GROUP_ROWS: usize = 1 << 20;
+
+fn properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build()
+}
+
+/// Writer of positions and velocities of pedestrians in Apache Parquet, with the same columns as the CSV of
+/// [`TrajectoryWriter`](crate::trajectory::TrajectoryWriter).
+///
+/// Rows are buffered and written in large row groups. The file is readable only after [`ParquetTrajectoryWriter::close`]
+/// writes its footer.
+pub struct ParquetTrajectoryWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    /// Interval of steps between samples
+    interval: i32,
+    rows: TrajectoryRows,
+}
+
+#[derive(Default)]
+struct TrajectoryRows {
+    step: Vec<i32>,
+    time: Vec<f64>,
+    id: Vec<u32>,
+    group: Vec<u32>,
+    destination: Vec<u32>,
+    x: Vec<f32>,
+    y: Vec<f32>,
+    vx: Vec<f32>,
+    vy: Vec<f32>,
+}
+
+impl<W: Write + Send> ParquetTrajectoryWriter<W> {
+    /// Prepare to sample every `interval` steps.
+    pub fn new(writer: W, interval: i32) -> Result<Self> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("step", DataType::Int32, false),
+            Field::new("time", DataType::Float64, false),
+            Field::new("id", DataType::UInt32, false),
+            Field::new("group", DataType::UInt32, false),
+            Field::new("destination", DataType::UInt32, false),
+            Field::new("x", DataType::Float32, false),
+            Field::new("y", DataType::Float32, false),
+            Field::new("vx", DataType::Float32, false),
+            Field::new("vy", DataType::Float32, false),
+        ]));
+        Ok(ParquetTrajectoryWriter {
+            writer: ArrowWriter::try_new(writer, schema.clone(), Some(properties()))?,
+            schema,
+            interval: interval.max(1),
+            rows: TrajectoryRows::default(),
+        })
+    }
+
+    /// Add a row for each pedestrian if the current step is sampled and out of the warm-up period.
+    pub fn record(&mut self, simulator: &Simulator) -> Result<()> {
+        if simulator.is_warming_up() || simulator.step % self.interval != 0 {
+            return Ok(());
+        }
+
+        let rows = &mut self.rows;
+        simulator.for_each_pedestrian(|p| {
+            rows.step.push(simulator.step);
+            rows.time.push(simulator.step as f64 * 0.1);
+            rows.id.push(p.id as u32);
+            rows.group.push(p.group as u32);
+            rows.destination.push(p.destination as u32);
+            rows.x.push(p.pos.x);
+            rows.y.push(p.pos.y);
+            rows.vx.push(p.vel.x);
+            rows.vy.push(p.vel.y);
+        });
+        if self.rows.step.len() >= ROW_GROUP_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered rows as a row group.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.rows.step.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from(rows.step)),
+            Arc::new(Float64Array::from(rows.time)),
+            Arc::new(UInt32Array::from(rows.id)),
+            Arc::new(UInt32Array::from(rows.group)),
+            Arc::new(UInt32Array::from(rows.destination)),
+            Arc::new(Float32Array::from(rows.x)),
+            Arc::new(Float32Array::from(rows.y)),
+            Arc::new(Float32Array::from(rows.vx)),
+            Arc::new(Float32Array::from(rows.vy)),
+        ];
+        self.writer
+            .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        self.writer.flush()
+    }
+
+    /// Write the remaining rows and the footer.
+    pub fn close(mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+/// Write the scalar metrics of each step in the log to Apache Parquet, one row per recorded step. Metrics per gate,
+/// exit or sensor and nested statistics are left to the JSON log.
+pub fn write_step_metrics<W: Write + Send>(log: &DiagnositcLog, writer: W) -> Result<()> {
+    let metrics = &log.step_metrics;
+    let steps: Vec<i32> = (0..metrics.len())
+        .map(|i| (log.warmup_steps + i + 1) as i32)
+        .collect();
+    let i32_column = |name: &str, values: &[i32]| {
+        (
+            Field::new(name, DataType::Int32, false),
+            Arc::new(Int32Array::from(values.to_vec())) as ArrayRef,
+        )
+    };
+    let f64_column = |name: &str, values: &[f64]| {
+        (
+            Field::new(name, DataType::Float64, false),
+            Arc::new(Float64Array::from(values.to_vec())) as ArrayRef,
+        )
+    };
+    let optional_f64_column = |name: &str, values: &[Option<f64>]| {
+        (
+            Field::new(name, DataType::Float64, true),
+            Arc::new(Float64Array::from(values.to_vec())) as ArrayRef,
+        )
+    };
+    let (fields, columns): (Vec<_>, Vec<_>) = [
+        i32_column("step", &steps),
+        i32_column("active_ped_count", &metrics.active_ped_count),
+        f64_column("time_spawn", &metrics.time_spawn),
+        f64_column("time_calc_state", &metrics.time_calc_state),
+        optional_f64_column("time_calc_state_kernel", &metrics.time_calc_state_kernel),
+        i32_column("spawn_queue_length", &metrics.spawn_queue_length),
+        i32_column("spawned_count", &metrics.spawned_count),
+        i32_column("refused_count", &metrics.refused_count),
+        i32_column("unplaced_count", &metrics.unplaced_count),
+        i32_column("arrived_count", &metrics.arrived_count),
+        i32_column("out_of_bounds_count", &metrics.out_of_bounds_count),
+        i32_column("despawned_count", &metrics.despawned_count),
+        optional_f64_column("lane_order", &metrics.lane_order),
+    ]
+    .into_iter()
+    .unzip();
+
+    let schema = Arc::new(Schema::new(fields));
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), Some(properties()))?;
+    writer.write(&RecordBatch::try_new(schema, columns)?)?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::{self, File},
+        path::Path,
+    };
+
+    use arrow_array::{cast::AsArray, types::Int32Type, RecordBatch};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::{diagnostic::DiagnositcLog, scenario::Scenario, Simulator, SimulatorOptions};
+
+    use super::{write_step_metrics, ParquetTrajectoryWriter};
+
+    fn read(path: &Path) -> Vec<RecordBatch> {
+        ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_parquet() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 3, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        let id = std::process::id();
+        let trajectories_path =
+            env::temp_dir().join(format!("pedoni-test-trajectories-{id}.parquet"));
+        let metrics_path = env::temp_dir().join(format!("pedoni-test-metrics-{id}.parquet"));
+        let mut trajectories =
+            ParquetTrajectoryWriter::new(File::create(&trajectories_path).unwrap(), 2).unwrap();
+        let mut log = DiagnositcLog::default();
+        for _ in 0..4 {
+            log.push(simulator.tick().unwrap());
+            trajectories.record(&simulator).unwrap();
+        }
+        trajectories.close().unwrap();
+        write_step_metrics(&log, File::create(&metrics_path).unwrap()).unwrap();
+
+        // Two sampled steps of three pedestrians
+        let steps: Vec<i32> = read(&trajectories_path)
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column(0)
+                    .as_primitive::<Int32Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect();
+        assert_eq!(steps, [2, 2, 2, 4, 4, 4]);
+
+        let batch = &read(&metrics_path)[0];
+        assert_eq!(batch.num_rows(), 4);
+        let active = batch.column_by_name("active_ped_count").unwrap();
+        assert_eq!(active.as_primitive::<Int32Type>().values(), &[3, 3, 3, 3]);
+
+        fs::remove_file(&trajectories_path).unwrap();
+        fs::remove_file(&metrics_path).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use glam::{vec2, Vec2};
+use log::{info, warn};
+
+use crate::{
+    error::Result,
+    scenario::{
+        BehaviorConfig, FieldConfig, ObstacleConfig, PedestrianConfig, PedestrianSpawnConfig,
+        Scenario, SpawnRegion, WaypointConfig,
+    },
+    Simulator, SimulatorOptions,
+};
+
+/// Size of the room in front of the door (meters)
+const ROOM_SIZE: Vec2 = vec2(8.0, 8.0);
+/// Number of pedestrians initially in the room
+const PEDESTRIAN_COUNT: i32 = 120;
+/// Pedestrians passing first and last, excluded from the flow as they are not in the steady state
+const TRANSIENT_COUNT: usize = 20;
+/// Max steps of a run, after which the flow is measured from the pedestrians passed so far
+const MAX_STEPS: i32 = 3000;
+/// Range of [`BehaviorConfig::repulsion_range`] searched by [`fit_repulsion_range`] (meters)
+const REPULSION_RANGES: [f32; 2] = [0.05, 2.0];
+/// Iterations of the bisection in [`fit_repulsion_range`]
+const FIT_ITERATIONS: usize = 10;
+
+/// Specific flow through a door of a width, measured by [`measure`].
+#[derive(Debug, Clone, Copy)]
+pub struct FlowSample {
+    /// Width of the door (meters)
+    pub width: f32,
+    /// Pedestrians passing through the door per unit width and time (persons/m/s), or `None` if too few passed
+    pub specific_flow: Option<f64>,
+}
+
+/// Standardized bottleneck: pedestrians crowded in a room leave through a door of `width` in the middle of a wall.
+pub fn bottleneck(width: f32, behavior: &BehaviorConfig) -> Scenario {
+    let door = [ROOM_SIZE.y - width, ROOM_SIZE.y + width].map(|y| y * 0.5);
+    let wall = |y_1: f32, y_2: f32| ObstacleConfig {
+        line: [vec2(ROOM_SIZE.x, y_1), vec2(ROOM_SIZE.x, y_2)],
+        width: 0.2,
+        soft: false,
+        ..Default::default()
+    };
+
+    Scenario {
+        field: FieldConfig {
+            size: vec2(ROOM_SIZE.x + 4.0, ROOM_SIZE.y),
+            ..Default::default()
+        },
+        waypoints: vec![
+            WaypointConfig {
+                line: [vec2(0.5, 0.5), vec2(0.5, ROOM_SIZE.y - 0.5)],
+                ..Default::default()
+            },
+            // Just behind the door, so that pedestrians arrive as soon as they pass through it.
+            WaypointConfig {
+                line: [
+                    vec2(ROOM_SIZE.x + 0.5, 0.5),
+                    vec2(ROOM_SIZE.x + 0.5, ROOM_SIZE.y - 0.5),
+                ],
+                ..Default::default()
+            },
+        ],
+        obstacles: vec![wall(0.0, door[0]), wall(door[1], ROOM_SIZE.y)],
+        pedestrians: vec![PedestrianConfig {
+            origin: 0,
+            destination: Some(1),
+            spawn: PedestrianSpawnConfig::Once {
+                count: PEDESTRIAN_COUNT,
+                region: Some(SpawnRegion::Polygon(vec![
+                    vec2(0.5, 0.5),
+                    vec2(ROOM_SIZE.x - 1.0, 0.5),
+                    vec2(ROOM_SIZE.x - 1.0, ROOM_SIZE.y - 0.5),
+                    vec2(0.5, ROOM_SIZE.y - 0.5),
+                ])),
+            },
+            ..Default::default()
+        }],
+        behavior: behavior.clone(),
+        ..Default::default()
+    }
+}
+
+/// Simulate the bottleneck with a door of `width` and measure the specific flow in the steady state.
+///
+/// The flow is counted between the [`TRANSIENT_COUNT`]-th pedestrian passing through the door and the same number
+/// before the last one.
+pub fn specific_flow(
+    options: &SimulatorOptions,
+    behavior: &BehaviorConfig,
+    width: f32,
+) -> Result<Option<f64>> {
+    let mut simulator = Simulator::new(options.clone(), bottleneck(width, behavior))?;
+
+    // Times at which pedestrians passed through the door (seconds)
+    let mut times = Vec::new();
+    while times.len() < PEDESTRIAN_COUNT as usize && simulator.step < MAX_STEPS {
+        let metrics = simulator.tick()?;
+        let time = simulator.step as f64 * 0.1;
+        times.extend(std::iter::repeat_n(time, metrics.arrived_count as usize));
+    }
+
+    if times.len() < TRANSIENT_COUNT * 2 + 2 {
+        return Ok(None);
+    }
+    let first = TRANSIENT_COUNT;
+    let last = times.len() - 1 - TRANSIENT_COUNT;
+    let duration = times[last] - times[first];
+    Ok((duration > 0.0).then(|| (last - first) as f64 / duration / width as f64))
+}
+
+/// Measure the specific flow for each door width.
+pub fn measure(
+    options: &SimulatorOptions,
+    behavior: &BehaviorConfig,
+    widths: &[f32],
+) -> Result<Vec<FlowSample>> {
+    widths
+        .iter()
+        .map(|&width| {
+            let specific_flow = specific_flow(options, behavior, width)?;
+            match specific_flow {
+                Some(flow) => info!("Calibration: width: {width} m, specific flow: {flow:.3} /m/s"),
+                None => info!("Calibration: width: {width} m, too few pedestrians passed"),
+            }
+            Ok(FlowSample {
+                width,
+                specific_flow,
+            })
+        })
+        .collect()
+}
+
+/// Find [`BehaviorConfig::repulsion_range`] with which the mean specific flow over the widths matches `target`
+/// (persons/m/s).
+///
+/// The flow decreases as pedestrians keep longer distances, so the range is found by bisection. The other parameters
+/// are taken from `base`. Returns the fitted behavior and its samples.
+pub fn fit_repulsion_range(
+    options: &SimulatorOptions,
+    base: &BehaviorConfig,
+    widths: &[f32],
+    target: f64,
+) -> Result<(BehaviorConfig, Vec<FlowSample>)> {
+    let mean_flow = |samples: &[FlowSample]| {
+        let flows: Vec<f64> = samples.iter().filter_map(|s| s.specific_flow).collect();
+        flows.iter().sum::<f64>() / flows.len().max(1) as f64
+    };
+
+    let [mut low, mut high] = REPULSION_RANGES;
+    let mut best: Option<(f64, BehaviorConfig, Vec<FlowSample>)> = None;
+    for _ in 0..FIT_ITERATIONS {
+        let behavior = BehaviorConfig {
+            repulsion_range: Some((low + high) * 0.5),
+            ..base.clone()
+        };
+        let samples = measure(options, &behavior, widths)?;
+        let flow = mean_flow(&samples);
+        info!(
+            "Calibration: repulsion range: {:.3} m, mean specific flow: {flow:.3} /m/s",
+            behavior.repulsion_range()
+        );
+
+        if flow > target {
+            low = behavior.repulsion_range();
+        } else {
+            high = behavior.repulsion_range();
+        }
+        let error = (flow - target).abs();
+        if best
+            .as_ref()
+            .is_none_or(|(best_error, ..)| error < *best_error)
+        {
+            best = Some((error, behavior, samples));
+        }
+    }
+
+    let (error, behavior, samples) = best.unwrap();
+    if error > target * 0.1 {
+        warn!(
+            "Calibration: the mean specific flow differs from the target {target} /m/s by {error:.3} /m/s at best"
+        );
+    }
+    Ok((behavior, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{scenario::BehaviorConfig, SimulatorOptions};
+
+    use super::specific_flow;
+
+    #[test]
+    fn test_specific_flow() {
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let behavior = BehaviorConfig::default();
+
+        let flow = specific_flow(&options, &behavior, 1.2).unwrap().unwrap();
+        assert!(flow > 0.0);
+
+        let cautious = BehaviorConfig {
+            repulsion_range: Some(0.6),
+            ..Default::default()
+        };
+        let cautious_flow = specific_flow(&options, &cautious, 1.2).unwrap().unwrap();
+        assert!(cautious_flow < flow, "{cautious_flow} vs {flow}");
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::BinaryHeap,
+    fmt::{self, Display},
+};
+
+use glam::{vec2, Vec2};
+use ndarray::Array2;
+use ordered_float::NotNan;
+use serde::Serialize;
+
+use crate::{
+    dry_run::route_distance, field::Field, models::BODY_RADIUS, scenario::Scenario, util::Index,
+};
+
+/// Connectivity of waypoints in a scenario, to find passages too narrow for pedestrians, e.g. ones closed by
+/// rasterizing obstacles.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectivityGraph {
+    pub nodes: Vec<Node>,
+    /// Routes from origins to destinations of pedestrian groups, without duplicates
+    pub edges: Vec<Edge>,
+}
+
+/// Waypoint and its roles in pedestrian groups.
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    pub waypoint: usize,
+    /// Midpoint of the line of the waypoint
+    pub pos: Vec2,
+    pub is_origin: bool,
+    pub is_destination: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Edge {
+    /// Waypoint of the origin
+    pub from: usize,
+    /// Destination, i.e. a waypoint or a waypoint group
+    pub to: usize,
+    /// Walking distance along the shortest path, or `None` if unreachable (meters)
+    pub distance: Option<f32>,
+    /// Width of the narrowest passage on the widest route (meters), which is `None` if unreachable.
+    ///
+    /// The width is twice the distance from obstacles on the grid, so it is accurate within a cell. Passages are
+    /// searched only on the level of the origin, so routes through links to other levels have no width.
+    pub min_width: Option<f32>,
+    /// Position of the narrowest passage
+    pub narrowest_at: Option<Vec2>,
+}
+
+impl Edge {
+    /// Whether the route passes somewhere narrower than the body of a pedestrian.
+    pub fn is_narrow(&self) -> bool {
+        self.min_width
+            .is_some_and(|width| width < 2.0 * BODY_RADIUS)
+    }
+}
+
+/// Build the connectivity graph of the scenario on the built fields of its levels.
+pub fn analyze(scenario: &Scenario, fields: &[Field]) -> ConnectivityGraph {
+    let nodes = scenario
+        .waypoints
+        .iter()
+        .enumerate()
+        .map(|(waypoint, config)| Node {
+            waypoint,
+            pos: (config.line[0] + config.line[1]) * 0.5,
+            is_origin: scenario.pedestrians.iter().any(|p| p.origin == waypoint),
+            is_destination: scenario.pedestrians.iter().any(|p| {
+                p.destination_shares()
+                    .iter()
+                    .any(|&(destination, _)| destination == waypoint)
+            }),
+        })
+        .collect();
+
+    let mut routes: Vec<(usize, usize)> = scenario
+        .pedestrians
+        .iter()
+        .flat_map(|p| {
+            p.destination_shares()
+                .into_iter()
+                .map(move |(destination, _)| (p.origin, destination))
+        })
+        .filter(|&(origin, destination)| {
+            origin < scenario.waypoints.len() && destination < scenario.destination_count()
+        })
+        .collect();
+    routes.sort_unstable();
+    routes.dedup();
+
+    let edges = routes
+        .into_iter()
+        .map(|(from, to)| {
+            let origin = &scenario.waypoints[from];
+            let (line, field) = (origin.line, &fields[origin.level]);
+            let distance = route_distance(field, to, line);
+            let widest = distance.and_then(|_| widest_route(field, to, line));
+            Edge {
+                from,
+                to,
+                distance,
+                min_width: widest.map(|(clearance, _)| clearance * 2.0),
+                narrowest_at: widest.map(|(_, pos)| pos),
+            }
+        })
+        .collect();
+
+    ConnectivityGraph { nodes, edges }
+}
+
+/// Find the route from `line` to `destination` keeping the largest clearance from obstacles at its narrowest point.
+///
+/// Shortest paths cut corners along walls, so their clearance says little about the width of passages. Instead,
+/// cells are visited in descending order of the clearance of the best route to them, like Dijkstra's algorithm.
+/// Returns the clearance and the position where it is the smallest.
+///
+/// Cells on `line` and the destination do not narrow the route, since waypoints usually end at walls and the
+/// clearance there is that of the wall rather than of a passage. A route through those cells only takes the
+/// clearance where it reaches the destination.
+fn widest_route(field: &Field, destination: usize, line: [Vec2; 2]) -> Option<(f32, Vec2)> {
+    let potential = field.potential_map(destination);
+    let center = |ix: Index| (vec2(ix.x as f32, ix.y as f32) + 0.5) * field.unit;
+    let clearance = |ix: Index| {
+        field
+            .distance_map
+            .get(ix)
+            .filter(|_| field.obstacle_exist.get(ix) == Some(&false))
+            .copied()
+    };
+
+    // Bottleneck clearance of the best route found to each cell, and where it is
+    let mut best: Array2<Option<(f32, Index)>> = Array2::from_elem(field.shape, None);
+    let mut queue = BinaryHeap::new();
+    let samples = ((line[1] - line[0]).length() / field.unit).ceil() as usize + 1;
+    for i in 0..=samples {
+        let pos = line[0].lerp(line[1], i as f32 / samples as f32) / field.unit;
+        let ix = Index::new(pos.x as i32, pos.y as i32);
+        if clearance(ix).is_some() {
+            best[ix] = Some((f32::INFINITY, ix));
+            queue.push((NotNan::new(f32::INFINITY).ok()?, ix));
+        }
+    }
+
+    while let Some((d, ix)) = queue.pop() {
+        let (bottleneck, at) = best[ix]?;
+        if *d < bottleneck {
+            continue;
+        }
+        if potential[ix] <= 0.0 {
+            if bottleneck.is_infinite() {
+                return clearance(ix).map(|d| (d, center(ix)));
+            }
+            return Some((bottleneck, center(at)));
+        }
+
+        for (i, j) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = ix.add(i, j);
+            let Some(d_next) = clearance(next) else {
+                continue;
+            };
+            let candidate = if d_next < bottleneck && potential[next] > 0.0 {
+                (d_next, next)
+            } else {
+                (bottleneck, at)
+            };
+            if best[next].is_none_or(|(current, _)| current < candidate.0) {
+                best[next] = Some(candidate);
+                queue.push((NotNan::new(candidate.0).ok()?, next));
+            }
+        }
+    }
+    None
+}
+
+impl Display for ConnectivityGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:>16} {:>6} {:>11}",
+            "Waypoint", "Position", "Origin", "Destination"
+        )?;
+        let mark = |b: bool| if b { "x" } else { "" };
+        for node in &self.nodes {
+            writeln!(
+                f,
+                "{:>8} {:>16} {:>6} {:>11}",
+                node.waypoint,
+                format!("({:.1}, {:.1})", node.pos.x, node.pos.y),
+                mark(node.is_origin),
+                mark(node.is_destination)
+            )?;
+        }
+
+        write!(
+            f,
+            "{:>4} {:>4} {:>12} {:>13} {:>16}",
+            "From", "To", "Distance (m)", "Min width (m)", "Narrowest at"
+        )?;
+        for edge in &self.edges {
+            let (Some(distance), Some(width), Some(pos)) =
+                (edge.distance, edge.min_width, edge.narrowest_at)
+            else {
+                write!(f, "\n{:>4} {:>4} {:>12}", edge.from, edge.to, "unreachable")?;
+                continue;
+            };
+            write!(
+                f,
+                "\n{:>4} {:>4} {:>12.1} {:>13.2} {:>16}",
+                edge.from,
+                edge.to,
+                distance,
+                width,
+                format!("({:.1}, {:.1})", pos.x, pos.y)
+            )?;
+            if edge.is_narrow() {
+                write!(f, "  narrower than a person")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{
+        field::Field,
+        scenario::{
+            FieldConfig, ObstacleConfig, PedestrianConfig, PedestrianSpawnConfig, Scenario,
+            WaypointConfig,
+        },
+    };
+
+    use super::analyze;
+
+    #[test]
+    fn test_analyze() {
+        let waypoint = |x: f32| WaypointConfig {
+            line: [vec2(x, 1.0), vec2(x, 9.0)],
+            ..Default::default()
+        };
+        let group = |origin, destination| PedestrianConfig {
+            origin,
+            destination: Some(destination),
+            spawn: PedestrianSpawnConfig::Periodic { rate: 1.0 },
+            ..Default::default()
+        };
+        // A wall across the field leaves a gap of 2.5 m at x = 8, and a closed wall blocks the third waypoint.
+        let wall = |line: [_; 2]| ObstacleConfig {
+            line,
+            width: 0.5,
+            soft: false,
+            ..Default::default()
+        };
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(30.0, 10.0),
+                ..Default::default()
+            },
+            waypoints: vec![waypoint(2.0), waypoint(15.0), waypoint(28.0)],
+            obstacles: vec![
+                wall([vec2(8.0, -1.0), vec2(8.0, 3.75)]),
+                wall([vec2(8.0, 6.25), vec2(8.0, 11.0)]),
+                wall([vec2(22.0, -1.0), vec2(22.0, 11.0)]),
+            ],
+            pedestrians: vec![group(0, 1), group(0, 2), group(1, 0)],
+            ..Default::default()
+        };
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        let graph = analyze(&scenario, &[field]);
+
+        assert_eq!(graph.nodes.len(), 3);
+        assert!(graph.nodes[1].is_origin && graph.nodes[1].is_destination);
+        assert!(!graph.nodes[2].is_origin && graph.nodes[2].is_destination);
+
+        assert_eq!(graph.edges.len(), 3);
+        let edge = &graph.edges[0];
+        assert_eq!((edge.from, edge.to), (0, 1));
+        let width = edge.min_width.unwrap();
+        assert!((2.0..3.0).contains(&width), "{width}");
+        let pos = edge.narrowest_at.unwrap();
+        assert!(
+            (pos.x - 8.0).abs() < 1.0 && (pos.y - 5.0).abs() < 1.0,
+            "{pos}"
+        );
+        assert!(!edge.is_narrow());
+
+        assert!(graph.edges[1].distance.is_none());
+        assert!(graph.edges[1].min_width.is_none());
+    }
+
+    #[test]
+    fn test_waypoint_along_wall() {
+        // A waypoint drawn along a wall does not make its routes as narrow as the clearance on the line.
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 10.0),
+                ..Default::default()
+            },
+            waypoints: vec![
+                WaypointConfig {
+                    line: [vec2(0.3, 1.0), vec2(0.3, 9.0)],
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(9.0, 1.0), vec2(9.0, 9.0)],
+                    ..Default::default()
+                },
+            ],
+            obstacles: vec![ObstacleConfig {
+                line: [vec2(0.0, 0.0), vec2(0.0, 10.0)],
+                width: 0.1,
+                soft: false,
+                ..Default::default()
+            }],
+            pedestrians: vec![PedestrianConfig {
+                origin: 0,
+                destination: Some(1),
+                spawn: PedestrianSpawnConfig::Periodic { rate: 1.0 },
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        let graph = analyze(&scenario, &[field]);
+        // Cells on the line are 0.25 m from the wall on the grid, and the next ones 0.5 m.
+        let width = graph.edges[0].min_width.unwrap();
+        assert!(width > 0.75, "{width}");
+        assert!(!graph.edges[0].is_narrow());
+    }
+}
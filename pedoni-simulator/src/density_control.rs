@@ -0,0 +1,111 @@
+use geo::{Area, Polygon};
+use serde::Serialize;
+
+use crate::{models::PedestrianModel, scenario::DensityControlConfig, util};
+
+/// PI controller of the scale of periodic spawn rates, configured by [`crate::scenario::Scenario::density_control`].
+#[derive(Debug, Clone)]
+pub struct DensityController {
+    config: DensityControlConfig,
+    /// Region where the density is measured, and its area (m^2)
+    region: Option<(Polygon<f32>, f64)>,
+    /// Integral of the relative error (seconds)
+    integral: f64,
+}
+
+/// State of [`DensityController`] in a step, recorded in the diagnostic log.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct DensityControlState {
+    /// Number of pedestrians in the field, or density in the region (persons/m^2)
+    pub measured: f64,
+    pub setpoint: f64,
+    /// Scale applied to the frequencies of periodic spawning
+    pub scale: f64,
+}
+
+impl DensityController {
+    pub fn new(config: &DensityControlConfig) -> Self {
+        let region = config.region.as_ref().map(|vertices| {
+            let polygon = util::polygon(vertices);
+            let area = polygon.unsigned_area() as f64;
+            (polygon, area)
+        });
+
+        DensityController {
+            config: config.clone(),
+            region,
+            integral: 0.0,
+        }
+    }
+
+    /// Measure the crowd and update the scale of spawn rates for the next `delta_time` seconds.
+    pub fn update(&mut self, model: &dyn PedestrianModel, delta_time: f64) -> DensityControlState {
+        let measured = match &self.region {
+            Some((polygon, area)) => {
+                let mut count = 0;
+                model.for_each_pedestrian(&mut |p| {
+                    if util::polygon_contains(polygon, p.pos) {
+                        count += 1;
+                    }
+                });
+                count as f64 / area.max(f64::EPSILON)
+            }
+            None => model.get_pedestrian_count() as f64,
+        };
+        self.control(measured, delta_time)
+    }
+
+    fn control(&mut self, measured: f64, delta_time: f64) -> DensityControlState {
+        let config = &self.config;
+        let error = (config.setpoint - measured) / config.setpoint.max(f64::EPSILON);
+        let output = |integral: f64| {
+            1.0 + config.gain * (error + integral / config.integral_time.max(f64::EPSILON))
+        };
+
+        // The error is not integrated while the output is saturated, which would only delay the recovery.
+        let integral = self.integral + error * delta_time;
+        if (0.0..=config.max_scale).contains(&output(integral)) {
+            self.integral = integral;
+        }
+
+        DensityControlState {
+            measured,
+            setpoint: config.setpoint,
+            scale: output(self.integral).clamp(0.0, config.max_scale),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+
+    use crate::scenario::DensityControlConfig;
+
+    use super::DensityController;
+
+    #[test]
+    fn test_control() {
+        let mut controller = DensityController::new(&DensityControlConfig {
+            setpoint: 100.0,
+            ..Default::default()
+        });
+
+        // Spawn faster while the crowd is below the setpoint, and stop spawning far above it.
+        let state = controller.control(50.0, 0.1);
+        assert!(state.scale > 1.5);
+        assert_eq!(controller.control(300.0, 0.1).scale, 0.0);
+
+        // Errors are integrated while the crowd is short, so the scale stays above 1 on reaching the setpoint.
+        let mut controller = DensityController::new(&DensityControlConfig {
+            setpoint: 100.0,
+            ..Default::default()
+        });
+        for _ in 0..50 {
+            controller.control(80.0, 0.1);
+        }
+        let state = controller.control(100.0, 0.1);
+        assert_float_absolute_eq!(state.scale, 1.0 + 0.2 * 5.0 / 10.0, 1e-6);
+        assert_float_absolute_eq!(state.measured, 100.0);
+    }
+}
@@ -1,12 +1,22 @@
-use std::fmt::Debug;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Display},
+};
 
+use glam::{vec2, Vec2};
+use ndarray::{Array2, Zip};
 use serde::Serialize;
 
+use crate::density_control::DensityControlState;
+
 #[derive(Debug, Default, Clone, Serialize)]
 pub struct DiagnositcLog {
     pub model: String,
     pub scenario: String,
     pub total_steps: usize,
+    /// Number of warm-up steps before the first step of [`DiagnositcLog::step_metrics`], which are not recorded.
+    /// See [`SimulatorOptions::warmup_steps`](crate::SimulatorOptions::warmup_steps).
+    pub warmup_steps: usize,
     pub preprocess_metrics: PreprocessMetrics,
     pub step_metrics: StepMetricsCollection,
 }
@@ -16,6 +26,155 @@ impl DiagnositcLog {
         self.step_metrics.push(step_metrics);
         self.total_steps += 1;
     }
+
+    /// Aggregate statistics of all steps, e.g. to print at the end of a run.
+    pub fn summary(&self) -> LogSummary {
+        let metrics = &self.step_metrics;
+        let time_total: Vec<f64> = (0..metrics.len()).map(|i| metrics.time_total(i)).collect();
+        let kernel: Vec<f64> = metrics
+            .time_calc_state_kernel
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+
+        LogSummary {
+            model: self.model.clone(),
+            scenario: self.scenario.clone(),
+            total_steps: self.total_steps,
+            warmup_steps: self.warmup_steps,
+            time_total: Stats::new(&time_total),
+            time_spawn: Stats::new(&metrics.time_spawn),
+            time_calc_state: Stats::new(&metrics.time_calc_state),
+            time_calc_state_kernel: (!kernel.is_empty()).then(|| Stats::new(&kernel)),
+            max_active_ped_count: metrics.active_ped_count.iter().copied().max().unwrap_or(0),
+            total_despawned: metrics.despawned_count.iter().sum(),
+            total_out_of_bounds: metrics.out_of_bounds_count.iter().sum(),
+            total_refused: metrics.refused_count.iter().sum(),
+            total_exit_counts: metrics
+                .exit_counts
+                .iter()
+                .fold(Vec::new(), |mut totals, counts| {
+                    totals.resize(totals.len().max(counts.len()), 0);
+                    for (total, count) in totals.iter_mut().zip(counts) {
+                        *total += count;
+                    }
+                    totals
+                }),
+        }
+    }
+}
+
+/// Aggregate statistics of a [`DiagnositcLog`], displayed as a table of times in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogSummary {
+    pub model: String,
+    pub scenario: String,
+    pub total_steps: usize,
+    /// Number of warm-up steps before the steps aggregated
+    pub warmup_steps: usize,
+    /// Time of each step spent on spawning and updating states (seconds)
+    pub time_total: Stats,
+    pub time_spawn: Stats,
+    pub time_calc_state: Stats,
+    /// Time of the GPU kernel, if the model runs on the GPU.
+    pub time_calc_state_kernel: Option<Stats>,
+    pub max_active_ped_count: i32,
+    pub total_despawned: i32,
+    pub total_out_of_bounds: i32,
+    pub total_refused: i32,
+    /// Number of pedestrians removed on arrival at each waypoint over the run, indexed by waypoint
+    pub total_exit_counts: Vec<i32>,
+}
+
+impl Display for LogSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Model: {}, Scenario: {}", self.model, self.scenario)?;
+        if self.warmup_steps > 0 {
+            writeln!(
+                f,
+                "Steps after {} warm-up steps are aggregated",
+                self.warmup_steps
+            )?;
+        }
+        writeln!(
+            f,
+            "Steps: {}, Max active pedestrians: {}, Despawned: {}, Out of bounds: {}, Refused: {}",
+            self.total_steps,
+            self.max_active_ped_count,
+            self.total_despawned,
+            self.total_out_of_bounds,
+            self.total_refused
+        )?;
+        let exits: Vec<_> = self
+            .total_exit_counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(waypoint, count)| format!("{waypoint}: {count}"))
+            .collect();
+        if !exits.is_empty() {
+            writeln!(f, "Arrived at waypoints: {}", exits.join(", "))?;
+        }
+        write!(
+            f,
+            "{:<12} {:>9} {:>9} {:>9} {:>9}",
+            "Time (ms)", "Mean", "Std", "P95", "Max"
+        )?;
+        let rows = [
+            ("Step", Some(&self.time_total)),
+            ("Spawn", Some(&self.time_spawn)),
+            ("Calc state", Some(&self.time_calc_state)),
+            ("Kernel", self.time_calc_state_kernel.as_ref()),
+        ];
+        for (name, stats) in rows {
+            if let Some(s) = stats {
+                write!(
+                    f,
+                    "\n{:<12} {:>9.3} {:>9.3} {:>9.3} {:>9.3}",
+                    name,
+                    s.mean * 1e3,
+                    s.std * 1e3,
+                    s.p95 * 1e3,
+                    s.max * 1e3
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mean, standard deviation, 95th percentile and max of samples
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Stats {
+    pub mean: f64,
+    pub std: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    /// Calculate statistics of samples, which are all zero if there is none.
+    pub fn new(samples: &[f64]) -> Self {
+        if samples.is_empty() {
+            return Stats::default();
+        }
+
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        // Nearest-rank percentile
+        let rank = (0.95 * n).ceil() as usize;
+
+        Stats {
+            mean,
+            std: variance.sqrt(),
+            p95: sorted[rank.saturating_sub(1)],
+            max: sorted[sorted.len() - 1],
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize)]
@@ -24,6 +183,21 @@ pub struct StepMetricsCollection {
     pub time_spawn: Vec<f64>,
     pub time_calc_state: Vec<f64>,
     pub time_calc_state_kernel: Vec<Option<f64>>,
+    pub gate_queue_lengths: Vec<Vec<i32>>,
+    pub gate_occupancies: Vec<Vec<Option<i32>>>,
+    pub spawn_queue_length: Vec<i32>,
+    pub spawned_count: Vec<i32>,
+    pub refused_count: Vec<i32>,
+    pub unplaced_count: Vec<i32>,
+    pub arrived_count: Vec<i32>,
+    pub exit_counts: Vec<Vec<i32>>,
+    pub out_of_bounds_count: Vec<i32>,
+    pub despawned_count: Vec<i32>,
+    pub neighbors: Vec<Option<NeighborStats>>,
+    pub lane_order: Vec<Option<f64>>,
+    pub forces: Vec<Option<ForceMetrics>>,
+    pub density_control: Vec<Option<DensityControlState>>,
+    pub crowd_pressure: Vec<Option<CrowdPressure>>,
 }
 
 impl StepMetricsCollection {
@@ -33,6 +207,35 @@ impl StepMetricsCollection {
         self.time_calc_state.push(metrics.time_calc_state);
         self.time_calc_state_kernel
             .push(metrics.time_calc_state_kernel);
+        self.gate_queue_lengths.push(metrics.gate_queue_lengths);
+        self.gate_occupancies.push(metrics.gate_occupancies);
+        self.spawn_queue_length.push(metrics.spawn_queue_length);
+        self.spawned_count.push(metrics.spawned_count);
+        self.refused_count.push(metrics.refused_count);
+        self.unplaced_count.push(metrics.unplaced_count);
+        self.arrived_count.push(metrics.arrived_count);
+        self.exit_counts.push(metrics.exit_counts);
+        self.out_of_bounds_count.push(metrics.out_of_bounds_count);
+        self.despawned_count.push(metrics.despawned_count);
+        self.neighbors.push(metrics.neighbors);
+        self.lane_order.push(metrics.lane_order);
+        self.forces.push(metrics.forces);
+        self.density_control.push(metrics.density_control);
+        self.crowd_pressure.push(metrics.crowd_pressure);
+    }
+
+    /// Number of recorded steps
+    pub fn len(&self) -> usize {
+        self.active_ped_count.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Time spent on the `i`-th recorded step, excluding rendering and logging. (seconds)
+    pub fn time_total(&self, i: usize) -> f64 {
+        self.time_spawn[i] + self.time_calc_state[i]
     }
 }
 
@@ -47,4 +250,380 @@ pub struct StepMetrics {
     pub time_spawn: f64,
     pub time_calc_state: f64,
     pub time_calc_state_kernel: Option<f64>,
+    /// Number of pedestrians held back at each gate.
+    pub gate_queue_lengths: Vec<i32>,
+    /// Number of pedestrians in the region of each gate with an occupancy limit, or `None` for other gates.
+    pub gate_occupancies: Vec<Option<i32>>,
+    /// Number of pedestrians waiting to be spawned.
+    pub spawn_queue_length: i32,
+    /// Number of pedestrians entering the field in the step.
+    pub spawned_count: i32,
+    /// Number of pedestrians dropped instead of spawned in the step, since the field is full.
+    /// See [`SimulatorOptions::max_pedestrians`](crate::SimulatorOptions::max_pedestrians).
+    pub refused_count: i32,
+    /// Number of pedestrians dropped instead of spawned in the step, since no position clear of obstacles was
+    /// found on their origin lines.
+    pub unplaced_count: i32,
+    /// Number of pedestrians removed on arrival at their destinations in the step.
+    pub arrived_count: i32,
+    /// Number of pedestrians removed on arrival at each waypoint in the step, indexed by waypoint.
+    pub exit_counts: Vec<i32>,
+    /// Number of pedestrians found outside the field, which usually indicates problems in the geometry.
+    pub out_of_bounds_count: i32,
+    /// Number of pedestrians removed by kill zones, [`Simulator::despawn`](crate::Simulator::despawn) or
+    /// [`SimulatorOptions::out_of_bounds`](crate::SimulatorOptions::out_of_bounds).
+    pub despawned_count: i32,
+    /// Statistics of the neighbor search. (CPU backend only)
+    pub neighbors: Option<NeighborStats>,
+    /// Lane order parameter calculated by [`lane_order`]. Recorded only if `record_lane_order` option is enabled.
+    pub lane_order: Option<f64>,
+    /// Mean magnitude of each force term. Recorded only if `record_forces` option is enabled.
+    pub forces: Option<ForceMetrics>,
+    /// State of the controller of spawn rates, if the scenario has one.
+    pub density_control: Option<DensityControlState>,
+    /// Max of [`PressureField`]. Recorded only if `record_crowd_pressure` option is enabled.
+    pub crowd_pressure: Option<CrowdPressure>,
+}
+
+impl StepMetrics {
+    /// Time spent on the step, excluding rendering and logging. (seconds)
+    pub fn time_total(&self) -> f64 {
+        self.time_spawn + self.time_calc_state
+    }
+
+    /// Format metrics of a step as a line of log.
+    pub fn to_log_line(&self, step: i32, format: LogFormat) -> String {
+        match format {
+            LogFormat::Plain => {
+                let mut line = format!(
+                    "Step: {:6}, Active pedestrians: {:6}, Spawn: {:.2} ms, Calc state: {:.2} ms",
+                    step,
+                    self.active_ped_count,
+                    self.time_spawn * 1e3,
+                    self.time_calc_state * 1e3
+                );
+                if let Some(time) = self.time_calc_state_kernel {
+                    line += &format!(", Kernel: {:.2} ms", time * 1e3);
+                }
+                if let Some(order) = self.lane_order {
+                    line += &format!(", Lane order: {order:.2}");
+                }
+                if let Some(control) = self.density_control {
+                    line += &format!(
+                        ", Controlled: {:.2}/{:.2} (rate x{:.2})",
+                        control.measured, control.setpoint, control.scale
+                    );
+                }
+                if let Some(pressure) = self.crowd_pressure {
+                    line += &format!(
+                        ", Max pressure: {:.4} /s^2 at ({:.1}, {:.1})",
+                        pressure.max, pressure.pos.x, pressure.pos.y
+                    );
+                }
+                if self.out_of_bounds_count > 0 {
+                    line += &format!(", Out of bounds: {}", self.out_of_bounds_count);
+                }
+                if self.refused_count > 0 {
+                    line += &format!(", Refused: {}", self.refused_count);
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut value = serde_json::to_value(self).unwrap();
+                value["step"] = step.into();
+                value.to_string()
+            }
+        }
+    }
+}
+
+/// Format of step metrics printed to the terminal.
+#[derive(Debug, Default, Clone, Copy)]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+/// Width of lanes in which [`lane_order`] counts pedestrians. (meters)
+pub const LANE_WIDTH: f32 = 0.5;
+
+/// Calculate the lane order parameter of bidirectional flow from positions and velocities of pedestrians.
+///
+/// The main axis of the flow is the x or y axis along which pedestrians move more, and pedestrians are grouped into
+/// lanes of [`LANE_WIDTH`] across it. The parameter is the mean of `((n_+ - n_-) / (n_+ + n_-))^2` of the lane each
+/// pedestrian belongs to, where `n_+` and `n_-` are the numbers of pedestrians moving forward and backward in the lane
+/// (Rex & Löwen, 2007). It is 1 if lanes are completely segregated and close to 0 if directions are mixed.
+///
+/// Intended for corridor scenarios, since lanes span the whole field. Returns `None` if no pedestrian is moving.
+pub fn lane_order(pedestrians: impl IntoIterator<Item = (Vec2, Vec2)>) -> Option<f64> {
+    let pedestrians: Vec<(Vec2, Vec2)> = pedestrians.into_iter().collect();
+    let flow = pedestrians
+        .iter()
+        .fold(Vec2::ZERO, |sum, (_, vel)| sum + vel.abs());
+    let (along, across) = if flow.x >= flow.y {
+        (Vec2::X, Vec2::Y)
+    } else {
+        (Vec2::Y, Vec2::X)
+    };
+
+    // Lanes are summed in a fixed order so that the result does not depend on hashing.
+    let mut lanes: BTreeMap<i32, (i32, i32)> = BTreeMap::new();
+    for (pos, vel) in pedestrians {
+        let speed = vel.dot(along);
+        if speed.abs() < 0.1 {
+            continue;
+        }
+        let lane = lanes
+            .entry((pos.dot(across) / LANE_WIDTH).floor() as i32)
+            .or_default();
+        if speed > 0.0 {
+            lane.0 += 1;
+        } else {
+            lane.1 += 1;
+        }
+    }
+
+    let total: i32 = lanes.values().map(|(n_p, n_m)| n_p + n_m).sum();
+    (total > 0).then(|| {
+        let sum: f64 = lanes
+            .values()
+            .map(|&(n_p, n_m)| ((n_p - n_m) as f64).powi(2) / (n_p + n_m) as f64)
+            .sum();
+        sum / total as f64
+    })
+}
+
+/// Unit length of the grid on which [`PressureField`] is calculated. (meters)
+pub const PRESSURE_GRID_UNIT: f32 = 0.5;
+/// Radius of the Gaussian kernel averaging density and velocities in [`PressureField`]. (meters)
+const PRESSURE_KERNEL_RADIUS: f32 = 0.7;
+/// Time constant of the moving averages over which [`PressureField`] takes the variance of local velocities. (seconds)
+const PRESSURE_TIME_CONSTANT: f32 = 2.0;
+
+/// Crowd pressure at each cell of a grid of [`PRESSURE_GRID_UNIT`], i.e. the local density times the variance of the
+/// local velocity over time (Helbing et al., 2007). Pressure above 0.02 s^-2 indicates turbulent motion in which crowd
+/// disasters happen.
+///
+/// Density and velocities are averaged with a Gaussian kernel of radius [`PRESSURE_KERNEL_RADIUS`] around the center
+/// of each cell. The variance is taken from moving averages with the time constant [`PRESSURE_TIME_CONSTANT`], which
+/// are weighted by the density so that cells start from the velocity of the first pedestrians entering them.
+#[derive(Debug, Default, Clone)]
+pub struct PressureField {
+    /// Pressure at each cell as (y, x) (s^-2)
+    pub values: Array2<f32>,
+    /// Moving averages of the density, the density times the local velocity and the density times its square
+    averages: Array2<(f32, Vec2, f32)>,
+}
+
+impl PressureField {
+    /// Pressure field of a field of `size`, which is zero until updated.
+    pub fn new(size: Vec2) -> Self {
+        let shape = (size / PRESSURE_GRID_UNIT).ceil().as_uvec2();
+        let shape = (shape.y as usize, shape.x as usize);
+        PressureField {
+            values: Array2::zeros(shape),
+            averages: Array2::from_elem(shape, (0.0, Vec2::ZERO, 0.0)),
+        }
+    }
+
+    /// Update the pressure with positions and velocities of pedestrians `dt` seconds after the last update.
+    pub fn update(&mut self, pedestrians: impl IntoIterator<Item = (Vec2, Vec2)>, dt: f32) {
+        let shape = self.values.dim();
+        // Sums of the weights and weighted velocities
+        let mut weights = Array2::<f32>::zeros(shape);
+        let mut velocities = Array2::<Vec2>::from_elem(shape, Vec2::ZERO);
+
+        // Contributions beyond 3 radii are negligible.
+        let reach = (3.0 * PRESSURE_KERNEL_RADIUS / PRESSURE_GRID_UNIT).ceil() as i32;
+        let norm = (std::f32::consts::PI * PRESSURE_KERNEL_RADIUS.powi(2)).recip();
+        for (pos, vel) in pedestrians {
+            let center = (pos / PRESSURE_GRID_UNIT).floor().as_ivec2();
+            for y in (center.y - reach).max(0)..=(center.y + reach).min(shape.0 as i32 - 1) {
+                for x in (center.x - reach).max(0)..=(center.x + reach).min(shape.1 as i32 - 1) {
+                    let index = (y as usize, x as usize);
+                    let cell = self.cell_center(index);
+                    let distance_squared = cell.distance_squared(pos);
+                    let weight = norm * (-distance_squared / PRESSURE_KERNEL_RADIUS.powi(2)).exp();
+                    weights[index] += weight;
+                    velocities[index] += weight * vel;
+                }
+            }
+        }
+
+        let alpha = 1.0 - (-dt / PRESSURE_TIME_CONSTANT).exp();
+        Zip::from(&mut self.values)
+            .and(&mut self.averages)
+            .and(&weights)
+            .and(&velocities)
+            .for_each(
+                |value, (density_mean, velocity_mean, square_mean), &density, &velocity| {
+                    // `velocity` is the density times the local velocity.
+                    let square = if density > 0.0 {
+                        velocity.length_squared() / density
+                    } else {
+                        0.0
+                    };
+                    *density_mean += alpha * (density - *density_mean);
+                    *velocity_mean += alpha * (velocity - *velocity_mean);
+                    *square_mean += alpha * (square - *square_mean);
+
+                    *value = if *density_mean > 0.0 {
+                        let mean = *velocity_mean / *density_mean;
+                        let variance =
+                            (*square_mean / *density_mean - mean.length_squared()).max(0.0);
+                        density * variance
+                    } else {
+                        0.0
+                    };
+                },
+            );
+    }
+
+    /// Center of the cell at the index (y, x)
+    pub fn cell_center(&self, (y, x): (usize, usize)) -> Vec2 {
+        (vec2(x as f32, y as f32) + 0.5) * PRESSURE_GRID_UNIT
+    }
+
+    /// Find the max pressure and where it is, or `None` if the grid is empty.
+    pub fn max(&self) -> Option<CrowdPressure> {
+        self.values
+            .indexed_iter()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(index, &value)| CrowdPressure {
+                max: value as f64,
+                pos: self.cell_center(index),
+            })
+    }
+}
+
+/// Max crowd pressure in a step, recorded if `record_crowd_pressure` option is enabled.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct CrowdPressure {
+    /// (s^-2)
+    pub max: f64,
+    /// Center of the cell where the pressure is max
+    pub pos: Vec2,
+}
+
+/// Statistics of the neighbor search, useful for tuning `neighbor_grid_unit`.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct NeighborStats {
+    /// Max number of pedestrians in a cell of the neighbor grid (0 if the grid is disabled)
+    pub max_cell_occupancy: u32,
+    /// Mean number of other pedestrians within the interaction range
+    pub mean_neighbors: f64,
+    /// Number of pairs whose distances were checked
+    pub candidates: u64,
+    /// Number of pairs within the interaction range, whose forces were calculated
+    pub interactions: u64,
+    /// Number of pedestrians frozen at rest, whose forces were skipped. See
+    /// [`SimulatorOptions::freeze_idle`](crate::SimulatorOptions::freeze_idle).
+    pub frozen: u32,
+    /// Number of pedestrians with nobody and no obstacle within the interaction range, whose forces from pedestrians
+    /// and obstacles were skipped. See [`SimulatorOptions::freeze_idle`](crate::SimulatorOptions::freeze_idle).
+    pub isolated: u32,
+}
+
+/// Mean magnitude of forces acting on pedestrians.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ForceMetrics {
+    pub destination: f64,
+    pub pedestrians: f64,
+    pub obstacles: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+    use glam::{vec2, Vec2};
+
+    use super::{lane_order, DiagnositcLog, PressureField, Stats, StepMetrics};
+
+    #[test]
+    fn test_lane_order() {
+        let forward = vec2(1.0, 0.0);
+        let backward = vec2(-1.0, 0.0);
+
+        // Two lanes each of which has a single direction
+        let segregated = [
+            (vec2(1.0, 1.2), forward),
+            (vec2(3.0, 1.3), forward),
+            (vec2(2.0, 2.2), backward),
+            (vec2(4.0, 2.3), backward),
+        ];
+        assert_float_absolute_eq!(lane_order(segregated).unwrap(), 1.0);
+
+        // Both directions mixed in each lane
+        let mixed = [
+            (vec2(1.0, 1.2), forward),
+            (vec2(3.0, 1.3), backward),
+            (vec2(2.0, 2.2), forward),
+            (vec2(4.0, 2.3), backward),
+        ];
+        assert_float_absolute_eq!(lane_order(mixed).unwrap(), 0.0);
+
+        assert_eq!(lane_order([(vec2(1.0, 1.0), vec2(0.0, 0.0))]), None);
+    }
+
+    #[test]
+    fn test_crowd_pressure() {
+        let size = vec2(10.0, 10.0);
+        // Pedestrians in a cluster, each moving in the direction given by its index and the step
+        let pressure = |direction: fn(usize, usize) -> f32| {
+            let mut field = PressureField::new(size);
+            for step in 0..30 {
+                field.update(
+                    (0..10).map(|i| {
+                        (
+                            vec2(2.0 + (i % 3) as f32 * 0.3, 7.0 + (i / 3) as f32 * 0.3),
+                            Vec2::from_angle(direction(i, step)),
+                        )
+                    }),
+                    0.1,
+                );
+            }
+            field.max().unwrap()
+        };
+
+        // A steady flow exerts no pressure, even if pedestrians in it move in various directions.
+        assert!(pressure(|_, _| 0.0).max < 1e-6);
+        assert!(pressure(|i, _| i as f32).max < 1e-6);
+
+        // The local velocity fluctuating over time exerts pressure there.
+        let turbulent = pressure(|_, step| step as f32 * 2.0);
+        assert!(turbulent.max > 0.1, "{}", turbulent.max);
+        assert!(turbulent.pos.distance(vec2(2.3, 7.3)) < 0.5);
+    }
+
+    #[test]
+    fn test_summary() {
+        let samples: Vec<f64> = (1..=100).map(|i| i as f64).collect();
+        let stats = Stats::new(&samples);
+        assert_float_absolute_eq!(stats.mean, 50.5);
+        assert_float_absolute_eq!(stats.std, 28.866, 1e-3);
+        assert_float_absolute_eq!(stats.p95, 95.0);
+        assert_float_absolute_eq!(stats.max, 100.0);
+        assert_eq!(Stats::new(&[]), Stats::default());
+
+        let mut log = DiagnositcLog::default();
+        for i in 0..10 {
+            log.push(StepMetrics {
+                active_ped_count: i,
+                time_spawn: 0.001,
+                time_calc_state: 0.002,
+                despawned_count: 1,
+                exit_counts: vec![1, i % 2],
+                ..Default::default()
+            });
+        }
+        let summary = log.summary();
+        assert_eq!(summary.total_steps, 10);
+        assert_eq!(summary.max_active_ped_count, 9);
+        assert_eq!(summary.total_despawned, 10);
+        assert_eq!(summary.total_exit_counts, [10, 5]);
+        assert_float_absolute_eq!(summary.time_total.mean, 0.003);
+        assert!(summary.time_calc_state_kernel.is_none());
+        assert!(summary.to_string().contains("Calc state"));
+    }
 }
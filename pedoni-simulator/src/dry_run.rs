@@ -0,0 +1,344 @@
+use std::fmt::{self, Display};
+
+use glam::{vec2, Vec2};
+use serde::Serialize;
+
+use crate::{
+    field::Field,
+    scenario::{PedestrianSpawnConfig, Scenario},
+};
+
+/// Report of a scenario checked without simulating it, to catch authoring mistakes quickly.
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunReport {
+    /// Shape of the field grid (y, x)
+    pub shape: (usize, usize),
+    /// Unit length of the field grid (meters)
+    pub unit: f32,
+    /// Ratio of cells free of hard obstacles over all levels
+    pub walkable_ratio: f64,
+    /// Routes from origins to destinations of each pedestrian group
+    pub routes: Vec<Route>,
+    /// Statistics of the potential map against each destination
+    pub potentials: Vec<PotentialStats>,
+}
+
+/// Route of pedestrians of a group between an origin and one of their destinations.
+#[derive(Debug, Clone, Serialize)]
+pub struct Route {
+    pub group: usize,
+    pub origin: usize,
+    pub destination: usize,
+    /// Level of the origin, on which the spawn area is checked
+    pub level: usize,
+    /// Expected spawn rate of pedestrians taking the route (persons/s), if spawned periodically
+    pub rate: Option<f64>,
+    /// Expected number of pedestrians taking the route, if spawned at once
+    pub count: Option<f64>,
+    /// Walking distance from the origin to the destination, or `None` if unreachable (meters)
+    pub distance: Option<f32>,
+    /// Ratio of the spawn area from which the destination is unreachable
+    pub blocked_ratio: f64,
+    /// Centers of cells in the spawn area from which the destination is unreachable, where pedestrians would stand
+    /// still forever, pushed against obstacles
+    pub blocked_cells: Vec<Vec2>,
+}
+
+impl Route {
+    /// Warning about the part of the spawn area from which the destination is unreachable, if any.
+    pub fn warning(&self) -> Option<String> {
+        (self.blocked_ratio > 0.0).then(|| {
+            format!(
+                "Destination {} is unreachable from {:.0}% of the spawn area of pedestrian group {} (origin {}); \
+                 obstacles block the way",
+                self.destination,
+                self.blocked_ratio * 100.0,
+                self.group,
+                self.origin
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PotentialStats {
+    pub destination: usize,
+    /// Ratio of walkable cells on all levels from which the destination is reachable
+    pub reachable_ratio: f64,
+    /// Longest walking distance to the destination from reachable cells (meters)
+    pub max_distance: f32,
+}
+
+/// Check routes and potentials of the scenario on the built fields of its levels.
+pub fn inspect(scenario: &Scenario, fields: &[Field]) -> DryRunReport {
+    let field = &fields[0];
+    let walkable_count: usize = fields
+        .iter()
+        .map(|field| field.obstacle_exist.iter().filter(|&&exist| !exist).count())
+        .sum();
+
+    let potentials = (0..scenario.destination_count().min(field.potentials.dim().0))
+        .map(|destination| {
+            let (reachable, max_distance) = fields
+                .iter()
+                .flat_map(|field| {
+                    field
+                        .potential_map(destination)
+                        .into_iter()
+                        .zip(&field.obstacle_exist)
+                        .filter(|&(&u, &exist)| !exist && field.is_reachable(u))
+                })
+                .fold((0, 0.0_f32), |(n, max), (&u, _)| (n + 1, max.max(u)));
+            PotentialStats {
+                destination,
+                reachable_ratio: reachable as f64 / walkable_count.max(1) as f64,
+                max_distance,
+            }
+        })
+        .collect();
+
+    DryRunReport {
+        shape: field.shape,
+        unit: field.unit,
+        walkable_ratio: walkable_count as f64
+            / (field.obstacle_exist.len() * fields.len()).max(1) as f64,
+        routes: routes(scenario, fields),
+        potentials,
+    }
+}
+
+/// Routes from origins to destinations of each pedestrian group.
+///
+/// The distance of a route is the potential at the nearest cell on the line of the origin waypoint, so
+/// pedestrians scattered over regions at the beginning are checked from their origins as well. Blocked cells are
+/// checked over the whole spawn area, i.e. the line of the origin waypoint or the region of the group, on the level
+/// of the origin.
+pub fn routes(scenario: &Scenario, fields: &[Field]) -> Vec<Route> {
+    scenario
+        .pedestrians
+        .iter()
+        .enumerate()
+        .flat_map(|(group, pedestrian)| {
+            let level = scenario
+                .waypoints
+                .get(pedestrian.origin)
+                .map_or(0, |wp| wp.level);
+            let field = &fields[level];
+            let cells = field.spawn_cells(scenario, &pedestrian.spawn, pedestrian.origin);
+            let shares = pedestrian.destination_shares();
+            shares.into_iter().map(move |(destination, share)| {
+                let (rate, count) = match pedestrian.spawn {
+                    PedestrianSpawnConfig::Periodic { rate } => (Some(rate * share), None),
+                    PedestrianSpawnConfig::Once { count, .. } => (None, Some(count as f64 * share)),
+                };
+                let blocked_cells: Vec<Vec2> = if destination < field.potentials.dim().0 {
+                    let map = field.potential_map(destination);
+                    cells
+                        .iter()
+                        .filter(|&&index| !field.is_reachable(map[index]))
+                        .map(|&(y, x)| (vec2(x as f32, y as f32) + 0.5) * field.unit)
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                Route {
+                    group,
+                    origin: pedestrian.origin,
+                    destination,
+                    level,
+                    rate,
+                    count,
+                    distance: scenario
+                        .waypoints
+                        .get(pedestrian.origin)
+                        .and_then(|wp| route_distance(field, destination, wp.line)),
+                    blocked_ratio: blocked_cells.len() as f64 / cells.len().max(1) as f64,
+                    blocked_cells,
+                }
+            })
+        })
+        .collect()
+}
+
+/// Min potential against `destination` over cells on `line`, or `None` if all of them are unreachable.
+///
+/// Cells are read without interpolation, which would mix in the huge potentials of obstacle cells.
+pub(crate) fn route_distance(field: &Field, destination: usize, line: [Vec2; 2]) -> Option<f32> {
+    if destination >= field.potentials.dim().0 {
+        return None;
+    }
+    let map = field.potential_map(destination);
+    let samples = ((line[1] - line[0]).length() / field.unit).ceil() as usize + 1;
+
+    (0..=samples)
+        .filter_map(|i| {
+            let pos = line[0].lerp(line[1], i as f32 / samples as f32) / field.unit;
+            map.get((pos.y as usize, pos.x as usize)).copied()
+        })
+        .min_by(f32::total_cmp)
+        .filter(|&u| field.is_reachable(u))
+}
+
+impl DryRunReport {
+    /// Routes along which some pedestrians cannot reach the destination.
+    pub fn unreachable_routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter().filter(|route| route.blocked_ratio > 0.0)
+    }
+}
+
+impl Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Field: {} x {} cells of {} m, walkable: {:.1}%",
+            self.shape.1,
+            self.shape.0,
+            self.unit,
+            self.walkable_ratio * 100.0
+        )?;
+
+        writeln!(
+            f,
+            "{:>5} {:>6} {:>11} {:>12} {:>12} {:>12}",
+            "Group", "Origin", "Destination", "Rate (/s)", "Count", "Distance (m)"
+        )?;
+        let number = |value: Option<f64>| value.map_or("-".to_string(), |x| format!("{x:.3}"));
+        for route in &self.routes {
+            writeln!(
+                f,
+                "{:>5} {:>6} {:>11} {:>12} {:>12} {:>12}",
+                route.group,
+                route.origin,
+                route.destination,
+                number(route.rate),
+                number(route.count),
+                route
+                    .distance
+                    .map_or("unreachable".to_string(), |d| format!("{d:.1}"))
+            )?;
+        }
+        let total_rate: f64 = self.routes.iter().filter_map(|r| r.rate).sum();
+        writeln!(f, "Total spawn rate: {total_rate:.3} /s")?;
+
+        write!(
+            f,
+            "{:>11} {:>14} {:>16}",
+            "Destination", "Reachable (%)", "Max distance (m)"
+        )?;
+        for stats in &self.potentials {
+            write!(
+                f,
+                "\n{:>11} {:>14.1} {:>16.1}",
+                stats.destination,
+                stats.reachable_ratio * 100.0,
+                stats.max_distance
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{
+        field::Field,
+        scenario::{
+            FieldConfig, ObstacleConfig, PedestrianConfig, PedestrianSpawnConfig, Scenario,
+            SpawnRegion, WaypointConfig,
+        },
+    };
+
+    use super::{inspect, routes};
+
+    #[test]
+    fn test_inspect() {
+        let waypoint = |x: f32| WaypointConfig {
+            line: [vec2(x, 1.0), vec2(x, 4.0)],
+            ..Default::default()
+        };
+        let group = |origin, destination| PedestrianConfig {
+            origin,
+            destination: Some(destination),
+            spawn: PedestrianSpawnConfig::Periodic { rate: 2.0 },
+            ..Default::default()
+        };
+        // The third waypoint is walled off.
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(20.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![waypoint(1.0), waypoint(9.0), waypoint(18.0)],
+            obstacles: vec![ObstacleConfig {
+                line: [vec2(15.0, -1.0), vec2(15.0, 6.0)],
+                width: 0.5,
+                soft: false,
+                ..Default::default()
+            }],
+            pedestrians: vec![group(0, 1), group(0, 2)],
+            ..Default::default()
+        };
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        let report = inspect(&scenario, &[field]);
+
+        assert_eq!(report.routes.len(), 2);
+        let distance = report.routes[0].distance.unwrap();
+        assert!((7.0..9.0).contains(&distance), "{distance}");
+        assert_eq!(report.routes[0].rate, Some(2.0));
+        assert!(report.routes[1].distance.is_none());
+        assert_eq!(report.unreachable_routes().count(), 1);
+
+        // Cells behind the wall cannot reach the first waypoint.
+        assert!(report.potentials[0].reachable_ratio < 0.9);
+        assert!(report.potentials[0].reachable_ratio > 0.5);
+    }
+
+    #[test]
+    fn test_blocked_cells() {
+        // Pedestrians are scattered on both sides of a wall, but the destination is on the right.
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(20.0, 5.0),
+                ..Default::default()
+            },
+            obstacles: vec![ObstacleConfig {
+                line: [vec2(10.0, -1.0), vec2(10.0, 6.0)],
+                width: 0.5,
+                soft: false,
+                ..Default::default()
+            }],
+            waypoints: vec![WaypointConfig {
+                line: [vec2(18.0, 1.0), vec2(18.0, 4.0)],
+                ..Default::default()
+            }],
+            pedestrians: vec![PedestrianConfig {
+                origin: 0,
+                destination: Some(0),
+                spawn: PedestrianSpawnConfig::Once {
+                    count: 10,
+                    region: Some(SpawnRegion::Polygon(vec![
+                        vec2(5.0, 1.0),
+                        vec2(15.0, 1.0),
+                        vec2(15.0, 4.0),
+                        vec2(5.0, 4.0),
+                    ])),
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+
+        let routes = routes(&scenario, &[field]);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].level, 0);
+        // The origin waypoint itself is reachable.
+        assert!(routes[0].distance.is_some());
+        // Cells inside the outline of the wall are enclosed as well.
+        assert!((0.5..0.6).contains(&routes[0].blocked_ratio));
+        assert!(routes[0].blocked_cells.iter().all(|pos| pos.x < 10.5));
+        assert!(routes[0].warning().is_some());
+    }
+}
@@ -0,0 +1,47 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+
+/// Error in preparing a simulation, returned instead of panicking so that embedding applications can handle it.
+#[derive(Debug, Error)]
+pub enum SimulatorError {
+    /// Scenario refers to objects which do not exist or has geometries which cannot be simulated. See
+    /// [`Scenario::validate`](crate::scenario::Scenario::validate).
+    #[error("invalid scenario: {0:#}")]
+    Scenario(anyhow::Error),
+    /// Geometry of the scenario cannot be rasterized, e.g. because of non-finite coordinates.
+    #[error("failed to rasterize the field: {0}")]
+    Rasterize(#[from] geo_rasterize::RasterizeError),
+    /// Line of a waypoint has the same ends, so its area is undefined.
+    #[error("line of waypoint {index} has the same ends")]
+    DegenerateWaypoint { index: usize },
+    /// Potentials or distances became NaN, e.g. because of a zero grid unit.
+    #[error("potential of the field is NaN")]
+    NanPotential(#[from] ordered_float::FloatIsNan),
+    /// Scenario uses a behavior which the kernels of the GPU backend do not implement.
+    #[error("{0} is not supported on the GPU backend")]
+    UnsupportedOnGpu(&'static str),
+    /// OpenCL failed to select a device, build the kernels, allocate buffers or run a step, e.g. because the device
+    /// was lost or ran out of memory.
+    #[error("OpenCL error: {0}")]
+    Gpu(#[from] ocl::Error),
+    /// Dedicated thread pool cannot be created.
+    #[error("failed to build the thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    /// Floor plan of the field cannot be read or decoded.
+    #[error("failed to read the floor plan {}: {source}", path.display())]
+    FloorPlan {
+        path: PathBuf,
+        #[source]
+        source: png::DecodingError,
+    },
+    /// Source of the OpenCL kernels cannot be read.
+    #[error("failed to read the kernel source {}: {source}", path.display())]
+    KernelSource {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, SimulatorError>;
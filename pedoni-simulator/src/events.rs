@@ -0,0 +1,86 @@
+use glam::Vec2;
+
+use crate::diagnostic::StepMetrics;
+
+/// Event emitted by the simulator.
+///
+/// Metrics are not boxed, since only one [`Event::StepCompleted`] is sent per step.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A pedestrian entered the field.
+    PedestrianSpawned { id: usize, pos: Vec2, origin: usize },
+    /// A pedestrian arrived at its destination and left the field.
+    PedestrianArrived {
+        id: usize,
+        /// Time from spawning to arrival (seconds)
+        travel_time: f64,
+    },
+    /// A pedestrian was removed by [`Simulator::despawn`](crate::Simulator::despawn), a kill zone or leaving the field.
+    PedestrianDespawned {
+        id: usize,
+        /// Index of the kill zone in [`Scenario::kill_zones`](crate::scenario::Scenario::kill_zones), if removed by one
+        kill_zone: Option<usize>,
+    },
+    /// A pedestrian passed through a gate.
+    GatePassed { id: usize, gate: usize },
+    /// A step was completed.
+    StepCompleted { step: i32, metrics: StepMetrics },
+}
+
+/// Distributor of events to subscribers through channels.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Vec<flume::Sender<Event>>,
+}
+
+impl EventBus {
+    /// Register a new subscriber. Events are sent until the receiver is dropped.
+    pub fn subscribe(&mut self) -> flume::Receiver<Event> {
+        let (sender, receiver) = flume::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Whether any subscriber is registered. Events need not be built otherwise.
+    pub fn is_active(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        let Some((last, others)) = self.subscribers.split_last() else {
+            return;
+        };
+        let mut disconnected = false;
+        for subscriber in others {
+            disconnected |= subscriber.send(event.clone()).is_err();
+        }
+        disconnected |= last.send(event).is_err();
+
+        if disconnected {
+            self.subscribers
+                .retain(|subscriber| !subscriber.is_disconnected());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventBus};
+
+    #[test]
+    fn test_unsubscribe() {
+        let mut bus = EventBus::default();
+        let receiver_1 = bus.subscribe();
+        let receiver_2 = bus.subscribe();
+
+        drop(receiver_1);
+        bus.emit(Event::GatePassed { id: 0, gate: 0 });
+        assert!(bus.is_active());
+        assert_eq!(receiver_2.len(), 1);
+
+        drop(receiver_2);
+        bus.emit(Event::GatePassed { id: 0, gate: 0 });
+        assert!(!bus.is_active());
+    }
+}
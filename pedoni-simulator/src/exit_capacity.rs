@@ -0,0 +1,171 @@
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+use crate::scenario::Scenario;
+
+/// Flows achieved through exits in a run, checked against the capacity required by a design code.
+///
+/// Exits are waypoints annotated with [`WaypointConfig::exit_width`](crate::scenario::WaypointConfig::exit_width),
+/// and flows are counted from pedestrians removed on arrival at them.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExitCapacityReport {
+    /// Max flow per effective width allowed by the code (persons/m/s)
+    pub max_specific_flow: f64,
+    pub exits: Vec<ExitFlow>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExitFlow {
+    pub waypoint: usize,
+    /// Clear width minus the boundary layers on both sides (meters)
+    pub effective_width: f32,
+    /// Number of pedestrians who left through the exit
+    pub total: i32,
+    /// Flow per effective width from the first departure to the last one (persons/m/s), or `None` if less than two
+    pub mean_specific_flow: Option<f64>,
+    /// Max flow per effective width over the window of [`ExitCapacityConfig::window`](crate::scenario::ExitCapacityConfig::window)
+    /// (persons/m/s), or `None` if the run is shorter than the window
+    pub peak_specific_flow: Option<f64>,
+}
+
+impl ExitFlow {
+    /// Whether the peak flow is within the capacity. Exits are not loaded beyond their capacity in the code's
+    /// calculation of evacuation times, which underestimates queues otherwise.
+    pub fn passed(&self, max_specific_flow: f64) -> bool {
+        self.peak_specific_flow
+            .is_none_or(|flow| flow <= max_specific_flow)
+    }
+}
+
+impl ExitCapacityReport {
+    pub fn passed(&self) -> bool {
+        self.exits
+            .iter()
+            .all(|exit| exit.passed(self.max_specific_flow))
+    }
+}
+
+/// Measure flows through exits of the scenario from the numbers of pedestrians leaving through each waypoint in each
+/// step, i.e. [`StepMetricsCollection::exit_counts`](crate::diagnostic::StepMetricsCollection::exit_counts).
+pub fn report(scenario: &Scenario, exit_counts: &[Vec<i32>]) -> ExitCapacityReport {
+    let config = &scenario.exit_capacity;
+    let window = ((config.window / 0.1).round() as usize).max(1);
+
+    let exits = scenario
+        .waypoints
+        .iter()
+        .enumerate()
+        .filter_map(|(waypoint, wp)| {
+            let effective_width = (wp.exit_width? - 2.0 * config.boundary_layer()).max(0.01);
+            let counts: Vec<i32> = exit_counts
+                .iter()
+                .map(|counts| counts.get(waypoint).copied().unwrap_or(0))
+                .collect();
+            let total = counts.iter().sum();
+
+            let first = counts.iter().position(|&c| c > 0);
+            let last = counts.iter().rposition(|&c| c > 0);
+            let mean_specific_flow = match (first, last) {
+                (Some(first), Some(last)) if total >= 2 => {
+                    let duration = (last - first + 1) as f64 * 0.1;
+                    Some(total as f64 / duration / effective_width as f64)
+                }
+                _ => None,
+            };
+            let peak_specific_flow = counts
+                .windows(window)
+                .map(|w| w.iter().sum::<i32>())
+                .max()
+                .map(|count| count as f64 / (window as f64 * 0.1) / effective_width as f64);
+
+            Some(ExitFlow {
+                waypoint,
+                effective_width,
+                total,
+                mean_specific_flow,
+                peak_specific_flow,
+            })
+        })
+        .collect();
+
+    ExitCapacityReport {
+        max_specific_flow: config.max_specific_flow,
+        exits,
+    }
+}
+
+impl Display for ExitCapacityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>8} {:>9} {:>5} {:>12} {:>12} {:>6}",
+            "Waypoint", "Width (m)", "Total", "Mean (/m/s)", "Peak (/m/s)", "Result"
+        )?;
+        let format_flow = |flow: Option<f64>| flow.map_or("-".into(), |flow| format!("{flow:.2}"));
+        for exit in &self.exits {
+            write!(
+                f,
+                "\n{:>8} {:>9.2} {:>5} {:>12} {:>12} {:>6}",
+                exit.waypoint,
+                exit.effective_width,
+                exit.total,
+                format_flow(exit.mean_specific_flow),
+                format_flow(exit.peak_specific_flow),
+                if exit.passed(self.max_specific_flow) {
+                    "pass"
+                } else {
+                    "FAIL"
+                }
+            )?;
+        }
+        write!(
+            f,
+            "\nCapacity: {:.2} persons/m/s per effective width",
+            self.max_specific_flow
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::scenario::{Scenario, WaypointConfig};
+
+    use super::report;
+
+    #[test]
+    fn test_report() {
+        let waypoint = |exit_width| WaypointConfig {
+            line: [vec2(0.0, 0.0), vec2(0.0, 1.0)],
+            exit_width,
+            ..Default::default()
+        };
+        let mut scenario = Scenario {
+            waypoints: vec![waypoint(None), waypoint(Some(1.3)), waypoint(Some(2.3))],
+            ..Default::default()
+        };
+        scenario.exit_capacity.window = 1.0;
+        // 1 person/step through the first exit for 2 s, and 1 person every 2 steps through the second one
+        let exit_counts: Vec<Vec<i32>> = (0..20)
+            .map(|step| vec![1, 1, (step % 2 == 0) as i32])
+            .collect();
+        let report = report(&scenario, &exit_counts);
+
+        assert_eq!(report.exits.len(), 2);
+        let exit = &report.exits[0];
+        assert_eq!((exit.waypoint, exit.total), (1, 20));
+        assert!((exit.effective_width - 1.0).abs() < 1e-6);
+        assert!((exit.mean_specific_flow.unwrap() - 10.0).abs() < 1e-6);
+        assert!((exit.peak_specific_flow.unwrap() - 10.0).abs() < 1e-6);
+        assert!(!exit.passed(report.max_specific_flow));
+
+        let exit = &report.exits[1];
+        assert!((exit.peak_specific_flow.unwrap() - 2.5).abs() < 1e-6);
+        assert!(!report.passed());
+
+        scenario.exit_capacity.max_specific_flow = 12.0;
+        assert!(super::report(&scenario, &exit_counts).passed());
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+
+use glam::Vec2;
+
+use crate::{
+    field::Field,
+    models::PedestrianModel,
+    routing::Routing,
+    scenario::{ExitChoiceConfig, Scenario},
+    util,
+};
+
+/// Adaptive choice of exits in waypoint groups with [`crate::scenario::WaypointGroupConfig::exit_choice`].
+///
+/// Pedestrians heading for such a group follow its potential to the nearest member until the first comparison,
+/// after which their destination is the chosen member.
+#[derive(Debug, Default, Clone)]
+pub struct ExitChoice {
+    groups: Vec<AdaptiveGroup>,
+    /// Index in `groups` of each pedestrian ID choosing among its members
+    choosers: HashMap<usize, usize>,
+}
+
+#[derive(Debug, Clone)]
+struct AdaptiveGroup {
+    /// Destination ID of the group, following waypoints
+    destination: usize,
+    members: Vec<usize>,
+    config: ExitChoiceConfig,
+    /// Time of the next comparison (seconds)
+    next_time: f64,
+}
+
+impl ExitChoice {
+    pub fn new(scenario: &Scenario) -> Self {
+        let groups = scenario
+            .waypoint_groups
+            .iter()
+            .enumerate()
+            .filter_map(|(i, group)| {
+                Some(AdaptiveGroup {
+                    destination: scenario.waypoints.len() + i,
+                    members: group.members.clone(),
+                    config: group.exit_choice.clone()?,
+                    next_time: 0.0,
+                })
+            })
+            .collect();
+
+        ExitChoice {
+            groups,
+            choosers: HashMap::new(),
+        }
+    }
+
+    /// Let pedestrians of groups due at the given time (seconds) compare open members and switch their destinations.
+    pub fn update(
+        &mut self,
+        scenario: &Scenario,
+        fields: &[Field],
+        routing: &Routing,
+        model: &mut dyn PedestrianModel,
+        time: f64,
+    ) {
+        let due: Vec<usize> = (0..self.groups.len())
+            .filter(|&i| self.groups[i].next_time <= time)
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        for &i in &due {
+            let group = &mut self.groups[i];
+            group.next_time = time + group.config.interval;
+        }
+
+        let mut pedestrians = Vec::new();
+        model.for_each_pedestrian(&mut |p| pedestrians.push((p.id, p.pos, p.level, p.destination)));
+
+        // Register new pedestrians heading for the groups, and forget ones which left or were sent elsewhere.
+        let mut alive = HashSet::new();
+        for &(id, _, _, destination) in &pedestrians {
+            alive.insert(id);
+            match self.choosers.get(&id) {
+                Some(&i) if !self.groups[i].members.contains(&destination) => {
+                    self.choosers.remove(&id);
+                }
+                None => {
+                    if let Some(i) = self
+                        .groups
+                        .iter()
+                        .position(|g| g.destination == destination)
+                    {
+                        self.choosers.insert(id, i);
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.choosers.retain(|id, _| alive.contains(id));
+
+        let mut switches = HashMap::new();
+        for i in due {
+            let group = &self.groups[i];
+            let config = &group.config;
+            let is_queueing = |pos: Vec2, level: usize, member: usize| {
+                let waypoint = &scenario.waypoints[member];
+                waypoint.level == level
+                    && util::distance_from_line(pos, waypoint.line).length()
+                        <= config.queue_radius()
+            };
+            let queue_lengths: Vec<usize> = group
+                .members
+                .iter()
+                .map(|&member| {
+                    pedestrians
+                        .iter()
+                        .filter(|&&(_, pos, level, _)| is_queueing(pos, level, member))
+                        .count()
+                })
+                .collect();
+
+            for &(id, pos, level, destination) in &pedestrians {
+                if self.choosers.get(&id) != Some(&i) {
+                    continue;
+                }
+
+                let cost = |k: usize| {
+                    let member = group.members[k];
+                    if !routing.is_open(member) {
+                        return f32::INFINITY;
+                    }
+                    // Pedestrians do not wait for themselves.
+                    let queue = queue_lengths[k] - is_queueing(pos, level, member) as usize;
+                    fields[level].get_potential(member, pos)
+                        + config.congestion_weight() * queue as f32
+                };
+                let Some((best, best_cost)) = (0..group.members.len())
+                    .map(|k| (k, cost(k)))
+                    .filter(|(_, cost)| cost.is_finite())
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                else {
+                    continue;
+                };
+                let current_cost = match group.members.iter().position(|&m| m == destination) {
+                    Some(k) => cost(k),
+                    None => f32::INFINITY,
+                };
+                if best_cost < current_cost - config.threshold() {
+                    switches.insert(id, group.members[best]);
+                }
+            }
+        }
+
+        if !switches.is_empty() {
+            model.reroute_pedestrians(&mut |id, _| switches.get(&id).copied());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{
+        field::Field,
+        models::{Pedestrian, PedestrianModel, SocialForceModel},
+        routing::Routing,
+        scenario::{ExitChoiceConfig, FieldConfig, Scenario, WaypointConfig, WaypointGroupConfig},
+        SimulatorOptions,
+    };
+
+    use super::ExitChoice;
+
+    #[test]
+    fn test_exit_choice() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(20.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![
+                WaypointConfig {
+                    line: [vec2(1.0, 1.0), vec2(1.0, 4.0)],
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(19.0, 1.0), vec2(19.0, 4.0)],
+                    ..Default::default()
+                },
+            ],
+            waypoint_groups: vec![WaypointGroupConfig {
+                members: vec![0, 1],
+                exit_choice: Some(ExitChoiceConfig::default()),
+            }],
+            ..Default::default()
+        };
+        let options = SimulatorOptions::default();
+        let fields = [Field::from_scenario(&scenario, 0.25).unwrap()];
+        let routing = Routing::new(&options, &scenario);
+
+        // A queue of 10 pedestrians in front of the left exit
+        let mut pedestrians: Vec<_> = (0..10)
+            .map(|i| Pedestrian {
+                id: i,
+                pos: vec2(3.5, 1.0 + i as f32 * 0.3),
+                destination: 0,
+                ..Default::default()
+            })
+            .collect();
+        // Nearer to the left exit but outside the queue, and at the end of the queue
+        for (id, x) in [(100, 8.0), (101, 3.8)] {
+            pedestrians.push(Pedestrian {
+                id,
+                pos: vec2(x, 2.5),
+                destination: 2,
+                ..Default::default()
+            });
+        }
+        let mut model = SocialForceModel::new(&options, &scenario, &fields).unwrap();
+        model.spawn_pedestrians(&fields, &routing, pedestrians);
+
+        let mut exit_choice = ExitChoice::new(&scenario);
+        exit_choice.update(&scenario, &fields, &routing, &mut model, 0.0);
+
+        let destination = |id| {
+            model
+                .list_pedestrians()
+                .into_iter()
+                .find(|p| p.id == id)
+                .unwrap()
+                .destination
+        };
+        // 7 m + 11 persons against 11 m
+        assert_eq!(destination(100), 1);
+        // 3 m + 10 persons against 15 m, not counting itself
+        assert_eq!(destination(101), 0);
+        assert_eq!(destination(0), 0);
+    }
+}
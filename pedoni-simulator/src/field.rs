@@ -1,23 +1,61 @@
-use core::f32;
-use std::{cmp::Reverse, collections::BinaryHeap};
+use core::f32::{self, consts::SQRT_2};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    hash::Hasher,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use geo::LineString;
 use geo_rasterize::{BinaryBuilder, LabelBuilder};
-use glam::Vec2;
-use ndarray::{s, Array2};
+use glam::{vec2, Vec2};
+use log::{info, warn};
+use ndarray::{s, stack, Array2, Array3, ArrayView2, ArrayViewMut2, Axis, Zip};
 use ordered_float::NotNan;
-use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use super::{
-    scenario::{ObstacleConfig, Scenario, WaypointConfig},
+    error::{Result, SimulatorError},
+    floor_plan::ObstacleMask,
+    scenario::{
+        LinkConfig, ObstacleConfig, PedestrianSpawnConfig, Scenario, SpawnRegion, WaypointConfig,
+        SOFT_OBSTACLE_SLOWNESS,
+    },
     util::{self, Index},
 };
 
+/// Cost of walking through hard obstacles relative to free space, which is so high that routes cross them only if
+/// there is no way around.
+const HARD_OBSTACLE_SLOWNESS: f32 = 1e6;
+
 pub struct FieldBuilder {
     unit: f32,
+    fmm: FmmOptions,
+    progress: Option<Box<dyn Fn(FieldProgress) + Send + Sync>>,
+    cache_dir: Option<PathBuf>,
+    /// Obstacles rasterized in advance, applied to the first level in addition to the obstacles of the scenario
+    masks: Vec<ObstacleMask>,
     shape: (usize, usize),
     obstacle_exist: Array2<bool>,
+    soft_obstacle_exist: Array2<bool>,
     potential_maps: Vec<Array2<f32>>,
+    /// Number of maps calculated so far over all levels
+    done: AtomicUsize,
+    /// Number of maps to calculate over all levels
+    total: usize,
+}
+
+/// Progress of building a field, reported to [`FieldBuilder::on_progress`].
+#[derive(Debug, Clone, Copy)]
+pub struct FieldProgress {
+    /// Number of maps calculated so far
+    pub done: usize,
+    /// Number of maps to calculate: the distance maps and a potential map for each waypoint on each level
+    pub total: usize,
 }
 
 impl FieldBuilder {
@@ -33,89 +71,343 @@ impl FieldBuilder {
 
         FieldBuilder {
             unit,
+            fmm: FmmOptions::default(),
+            progress: None,
+            cache_dir: None,
+            masks: Vec::new(),
             shape,
             obstacle_exist,
+            soft_obstacle_exist: Array2::from_elem(shape, false),
             potential_maps: Vec::new(),
+            done: AtomicUsize::new(0),
+            total: 0,
         }
     }
 
-    fn add_obstacle(&mut self, obstacle: &ObstacleConfig) {
-        let vertices = util::line_with_width(obstacle.line, obstacle.width);
-        let mut shape = LineString::from(
-            vertices
-                .into_iter()
-                .map(|v| {
-                    let v = v / self.unit;
-                    (v.x, v.y)
-                })
-                .collect::<Vec<_>>(),
-        );
-        shape.close();
+    /// Set options of the fast marching method used to calculate potentials.
+    pub fn fmm_options(mut self, options: FmmOptions) -> Self {
+        self.fmm = options;
+        self
+    }
 
-        let mut rasterizer = BinaryBuilder::new()
-            .width(self.shape.1)
-            .height(self.shape.0)
-            .build()
-            .unwrap();
-        rasterizer.rasterize(&shape).unwrap();
-        let grid = rasterizer.finish();
+    /// Call `f` each time a map is calculated. It may be called from multiple threads.
+    pub fn on_progress(mut self, f: impl Fn(FieldProgress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Box::new(f));
+        self
+    }
 
-        self.obstacle_exist.zip_mut_with(&grid, |a, b| *a |= b);
+    /// Save built fields in `dir` and reuse them for scenarios with the same geometry and options.
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
     }
 
-    fn add_waypoint(&mut self, waypoint: &WaypointConfig) {
-        let vertices = util::line_with_width(waypoint.line, waypoint.width);
-        let mut shape = LineString::from(
-            vertices
-                .into_iter()
-                .map(|v| {
-                    let v = v / self.unit;
-                    (v.x, v.y)
-                })
-                .collect::<Vec<_>>(),
+    /// Add hard obstacles rasterized in advance to the first level, e.g. from a floor plan by [`ObstacleMask::from_png`].
+    pub fn obstacle_mask(mut self, mask: ObstacleMask) -> Self {
+        self.masks.push(mask);
+        self
+    }
+
+    /// Build a field for each level of the scenario, with its obstacles and waypoints, and propagate potentials through
+    /// the links between them. See [`Scenario::levels`].
+    ///
+    /// The floor plan of the scenario is read here if [`FieldConfig::floor_plan`](crate::scenario::FieldConfig::floor_plan)
+    /// is given, which is applied to the first level.
+    pub fn build_scenario(mut self, scenario: &Scenario) -> Result<Vec<Field>> {
+        if let Some(plan) = &scenario.field.floor_plan {
+            let mask = ObstacleMask::load(&plan.path, plan.scale, plan.threshold)?;
+            self.masks.push(mask);
+        }
+
+        let levels = scenario.level_count();
+        let has_soft_obstacles = |level| {
+            scenario
+                .obstacles
+                .iter()
+                .any(|obs| obs.soft && obs.level == level)
+        };
+        self.total = (0..levels)
+            .map(|level| scenario.waypoints.len() + 1 + has_soft_obstacles(level) as usize)
+            .sum();
+
+        let cache_path = self.cache_dir.as_ref().map(|dir| {
+            let key = cache_key(scenario, &self.masks, self.unit, self.fmm);
+            dir.join(format!("{key:016x}.bin"))
+        });
+        if let Some(path) = cache_path.as_ref().filter(|path| path.exists()) {
+            match load_cache(path) {
+                Ok(fields) => {
+                    info!("Loaded the field from {}", path.display());
+                    if let Some(f) = &self.progress {
+                        let total = self.total;
+                        f(FieldProgress { done: total, total });
+                    }
+                    return Ok(fields);
+                }
+                Err(err) => warn!("Failed to load the field from {}: {err}", path.display()),
+            }
+        }
+
+        let (obstacle_exist, soft_obstacle_exist) = (
+            self.obstacle_exist.clone(),
+            self.soft_obstacle_exist.clone(),
         );
-        shape.close();
 
-        let mut rasterizer = LabelBuilder::background(f32::MAX)
-            .width(self.shape.1)
-            .height(self.shape.0)
-            .build()
-            .unwrap();
-        rasterizer.rasterize(&shape, 0.0).unwrap();
-        let grid = rasterizer.finish();
+        let mut fields = Vec::with_capacity(levels);
+        for level in 0..levels {
+            self.obstacle_exist = obstacle_exist.clone();
+            self.soft_obstacle_exist = soft_obstacle_exist.clone();
+            let obstacles: Vec<_> = scenario
+                .obstacles
+                .iter()
+                .filter(|obs| obs.level == level)
+                .cloned()
+                .collect();
+            self.add_obstacles(&obstacles)?;
+            if level == 0 {
+                for mask in &self.masks {
+                    mask.rasterize(&mut self.obstacle_exist, self.unit);
+                }
+            }
+            self.add_waypoints(&scenario.waypoints, level)?;
+            fields.push(self.build()?);
+        }
+
+        if !scenario.links.is_empty() {
+            apply_links(&mut fields, scenario);
+        }
+        if !scenario.waypoint_groups.is_empty() {
+            for field in &mut fields {
+                field.add_waypoint_groups(scenario);
+            }
+        }
 
-        self.potential_maps.push(grid);
+        if let Some(path) = &cache_path {
+            match save_cache(path, &fields) {
+                Ok(()) => info!("Saved the field to {}", path.display()),
+                Err(err) => warn!("Failed to save the field to {}: {err}", path.display()),
+            }
+        }
+        Ok(fields)
     }
 
-    fn build(self) -> Field {
-        let FieldBuilder {
-            unit,
-            shape,
-            obstacle_exist,
-            mut potential_maps,
-        } = self;
+    /// Vertices of a line with width in grid coordinates.
+    fn outline(&self, line: [Vec2; 2], width: f32) -> Vec<Vec2> {
+        util::line_with_width(line, width)
+            .into_iter()
+            .map(|v| v / self.unit)
+            .collect()
+    }
+
+    fn add_obstacles(&mut self, obstacles: &[ObstacleConfig]) -> Result<()> {
+        // Each polygon of obstacles is rasterized in parallel within its bounding box, which is much smaller than the
+        // field.
+        let polygons: Vec<_> = obstacles
+            .iter()
+            .flat_map(|obstacle| {
+                util::polyline_with_width(obstacle.vertices(), obstacle.width)
+                    .into_iter()
+                    .map(|polygon| (obstacle.soft, polygon))
+            })
+            .collect();
+        let grids: Vec<_> = polygons
+            .into_par_iter()
+            .map(|(soft, polygon)| {
+                let vertices: Vec<_> = polygon.into_iter().map(|v| v / self.unit).collect();
+                let min = vertices.iter().copied().fold(Vec2::INFINITY, Vec2::min);
+                let max = vertices.iter().copied().fold(Vec2::NEG_INFINITY, Vec2::max);
+                // Cells on the edges of the box are touched by the outline, so they are included with a margin.
+                let min = (min.floor() - 1.0).max(Vec2::ZERO).as_uvec2();
+                let max = (max.ceil() + 1.0)
+                    .min(vec2(self.shape.1 as f32, self.shape.0 as f32))
+                    .as_uvec2();
+                if min.x >= max.x || min.y >= max.y {
+                    return Ok(None);
+                }
+
+                let offset = min.as_vec2();
+                let mut shape = LineString::from(
+                    vertices
+                        .into_iter()
+                        .map(|v| (v.x - offset.x, v.y - offset.y))
+                        .collect::<Vec<_>>(),
+                );
+                shape.close();
+
+                let mut rasterizer = BinaryBuilder::new()
+                    .width((max.x - min.x) as usize)
+                    .height((max.y - min.y) as usize)
+                    .build()?;
+                rasterizer.rasterize(&shape)?;
+                Ok(Some((soft, min, max, rasterizer.finish())))
+            })
+            .collect::<Result<_>>()?;
+
+        for (soft, min, max, grid) in grids.into_iter().flatten() {
+            let (x, y) = (
+                min.x as usize..max.x as usize,
+                min.y as usize..max.y as usize,
+            );
+            let target = if soft {
+                &mut self.soft_obstacle_exist
+            } else {
+                &mut self.obstacle_exist
+            };
+            target
+                .slice_mut(s![y, x])
+                .zip_mut_with(&grid, |a, b| *a |= b);
+        }
+        Ok(())
+    }
+
+    /// Add potential maps of all waypoints, which are unreachable on levels other than their own until links are
+    /// applied.
+    fn add_waypoints(&mut self, waypoints: &[WaypointConfig], level: usize) -> Result<()> {
+        // The direction of a line without length is undefined, which would make its outline NaN.
+        if let Some(index) = waypoints
+            .iter()
+            .position(|waypoint| waypoint.line[0] == waypoint.line[1])
+        {
+            return Err(SimulatorError::DegenerateWaypoint { index });
+        }
+
+        let grids: Vec<_> = waypoints
+            .par_iter()
+            .map(|waypoint| {
+                if waypoint.level != level {
+                    return Ok(Array2::from_elem(self.shape, f32::MAX));
+                }
+                let mut shape = LineString::from(
+                    self.outline(waypoint.line, waypoint.width)
+                        .into_iter()
+                        .map(|v| (v.x, v.y))
+                        .collect::<Vec<_>>(),
+                );
+                shape.close();
+
+                let mut rasterizer = LabelBuilder::background(f32::MAX)
+                    .width(self.shape.1)
+                    .height(self.shape.0)
+                    .build()?;
+                rasterizer.rasterize(&shape, 0.0)?;
+                Ok(rasterizer.finish())
+            })
+            .collect::<Result<_>>()?;
+
+        self.potential_maps.extend(grids);
+        Ok(())
+    }
+
+    /// Build the field of a level from the obstacles and waypoints added to the builder, which are taken from it.
+    fn build(&mut self) -> Result<Field> {
+        let (unit, fmm, shape) = (self.unit, self.fmm, self.shape);
+        let obstacle_exist = std::mem::take(&mut self.obstacle_exist);
+        let soft_obstacle_exist = std::mem::take(&mut self.soft_obstacle_exist);
+        let potential_maps = std::mem::take(&mut self.potential_maps);
+
+        let has_soft_obstacles = soft_obstacle_exist.iter().any(|&soft| soft);
+        let report = || {
+            let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(f) = &self.progress {
+                f(FieldProgress {
+                    done,
+                    total: self.total,
+                });
+            }
+        };
 
         let mut distance_map = obstacle_exist.map(|&obs| if obs { 0.0 } else { 1e24 });
-        apply_fmm(&mut distance_map, &Array2::from_elem(shape, unit));
+        apply_fmm(
+            distance_map.view_mut(),
+            &Array2::from_elem(shape, unit),
+            fmm,
+        )?;
+        report();
+        let distance_grad_map = Array2::from_shape_fn(shape, |(y, x)| {
+            util::sobel_filter(&distance_map, vec2(x as f32, y as f32))
+        });
+
+        // Soft obstacles have their own distance map, since they repel pedestrians differently.
+        let (soft_distance_map, soft_distance_grad_map) = if has_soft_obstacles {
+            let mut map = soft_obstacle_exist.map(|&obs| if obs { 0.0 } else { 1e24 });
+            apply_fmm(map.view_mut(), &Array2::from_elem(shape, unit), fmm)?;
+            report();
+            let grad_map = Array2::from_shape_fn(shape, |(y, x)| {
+                util::sobel_filter(&map, vec2(x as f32, y as f32))
+            });
+            (map, grad_map)
+        } else {
+            Default::default()
+        };
+
+        let mut potentials = if potential_maps.is_empty() {
+            Array3::zeros((0, shape.0, shape.1))
+        } else {
+            let views: Vec<_> = potential_maps.iter().map(Array2::view).collect();
+            stack(Axis(0), &views).unwrap()
+        };
 
         // let slowness = distance_from_obstacle.map(|&d| (1e4 * (-10.0 * d).exp() + 1.0) * unit);
-        let slowness = obstacle_exist.map(|&d| unit * if d { 1e6 } else { 1.0 });
-        potential_maps.par_iter_mut().for_each(|potential_map| {
-            apply_fmm(potential_map, &slowness);
-        });
+        let slowness = Zip::from(&obstacle_exist)
+            .and(&soft_obstacle_exist)
+            .map_collect(|&hard, &soft| {
+                unit * if hard {
+                    HARD_OBSTACLE_SLOWNESS
+                } else if soft {
+                    SOFT_OBSTACLE_SLOWNESS
+                } else {
+                    1.0
+                }
+            });
+        potentials
+            .outer_iter_mut()
+            .into_par_iter()
+            .try_for_each(|potential_map| {
+                apply_fmm(potential_map, &slowness, fmm)?;
+                report();
+                Ok::<_, SimulatorError>(())
+            })?;
 
-        Field {
+        Ok(Field {
             unit,
             shape,
             obstacle_exist,
             distance_map,
-            potential_maps,
-        }
+            distance_grad_map,
+            soft_obstacle_exist,
+            soft_distance_map,
+            soft_distance_grad_map,
+            potentials,
+        })
     }
 }
 
+/// Options of the fast marching method which trade build time for accuracy of potentials.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FmmOptions {
+    /// Use second-order upwind differences where two accepted cells line up, which makes potentials smoother.
+    pub second_order: bool,
+    /// Also solve along diagonals (8-neighborhood), which reduces artifacts along the grid axes.
+    pub diagonal: bool,
+}
+
+const NEIGHBORS: [(i32, i32); 8] = [
+    (1, 0),
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 1),
+    (-1, -1),
+    (1, -1),
+    (-1, 1),
+];
+
 /// Calculate potential against a waypoint using [fast marching method](https://en.wikipedia.org/wiki/Fast_marching_method).    
-fn apply_fmm(potential: &mut Array2<f32>, f: &Array2<f32>) {
+fn apply_fmm(
+    mut potential: ArrayViewMut2<f32>,
+    f: &Array2<f32>,
+    options: FmmOptions,
+) -> Result<()> {
     type Float = Reverse<NotNan<f32>>;
 
     assert_eq!(potential.dim(), f.dim());
@@ -123,7 +415,12 @@ fn apply_fmm(potential: &mut Array2<f32>, f: &Array2<f32>) {
     let shape = potential.dim();
     let mut accepted = Array2::from_elem(shape, false);
     let mut queue = BinaryHeap::<(Float, Index)>::new();
-    let float = |x: f32| Reverse(NotNan::new(x).unwrap());
+    let float = |x: f32| NotNan::new(x).map(Reverse);
+    let neighbors = if options.diagonal {
+        &NEIGHBORS[..]
+    } else {
+        &NEIGHBORS[..4]
+    };
 
     for y in 0..shape.0 {
         for x in 0..shape.1 {
@@ -131,13 +428,17 @@ fn apply_fmm(potential: &mut Array2<f32>, f: &Array2<f32>) {
             if potential[ix] == 0.0 {
                 accepted[ix] = true;
 
-                for (j, i) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                for &(i, j) in neighbors {
                     let ix = ix.add(i, j);
                     if let Some(potential) = potential.get_mut(ix) {
                         if *potential != 0.0 {
-                            let u = f[ix];
-                            *potential = u;
-                            queue.push((float(u), ix));
+                            let u = if i != 0 && j != 0 {
+                                f[ix] * SQRT_2
+                            } else {
+                                f[ix]
+                            };
+                            *potential = potential.min(u);
+                            queue.push((float(*potential)?, ix));
                         }
                     }
                 }
@@ -145,52 +446,182 @@ fn apply_fmm(potential: &mut Array2<f32>, f: &Array2<f32>) {
         }
     }
 
-    while let Some((u, ix)) = queue.pop() {
+    while let Some((_, ix)) = queue.pop() {
         if accepted[ix] {
             continue;
         }
-
         accepted[ix] = true;
-        let u = u.0.into_inner();
 
-        for (j, i) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+        for &(i, j) in neighbors {
             let ix = ix.add(i, j);
             if matches!(accepted.get(ix), None | Some(true)) {
                 continue;
             }
 
             let f = f[ix];
-            let (u1, u2) = if j == 0 {
-                let u2a = potential.get(ix.add(0, -1)).cloned().unwrap_or(f32::MAX);
-                let u2b = potential.get(ix.add(0, 1)).cloned().unwrap_or(f32::MAX);
-                (u, u2a.min(u2b))
-            } else {
-                let u1a = potential.get(ix.add(-1, 0)).cloned().unwrap_or(f32::MAX);
-                let u1b = potential.get(ix.add(1, 0)).cloned().unwrap_or(f32::MAX);
-                (u1a.min(u1b), u)
-            };
-
-            let u = if u1 == f32::MAX {
-                u2 + f
-            } else if u2 == f32::MAX {
-                u1 + f
-            } else {
-                let sq = 2.0 * f * f - (u1 - u2).powi(2);
-                if sq >= 0.0 {
-                    (u1 + u2 + sq.sqrt()) / 2.0
-                } else {
-                    u1.min(u2) + f
-                }
-            };
+            let mut u = solve_eikonal(&potential, &accepted, ix, [(1, 0), (0, 1)], f, options);
+            if options.diagonal {
+                let axes = [(1, 1), (1, -1)];
+                u = u.min(solve_eikonal(
+                    &potential,
+                    &accepted,
+                    ix,
+                    axes,
+                    f * SQRT_2,
+                    options,
+                ));
+            }
 
             if u < potential[ix] {
                 potential[ix] = u;
-                queue.push((float(u), ix));
+                queue.push((float(u)?, ix));
             }
         }
     }
+    Ok(())
 }
 
+/// Solve the eikonal equation at `ix` from its upwind neighbors along two orthogonal `axes`.
+///
+/// `f` is the cost to move one step along the axes.
+fn solve_eikonal(
+    potential: &ArrayViewMut2<f32>,
+    accepted: &Array2<bool>,
+    ix: Index,
+    axes: [(i32, i32); 2],
+    f: f32,
+    options: FmmOptions,
+) -> f32 {
+    // Coefficient and upwind value of the finite difference along each axis
+    let terms = axes.map(|(i, j)| {
+        let (u1, side) = [1, -1]
+            .into_iter()
+            .map(|s| {
+                let u = potential.get(ix.add(i * s, j * s)).cloned();
+                (u.unwrap_or(f32::MAX), s)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap();
+
+        if options.second_order && u1 != f32::MAX {
+            let ix2 = ix.add(i * side * 2, j * side * 2);
+            let is_upwind = accepted.get(ix.add(i * side, j * side)) == Some(&true)
+                && accepted.get(ix2) == Some(&true);
+            if is_upwind && potential[ix2] <= u1 {
+                return (2.25, (4.0 * u1 - potential[ix2]) / 3.0);
+            }
+        }
+        (1.0, u1)
+    });
+
+    let single = |(a, u): (f32, f32)| u + f / a.sqrt();
+    let [(a1, u1), (a2, u2)] = terms;
+    if u1 == f32::MAX {
+        return single(terms[1]);
+    } else if u2 == f32::MAX {
+        return single(terms[0]);
+    }
+
+    // Solve a1 (u - u1)^2 + a2 (u - u2)^2 = f^2.
+    let a = a1 + a2;
+    let b = a1 * u1 + a2 * u2;
+    let c = a1 * u1 * u1 + a2 * u2 * u2 - f * f;
+    let discriminant = b * b - a * c;
+    let u = (b + discriminant.max(0.0).sqrt()) / a;
+    // The solution must be downwind of both neighbors, otherwise only one of them is upwind.
+    if discriminant >= 0.0 && u >= u1.max(u2) {
+        u
+    } else {
+        single(terms[0]).min(single(terms[1]))
+    }
+}
+
+/// Version of cache files, which must be bumped when [`Field`] or the way to build it changes.
+const CACHE_VERSION: u32 = 4;
+
+/// Hash the geometry of the scenario and build options, which determine the field.
+///
+/// FNV-1a is used instead of the standard hasher, whose output may change between Rust versions.
+fn cache_key(scenario: &Scenario, masks: &[ObstacleMask], unit: f32, fmm: FmmOptions) -> u64 {
+    struct Fnv(u64);
+
+    impl Hasher for Fnv {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &byte in bytes {
+                self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+
+    let mut hasher = Fnv(0xcbf29ce484222325);
+    let mut write_f32s = |values: &[f32]| values.iter().for_each(|v| hasher.write_u32(v.to_bits()));
+    write_f32s(&[scenario.field.size.x, scenario.field.size.y, unit]);
+    for obstacle in &scenario.obstacles {
+        let vertices = obstacle.vertices();
+        let soft = obstacle.soft as u8 as f32;
+        write_f32s(&[vertices.len() as f32]);
+        vertices.iter().for_each(|v| write_f32s(&[v.x, v.y]));
+        write_f32s(&[obstacle.width, soft, obstacle.level as f32]);
+    }
+    for waypoint in &scenario.waypoints {
+        let [p_1, p_2] = waypoint.line;
+        write_f32s(&[
+            p_1.x,
+            p_1.y,
+            p_2.x,
+            p_2.y,
+            waypoint.width,
+            waypoint.level as f32,
+        ]);
+    }
+    for link in &scenario.links {
+        write_f32s(&[link.from as f32, link.to as f32, link.length]);
+    }
+    for group in &scenario.waypoint_groups {
+        hasher.write_usize(group.members.len());
+        group.members.iter().for_each(|&id| hasher.write_usize(id));
+    }
+    for mask in masks {
+        hasher.write_u32(mask.scale.to_bits());
+        hasher.write_usize(mask.cells.ncols());
+        mask.cells.iter().for_each(|&c| hasher.write_u8(c as u8));
+    }
+
+    hasher.write_u32(CACHE_VERSION);
+    hasher.write_usize(scenario.obstacles.len());
+    hasher.write_usize(scenario.waypoints.len());
+    hasher.write_usize(scenario.links.len());
+    hasher.write_usize(scenario.waypoint_groups.len());
+    hasher.write_usize(scenario.level_count());
+    hasher.write_usize(masks.len());
+    hasher.write_u8(fmm.second_order as u8);
+    hasher.write_u8(fmm.diagonal as u8);
+    hasher.finish()
+}
+
+fn load_cache(path: &Path) -> anyhow::Result<Vec<Field>> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+/// Save the fields of levels atomically, so that a half-written cache is never loaded.
+fn save_cache(path: &Path, fields: &[Field]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let temp_path = path.with_extension("bin.tmp");
+    bincode::serialize_into(BufWriter::new(File::create(&temp_path)?), fields)?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Field {
     /// Unit of length (in meters)
     pub unit: f32,
@@ -200,8 +631,17 @@ pub struct Field {
     pub obstacle_exist: Array2<bool>,
     /// Distance from nearest obstacle
     pub distance_map: Array2<f32>,
-    /// Potential against each waypoint
-    pub potential_maps: Vec<Array2<f32>>,
+    /// Gradient of [`Field::distance_map`] at each cell, i.e. normals of the nearest obstacles
+    pub distance_grad_map: Array2<Vec2>,
+    /// Boolean grid of soft obstacles, which are not included in [`Field::obstacle_exist`]
+    pub soft_obstacle_exist: Array2<bool>,
+    /// Distance from the nearest soft obstacle, empty if there is none
+    pub soft_distance_map: Array2<f32>,
+    /// Gradient of [`Field::soft_distance_map`]
+    pub soft_distance_grad_map: Array2<Vec2>,
+    /// Potential against each destination, laid out contiguously as (destination, y, x).
+    /// Waypoints are followed by [`Scenario::waypoint_groups`].
+    pub potentials: Array3<f32>,
 }
 
 impl Default for Field {
@@ -211,31 +651,158 @@ impl Default for Field {
             shape: (0, 0),
             obstacle_exist: Default::default(),
             distance_map: Default::default(),
-            potential_maps: Vec::default(),
+            distance_grad_map: Default::default(),
+            soft_obstacle_exist: Default::default(),
+            soft_distance_map: Default::default(),
+            soft_distance_grad_map: Default::default(),
+            potentials: Array3::zeros((0, 0, 0)),
         }
     }
 }
 
-impl Field {
-    pub fn from_scenario(scenario: &Scenario, unit: f32) -> Self {
-        let mut builder = FieldBuilder::new(scenario.field.size, unit);
+/// Propagate potentials of each waypoint through links, so that pedestrians can head for destinations on other levels.
+///
+/// A link lowers the potential on the level of `from` to the potential at `to` on its level plus the length of the
+/// link and the distance to `from`.
+fn apply_links(fields: &mut [Field], scenario: &Scenario) {
+    let unit = fields[0].unit;
+    let sample = |grid: &ArrayViewMut2<f32>, position: Vec2| {
+        util::bilinear(grid, position / unit - Vec2::splat(0.5))
+    };
+    let center = |waypoint_id: usize| {
+        let [p_1, p_2] = scenario.waypoints[waypoint_id].line;
+        p_1.lerp(p_2, 0.5)
+    };
+    let level = |waypoint_id: usize| scenario.waypoints[waypoint_id].level;
+    let base_maps: Vec<_> = fields
+        .iter()
+        .map(|field| field.potentials.clone())
+        .collect();
 
-        for obstacle in scenario.obstacles.iter() {
-            builder.add_obstacle(obstacle);
+    // Maps of each waypoint on all levels
+    let mut maps: Vec<Vec<ArrayViewMut2<f32>>> =
+        (0..scenario.waypoints.len()).map(|_| Vec::new()).collect();
+    for field in fields.iter_mut() {
+        for (maps, map) in maps.iter_mut().zip(field.potentials.outer_iter_mut()) {
+            maps.push(map);
         }
+    }
 
-        for waypoint in scenario.waypoints.iter() {
-            builder.add_waypoint(waypoint);
+    maps.into_par_iter().for_each(|mut levels| {
+        // Each iteration extends routes by one more link.
+        for _ in 0..scenario.links.len() {
+            let mut updated = false;
+
+            for &LinkConfig { from, to, length } in scenario.links.iter() {
+                let cost = sample(&levels[level(to)], center(to)) + length;
+                let potential_map = &mut levels[level(from)];
+                if cost + 1e-3 < sample(potential_map, center(from)) {
+                    Zip::from(potential_map)
+                        .and(base_maps[level(from)].index_axis(Axis(0), from))
+                        .for_each(|u, &u_from| *u = u.min(cost + u_from));
+                    updated = true;
+                }
+            }
+
+            if !updated {
+                break;
+            }
         }
+    });
+}
+
+impl Field {
+    /// Build the field of the first level of the scenario, e.g. of a scenario with a single level. See
+    /// [`FieldBuilder::build_scenario`] for all levels.
+    pub fn from_scenario(scenario: &Scenario, unit: f32) -> Result<Self> {
+        let mut fields = FieldBuilder::new(scenario.field.size, unit).build_scenario(scenario)?;
+        Ok(fields.swap_remove(0))
+    }
+
+    /// Append potential maps of waypoint groups, which are the minimum over their members.
+    fn add_waypoint_groups(&mut self, scenario: &Scenario) {
+        let groups: Vec<Array2<f32>> = scenario
+            .waypoint_groups
+            .iter()
+            .map(|group| {
+                let mut map = Array2::from_elem(self.shape, f32::MAX);
+                for &member in &group.members {
+                    Zip::from(&mut map)
+                        .and(self.potential_map(member))
+                        .for_each(|u, &u_member| *u = u.min(u_member));
+                }
+                map
+            })
+            .collect();
+
+        let views: Vec<_> = groups.iter().map(Array2::view).collect();
+        self.potentials
+            .append(Axis(0), stack(Axis(0), &views).unwrap().view())
+            .unwrap();
+    }
+
+    /// Potential map against the waypoint.
+    pub fn potential_map(&self, waypoint_id: usize) -> ArrayView2<'_, f32> {
+        self.potentials.index_axis(Axis(0), waypoint_id)
+    }
 
-        builder.build()
+    /// Check if the destination with the potential is reachable without crossing hard obstacles.
+    ///
+    /// Fronts cutting corners of obstacle cells cost less than a full cell, so half of it is taken as the threshold,
+    /// which is still far longer than any route in the field.
+    pub fn is_reachable(&self, potential: f32) -> bool {
+        potential < HARD_OBSTACLE_SLOWNESS * self.unit * 0.5
+    }
+
+    /// Indices (y, x) of walkable cells where pedestrians of a group are spawned, i.e. the line of the origin waypoint
+    /// or the region of groups scattered at the beginning.
+    pub(crate) fn spawn_cells(
+        &self,
+        scenario: &Scenario,
+        spawn: &PedestrianSpawnConfig,
+        origin: usize,
+    ) -> Vec<(usize, usize)> {
+        let walkable = |&(y, x): &(usize, usize)| !self.obstacle_exist[(y, x)];
+        let center = |(y, x): (usize, usize)| (vec2(x as f32, y as f32) + 0.5) * self.unit;
+
+        let mut cells: Vec<(usize, usize)> = match spawn {
+            PedestrianSpawnConfig::Once {
+                region: Some(SpawnRegion::Field(_)),
+                ..
+            } => self.obstacle_exist.indexed_iter().map(|(i, _)| i).collect(),
+            PedestrianSpawnConfig::Once {
+                region: Some(SpawnRegion::Polygon(vertices)),
+                ..
+            } => {
+                let polygon = util::polygon(vertices);
+                self.obstacle_exist
+                    .indexed_iter()
+                    .map(|(i, _)| i)
+                    .filter(|&i| util::polygon_contains(&polygon, center(i)))
+                    .collect()
+            }
+            _ => {
+                let Some(waypoint) = scenario.waypoints.get(origin) else {
+                    return Vec::new();
+                };
+                let [p_1, p_2] = waypoint.line;
+                let samples = ((p_2 - p_1).length() / self.unit).ceil() as usize + 1;
+                (0..=samples)
+                    .map(|i| (p_1.lerp(p_2, i as f32 / samples as f32) / self.unit).as_uvec2())
+                    .map(|cell| (cell.y as usize, cell.x as usize))
+                    .filter(|&(y, x)| y < self.shape.0 && x < self.shape.1)
+                    .collect()
+            }
+        };
+        cells.retain(walkable);
+        cells.dedup();
+        cells
     }
 
     /// Get field potential against the waypoint.
     pub fn get_potential(&self, waypoint_id: usize, position: Vec2) -> f32 {
         let position = position / self.unit - Vec2::splat(0.5);
-        let potential = &self.potential_maps[waypoint_id];
-        util::bilinear(potential, position)
+        util::bilinear(&self.potential_map(waypoint_id), position)
     }
 
     /// Get distance from the nearest obstacle.
@@ -246,15 +813,52 @@ impl Field {
 
     /// Calculate field potential gradient.
     pub fn get_potential_grad(&self, waypoint_id: usize, position: Vec2) -> Vec2 {
-        let potential = &self.potential_maps[waypoint_id];
         let position = position / self.unit - Vec2::splat(0.5);
-        util::sobel_filter(&potential, position)
+        util::sobel_filter(&self.potential_map(waypoint_id), position)
+    }
+
+    /// Sample desired directions toward the waypoint on a grid of `spacing` (meters), e.g. to draw them as arrows.
+    ///
+    /// Returns pairs of a position and a unit direction. Points inside obstacles or with no direction are skipped.
+    pub fn sample_directions(&self, waypoint_id: usize, spacing: f32) -> Vec<(Vec2, Vec2)> {
+        let size = vec2(self.shape.1 as f32, self.shape.0 as f32) * self.unit;
+        let count = (size / spacing).floor().as_uvec2();
+
+        let mut samples = Vec::new();
+        for y in 0..count.y {
+            for x in 0..count.x {
+                let pos = (vec2(x as f32, y as f32) + 0.5) * spacing;
+                let cell = (pos / self.unit).as_uvec2();
+                if self.obstacle_exist[(cell.y as usize, cell.x as usize)] {
+                    continue;
+                }
+                let direction = self
+                    .get_potential_grad(waypoint_id, pos)
+                    .normalize_or_zero();
+                if direction != Vec2::ZERO {
+                    samples.push((pos, direction));
+                }
+            }
+        }
+        samples
+    }
+
+    /// Get distance from the nearest soft obstacle and its gradient, if there are soft obstacles.
+    pub fn get_soft_obstacle_distance(&self, position: Vec2) -> Option<(f32, Vec2)> {
+        if self.soft_distance_map.is_empty() {
+            return None;
+        }
+        let position = position / self.unit - Vec2::splat(0.5);
+        Some((
+            util::bilinear(&self.soft_distance_map, position),
+            util::bilinear_vec2(&self.soft_distance_grad_map, position),
+        ))
     }
 
-    /// Calculate gradient of distance from obstacles.
+    /// Get gradient of distance from obstacles, interpolated from [`Field::distance_grad_map`].
     pub fn get_obstacle_distance_grad(&self, position: Vec2) -> Vec2 {
         let position = position / self.unit - Vec2::splat(0.5);
-        util::sobel_filter(&self.distance_map, position)
+        util::bilinear_vec2(&self.distance_grad_map, position)
     }
 }
 
@@ -262,12 +866,18 @@ impl Field {
 mod tests {
     use geo::{LineString, Polygon};
     use geo_rasterize::BinaryBuilder;
-    use glam::vec2;
-    use ndarray::Array2;
+    use glam::{vec2, Vec2};
+    use ndarray::{Array2, Axis};
 
-    use crate::scenario::{FieldConfig, ObstacleConfig, Scenario, WaypointConfig};
+    use crate::{
+        error::SimulatorError,
+        scenario::{
+            FieldConfig, LinkConfig, ObstacleConfig, Scenario, WaypointConfig, WaypointGroupConfig,
+        },
+        util,
+    };
 
-    use super::Field;
+    use super::{Field, FieldBuilder, FmmOptions};
 
     #[test]
     fn test_obstacle() {
@@ -284,11 +894,90 @@ mod tests {
         println!("{:?}", Array2::<i32>::zeros((4, 2)));
     }
 
+    #[test]
+    fn test_polyline() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = []
+
+            [[obstacles]]
+            polyline = [[2.0, 2.0], [8.0, 2.0], [8.0, 8.0]]
+            width = 1.0
+            "#,
+        )
+        .unwrap();
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        let obstacle =
+            |x: f32, y: f32| field.obstacle_exist[((y / 0.25) as usize, (x / 0.25) as usize)];
+
+        // Outlines are rasterized, whose outer corner at the joint is mitered unlike with two separate segments.
+        assert!(obstacle(8.4, 1.6));
+        assert!(obstacle(5.0, 1.6) && obstacle(8.6, 5.0));
+        assert!(!obstacle(7.0, 3.0) && !obstacle(9.0, 1.0));
+
+        let single = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = []
+            obstacles = [{ polyline = [[1.0, 1.0]] }]
+            "#,
+        );
+        assert!(single.is_err());
+    }
+
+    #[test]
+    fn test_curves() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = []
+
+            [[obstacles]]
+            circle = { center = [5.0, 5.0], radius = 1.0 }
+
+            [[obstacles]]
+            arc = { center = [5.0, 5.0], radius = 3.0, angles = [180.0, 270.0] }
+            width = 0.3
+            "#,
+        )
+        .unwrap();
+        let circle = scenario.obstacles[0].vertices();
+        assert_eq!(circle.first(), circle.last());
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        let obstacle =
+            |x: f32, y: f32| field.obstacle_exist[((y / 0.25) as usize, (x / 0.25) as usize)];
+
+        // Rim and center of the pillar
+        assert!(obstacle(5.9, 5.0) && obstacle(5.0, 4.1) && obstacle(5.0, 5.0));
+        assert!(!obstacle(6.3, 5.0));
+        // Inner edge of the arc at 225 degrees, and outside of its angles
+        assert!(obstacle(2.9, 2.9));
+        assert!(!obstacle(3.5, 3.5) && !obstacle(8.0, 5.0) && !obstacle(5.0, 8.0));
+
+        // Polylines set along curves by normalization pass validation again, but not those given with curves, as
+        // in scenarios built in code.
+        assert!(scenario.validate().is_ok());
+        let mut scenario = scenario;
+        scenario.obstacles[1].polyline = vec![vec2(1.0, 1.0), vec2(2.0, 1.0)];
+        assert!(scenario.validate().is_err());
+
+        let empty_arc = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = []
+            obstacles = [{ arc = { center = [5.0, 5.0], radius = 3.0, angles = [90.0, 90.0] } }]
+            "#,
+        );
+        assert!(empty_arc.is_err());
+    }
+
     #[test]
     fn test_parse_scenario() {
         let scenario = Scenario {
             field: FieldConfig {
                 size: vec2(5.0, 5.0),
+                ..Default::default()
             },
             obstacles: vec![
                 ObstacleConfig {
@@ -307,11 +996,11 @@ mod tests {
             ..Default::default()
         };
 
-        let field = Field::from_scenario(&scenario, 0.25);
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
 
         println!("{:?}", field.obstacle_exist.map(|v| if *v { 1 } else { 0 }));
 
-        println!("{:?}", field.potential_maps[0].map(|v| *v as i32));
+        println!("{:?}", field.potential_map(0).map(|v| *v as i32));
 
         println!("{:?}", field.get_potential(0, vec2(-1.5, 2.0)));
 
@@ -322,4 +1011,286 @@ mod tests {
 
         // println!("{:#?}", potential.map(|v| *v as i32));
     }
+
+    #[test]
+    fn test_degenerate_waypoint() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![
+                WaypointConfig {
+                    line: [vec2(9.0, 1.0), vec2(9.0, 4.0)],
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(1.0, 1.0); 2],
+                    width: 1.0,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(matches!(
+            Field::from_scenario(&scenario, 0.25),
+            Err(SimulatorError::DegenerateWaypoint { index: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_links() {
+        // Two levels on top of each other, the upper of which is divided by a wall, connected by a link.
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(20.0, 5.0),
+                ..Default::default()
+            },
+            levels: vec![Default::default(); 2],
+            obstacles: vec![ObstacleConfig {
+                line: [vec2(10.0, 0.0), vec2(10.0, 5.0)],
+                level: 1,
+                ..Default::default()
+            }],
+            waypoints: vec![
+                WaypointConfig {
+                    line: [vec2(18.0, 1.0), vec2(18.0, 4.0)],
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(2.0, 1.0), vec2(2.0, 4.0)],
+                    level: 1,
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(12.0, 1.0), vec2(12.0, 4.0)],
+                    ..Default::default()
+                },
+            ],
+            links: vec![LinkConfig {
+                from: 1,
+                to: 2,
+                length: 5.0,
+            }],
+            ..Default::default()
+        };
+
+        let fields = FieldBuilder::new(scenario.field.size, 0.25)
+            .build_scenario(&scenario)
+            .unwrap();
+        assert_eq!(fields.len(), 2);
+        assert!(fields[1].obstacle_exist[(10, 38)] && !fields[0].obstacle_exist[(10, 38)]);
+
+        // 1.5m to the link, 5m through the link, then 5.5m to the destination.
+        let potential = fields[1].get_potential(0, vec2(4.0, 2.5));
+        assert!((potential - 12.0).abs() < 1.0, "{potential}");
+        // The link leads only from the upper level, so the ground level is walked straight.
+        let potential = fields[0].get_potential(0, vec2(4.0, 2.5));
+        assert!((potential - 13.5).abs() < 1.0, "{potential}");
+        // The link waypoint itself is not on the ground level.
+        assert!(!fields[0].is_reachable(fields[0].get_potential(1, vec2(4.0, 2.5))));
+    }
+
+    #[test]
+    fn test_obstacle_distance_grad() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 10.0),
+                ..Default::default()
+            },
+            obstacles: vec![ObstacleConfig {
+                line: [vec2(5.0, 2.0), vec2(5.0, 8.0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+
+        // The lookup table gives the same gradient as the Sobel filter at the position.
+        let pos = vec2(3.1, 4.7);
+        let expected = crate::util::sobel_filter(&field.distance_map, pos / 0.25 - 0.5);
+        let grad = field.get_obstacle_distance_grad(pos);
+        assert!(grad.abs_diff_eq(expected, 1e-3), "{grad} != {expected}");
+    }
+
+    #[test]
+    fn test_sample_directions() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![WaypointConfig {
+                line: [vec2(9.0, 1.0), vec2(9.0, 4.0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        let samples = field.sample_directions(0, 1.0);
+
+        // Border cells are obstacles, so the samples are the interior of the 10 x 5 grid.
+        assert!(!samples.is_empty() && samples.len() <= 50);
+        // Directions point toward the waypoint on the right, away from walls which distort the gradient.
+        let interior = |pos: &Vec2| (2.0..7.0).contains(&pos.x) && (1.0..4.0).contains(&pos.y);
+        for (pos, direction) in samples.iter().filter(|(pos, _)| interior(pos)) {
+            assert!(direction.x > 0.9, "{direction} at {pos}");
+        }
+    }
+
+    #[test]
+    fn test_fmm_options() {
+        // Distance from a point source in an open field, whose exact value is the Euclidean distance.
+        let shape = (41, 41);
+        let error = |options| {
+            let mut potential = Array2::from_elem(shape, f32::MAX);
+            potential[(0, 0)] = 0.0;
+            super::apply_fmm(potential.view_mut(), &Array2::ones(shape), options).unwrap();
+            potential
+                .indexed_iter()
+                .map(|((y, x), &u)| (u - (x as f32).hypot(y as f32)).abs())
+                .fold(0.0, f32::max)
+        };
+
+        let first_order = error(FmmOptions::default());
+        let accurate = error(FmmOptions {
+            second_order: true,
+            diagonal: true,
+        });
+        assert!(accurate < first_order * 0.5, "{accurate} vs {first_order}");
+    }
+
+    #[test]
+    fn test_rasterize_obstacles() {
+        let obstacles = [
+            ObstacleConfig {
+                line: [vec2(1.3, 0.7), vec2(8.2, 4.1)],
+                width: 0.4,
+                soft: false,
+                ..Default::default()
+            },
+            ObstacleConfig {
+                line: [vec2(2.0, 4.0), vec2(2.0, 6.0)],
+                width: 0.5,
+                soft: false,
+                ..Default::default()
+            },
+        ];
+        let mut builder = FieldBuilder::new(vec2(10.0, 5.0), 0.25);
+        builder.add_obstacles(&obstacles).unwrap();
+
+        // Rasterizing within bounding boxes gives the same cells as rasterizing on the whole grid.
+        let mut expected = FieldBuilder::new(vec2(10.0, 5.0), 0.25).obstacle_exist;
+        for obstacle in &obstacles {
+            let vertices = util::line_with_width(obstacle.line, obstacle.width);
+            let mut shape: LineString<f32> =
+                vertices.iter().map(|v| (v.x / 0.25, v.y / 0.25)).collect();
+            shape.close();
+            let mut rasterizer = BinaryBuilder::new().width(40).height(20).build().unwrap();
+            rasterizer.rasterize(&shape).unwrap();
+            expected.zip_mut_with(&rasterizer.finish(), |a, b| *a |= b);
+        }
+        assert_eq!(builder.obstacle_exist, expected);
+    }
+
+    #[test]
+    fn test_cache() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![WaypointConfig {
+                line: [vec2(9.0, 1.0), vec2(9.0, 4.0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let dir = std::env::temp_dir().join(format!("pedoni-field-cache-{}", std::process::id()));
+        let build = || {
+            FieldBuilder::new(scenario.field.size, 0.25)
+                .cache_dir(&dir)
+                .build_scenario(&scenario)
+                .unwrap()
+        };
+
+        let built = build();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let loaded = build();
+        assert_eq!(loaded[0].potentials, built[0].potentials);
+        assert_eq!(loaded[0].distance_map, built[0].distance_map);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_waypoint_groups() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(20.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![
+                WaypointConfig {
+                    line: [vec2(1.0, 1.0), vec2(1.0, 4.0)],
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(19.0, 1.0), vec2(19.0, 4.0)],
+                    ..Default::default()
+                },
+            ],
+            waypoint_groups: vec![WaypointGroupConfig {
+                members: vec![0, 1],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        assert_eq!(field.potentials.len_of(Axis(0)), 3);
+        // The group potential leads to the nearest member.
+        for pos in [vec2(4.0, 2.5), vec2(16.0, 2.5)] {
+            let nearest = field.get_potential(0, pos).min(field.get_potential(1, pos));
+            assert_eq!(field.get_potential(2, pos), nearest);
+        }
+    }
+
+    #[test]
+    fn test_soft_obstacles() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(20.0, 5.0),
+                ..Default::default()
+            },
+            obstacles: vec![ObstacleConfig {
+                line: [vec2(10.0, 0.0), vec2(10.0, 5.0)],
+                width: 0.5,
+                soft: true,
+                ..Default::default()
+            }],
+            waypoints: vec![WaypointConfig {
+                line: [vec2(18.0, 1.0), vec2(18.0, 4.0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+        // The outline of the barrier is rasterized.
+        assert!(field.soft_obstacle_exist[(10, 39)]);
+        assert!(!field.obstacle_exist[(10, 39)]);
+
+        // 15.5 m of free space and 0.5 m through the barrier, which costs 10 times as much.
+        let potential = field.get_potential(0, vec2(2.0, 2.5));
+        assert!((potential - 20.5).abs() < 1.0, "{potential}");
+
+        let (distance, _) = field.get_soft_obstacle_distance(vec2(8.0, 2.5)).unwrap();
+        assert!((distance - 1.75).abs() < 0.25, "{distance}");
+        assert!(Field::default()
+            .get_soft_obstacle_distance(Vec2::ZERO)
+            .is_none());
+    }
 }
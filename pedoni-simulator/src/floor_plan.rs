@@ -0,0 +1,138 @@
+use std::{fs::File, io::Read, path::Path};
+
+use ndarray::Array2;
+use png::{ColorType, Decoder, DecodingError, Transformations};
+
+use crate::error::{Result, SimulatorError};
+
+/// Grid of hard obstacles rasterized in advance, e.g. from a floor plan, which
+/// [`FieldBuilder::obstacle_mask`](crate::field::FieldBuilder::obstacle_mask) applies to the field instead of
+/// rasterizing lines of obstacles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObstacleMask {
+    /// Whether each pixel is an obstacle, indexed by `(y, x)` from the bottom-left corner of the field
+    pub cells: Array2<bool>,
+    /// Size of a pixel (meters)
+    pub scale: f32,
+}
+
+impl ObstacleMask {
+    pub fn new(cells: Array2<bool>, scale: f32) -> Self {
+        ObstacleMask { cells, scale }
+    }
+
+    /// Read a floor plan in PNG, in which pixels darker than `threshold` are obstacles.
+    ///
+    /// The bottom-left corner of the image is placed at the origin of the field. Transparent pixels are free.
+    pub fn from_png(
+        reader: impl Read,
+        scale: f32,
+        threshold: u8,
+    ) -> std::result::Result<Self, DecodingError> {
+        let mut decoder = Decoder::new(reader);
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer)?;
+
+        let samples = info.color_type.samples();
+        let (width, height) = (info.width as usize, info.height as usize);
+        let cells = Array2::from_shape_fn((height, width), |(y, x)| {
+            // Rows of images run from the top.
+            let offset = (height - 1 - y) * info.line_size + x * samples;
+            let pixel = &buffer[offset..offset + samples];
+            let (luma, alpha) = match info.color_type {
+                ColorType::Grayscale => (pixel[0] as f32, 255),
+                ColorType::GrayscaleAlpha => (pixel[0] as f32, pixel[1]),
+                ColorType::Rgb => (luma(pixel), 255),
+                ColorType::Rgba => (luma(pixel), pixel[3]),
+                // Palettes are expanded by the transformation.
+                ColorType::Indexed => (255.0, 255),
+            };
+            alpha >= 128 && luma < threshold as f32
+        });
+        Ok(ObstacleMask { cells, scale })
+    }
+
+    /// Read the floor plan file. See [`ObstacleMask::from_png`].
+    pub fn load(path: &Path, scale: f32, threshold: u8) -> Result<Self> {
+        let result = File::open(path)
+            .map_err(DecodingError::from)
+            .and_then(|file| Self::from_png(file, scale, threshold));
+        result.map_err(|source| SimulatorError::FloorPlan {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Mark grid cells of `unit` overlapped by obstacle pixels, so that walls thinner than a cell are kept.
+    pub(crate) fn rasterize(&self, target: &mut Array2<bool>, unit: f32) {
+        let (rows, cols) = target.dim();
+        let cell_range = |pixel: usize, len: usize| {
+            let start = (pixel as f32 * self.scale / unit).floor() as usize;
+            let end = ((pixel + 1) as f32 * self.scale / unit).ceil() as usize;
+            start.min(len)..end.min(len)
+        };
+        for ((y, x), _) in self.cells.indexed_iter().filter(|(_, &obstacle)| obstacle) {
+            for row in cell_range(y, rows) {
+                for col in cell_range(x, cols) {
+                    target[(row, col)] = true;
+                }
+            }
+        }
+    }
+}
+
+/// Luma of an RGB pixel by ITU-R BT.601
+fn luma(pixel: &[u8]) -> f32 {
+    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+    use ndarray::Array2;
+    use png::{ColorType, Encoder};
+
+    use crate::field::FieldBuilder;
+
+    use super::ObstacleMask;
+
+    #[test]
+    fn test_from_png() {
+        // White image of 4 x 2 pixels whose top-left pixel is black
+        let mut data = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut data, 4, 2);
+            encoder.set_color(ColorType::Grayscale);
+            let mut writer = encoder.write_header().unwrap();
+            writer
+                .write_image_data(&[0, 255, 255, 255, 255, 255, 255, 255])
+                .unwrap();
+        }
+        let mask = ObstacleMask::from_png(data.as_slice(), 0.5, 128).unwrap();
+        assert_eq!(mask.cells.dim(), (2, 4));
+        assert!(mask.cells[(1, 0)]);
+        assert_eq!(mask.cells.iter().filter(|&&c| c).count(), 1);
+    }
+
+    #[test]
+    fn test_obstacle_mask() {
+        // A wall of a pixel thick across the field at x = 5 m, with a gap at the top
+        let mut cells = Array2::from_elem((100, 100), false);
+        for y in 0..80 {
+            cells[(y, 50)] = true;
+        }
+        let mask = ObstacleMask::new(cells, 0.1);
+        let fields = FieldBuilder::new(vec2(10.0, 10.0), 0.25)
+            .obstacle_mask(mask)
+            .build_scenario(&Default::default())
+            .unwrap();
+        let field = &fields[0];
+
+        // The wall thinner than a cell is kept.
+        assert!(field.obstacle_exist[(10, 20)]);
+        assert!(!field.obstacle_exist[(10, 19)]);
+        assert!(!field.obstacle_exist[(35, 20)]);
+    }
+}
@@ -0,0 +1,340 @@
+use geo::Polygon;
+use glam::Vec2;
+
+use crate::{models::PedestrianModel, scenario::GateConfig, util};
+
+/// State of gates, updated every step.
+#[derive(Debug, Default, Clone)]
+pub struct Gates {
+    gates: Vec<Gate>,
+    /// Pairs of a gate index and a pedestrian ID passed in the current step
+    passages: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone)]
+struct Gate {
+    line: [Vec2; 2],
+    service_rate: Option<f64>,
+    /// Number of pedestrians allowed to pass at this moment.
+    credit: f64,
+    /// Number of pedestrians held back in the current step.
+    queue_length: i32,
+    occupancy_limit: Option<OccupancyLimit>,
+}
+
+#[derive(Debug, Clone)]
+struct OccupancyLimit {
+    region: Polygon<f32>,
+    capacity: u32,
+    /// Number of pedestrians in the region at the beginning of the current step
+    occupancy: u32,
+    /// Number of pedestrians allowed to pass in the current step
+    room: u32,
+}
+
+impl Gates {
+    pub fn new(configs: &[GateConfig]) -> Self {
+        let gates = configs
+            .iter()
+            .map(|config| Gate {
+                line: config.line,
+                service_rate: config.service_rate,
+                credit: 1.0,
+                queue_length: 0,
+                occupancy_limit: config.occupancy_limit.as_ref().map(|limit| OccupancyLimit {
+                    region: util::polygon(&limit.region),
+                    capacity: limit.capacity,
+                    occupancy: 0,
+                    room: limit.capacity,
+                }),
+            })
+            .collect();
+
+        Gates {
+            gates,
+            passages: Vec::new(),
+        }
+    }
+
+    /// Refill the capacity of gates and reset queues. Must be called once per step.
+    pub fn begin_step(&mut self, delta_time: f64) {
+        for gate in self.gates.iter_mut() {
+            if let Some(rate) = gate.service_rate {
+                gate.credit = (gate.credit + rate * delta_time).min(1.0);
+            }
+            gate.queue_length = 0;
+        }
+        self.passages.clear();
+    }
+
+    /// Count pedestrians in the regions of gates with occupancy limits, and let as many pass in the current step as
+    /// the regions have room for. Must be called after [`Gates::begin_step`].
+    pub fn measure_occupancy(&mut self, model: &dyn PedestrianModel) {
+        let mut limits: Vec<&mut OccupancyLimit> = self
+            .gates
+            .iter_mut()
+            .filter_map(|gate| gate.occupancy_limit.as_mut())
+            .collect();
+        if limits.is_empty() {
+            return;
+        }
+
+        limits.iter_mut().for_each(|limit| limit.occupancy = 0);
+        model.for_each_pedestrian(&mut |p| {
+            for limit in limits.iter_mut() {
+                if util::polygon_contains(&limit.region, p.pos) {
+                    limit.occupancy += 1;
+                }
+            }
+        });
+        for limit in limits {
+            limit.room = limit.capacity.saturating_sub(limit.occupancy);
+        }
+    }
+
+    /// Check whether pedestrian `id` can move from `from` to `to`, and consume the capacity of crossed gates.
+    ///
+    /// The capacity is consumed only if all the crossed gates let the pedestrian pass, so a move refused by one gate
+    /// does not use up the others.
+    pub fn try_pass(&mut self, id: usize, from: Vec2, to: Vec2) -> bool {
+        let passages_len = self.passages.len();
+
+        for (gate_id, gate) in self.gates.iter_mut().enumerate() {
+            let Some(side) = crossing_side(gate.line, from, to) else {
+                continue;
+            };
+
+            if side < 0.0 {
+                self.passages.truncate(passages_len);
+                return false;
+            }
+
+            let no_credit = gate.service_rate.is_some() && gate.credit < 1.0;
+            let full = gate
+                .occupancy_limit
+                .as_ref()
+                .is_some_and(|limit| limit.room == 0);
+            if no_credit || full {
+                gate.queue_length += 1;
+                self.passages.truncate(passages_len);
+                return false;
+            }
+            self.passages.push((gate_id, id));
+        }
+
+        for &(gate_id, _) in &self.passages[passages_len..] {
+            let gate = &mut self.gates[gate_id];
+            if gate.service_rate.is_some() {
+                gate.credit -= 1.0;
+            }
+            if let Some(limit) = &mut gate.occupancy_limit {
+                limit.room -= 1;
+            }
+        }
+        true
+    }
+
+    /// Pairs of a gate index and a pedestrian ID passed in the current step.
+    pub fn passages(&self) -> &[(usize, usize)] {
+        &self.passages
+    }
+
+    /// Number of pedestrians held back at each gate in the current step.
+    pub fn queue_lengths(&self) -> Vec<i32> {
+        self.gates.iter().map(|gate| gate.queue_length).collect()
+    }
+
+    /// Number of pedestrians in the region of each gate with an occupancy limit at the beginning of the current step.
+    pub fn occupancies(&self) -> Vec<Option<i32>> {
+        self.gates
+            .iter()
+            .map(|gate| {
+                gate.occupancy_limit
+                    .as_ref()
+                    .map(|limit| limit.occupancy as i32)
+            })
+            .collect()
+    }
+}
+
+/// Check if segment `from`-`to` crosses `line`.
+///
+/// Returns positive value when it crosses toward the right-hand side of the line, and negative one otherwise.
+fn crossing_side(line: [Vec2; 2], from: Vec2, to: Vec2) -> Option<f32> {
+    let d = line[1] - line[0];
+    let m = to - from;
+    let denom = d.perp_dot(m);
+    if denom == 0.0 {
+        return None;
+    }
+
+    let w = from - line[0];
+    let s = w.perp_dot(m) / denom;
+    let t = w.perp_dot(d) / denom;
+
+    ((0.0..=1.0).contains(&s) && (0.0..=1.0).contains(&t)).then_some(-denom)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{
+        scenario::{GateConfig, OccupancyLimitConfig, Scenario},
+        Simulator, SimulatorOptions,
+    };
+
+    use super::Gates;
+
+    #[test]
+    fn test_one_way() {
+        let mut gates = Gates::new(&[GateConfig {
+            line: [vec2(0.0, 0.0), vec2(0.0, 2.0)],
+            ..Default::default()
+        }]);
+
+        assert!(!gates.try_pass(0, vec2(0.1, 1.0), vec2(-0.1, 1.0)));
+        assert!(gates.try_pass(0, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+        assert!(gates.try_pass(0, vec2(-0.1, 3.0), vec2(0.1, 3.0)));
+        assert_eq!(gates.passages(), &[(0, 0)]);
+    }
+
+    #[test]
+    fn test_service_rate() {
+        let mut gates = Gates::new(&[GateConfig {
+            line: [vec2(0.0, 0.0), vec2(0.0, 2.0)],
+            service_rate: Some(1.0),
+            ..Default::default()
+        }]);
+
+        gates.begin_step(0.1);
+        assert!(gates.try_pass(0, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+        assert!(!gates.try_pass(0, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+        assert_eq!(gates.queue_lengths(), vec![1]);
+
+        for _ in 0..11 {
+            gates.begin_step(0.1);
+        }
+        assert!(gates.try_pass(0, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+    }
+
+    #[test]
+    fn test_service_rate_of_two_gates() {
+        // A move across both gates is refused by the second one, whose credit is used up, keeping that of the first.
+        let gate = |x: f32| GateConfig {
+            line: [vec2(x, 0.0), vec2(x, 2.0)],
+            service_rate: Some(1.0),
+            ..Default::default()
+        };
+        let mut gates = Gates::new(&[gate(0.0), gate(0.2)]);
+
+        gates.begin_step(0.1);
+        assert!(gates.try_pass(0, vec2(0.1, 1.0), vec2(0.3, 1.0)));
+        assert!(!gates.try_pass(1, vec2(-0.1, 1.0), vec2(0.3, 1.0)));
+        assert_eq!(gates.queue_lengths(), vec![0, 1]);
+        assert!(gates.try_pass(1, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+        assert_eq!(gates.passages(), &[(1, 0), (0, 1)]);
+    }
+
+    #[test]
+    fn test_occupancy_limit_of_two_gates() {
+        // The room behind the first gate is kept for a move refused by the second gate.
+        let mut gates = Gates::new(&[
+            GateConfig {
+                line: [vec2(0.0, 0.0), vec2(0.0, 2.0)],
+                occupancy_limit: Some(OccupancyLimitConfig {
+                    region: vec![
+                        vec2(0.0, 0.0),
+                        vec2(2.0, 0.0),
+                        vec2(2.0, 2.0),
+                        vec2(0.0, 2.0),
+                    ],
+                    capacity: 1,
+                }),
+                ..Default::default()
+            },
+            GateConfig {
+                line: [vec2(0.2, 0.0), vec2(0.2, 2.0)],
+                service_rate: Some(1.0),
+                ..Default::default()
+            },
+        ]);
+
+        gates.begin_step(0.1);
+        assert!(gates.try_pass(0, vec2(0.1, 1.0), vec2(0.3, 1.0)));
+        assert!(!gates.try_pass(1, vec2(-0.1, 1.0), vec2(0.3, 1.0)));
+        assert!(gates.try_pass(1, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+        assert!(!gates.try_pass(2, vec2(-0.1, 1.0), vec2(0.1, 1.0)));
+        assert_eq!(gates.queue_lengths(), vec![1, 1]);
+    }
+
+    #[test]
+    fn test_occupancy_limit() {
+        // The room behind the gate holds 5 pedestrians, who wait there until released to the exit at 20 s.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [20.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[13.0, 1.0], [13.0, 9.0]]
+
+            [[waypoints]]
+            line = [[19.0, 1.0], [19.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 20, region = [[1.0, 1.0], [6.0, 1.0], [6.0, 9.0], [1.0, 9.0]] }
+
+            [[gates]]
+            line = [[10.0, 0.0], [10.0, 10.0]]
+            occupancy_limit = { region = [[10.0, 0.0], [17.0, 0.0], [17.0, 10.0], [10.0, 10.0]], capacity = 5 }
+
+            [[holding_areas]]
+            waypoint = 1
+            destination = 2
+
+            [[timeline]]
+            time = 20.0
+            action = "release_holding_area"
+            area = 0
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+
+        let mut max_occupancy = 0;
+        let mut queued = false;
+        for _ in 0..190 {
+            let metrics = simulator.tick().unwrap();
+            max_occupancy = max_occupancy.max(metrics.gate_occupancies[0].unwrap());
+            queued |= metrics.gate_queue_lengths[0] > 0;
+        }
+        assert_eq!(max_occupancy, 5);
+        assert!(queued);
+        let inside = |simulator: &Simulator| {
+            let pedestrians = simulator.list_pedestrians();
+            pedestrians
+                .iter()
+                .filter(|p| (10.0..17.0).contains(&p.pos.x))
+                .count()
+        };
+        assert_eq!(inside(&simulator), 5);
+
+        // As many pedestrians are let in as the released ones leave, and they wait in turn.
+        for _ in 0..300 {
+            simulator.tick().unwrap();
+            assert!(inside(&simulator) <= 5);
+        }
+        assert_eq!(simulator.list_pedestrians().len(), 15);
+        assert_eq!(inside(&simulator), 5);
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt::Write;
+
+use crate::migration::SCENARIO_VERSION;
+
+/// Thickness of generated walls (meters)
+const WALL: f32 = 0.2;
+/// Distance between the field boundary and the geometry (meters)
+const MARGIN: f32 = 1.0;
+
+/// Standard geometry of which [`Geometry::to_toml`] generates a ready-to-run scenario, used as benchmark inputs.
+///
+/// Lengths are the clear dimensions of walkable areas in meters, excluding walls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Geometry {
+    /// Straight corridor walked from the left end to the right one, and in the reverse if bidirectional.
+    Corridor {
+        length: f32,
+        width: f32,
+        /// Pedestrians spawned per second in each direction
+        rate: f64,
+        bidirectional: bool,
+    },
+    /// Square room evacuated through a door in its right wall, as in experiments of bottleneck flows.
+    Bottleneck {
+        /// Width of the door
+        door: f32,
+        /// Length of the sides of the room
+        room: f32,
+        /// Number of pedestrians scattered over the room at the beginning
+        count: i32,
+    },
+    /// Corridor whose left and right branches merge into a stem going up.
+    TJunction {
+        /// Length of each branch and the stem
+        length: f32,
+        width: f32,
+        /// Pedestrians spawned per second in each branch
+        rate: f64,
+    },
+}
+
+impl Geometry {
+    /// Scenario of the geometry in TOML.
+    pub fn to_toml(&self) -> String {
+        let mut toml = String::new();
+        match *self {
+            Geometry::Corridor {
+                length,
+                width,
+                rate,
+                bidirectional,
+            } => {
+                let (x0, x1) = (MARGIN, MARGIN + length);
+                let (y0, y1) = (MARGIN, MARGIN + width);
+                header(
+                    &mut toml,
+                    &format!("Corridor of {length} m long and {width} m wide"),
+                    [length + MARGIN * 2.0, width + MARGIN * 2.0],
+                );
+                waypoint(&mut toml, [[x0 + 0.5, y0], [x0 + 0.5, y1]]);
+                waypoint(&mut toml, [[x1 - 0.5, y0], [x1 - 0.5, y1]]);
+                wall(&mut toml, [[x0, y0 - WALL * 0.5], [x1, y0 - WALL * 0.5]]);
+                wall(&mut toml, [[x0, y1 + WALL * 0.5], [x1, y1 + WALL * 0.5]]);
+                periodic(&mut toml, 0, 1, rate);
+                if bidirectional {
+                    periodic(&mut toml, 1, 0, rate);
+                }
+            }
+            Geometry::Bottleneck { door, room, count } => {
+                let (x0, x1) = (MARGIN, MARGIN + room);
+                let (y0, y1) = (MARGIN, MARGIN + room);
+                let center = MARGIN + room * 0.5;
+                let h = WALL * 0.5;
+                header(
+                    &mut toml,
+                    &format!("Room of {room} m square with a door of {door} m wide"),
+                    [room + MARGIN + 4.0, room + MARGIN * 2.0],
+                );
+                // The origin is required though pedestrians are scattered over the room. It crosses the middle rather
+                // than running along the left wall, where routes from it would start in a narrow strip.
+                waypoint(&mut toml, [[center, y0], [center, y1]]);
+                waypoint(&mut toml, [[x1 + 2.5, y0], [x1 + 2.5, y1]]);
+                wall(&mut toml, [[x0 - h, y0 - h], [x1 + h, y0 - h]]);
+                wall(&mut toml, [[x0 - h, y1 + h], [x1 + h, y1 + h]]);
+                wall(&mut toml, [[x0 - h, y0 - h], [x0 - h, y1 + h]]);
+                wall(&mut toml, [[x1 + h, y0 - h], [x1 + h, center - door * 0.5]]);
+                wall(&mut toml, [[x1 + h, center + door * 0.5], [x1 + h, y1 + h]]);
+                let _ = write!(
+                    toml,
+                    "\n[[pedestrians]]\norigin = 0\ndestination = 1\nspawn = {{ kind = \"once\", count = {count}, \
+                     region = [[{}, {}], [{}, {}], [{}, {}], [{}, {}]] }}\n",
+                    x0 + 0.5,
+                    y0 + 0.5,
+                    x1 - 0.5,
+                    y0 + 0.5,
+                    x1 - 0.5,
+                    y1 - 0.5,
+                    x0 + 0.5,
+                    y1 - 0.5
+                );
+            }
+            Geometry::TJunction {
+                length,
+                width,
+                rate,
+            } => {
+                // Corners of the junction
+                let (xl, xr) = (MARGIN + length, MARGIN + length + width);
+                let (y0, y1) = (MARGIN, MARGIN + width);
+                let x_end = xr + length;
+                let y_end = y1 + length;
+                let h = WALL * 0.5;
+                header(
+                    &mut toml,
+                    &format!("T-junction of branches {length} m long and {width} m wide"),
+                    [x_end + MARGIN, y_end + MARGIN],
+                );
+                waypoint(&mut toml, [[MARGIN + 0.5, y0], [MARGIN + 0.5, y1]]);
+                waypoint(&mut toml, [[x_end - 0.5, y0], [x_end - 0.5, y1]]);
+                waypoint(&mut toml, [[xl, y_end - 0.5], [xr, y_end - 0.5]]);
+                wall(&mut toml, [[MARGIN, y0 - h], [x_end, y0 - h]]);
+                wall(&mut toml, [[MARGIN, y1 + h], [xl - h, y1 + h]]);
+                wall(&mut toml, [[xr + h, y1 + h], [x_end, y1 + h]]);
+                wall(&mut toml, [[xl - h, y1 + h], [xl - h, y_end]]);
+                wall(&mut toml, [[xr + h, y1 + h], [xr + h, y_end]]);
+                periodic(&mut toml, 0, 2, rate);
+                periodic(&mut toml, 1, 2, rate);
+            }
+        }
+        toml
+    }
+}
+
+fn header(toml: &mut String, description: &str, size: [f32; 2]) {
+    let _ = writeln!(toml, "# {description}\n");
+    let _ = writeln!(toml, "version = {SCENARIO_VERSION}\n");
+    let _ = writeln!(toml, "[field]\nsize = [{}, {}]", size[0], size[1]);
+}
+
+fn waypoint(toml: &mut String, line: [[f32; 2]; 2]) {
+    let _ = writeln!(toml, "\n[[waypoints]]\nline = {}", format_line(line));
+}
+
+fn wall(toml: &mut String, line: [[f32; 2]; 2]) {
+    let _ = writeln!(
+        toml,
+        "\n[[obstacles]]\nline = {}\nwidth = {WALL}",
+        format_line(line)
+    );
+}
+
+fn periodic(toml: &mut String, origin: usize, destination: usize, rate: f64) {
+    let _ = writeln!(
+        toml,
+        "\n[[pedestrians]]\norigin = {origin}\ndestination = {destination}\n\
+         spawn = {{ kind = \"periodic\", rate = {rate:?} }}"
+    );
+}
+
+fn format_line(line: [[f32; 2]; 2]) -> String {
+    format!(
+        "[[{:?}, {:?}], [{:?}, {:?}]]",
+        line[0][0], line[0][1], line[1][0], line[1][1]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{connectivity, dry_run, field::Field, scenario::Scenario};
+
+    use super::Geometry;
+
+    #[test]
+    fn test_generate() {
+        let geometries = [
+            Geometry::Corridor {
+                length: 20.0,
+                width: 4.0,
+                rate: 1.0,
+                bidirectional: true,
+            },
+            Geometry::Bottleneck {
+                door: 1.2,
+                room: 8.0,
+                count: 50,
+            },
+            Geometry::TJunction {
+                length: 10.0,
+                width: 3.0,
+                rate: 1.0,
+            },
+        ];
+        for geometry in geometries {
+            let toml = geometry.to_toml();
+            let scenario = Scenario::from_toml(&toml).unwrap();
+            let fields = [Field::from_scenario(&scenario, 0.25).unwrap()];
+            let routes = dry_run::routes(&scenario, &fields);
+            assert!(
+                routes.iter().all(|route| route.blocked_ratio == 0.0),
+                "{toml}"
+            );
+
+            let graph = connectivity::analyze(&scenario, &fields);
+            assert!(graph.edges.iter().all(|edge| !edge.is_narrow()), "{toml}");
+        }
+
+        // The door is the narrowest passage of the bottleneck, in the right wall at x = 9.1 centered at y = 5.
+        let mut widths = Vec::new();
+        for door in [1.2, 2.0] {
+            let scenario = Scenario::from_toml(
+                &Geometry::Bottleneck {
+                    door,
+                    room: 8.0,
+                    count: 50,
+                }
+                .to_toml(),
+            )
+            .unwrap();
+            let fields = [Field::from_scenario(&scenario, 0.25).unwrap()];
+            let edge = &connectivity::analyze(&scenario, &fields).edges[0];
+            let width = edge.min_width.unwrap();
+            assert!((door - 0.5..door + 0.5).contains(&width), "{door}: {width}");
+            let pos = edge.narrowest_at.unwrap();
+            assert!(
+                (pos.x - 9.1).abs() < 0.5 && (pos.y - 5.0).abs() < door * 0.5,
+                "{door}: {pos}"
+            );
+            widths.push(width);
+        }
+        assert!(widths[1] > widths[0], "{widths:?}");
+    }
+}
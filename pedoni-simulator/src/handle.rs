@@ -0,0 +1,303 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::{diagnostic::StepMetrics, error::SimulatorError, events::Event, Simulator};
+
+/// Longest time the thread sleeps without calling [`StepObserver::between_steps`], e.g. while stopped.
+const IDLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How fast a started [`SimulationHandle`] advances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pace {
+    /// Advance as fast as possible.
+    #[default]
+    MaxSpeed,
+    /// Advance one step per interval at most, e.g. real time at a playback speed.
+    Interval(Duration),
+    /// Advance only by the steps requested by [`SimulationHandle::step`], e.g. driven by frames or another clock.
+    Manual,
+}
+
+/// Hooks called on the thread of a [`SimulationHandle`].
+pub trait StepObserver: Send + 'static {
+    /// Called after each step with its metrics, e.g. to export outputs or publish states to a renderer.
+    fn on_step(&mut self, simulator: &Simulator, metrics: &StepMetrics);
+
+    /// Called before each step and periodically while idle, e.g. to serve requests which must not interleave with
+    /// steps. The simulator may be replaced here.
+    fn between_steps(&mut self, _simulator: &mut Simulator) {}
+}
+
+/// Observer doing nothing, for callers who only read the simulator or subscribe to its events.
+impl StepObserver for () {
+    fn on_step(&mut self, _simulator: &Simulator, _metrics: &StepMetrics) {}
+}
+
+/// Simulator advanced on its own thread, which is controlled by the methods of the handle.
+///
+/// This is shared by frontends embedding the simulator, such as the GUI and the headless runner, so that they only
+/// decide when to step and what to do with the results. The thread starts stopped and ends when the handle is dropped.
+pub struct SimulationHandle {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    simulator: Mutex<Simulator>,
+    control: Mutex<Control>,
+    /// Notified when the control changes or a step ends
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Control {
+    running: bool,
+    pace: Pace,
+    /// Steps requested by [`SimulationHandle::step`] and not taken yet
+    requested: u64,
+    /// Whether a step is in progress
+    stepping: bool,
+    shutdown: bool,
+    /// Error of the step which stopped the thread. See [`SimulationHandle::take_error`].
+    error: Option<SimulatorError>,
+}
+
+impl SimulationHandle {
+    /// Move the simulator to a new thread, calling `observer` there.
+    pub fn spawn(simulator: Simulator, observer: impl StepObserver) -> Self {
+        let shared = Arc::new(Shared {
+            simulator: Mutex::new(simulator),
+            control: Mutex::new(Control::default()),
+            changed: Condvar::new(),
+        });
+        let thread = thread::Builder::new()
+            .name("pedoni-simulation".into())
+            .spawn({
+                let shared = shared.clone();
+                move || run(&shared, observer)
+            })
+            .expect("failed to spawn the simulation thread");
+        SimulationHandle {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Advance continuously at the pace.
+    pub fn start(&self) {
+        self.update(|control| control.running = true);
+    }
+
+    /// Stop advancing, cancelling requested steps. Returns after the step in progress, if any, so that nothing is
+    /// recorded afterwards. This must not be called from [`StepObserver::on_step`].
+    pub fn stop(&self) {
+        self.update(|control| {
+            control.running = false;
+            control.requested = 0;
+        });
+        let control = self.shared.control.lock().unwrap();
+        drop(
+            self.shared
+                .changed
+                .wait_while(control, |control| control.stepping)
+                .unwrap(),
+        );
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.shared.control.lock().unwrap().running
+    }
+
+    pub fn set_pace(&self, pace: Pace) {
+        self.update(|control| control.pace = pace);
+    }
+
+    /// Request `steps` more steps, which are taken whether started or not. See [`SimulationHandle::wait`].
+    pub fn step(&self, steps: u64) {
+        self.update(|control| control.requested += steps);
+    }
+
+    /// Take the error of a step which failed, if any. The thread stops stepping after a failure until started again.
+    pub fn take_error(&self) -> Option<SimulatorError> {
+        self.shared.control.lock().unwrap().error.take()
+    }
+
+    /// Number of requested steps not taken yet
+    pub fn pending_steps(&self) -> u64 {
+        self.shared.control.lock().unwrap().requested
+    }
+
+    /// Block until the requested steps are taken.
+    pub fn wait(&self) {
+        let control = self.shared.control.lock().unwrap();
+        drop(
+            self.shared
+                .changed
+                .wait_while(control, |control| {
+                    (control.requested > 0 || control.stepping) && !control.shutdown
+                })
+                .unwrap(),
+        );
+    }
+
+    /// Access the simulator between steps.
+    pub fn with_simulator<R>(&self, f: impl FnOnce(&mut Simulator) -> R) -> R {
+        f(&mut self.shared.simulator.lock().unwrap())
+    }
+
+    /// Register a subscriber of simulation events. See [`Simulator::subscribe`].
+    pub fn subscribe(&self) -> flume::Receiver<Event> {
+        self.with_simulator(Simulator::subscribe)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Control)) {
+        f(&mut self.shared.control.lock().unwrap());
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for SimulationHandle {
+    fn drop(&mut self) {
+        self.update(|control| control.shutdown = true);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(shared: &Shared, mut observer: impl StepObserver) {
+    let mut last_step: Option<Instant> = None;
+    loop {
+        observer.between_steps(&mut shared.simulator.lock().unwrap());
+
+        let mut control = shared.control.lock().unwrap();
+        if control.shutdown {
+            return;
+        }
+        let requested = control.requested > 0;
+        let wait = if requested {
+            Duration::ZERO
+        } else {
+            match (control.running, control.pace) {
+                (true, Pace::MaxSpeed) => Duration::ZERO,
+                (true, Pace::Interval(interval)) => {
+                    interval.saturating_sub(last_step.map_or(interval, |last| last.elapsed()))
+                }
+                _ => IDLE_INTERVAL,
+            }
+        };
+        if !wait.is_zero() {
+            drop(
+                shared
+                    .changed
+                    .wait_timeout(control, wait.min(IDLE_INTERVAL))
+                    .unwrap(),
+            );
+            continue;
+        }
+        control.stepping = true;
+        drop(control);
+
+        last_step = Some(Instant::now());
+        let result = {
+            let mut simulator = shared.simulator.lock().unwrap();
+            simulator
+                .tick()
+                .map(|metrics| observer.on_step(&simulator, &metrics))
+                .inspect_err(|e| error!("Step {} failed: {e}", simulator.step))
+        };
+
+        let mut control = shared.control.lock().unwrap();
+        control.stepping = false;
+        if requested {
+            control.requested = control.requested.saturating_sub(1);
+        }
+        if let Err(e) = result {
+            control.running = false;
+            control.requested = 0;
+            control.error = Some(e);
+        }
+        drop(control);
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        diagnostic::StepMetrics, events::Event, scenario::Scenario, Simulator, SimulatorOptions,
+    };
+
+    use super::{SimulationHandle, StepObserver};
+
+    /// Observer keeping the latest step
+    struct LastStep(Arc<AtomicI32>);
+
+    impl StepObserver for LastStep {
+        fn on_step(&mut self, simulator: &Simulator, _metrics: &StepMetrics) {
+            self.0.store(simulator.step, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 3, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let simulator = Simulator::new(options, scenario).unwrap();
+        let last_step = Arc::new(AtomicI32::new(0));
+        let handle = SimulationHandle::spawn(simulator, LastStep(last_step.clone()));
+        let events = handle.subscribe();
+
+        handle.step(5);
+        handle.wait();
+        assert_eq!(handle.with_simulator(|s| s.step), 5);
+        assert_eq!(last_step.load(Ordering::SeqCst), 5);
+        let completed = events
+            .try_iter()
+            .filter(|event| matches!(event, Event::StepCompleted { .. }))
+            .count();
+        assert_eq!(completed, 5);
+
+        // Steps are not taken after stopping.
+        handle.start();
+        while last_step.load(Ordering::SeqCst) < 20 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.stop();
+        let step = handle.with_simulator(|s| s.step);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(handle.with_simulator(|s| s.step), step);
+        assert_eq!(last_step.load(Ordering::SeqCst), step);
+    }
+}
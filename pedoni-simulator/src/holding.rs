@@ -0,0 +1,174 @@
+use log::warn;
+
+use crate::{
+    models::{AgentState, PedestrianModel},
+    routing::Routing,
+    scenario::{HoldingAreaConfig, Scenario},
+};
+
+/// Let pedestrians arriving at holding areas wait there. This must precede the removal of arrived pedestrians.
+pub(crate) fn hold_arrived(
+    scenario: &Scenario,
+    routing: &Routing,
+    model: &mut dyn PedestrianModel,
+) {
+    if scenario.holding_areas.is_empty() {
+        return;
+    }
+
+    model.transition_pedestrians(&mut |pos, level, destination, state| {
+        let is_holding = |area: &HoldingAreaConfig| area.waypoint == destination;
+        (state == AgentState::Walking
+            && scenario.holding_areas.iter().any(is_holding)
+            && routing.has_arrived(destination, level, pos))
+        .then_some((AgentState::Waiting, destination))
+    });
+}
+
+/// Send pedestrians waiting in the holding area to its destination.
+pub(crate) fn release(scenario: &Scenario, area: usize, model: &mut dyn PedestrianModel) {
+    let Some(area) = scenario.holding_areas.get(area) else {
+        warn!("Holding area {area} to release does not exist");
+        return;
+    };
+
+    model.transition_pedestrians(&mut |_, _, destination, state| {
+        (state == AgentState::Waiting && destination == area.waypoint)
+            .then_some((AgentState::Released, area.destination))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+
+    use crate::{models::AgentState, scenario::Scenario, Simulator, SimulatorOptions};
+
+    #[test]
+    fn test_holding_area() {
+        // Pedestrians wait at the platform in the middle until released to the exit at 30 s.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [20.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[10.0, 1.0], [10.0, 9.0]]
+
+            [[waypoints]]
+            line = [[19.0, 1.0], [19.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 10, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+
+            [[holding_areas]]
+            waypoint = 1
+            destination = 2
+
+            [[timeline]]
+            time = 30.0
+            action = "release_holding_area"
+            area = 0
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        for _ in 0..290 {
+            simulator.tick().unwrap();
+        }
+        let pedestrians = simulator.list_pedestrians();
+        assert_eq!(pedestrians.len(), 10);
+        assert!(pedestrians.iter().all(|p| p.state == AgentState::Waiting));
+        // Waiting pedestrians stand around the platform without walking on.
+        assert!(
+            pedestrians.iter().all(|p| p.pos.x < 12.0),
+            "{pedestrians:?}"
+        );
+        assert!(pedestrians.iter().all(|p| p.vel.length() < 0.3));
+
+        for _ in 0..10 {
+            simulator.tick().unwrap();
+        }
+        let pedestrians = simulator.list_pedestrians();
+        assert!(pedestrians
+            .iter()
+            .all(|p| p.state == AgentState::Released && p.destination == 2));
+
+        for _ in 0..300 {
+            simulator.tick().unwrap();
+        }
+        assert_eq!(simulator.list_pedestrians().len(), 0);
+    }
+
+    #[test]
+    fn test_freeze_idle() {
+        // Two pedestrians wait far apart on the platform.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [20.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[10.0, 1.0], [10.0, 9.0]]
+
+            [[waypoints]]
+            line = [[19.0, 1.0], [19.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 1, region = [[1.0, 1.5], [2.0, 1.5], [2.0, 2.5], [1.0, 2.5]] }
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 1, region = [[1.0, 7.5], [2.0, 7.5], [2.0, 8.5], [1.0, 8.5]] }
+
+            [[holding_areas]]
+            waypoint = 1
+            destination = 2
+
+            [[timeline]]
+            time = 30.0
+            action = "release_holding_area"
+            area = 0
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            freeze_idle: true,
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        let mut frozen = 0;
+        for _ in 0..290 {
+            frozen = simulator.tick().unwrap().neighbors.unwrap().frozen;
+        }
+        assert_eq!(frozen, 2);
+        let pedestrians = simulator.list_pedestrians();
+        assert!(pedestrians.iter().all(|p| p.vel == Vec2::ZERO));
+
+        // Released pedestrians wake and leave.
+        for _ in 0..20 {
+            frozen = simulator.tick().unwrap().neighbors.unwrap().frozen;
+        }
+        assert_eq!(frozen, 0);
+        for _ in 0..150 {
+            simulator.tick().unwrap();
+        }
+        assert_eq!(simulator.list_pedestrians().len(), 0);
+    }
+}
@@ -1,107 +1,611 @@
+#[cfg(feature = "parquet")]
+pub mod arrow_export;
+pub mod calibration;
+pub mod connectivity;
+pub mod density_control;
 pub mod diagnostic;
+pub mod dry_run;
+pub mod error;
+pub mod events;
+pub mod exit_capacity;
+pub mod exit_choice;
 pub mod field;
+pub mod floor_plan;
+pub mod gate;
+pub mod generator;
+pub mod handle;
+mod holding;
+pub mod migration;
 pub mod models;
 mod neighbor_grid;
+pub mod obstacle_index;
+pub mod region;
+pub mod replication;
+pub mod rng;
+pub mod routing;
 pub mod scenario;
+pub mod sensor;
+pub mod snapshot;
+pub mod spawner;
+pub mod speed_density;
+pub mod timeline;
+pub mod trajectory;
+pub mod tuning;
 pub mod util;
+pub mod vehicle;
 
-use std::time::Instant;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
 
-use diagnostic::StepMetrics;
-use field::Field;
-use log::info;
-use models::{Pedestrian, PedestrianModel, SocialForceModel, SocialForceModelGpu};
-use scenario::{PedestrianSpawnConfig, Scenario};
+use density_control::DensityController;
+use diagnostic::{ForceMetrics, PressureField, StepMetrics};
+use dry_run::Route;
+use error::{Result, SimulatorError};
+use events::{Event, EventBus};
+use exit_choice::ExitChoice;
+use field::{Field, FieldBuilder, FmmOptions};
+use gate::Gates;
+use geo::Polygon;
+use glam::Vec2;
+use log::{info, warn};
+use models::{
+    ForceBreakdown, Pedestrian, PedestrianModel, PedestrianSlices, SocialForceModel,
+    SocialForceModelGpu,
+};
+use neighbor_grid::NeighborGrid;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rng::{RngStreams, Stream};
+use routing::Routing;
+use scenario::Scenario;
+use sensor::Sensors;
+use spawner::Spawner;
+use timeline::{Change, Timeline};
+use vehicle::Vehicle;
 
 /// Simulator instance.
 pub struct Simulator {
     pub options: SimulatorOptions,
     pub scenario: Scenario,
-    pub field: Field,
+    /// Fields of [`Scenario::levels`], one per level
+    pub fields: Vec<Field>,
     pub model: Box<dyn PedestrianModel>,
+    pub gates: Gates,
+    pub routing: Routing,
+    pub spawner: Spawner,
+    pub timeline: Timeline,
+    pub exit_choice: ExitChoice,
+    /// Controller of spawn rates, if [`Scenario::density_control`] is given
+    pub density_control: Option<DensityController>,
+    /// Counting cameras of [`Scenario::sensors`] and their readings in the last step
+    pub sensors: Sensors,
+    /// Crowd pressure in the last step, if [`SimulatorOptions::record_crowd_pressure`] is enabled
+    pub crowd_pressure: Option<PressureField>,
+    /// Spawn areas from which destinations are unreachable, found when the simulator is prepared
+    pub unreachable: Vec<Route>,
     pub step: i32,
+    pub events: EventBus,
+    /// Random streams of subsystems, seeded by [`SimulatorOptions::seed`]
+    pub rng: RngStreams,
+    /// Steps when pedestrians were spawned, recorded while events are subscribed
+    spawn_steps: HashMap<usize, i32>,
+    /// Regions of [`Scenario::kill_zones`]
+    kill_zones: Vec<Polygon<f32>>,
+    /// IDs of pedestrians to be removed in the next step
+    despawn_requests: HashSet<usize>,
+    /// Number of pedestrians refused so far because of [`SimulatorOptions::max_pedestrians`]
+    refused_total: usize,
+    /// Thread pool running steps, if [`SimulatorOptions::cpu_threads`] is given
+    pool: Option<Arc<ThreadPool>>,
+    /// Grid of positions built by the first [`Simulator::query_region`] in each step
+    region_index: OnceLock<NeighborGrid>,
 }
 
 impl Simulator {
     // Prepare a new simulator with given options and scenario.
-    pub fn new(options: SimulatorOptions, scenario: Scenario) -> Self {
-        info!("Simulator options: {options:#?}");
+    pub fn new(options: SimulatorOptions, scenario: Scenario) -> Result<Self> {
+        scenario.validate().map_err(SimulatorError::Scenario)?;
+        let pool = thread_pool(&options)?;
+        let fields = install(pool.as_deref(), || {
+            FieldBuilder::new(scenario.field.size, options.field_grid_unit)
+                .fmm_options(options.fmm)
+                .build_scenario(&scenario)
+        })?;
+        let model = new_model(&options, &scenario, &fields)?;
+        Ok(Self::assemble(options, scenario, fields, model, pool))
+    }
 
-        let field = Field::from_scenario(&scenario, options.field_grid_unit);
+    /// Prepare a new simulator with fields built in advance, e.g. with [`FieldBuilder::on_progress`].
+    pub fn with_fields(
+        options: SimulatorOptions,
+        scenario: Scenario,
+        fields: Vec<Field>,
+    ) -> Result<Self> {
+        // Models index objects of the scenario without checks, so it is validated before building one.
+        scenario.validate().map_err(SimulatorError::Scenario)?;
+        let model = new_model(&options, &scenario, &fields)?;
+        let pool = thread_pool(&options)?;
+        Ok(Self::assemble(options, scenario, fields, model, pool))
+    }
 
-        let mut model: Box<dyn PedestrianModel> = match options.backend {
-            Backend::Cpu => Box::new(SocialForceModel::new(&options, &scenario, &field)),
-            Backend::Gpu => Box::new(SocialForceModelGpu::new(&options, &scenario, &field)),
-        };
+    /// Prepare a new simulator driving a model other than the built-in ones, which is given no pedestrians yet.
+    pub fn with_model(
+        options: SimulatorOptions,
+        scenario: Scenario,
+        fields: Vec<Field>,
+        model: Box<dyn PedestrianModel>,
+    ) -> Result<Self> {
+        scenario.validate().map_err(SimulatorError::Scenario)?;
+        let pool = thread_pool(&options)?;
+        Ok(Self::assemble(options, scenario, fields, model, pool))
+    }
 
-        let mut new_pedestrians = Vec::new();
-        for pedestrian in scenario.pedestrians.iter() {
-            if let PedestrianSpawnConfig::Once { count } = pedestrian.spawn {
-                let [p_1, p_2] = scenario.waypoints[pedestrian.origin].line;
-
-                for _ in 0..count {
-                    let pos = p_1.lerp(p_2, fastrand::f32());
-                    new_pedestrians.push(Pedestrian {
-                        pos,
-                        destination: pedestrian.destination,
-                        ..Default::default()
-                    })
-                }
-            }
+    fn assemble(
+        options: SimulatorOptions,
+        scenario: Scenario,
+        fields: Vec<Field>,
+        mut model: Box<dyn PedestrianModel>,
+        pool: Option<Arc<ThreadPool>>,
+    ) -> Self {
+        info!("Simulator options: {options:#?}");
+        let mut unreachable = dry_run::routes(&scenario, &fields);
+        unreachable.retain(|route| route.blocked_ratio > 0.0);
+        for warning in unreachable.iter().filter_map(Route::warning) {
+            warn!("{warning}");
+        }
+
+        let mut rng = RngStreams::from_seed(options.seed);
+        let routing = Routing::new(&options, &scenario);
+        let mut spawner = Spawner::new(&scenario);
+        spawner.spawn_initial(&scenario, &fields, &mut rng);
+        let mut new_pedestrians = spawner.release(&options, &scenario, &[]);
+        let refused_total = refuse_excess(&options, 0, &mut new_pedestrians);
+        if refused_total > 0 {
+            warn!(
+                "Refused {refused_total} initial pedestrians beyond the max of {} pedestrians",
+                options.max_pedestrians.unwrap_or_default()
+            );
         }
-        model.spawn_pedestrians(&field, new_pedestrians);
+        model.spawn_pedestrians(&fields, &routing, new_pedestrians);
+
+        let gates = Gates::new(&scenario.gates);
+        let timeline = Timeline::new(&scenario);
+        let exit_choice = ExitChoice::new(&scenario);
+        let density_control = scenario
+            .density_control
+            .as_ref()
+            .map(DensityController::new);
+        let sensors = Sensors::new(&scenario);
+        let kill_zones = scenario
+            .kill_zones
+            .iter()
+            .map(|zone| util::polygon(&zone.polygon))
+            .collect();
 
         Simulator {
             options,
             scenario,
-            field,
+            fields,
             model,
+            gates,
+            routing,
+            spawner,
+            timeline,
+            exit_choice,
+            density_control,
+            sensors,
+            crowd_pressure: None,
+            unreachable,
             step: 0,
+            events: EventBus::default(),
+            rng,
+            spawn_steps: HashMap::new(),
+            kill_zones,
+            despawn_requests: HashSet::new(),
+            refused_total,
+            pool,
+            region_index: OnceLock::new(),
         }
     }
 
     // Step the time and update pedestrians' positions.
-    pub fn tick(&mut self) -> StepMetrics {
-        self.step += 1;
+    //
+    // Fails if the model cannot update the states, e.g. when the GPU device is lost, after which the simulator should
+    // not be stepped further.
+    pub fn tick(&mut self) -> Result<StepMetrics> {
+        self.region_index.take();
+        let pool = self.pool.clone();
+        install(pool.as_deref(), || self.tick_in_pool())
+    }
 
-        // Spawn / despawn pedestrians
-        let instant = Instant::now();
-        let mut new_pedestrians = Vec::new();
-        for pedestrian in self.scenario.pedestrians.iter() {
-            if let PedestrianSpawnConfig::Periodic { frequency } = pedestrian.spawn {
-                let [p_1, p_2] = self.scenario.waypoints[pedestrian.origin].line;
-                let count = util::poisson(frequency / 10.0);
-
-                for _ in 0..count {
-                    let pos = p_1.lerp(p_2, fastrand::f32());
-                    new_pedestrians.push(Pedestrian {
-                        pos,
-                        destination: pedestrian.destination,
-                        ..Default::default()
-                    })
+    fn tick_in_pool(&mut self) -> Result<StepMetrics> {
+        self.step += 1;
+        let time = self.step as f64 * 0.1;
+        for change in self.timeline.update(&mut self.scenario, time) {
+            match change {
+                Change::Reroute(reroute) => {
+                    let f = &mut |_, group| reroute.apply(group);
+                    self.model.reroute_pedestrians(f);
+                    self.spawner.reroute_waiting(f);
+                }
+                Change::Release { area } => {
+                    holding::release(&self.scenario, area, self.model.as_mut())
                 }
             }
         }
-        self.model.spawn_pedestrians(&self.field, new_pedestrians);
-        let time_spawn = instant.elapsed().as_secs_f64();
+        self.routing.update(&self.scenario, time);
+        self.exit_choice.update(
+            &self.scenario,
+            &self.fields,
+            &self.routing,
+            self.model.as_mut(),
+            time,
+        );
+
+        // Start updating states, which runs in the background on the GPU backend
+        let instant = Instant::now();
+        self.model
+            .dispatch_states(&self.scenario, &self.fields, &self.routing)?;
+        let time_dispatch = instant.elapsed().as_secs_f64();
+
+        // Generate pedestrians while the states are being calculated
+        let instant = Instant::now();
+        let density_control = self
+            .density_control
+            .as_mut()
+            .map(|controller| controller.update(self.model.as_ref(), 0.1));
+        let rate_scale = density_control.map_or(1.0, |state| state.scale);
+        self.spawner.spawn_periodic(
+            &self.options,
+            &self.scenario,
+            &self.fields,
+            &self.routing,
+            rate_scale,
+            &mut self.rng,
+        );
+        let mut new_pedestrians =
+            self.spawner
+                .release(&self.options, &self.scenario, self.model.slices().positions);
+        let mut time_spawn = instant.elapsed().as_secs_f64();
 
         // Update states
         let instant = Instant::now();
-        self.model.update_states(&self.scenario, &self.field);
-        let time_calc_state = instant.elapsed().as_secs_f64();
+        self.gates.begin_step(0.1);
+        self.gates.measure_occupancy(self.model.as_ref());
+        let out_of_bounds = self.model.update_states(
+            &self.scenario,
+            &self.fields,
+            &self.routing,
+            &mut self.gates,
+        )?;
+        let time_calc_state = time_dispatch + instant.elapsed().as_secs_f64();
+
+        // Move pedestrians between levels
+        if !self.scenario.links.is_empty() {
+            let Simulator {
+                scenario,
+                fields,
+                routing,
+                model,
+                rng,
+                ..
+            } = self;
+
+            model.relocate_pedestrians(&mut |pos, level, destination| {
+                let target = routing.target(destination)?;
+                let potential = fields[level].get_potential(target, pos);
+
+                scenario.links.iter().find_map(|link| {
+                    if !routing.is_in_arrival_area(link.from, level, pos) {
+                        return None;
+                    }
+
+                    let to = &scenario.waypoints[link.to];
+                    let [p_1, p_2] = to.line;
+                    let next_pos = p_1.lerp(p_2, rng.get(Stream::Link).f32());
+                    (fields[to.level].get_potential(target, next_pos) + 0.5 * link.length
+                        < potential)
+                        .then_some((next_pos, to.level))
+                })
+            });
+        }
+
+        // Remove pedestrians requested to despawn or in kill zones
+        let despawned_count =
+            self.despawn_pedestrians() + self.report_out_of_bounds(&out_of_bounds.despawned);
+        holding::hold_arrived(&self.scenario, &self.routing, self.model.as_mut());
+
+        // Spawn / despawn pedestrians, who will move from the next step
+        let instant = Instant::now();
+        let refused_count = refuse_excess(
+            &self.options,
+            self.model.get_pedestrian_count() as usize,
+            &mut new_pedestrians,
+        );
+        if refused_count > 0 && self.refused_total == 0 {
+            warn!(
+                "Reached the max of {} pedestrians at step {}; pedestrians spawned beyond it are refused",
+                self.options.max_pedestrians.unwrap_or_default(),
+                self.step
+            );
+        }
+        self.refused_total += refused_count;
+        let spawned_count = new_pedestrians.len() as i32;
+        if self.events.is_active() {
+            self.emit_spawn_events(&new_pedestrians);
+        }
+        let arrived = self
+            .model
+            .spawn_pedestrians(&self.fields, &self.routing, new_pedestrians);
+        if self.events.is_active() {
+            self.emit_arrival_events(&arrived);
+        }
+        time_spawn += instant.elapsed().as_secs_f64();
+
+        self.sensors.update(
+            &self.scenario,
+            self.model.as_ref(),
+            self.step,
+            time,
+            self.rng.get(Stream::Sensor),
+        );
 
         // Record performance metrics
-        StepMetrics {
+        let forces = self.model.list_forces().map(|forces| {
+            let n = forces.len().max(1) as f64;
+            let mean = |f: fn(&ForceBreakdown) -> Vec2| {
+                forces.iter().map(|x| f(x).length() as f64).sum::<f64>() / n
+            };
+            ForceMetrics {
+                destination: mean(|x| x.destination),
+                pedestrians: mean(|x| x.pedestrians),
+                obstacles: mean(|x| x.obstacles),
+            }
+        });
+
+        let lane_order = self.options.record_lane_order.then(|| {
+            let mut pedestrians = Vec::new();
+            self.model
+                .for_each_pedestrian(&mut |p| pedestrians.push((p.pos, p.vel)));
+            diagnostic::lane_order(pedestrians)
+        });
+
+        if self.options.record_crowd_pressure {
+            let mut pedestrians = Vec::new();
+            self.model
+                .for_each_pedestrian(&mut |p| pedestrians.push((p.pos, p.vel)));
+            let size = self.scenario.field.size;
+            self.crowd_pressure
+                .get_or_insert_with(|| PressureField::new(size))
+                .update(pedestrians, 0.1);
+        }
+
+        let metrics = StepMetrics {
             active_ped_count: self.model.get_pedestrian_count(),
             time_spawn,
             time_calc_state,
-            time_calc_state_kernel: None,
+            time_calc_state_kernel: self.model.time_kernel().map(|t| t.as_secs_f64()),
+            gate_queue_lengths: self.gates.queue_lengths(),
+            gate_occupancies: self.gates.occupancies(),
+            spawn_queue_length: self.spawner.queue_length(),
+            spawned_count,
+            refused_count: refused_count as i32,
+            unplaced_count: self.spawner.take_unplaced_count(),
+            arrived_count: arrived.len() as i32,
+            exit_counts: self.count_exits(&arrived),
+            out_of_bounds_count: out_of_bounds.count,
+            despawned_count,
+            neighbors: self.model.neighbor_stats(),
+            lane_order: lane_order.flatten(),
+            forces,
+            density_control,
+            crowd_pressure: self.crowd_pressure.as_ref().and_then(PressureField::max),
+        };
+
+        if self.events.is_active() {
+            for &(gate, id) in self.gates.passages() {
+                self.events.emit(Event::GatePassed { id, gate });
+            }
+            self.events.emit(Event::StepCompleted {
+                step: self.step,
+                metrics: metrics.clone(),
+            });
+        }
+
+        Ok(metrics)
+    }
+
+    /// Remove pedestrians with the given IDs. They are removed in the next step, emitting [`Event::PedestrianDespawned`].
+    ///
+    /// IDs of pedestrians which no longer exist are ignored.
+    pub fn despawn(&mut self, ids: impl IntoIterator<Item = usize>) {
+        self.despawn_requests.extend(ids);
+    }
+
+    /// Change destinations of pedestrians with the given IDs, including ones waiting to be spawned.
+    ///
+    /// Unlike [`Simulator::despawn`], the change takes effect immediately. IDs of pedestrians which no longer exist are ignored.
+    pub fn set_destination(&mut self, ids: impl IntoIterator<Item = usize>, destination: usize) {
+        let ids: HashSet<usize> = ids.into_iter().collect();
+        let f = &mut |id, _| ids.contains(&id).then_some(destination);
+        self.model.reroute_pedestrians(f);
+        self.spawner.reroute_waiting(f);
+    }
+
+    /// Replace vehicles which pedestrians avoid as moving obstacles, e.g. with states received from a traffic simulator
+    /// every step. They stay in place until replaced again.
+    pub fn set_vehicles(&mut self, vehicles: Vec<Vehicle>) {
+        self.model.set_vehicles(vehicles);
+    }
+
+    /// Remove pedestrians requested by [`Simulator::despawn`] and ones in kill zones. Returns the number of them.
+    fn despawn_pedestrians(&mut self) -> i32 {
+        if self.despawn_requests.is_empty() && self.kill_zones.is_empty() {
+            return 0;
         }
+
+        let Simulator {
+            model,
+            kill_zones,
+            despawn_requests,
+            ..
+        } = self;
+        let mut zones_entered = HashMap::new();
+        let removed = model.retain_pedestrians(&mut |id, pos| {
+            if despawn_requests.remove(&id) {
+                return false;
+            }
+            match kill_zones
+                .iter()
+                .position(|zone| util::polygon_contains(zone, pos))
+            {
+                Some(zone) => {
+                    zones_entered.insert(id, zone);
+                    false
+                }
+                None => true,
+            }
+        });
+        despawn_requests.clear();
+
+        if self.events.is_active() {
+            for p in &removed {
+                self.spawn_steps.remove(&p.id);
+                self.events.emit(Event::PedestrianDespawned {
+                    id: p.id,
+                    kill_zone: zones_entered.get(&p.id).copied(),
+                });
+            }
+        }
+
+        removed.len() as i32
+    }
+
+    /// Emit events of pedestrians removed for leaving the field. Returns the number of them.
+    fn report_out_of_bounds(&mut self, despawned: &[usize]) -> i32 {
+        if self.events.is_active() {
+            for &id in despawned {
+                self.spawn_steps.remove(&id);
+                self.events.emit(Event::PedestrianDespawned {
+                    id,
+                    kill_zone: None,
+                });
+            }
+        }
+        despawned.len() as i32
+    }
+
+    /// Register a subscriber of simulation events. See [`EventBus::subscribe`].
+    pub fn subscribe(&mut self) -> flume::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    fn emit_spawn_events(&mut self, new_pedestrians: &[Pedestrian]) {
+        for p in new_pedestrians {
+            self.spawn_steps.insert(p.id, self.step);
+            self.events.emit(Event::PedestrianSpawned {
+                id: p.id,
+                pos: p.pos,
+                origin: self.scenario.pedestrians[p.group].origin,
+            });
+        }
+    }
+
+    /// Count arrived pedestrians by the waypoint at which they were absorbed.
+    fn count_exits(&self, arrived: &[Pedestrian]) -> Vec<i32> {
+        let mut counts = vec![0; self.scenario.waypoints.len()];
+        for p in arrived {
+            if let Some(waypoint) = self.routing.arrival_waypoint(p.destination, p.level, p.pos) {
+                counts[waypoint] += 1;
+            }
+        }
+        counts
+    }
+
+    fn emit_arrival_events(&mut self, arrived: &[Pedestrian]) {
+        for p in arrived {
+            let spawn_step = self.spawn_steps.remove(&p.id).unwrap_or(0);
+            self.events.emit(Event::PedestrianArrived {
+                id: p.id,
+                travel_time: (self.step - spawn_step) as f64 * 0.1,
+            });
+        }
+    }
+
+    /// Whether the last step is in the warm-up period of [`SimulatorOptions::warmup_steps`].
+    pub fn is_warming_up(&self) -> bool {
+        self.step <= self.options.warmup_steps
     }
 
     pub fn list_pedestrians(&self) -> Vec<Pedestrian> {
         self.model.list_pedestrians()
     }
+
+    /// Call `f` with each pedestrian. See [`PedestrianModel::for_each_pedestrian`].
+    pub fn for_each_pedestrian(&self, mut f: impl FnMut(Pedestrian)) {
+        self.model.for_each_pedestrian(&mut f)
+    }
+
+    /// Borrow states of pedestrians as columns. See [`PedestrianModel::slices`].
+    pub fn slices(&self) -> PedestrianSlices<'_> {
+        self.model.slices()
+    }
+
+    /// List forces acting on each pedestrian in the last step. See [`PedestrianModel::list_forces`].
+    pub fn list_forces(&self) -> Option<Vec<ForceBreakdown>> {
+        self.model.list_forces()
+    }
+}
+
+fn new_model(
+    options: &SimulatorOptions,
+    scenario: &Scenario,
+    fields: &[Field],
+) -> Result<Box<dyn PedestrianModel>> {
+    Ok(match options.backend {
+        Backend::Cpu => Box::new(SocialForceModel::new(options, scenario, fields)?),
+        Backend::Gpu => Box::new(SocialForceModelGpu::new(options, scenario, fields)?),
+    })
+}
+
+/// Build a dedicated thread pool with [`SimulatorOptions::cpu_threads`] threads, if given.
+fn thread_pool(options: &SimulatorOptions) -> Result<Option<Arc<ThreadPool>>> {
+    let Some(threads) = options.cpu_threads else {
+        return Ok(None);
+    };
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("pedoni-simulator-{i}"))
+        .build()?;
+    Ok(Some(Arc::new(pool)))
+}
+
+/// Run `f` in the pool if given, or in the global pool of rayon otherwise.
+fn install<R: Send>(pool: Option<&ThreadPool>, f: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+/// Drop new pedestrians who would exceed [`SimulatorOptions::max_pedestrians`] given `active` ones in the field.
+///
+/// Returns the number of dropped pedestrians.
+fn refuse_excess(
+    options: &SimulatorOptions,
+    active: usize,
+    pedestrians: &mut Vec<Pedestrian>,
+) -> usize {
+    let Some(max) = options.max_pedestrians else {
+        return 0;
+    };
+    let count = pedestrians.len().min(max.saturating_sub(active));
+    let refused = pedestrians.len() - count;
+    pedestrians.truncate(count);
+    refused
 }
 
 /// Simulator options.
@@ -109,27 +613,110 @@ impl Simulator {
 pub struct SimulatorOptions {
     /// Backend type: CPU or GPU    
     pub backend: Backend,
-    /// Unit length of the neighbor search grid. (meters)
-    pub neighbor_grid_unit: f32,
+    /// Unit length of the neighbor search grid (meters), or `None` to derive it from
+    /// [`INTERACTION_CUTOFF`](models::INTERACTION_CUTOFF). Only adjacent cells are searched, so interactions are
+    /// missed with units smaller than the cutoff.
+    pub neighbor_grid_unit: Option<f32>,
     /// Unit length of potential maps and distance maps. (meters)
     pub field_grid_unit: f32,
+    /// Options of the fast marching method used to build potential maps.
+    pub fmm: FmmOptions,
     /// Whether to use neighbor search grid.
     pub use_neighbor_grid: bool,
     /// Whether to use a descretized distance map for calculating repusive effects against obstacles.
     pub use_distance_map: bool,
+    /// Whether to evaluate distances to obstacles nearby analytically, using the distance map only far from them.
+    /// See [`obstacle_index::ObstacleIndex`]. (CPU backend only)
+    pub exact_obstacle_distance: bool,
     /// Local workgroup size of GPU kernels.
     pub gpu_work_size: usize,
+    /// Index of the OpenCL platform used by the GPU backend. See [`models::list_gpu_devices`].
+    pub gpu_platform: usize,
+    /// Index of the OpenCL device in the platform used by the GPU backend.
+    pub gpu_device: usize,
+    /// Precision of positions and velocities stored in the GPU memory.
+    pub gpu_precision: GpuPrecision,
+    /// Path to an OpenCL source used instead of the embedded kernels.
+    pub gpu_kernel_path: Option<PathBuf>,
+    /// Options passed to the OpenCL compiler, e.g. `-cl-fast-relaxed-math -cl-mad-enable`.
+    ///
+    /// `COS_PHI` is no longer read by the kernels and is warned of. Set
+    /// [`BehaviorConfig::field_of_view`](scenario::BehaviorConfig::field_of_view) instead.
+    pub gpu_build_options: String,
+    /// Default distance from waypoints within which pedestrians are regarded as arrived. (meters)
+    pub arrival_distance: f32,
+    /// Max density around origins (persons/m^2). Pedestrians wait to be spawned while the density exceeds it.
+    pub spawn_density_limit: Option<f32>,
+    /// Min distance between pedestrians entering from the same origin in a step (meters). If given, their arrivals
+    /// are also spread over the step instead of all at its start, which avoids overlaps at high spawn rates.
+    pub spawn_spacing: Option<f32>,
+    /// Max number of pedestrians in the field. Pedestrians spawned beyond it are refused and counted in
+    /// [`StepMetrics::refused_count`].
+    pub max_pedestrians: Option<usize>,
+    /// Number of threads used for the CPU work of steps. Steps run in a dedicated pool of this size instead of the
+    /// global pool of rayon, which takes all cores.
+    pub cpu_threads: Option<usize>,
+    /// Whether to skip forces which have no effect, to accelerate scenarios where activity is localized.
+    /// (CPU backend only)
+    ///
+    /// - Pedestrians at rest with nowhere to go, e.g. in holding areas, are frozen while no pedestrian around moves.
+    ///   They wake when a moving pedestrian or a vehicle comes within the interaction range, or their destinations
+    ///   open.
+    /// - Pedestrians with no other pedestrian, obstacle or vehicle within the interaction range only follow the
+    ///   potential, skipping the built-in forces of the pedestrians and obstacles categories, until any of them comes
+    ///   within the range. Custom [`ForceTerm`](models::ForceTerm)s are always calculated.
+    pub freeze_idle: bool,
+    /// Whether to record forces acting on each pedestrian separately. (CPU backend only)
+    pub record_forces: bool,
+    /// How to handle pedestrians which leave the field.
+    pub out_of_bounds: OutOfBoundsPolicy,
+    /// Whether to record the lane order parameter of bidirectional flow. See [`diagnostic::lane_order`].
+    pub record_lane_order: bool,
+    /// Whether to calculate the crowd pressure each step. See [`PressureField`].
+    pub record_crowd_pressure: bool,
+    /// Number of steps at the beginning regarded as warm-up, while the field fills with pedestrians. Exporters and
+    /// the diagnostic log start recording after it, so that statistics are of the steady state.
+    pub warmup_steps: i32,
+    /// Whether to accumulate forces in a fixed order, so that runs with the same seed are bit-identical at some performance cost.
+    ///
+    /// The CPU backend is deterministic regardless. The GPU backend otherwise sums forces from neighbors in the order
+    /// they were sorted into the neighbor grid, which depends on thread scheduling.
+    pub strict_determinism: bool,
+    /// Seed of the random streams of subsystems. See [`RngStreams`].
+    ///
+    /// If not given, the seed is drawn from the global generator of `fastrand`, so that `fastrand::seed` still makes runs reproducible.
+    pub seed: Option<u64>,
 }
 
 impl Default for SimulatorOptions {
     fn default() -> Self {
         SimulatorOptions {
             backend: Backend::Cpu,
-            neighbor_grid_unit: 1.4,
+            neighbor_grid_unit: None,
             field_grid_unit: 0.25,
+            fmm: FmmOptions::default(),
             use_neighbor_grid: true,
             use_distance_map: true,
+            exact_obstacle_distance: false,
             gpu_work_size: 64,
+            gpu_platform: 0,
+            gpu_device: 0,
+            gpu_precision: GpuPrecision::Single,
+            gpu_kernel_path: None,
+            gpu_build_options: String::new(),
+            arrival_distance: 0.25,
+            spawn_density_limit: None,
+            spawn_spacing: None,
+            max_pedestrians: None,
+            cpu_threads: None,
+            freeze_idle: false,
+            warmup_steps: 0,
+            record_forces: false,
+            out_of_bounds: OutOfBoundsPolicy::Clamp,
+            record_lane_order: false,
+            record_crowd_pressure: false,
+            strict_determinism: false,
+            seed: None,
         }
     }
 }
@@ -140,3 +727,539 @@ pub enum Backend {
     Cpu,
     Gpu,
 }
+
+/// Precision of pedestrian states stored in the GPU memory.
+///
+/// Forces are always calculated in single precision. Half precision halves memory traffic for large crowds,
+/// while positions are rounded to about 3 cm at 100 m from the origin.
+#[derive(Debug, Clone, Copy)]
+pub enum GpuPrecision {
+    Single,
+    Half,
+}
+
+/// Handling of pedestrians which leave the field, e.g. by tunneling through the obstacles on its border.
+#[derive(Debug, Clone, Copy)]
+pub enum OutOfBoundsPolicy {
+    /// Move pedestrians back onto the border of the field.
+    Clamp,
+    /// Remove pedestrians with a warning.
+    Despawn,
+    /// Remove pedestrians with an error logged, e.g. so that the CLI stops the run.
+    Error,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use glam::{vec2, Vec2};
+
+    use crate::{
+        events::Event,
+        scenario::{
+            FieldConfig, KillZoneConfig, LevelConfig, PedestrianConfig, PedestrianSpawnConfig,
+            Scenario, SpawnRegion, WaypointConfig,
+        },
+        Backend, OutOfBoundsPolicy, Simulator, SimulatorError, SimulatorOptions,
+    };
+
+    #[test]
+    fn test_despawn() {
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 10.0),
+                ..Default::default()
+            },
+            waypoints: vec![WaypointConfig {
+                line: [vec2(9.0, 1.0), vec2(9.0, 9.0)],
+                ..Default::default()
+            }],
+            pedestrians: vec![PedestrianConfig {
+                origin: 0,
+                destination: Some(0),
+                spawn: PedestrianSpawnConfig::Once {
+                    count: 20,
+                    region: Some(SpawnRegion::Polygon(vec![
+                        vec2(1.0, 1.0),
+                        vec2(8.0, 1.0),
+                        vec2(8.0, 9.0),
+                        vec2(1.0, 9.0),
+                    ])),
+                },
+                ..Default::default()
+            }],
+            kill_zones: vec![KillZoneConfig {
+                polygon: vec![
+                    vec2(0.0, 0.0),
+                    vec2(5.0, 0.0),
+                    vec2(5.0, 10.0),
+                    vec2(0.0, 10.0),
+                ],
+            }],
+            ..Default::default()
+        };
+
+        fastrand::seed(1);
+        let mut simulator = Simulator::new(SimulatorOptions::default(), scenario).unwrap();
+        let events = simulator.subscribe();
+        let survivor = simulator
+            .list_pedestrians()
+            .into_iter()
+            .max_by(|a, b| a.pos.x.total_cmp(&b.pos.x))
+            .unwrap();
+        assert!(survivor.pos.x > 5.0);
+        simulator.despawn([survivor.id]);
+
+        simulator.tick().unwrap();
+        let pedestrians = simulator.list_pedestrians();
+        assert!(pedestrians.iter().all(|p| p.pos.x >= 5.0));
+        assert!(pedestrians.iter().all(|p| p.id != survivor.id));
+
+        let despawned: Vec<_> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::PedestrianDespawned { id, kill_zone } => Some((id, kill_zone)),
+                _ => None,
+            })
+            .collect();
+        assert!(despawned.contains(&(survivor.id, None)));
+        assert!(despawned.iter().any(|&(_, zone)| zone == Some(0)));
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        // Panicking pedestrians overshoot the thin destination near the border and leave the field.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 4.0] }
+            obstacles = []
+            panic = { desired_speed = 8.0 }
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 3.0]]
+
+            [[waypoints]]
+            line = [[9.9, 1.0], [9.9, 3.0]]
+            width = 0.01
+            arrival_distance = 0.0
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            panic = true
+            spawn = { kind = "periodic", rate = 2.0 }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            out_of_bounds: OutOfBoundsPolicy::Error,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        let events = simulator.subscribe();
+
+        let mut despawned_count = 0;
+        for _ in 0..100 {
+            let metrics = simulator.tick().unwrap();
+            assert_eq!(metrics.despawned_count, metrics.out_of_bounds_count);
+            despawned_count += metrics.despawned_count;
+        }
+        assert!(despawned_count > 0);
+        simulator.for_each_pedestrian(|p| assert!(p.pos.x <= 10.0));
+
+        let events = events
+            .try_iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    Event::PedestrianDespawned {
+                        kill_zone: None,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(events as i32, despawned_count);
+    }
+
+    #[test]
+    fn test_forces() {
+        // Pedestrians are spawned, leave the field and arrive every step, which reorders them in the model.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 4.0] }
+            obstacles = []
+            panic = { desired_speed = 8.0 }
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 3.0]]
+
+            [[waypoints]]
+            line = [[9.9, 1.0], [9.9, 3.0]]
+            width = 0.01
+            arrival_distance = 0.0
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            panic = true
+            spawn = { kind = "periodic", rate = 2.0 }
+
+            [[pedestrians]]
+            origin = 1
+            destination = 0
+            spawn = { kind = "periodic", rate = 2.0 }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            record_forces: true,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+
+        let mut checked = 0;
+        for _ in 0..200 {
+            let velocities: HashMap<_, _> = simulator
+                .list_pedestrians()
+                .iter()
+                .map(|p| (p.id, p.vel))
+                .collect();
+            simulator.tick().unwrap();
+            let forces: HashMap<_, _> = simulator
+                .list_forces()
+                .unwrap()
+                .into_iter()
+                .map(|f| (f.id, f))
+                .collect();
+            assert!(velocities.keys().all(|id| forces.contains_key(id)));
+
+            // Velocities below the max speed change exactly by the forces.
+            for p in simulator.list_pedestrians() {
+                let Some(vel_prev) = velocities.get(&p.id) else {
+                    continue;
+                };
+                if p.vel != Vec2::ZERO && p.vel.length() < 1.0 {
+                    let expected = *vel_prev + forces[&p.id].total() * 0.1;
+                    assert!(p.vel.distance(expected) < 1e-4, "{} {expected}", p.vel);
+                    checked += 1;
+                }
+            }
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn test_panic() {
+        let scenario = |panic: bool| {
+            Scenario::from_toml(&format!(
+                r#"
+                field = {{ size = [20.0, 4.0] }}
+                obstacles = []
+
+                [[waypoints]]
+                line = [[1.0, 1.0], [1.0, 3.0]]
+
+                [[waypoints]]
+                line = [[19.0, 1.0], [19.0, 3.0]]
+
+                [[pedestrians]]
+                origin = 0
+                destination = 1
+                panic = {panic}
+                spawn = {{ kind = "once", count = 10, region = [[1.0, 1.0], [4.0, 1.0], [4.0, 3.0], [1.0, 3.0]] }}
+                "#
+            ))
+            .unwrap()
+        };
+        let mean_x = |panic| {
+            let options = SimulatorOptions {
+                seed: Some(1),
+                ..Default::default()
+            };
+            let mut simulator = Simulator::new(options, scenario(panic)).unwrap();
+            for _ in 0..20 {
+                simulator.tick().unwrap();
+            }
+            let pedestrians = simulator.list_pedestrians();
+            pedestrians.iter().map(|p| p.pos.x).sum::<f32>() / pedestrians.len() as f32
+        };
+
+        // Panicking pedestrians rush to the destination faster than the desired speed of calm ones.
+        assert!(mean_x(true) > mean_x(false) + 1.0);
+
+        // Panic is not implemented in the kernels, so the GPU backend refuses the scenario before selecting a device.
+        let options = SimulatorOptions {
+            backend: Backend::Gpu,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Simulator::new(options, scenario(true)),
+            Err(SimulatorError::UnsupportedOnGpu("panic"))
+        ));
+    }
+
+    #[test]
+    fn test_validate_before_model() {
+        // The GPU backend would refuse the levels, but the missing waypoint is reported first.
+        let scenario = Scenario {
+            levels: vec![LevelConfig::default(); 2],
+            pedestrians: vec![PedestrianConfig {
+                destination: Some(0),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let options = SimulatorOptions {
+            backend: Backend::Gpu,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Simulator::with_fields(options, scenario, Vec::new()),
+            Err(SimulatorError::Scenario(_))
+        ));
+    }
+
+    #[test]
+    fn test_herding() {
+        // Unfamiliar pedestrians heading right walk among a crowd heading up.
+        let scenario = |herding: f32| {
+            Scenario::from_toml(&format!(
+                r#"
+                field = {{ size = [20.0, 20.0] }}
+                obstacles = []
+
+                [[waypoints]]
+                line = [[1.0, 1.0], [19.0, 1.0]]
+
+                [[waypoints]]
+                line = [[1.0, 19.0], [19.0, 19.0]]
+
+                [[waypoints]]
+                line = [[19.0, 1.0], [19.0, 19.0]]
+
+                [[pedestrians]]
+                origin = 0
+                destination = 1
+                spawn = {{ kind = "once", count = 30, region = [[6.0, 2.0], [14.0, 2.0], [14.0, 8.0], [6.0, 8.0]] }}
+
+                [[pedestrians]]
+                origin = 0
+                destination = 2
+                familiarity = {{ unfamiliar_ratio = 1.0, herding = {herding} }}
+                spawn = {{ kind = "once", count = 5, region = [[8.0, 4.0], [12.0, 4.0], [12.0, 6.0], [8.0, 6.0]] }}
+                "#
+            ))
+            .unwrap()
+        };
+        let heading = |herding| {
+            let options = SimulatorOptions {
+                seed: Some(1),
+                ..Default::default()
+            };
+            let mut simulator = Simulator::new(options, scenario(herding)).unwrap();
+            for _ in 0..20 {
+                simulator.tick().unwrap();
+            }
+            simulator
+                .list_pedestrians()
+                .iter()
+                .filter(|p| p.group == 1)
+                .map(|p| p.vel.normalize_or_zero())
+                .sum::<Vec2>()
+                .normalize()
+        };
+
+        // Herding turns them from the right toward the mean heading of their neighbors.
+        let (alone, herding) = (heading(0.0), heading(0.8));
+        assert!(alone.x > 0.8, "{alone}");
+        assert!(herding.y > alone.y + 0.3, "{alone} {herding}");
+
+        // Herding is not implemented in the kernels, so the GPU backend refuses the scenario.
+        let options = SimulatorOptions {
+            backend: Backend::Gpu,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Simulator::new(options, scenario(0.8)),
+            Err(SimulatorError::UnsupportedOnGpu("herding"))
+        ));
+    }
+
+    #[test]
+    fn test_set_destination() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 10, region = [[3.0, 1.0], [7.0, 1.0], [7.0, 9.0], [3.0, 9.0]] }
+
+            [[timeline]]
+            time = 1.0
+            action = "set_destination"
+            destination = 1
+            "#,
+        )
+        .unwrap();
+
+        fastrand::seed(1);
+        let mut simulator = Simulator::new(SimulatorOptions::default(), scenario).unwrap();
+        let ids: Vec<_> = simulator.list_pedestrians().iter().map(|p| p.id).collect();
+        simulator.set_destination(ids[..5].iter().copied(), 0);
+        for p in simulator.list_pedestrians() {
+            let expected = if ids[..5].contains(&p.id) { 0 } else { 1 };
+            assert_eq!(p.destination, expected);
+        }
+
+        for _ in 0..10 {
+            simulator.tick().unwrap();
+        }
+        assert!(simulator
+            .list_pedestrians()
+            .iter()
+            .all(|p| p.destination == 1));
+    }
+
+    #[test]
+    fn test_levels() {
+        // Pedestrians on the upper level take the stairs down to the exit on the ground level.
+        let scenario = Scenario::from_toml(
+            r#"
+            version = 2
+            field = { size = [10.0, 4.0] }
+            levels = [{ name = "ground" }, { name = "upper" }]
+            links = [{ from = 2, to = 3, length = 4.0 }]
+
+            [[obstacles]]
+            line = [[2.0, 0.0], [2.0, 4.0]]
+            level = 1
+
+            [[waypoints]]
+            line = [[1.0, 0.5], [1.0, 3.5]]
+
+            [[waypoints]]
+            line = [[9.0, 0.5], [9.0, 3.5]]
+            level = 1
+
+            [[waypoints]]
+            line = [[4.0, 0.5], [4.0, 3.5]]
+            level = 1
+
+            [[waypoints]]
+            line = [[8.0, 0.5], [8.0, 3.5]]
+
+            [[pedestrians]]
+            origin = 1
+            destination = 0
+            spawn = { kind = "once", count = 5 }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        assert!(simulator.list_pedestrians().iter().all(|p| p.level == 1));
+        let mut went_down = false;
+        for _ in 0..600 {
+            simulator.tick().unwrap();
+            went_down |= simulator.list_pedestrians().iter().any(|p| p.level == 0);
+            if simulator.model.get_pedestrian_count() == 0 {
+                break;
+            }
+        }
+        assert!(went_down);
+        assert_eq!(simulator.model.get_pedestrian_count(), 0);
+    }
+
+    #[test]
+    fn test_max_pedestrians() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [20.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[19.0, 1.0], [19.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "periodic", rate = 20.0 }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            max_pedestrians: Some(10),
+            seed: Some(1),
+            ..Default::default()
+        };
+
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        let mut refused = 0;
+        for _ in 0..30 {
+            let metrics = simulator.tick().unwrap();
+            assert!(metrics.active_ped_count <= 10);
+            refused += metrics.refused_count;
+        }
+        assert_eq!(simulator.model.get_pedestrian_count(), 10);
+        assert!(refused > 0);
+    }
+
+    #[test]
+    fn test_cpu_threads() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 50, region = [[1.0, 1.0], [7.0, 1.0], [7.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let run = |cpu_threads| {
+            let options = SimulatorOptions {
+                cpu_threads,
+                seed: Some(1),
+                ..Default::default()
+            };
+            let mut simulator = Simulator::new(options, scenario.clone()).unwrap();
+            for _ in 0..20 {
+                simulator.tick().unwrap();
+            }
+            simulator.list_pedestrians()
+        };
+
+        // The CPU backend gives the same result regardless of the number of threads.
+        let expected = run(None);
+        let actual = run(Some(2));
+        assert_eq!(actual.len(), expected.len());
+        for (a, b) in actual.iter().zip(&expected) {
+            assert_eq!((a.id, a.pos), (b.id, b.pos));
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::{ensure, Context};
+use log::warn;
+use toml::{Table, Value};
+
+/// Version of the scenario format written by this version of the simulator. See
+/// [`Scenario::version`](crate::scenario::Scenario::version).
+pub const SCENARIO_VERSION: u32 = 2;
+
+/// Upgrade of the format from a version to the next, indexed by the version it upgrades from minus 1
+const MIGRATIONS: [fn(&mut Table); SCENARIO_VERSION as usize - 1] = [v1_to_v2];
+
+/// Upgrade a parsed scenario in an older format to the current one in place, warning of each deprecated form.
+///
+/// Scenarios without `version` are version 1.
+pub fn migrate(table: &mut Table) -> anyhow::Result<()> {
+    let version = match table.get("version") {
+        Some(version) => version
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .context("Scenario version must be a positive integer")?,
+        None => 1,
+    };
+    ensure!(
+        (1..=SCENARIO_VERSION).contains(&version),
+        "Scenario version {version} is not supported; the latest is {SCENARIO_VERSION}"
+    );
+
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(table);
+    }
+    Ok(())
+}
+
+/// Version 2 renamed `frequency` of periodic spawns to `rate`, since it is a rate of pedestrians per second, and
+/// the timeline action `set_spawn_frequency` to `set_spawn_rate` likewise.
+fn v1_to_v2(table: &mut Table) {
+    if let Some(Value::Array(groups)) = table.get_mut("pedestrians") {
+        for (i, group) in groups.iter_mut().enumerate() {
+            let Some(spawn) = group.get_mut("spawn").and_then(Value::as_table_mut) else {
+                continue;
+            };
+            if let Some(frequency) = spawn.remove("frequency") {
+                warn!("`pedestrians[{i}].spawn.frequency` is deprecated since version 2; use `rate` instead");
+                spawn.entry("rate").or_insert(frequency);
+            }
+        }
+    }
+
+    if let Some(Value::Array(entries)) = table.get_mut("timeline") {
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let Some(entry) = entry.as_table_mut() else {
+                continue;
+            };
+            if entry.get("action").and_then(Value::as_str) != Some("set_spawn_frequency") {
+                continue;
+            }
+            warn!("`timeline[{i}]` of `set_spawn_frequency` is deprecated since version 2; use `set_spawn_rate` with `rate` instead");
+            entry.insert("action".into(), "set_spawn_rate".into());
+            if let Some(frequency) = entry.remove("frequency") {
+                entry.entry("rate").or_insert(frequency);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scenario::{PedestrianSpawnConfig, Scenario, TimelineAction};
+
+    #[test]
+    fn test_migrate() {
+        let text = r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = [{ line = [[1.0, 1.0], [1.0, 9.0]] }]
+            obstacles = []
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "periodic", frequency = 2.0 }
+
+            [[timeline]]
+            time = 10.0
+            action = "set_spawn_frequency"
+            group = 0
+            frequency = 3.0
+            "#;
+        let scenario = Scenario::from_toml(text).unwrap();
+        assert_eq!(scenario.version, 1);
+        let PedestrianSpawnConfig::Periodic { rate } = scenario.pedestrians[0].spawn else {
+            panic!("spawn is not periodic");
+        };
+        assert_eq!(rate, 2.0);
+        let TimelineAction::SetSpawnRate { group: 0, rate } = scenario.timeline[0].action else {
+            panic!("timeline action is not set_spawn_rate");
+        };
+        assert_eq!(rate, 3.0);
+
+        // The old form is not accepted in the current version.
+        assert!(Scenario::from_toml(&format!("version = 2\n{text}")).is_err());
+        assert!(Scenario::from_toml(&format!("version = 3\n{text}")).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+use glam::{vec2, Vec2};
+
+use crate::{
+    field::Field, obstacle_index::ObstacleIndex, scenario::Scenario, util, vehicle::Vehicle,
+    SimulatorOptions,
+};
+
+use super::{
+    herding, soft_obstacle_force, Behavior, ForceBreakdown, BODY_RADIUS, SOFT_OBSTACLE_FORCE,
+};
+
+/// Component of [`ForceBreakdown`] which a [`ForceTerm`] contributes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForceCategory {
+    Destination,
+    Pedestrians,
+    Obstacles,
+    Other,
+}
+
+/// State of a pedestrian and its surroundings in a step, given to [`ForceTerm`]s.
+pub struct ForceContext<'a> {
+    pub id: usize,
+    pub group: usize,
+    pub pos: Vec2,
+    pub vel: Vec2,
+    /// Level the pedestrian is on, whose field is `field`
+    pub level: usize,
+    pub destination: usize,
+    /// Desired speed, which is raised in panic (m/s)
+    pub desired_speed: f32,
+    /// Unit vector down the potential toward the destination, or zero if the destination is closed
+    pub desired_direction: Vec2,
+    /// Sum of walking directions of neighbors within 1 m, which is given only to [`ForceTerm::force`].
+    pub neighbor_heading: Vec2,
+    /// Personal space multiplier of the other pedestrian, which is given only to [`ForceTerm::interaction`]. See
+    /// [`PedestrianConfig::personal_space`](crate::scenario::PedestrianConfig::personal_space).
+    pub neighbor_personal_space: f32,
+    pub unfamiliar: bool,
+    pub scenario: &'a Scenario,
+    pub field: &'a Field,
+    /// Vehicles avoided as moving obstacles
+    pub vehicles: &'a [Vehicle],
+    pub(super) behavior: Behavior,
+}
+
+/// Term of the acceleration of pedestrians in [`SocialForceModel`](super::SocialForceModel), which sums up the
+/// forces of its terms in each step.
+///
+/// A custom term is added by [`SocialForceModel::with_force_term`](super::SocialForceModel::with_force_term),
+/// without changing the built-in terms of the destination, other pedestrians and obstacles. Forces are
+/// accelerations per unit mass (m/s^2).
+pub trait ForceTerm: Send + Sync {
+    /// Component of [`ForceBreakdown`] the forces are recorded in.
+    fn category(&self) -> ForceCategory {
+        ForceCategory::Other
+    }
+
+    /// Force from another pedestrian within 2 m at `p.pos - difference` moving at `vel_other`.
+    fn interaction(&self, _p: &ForceContext, _difference: Vec2, _vel_other: Vec2) -> Vec2 {
+        Vec2::ZERO
+    }
+
+    /// Force independent of other pedestrians, called after [`ForceTerm::interaction`] with all neighbors.
+    fn force(&self, _p: &ForceContext) -> Vec2 {
+        Vec2::ZERO
+    }
+}
+
+impl ForceBreakdown {
+    fn add(&mut self, category: ForceCategory, force: Vec2) {
+        match category {
+            ForceCategory::Destination => self.destination += force,
+            ForceCategory::Pedestrians => self.pedestrians += force,
+            ForceCategory::Obstacles => self.obstacles += force,
+            ForceCategory::Other => self.other += force,
+        }
+    }
+
+    /// Add the forces of `term` on a pedestrian. See [`ForceTerm::interaction`].
+    pub(super) fn add_interaction(
+        &mut self,
+        term: &dyn ForceTerm,
+        p: &ForceContext,
+        difference: Vec2,
+        vel_other: Vec2,
+    ) {
+        self.add(term.category(), term.interaction(p, difference, vel_other));
+    }
+
+    /// Add the force of `term` on a pedestrian. See [`ForceTerm::force`].
+    pub(super) fn add_force(&mut self, term: &dyn ForceTerm, p: &ForceContext) {
+        self.add(term.category(), term.force(p));
+    }
+}
+
+/// Built-in terms of the social force model, in the order of summation.
+pub(super) fn builtin_terms(
+    options: &SimulatorOptions,
+    scenario: &Scenario,
+) -> Vec<Box<dyn ForceTerm>> {
+    let obstacle_indices = if options.use_distance_map && options.exact_obstacle_distance {
+        (0..scenario.level_count())
+            .map(|level| {
+                let obstacles: Vec<_> = scenario
+                    .obstacles
+                    .iter()
+                    .filter(|obs| obs.level == level)
+                    .cloned()
+                    .collect();
+                ObstacleIndex::new(scenario.field.size, &obstacles)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    vec![
+        Box::new(DrivingForce),
+        Box::new(PedestrianRepulsion),
+        Box::new(ObstacleRepulsion {
+            use_distance_map: options.use_distance_map,
+            obstacle_indices,
+        }),
+    ]
+}
+
+/// Relaxation toward the desired velocity, drawn toward the walking direction of neighbors by herding.
+struct DrivingForce;
+
+impl ForceTerm for DrivingForce {
+    fn category(&self) -> ForceCategory {
+        ForceCategory::Destination
+    }
+
+    fn force(&self, p: &ForceContext) -> Vec2 {
+        // Unfamiliar pedestrians follow the crowd around them in part.
+        let herding = herding(p.scenario, p.group, p.unfamiliar);
+        let e = if herding > 0.0 && p.neighbor_heading != Vec2::ZERO {
+            p.desired_direction
+                .lerp(p.neighbor_heading.normalize(), herding)
+                .normalize_or_zero()
+        } else {
+            p.desired_direction
+        };
+        (e * p.desired_speed - p.vel) / 0.5
+    }
+}
+
+struct PedestrianRepulsion;
+
+impl ForceTerm for PedestrianRepulsion {
+    fn category(&self) -> ForceCategory {
+        ForceCategory::Pedestrians
+    }
+
+    fn interaction(&self, p: &ForceContext, difference: Vec2, vel_other: Vec2) -> Vec2 {
+        pedestrian_force(
+            difference,
+            vel_other,
+            p.desired_direction,
+            &p.behavior,
+            p.neighbor_personal_space,
+        )
+    }
+}
+
+/// Repulsion from walls, soft obstacles and vehicles
+struct ObstacleRepulsion {
+    use_distance_map: bool,
+    /// Index of exact distances on each level, if [`SimulatorOptions::exact_obstacle_distance`] is enabled
+    obstacle_indices: Vec<ObstacleIndex>,
+}
+
+impl ForceTerm for ObstacleRepulsion {
+    fn category(&self) -> ForceCategory {
+        ForceCategory::Obstacles
+    }
+
+    fn force(&self, p: &ForceContext) -> Vec2 {
+        let (pos, field) = (p.pos, p.field);
+        let mut force = Vec2::ZERO;
+        if self.use_distance_map {
+            let nearest = self
+                .obstacle_indices
+                .get(p.level)
+                .and_then(|index| index.nearest(pos));
+            let (distance, direction) = nearest.unwrap_or_else(|| {
+                (
+                    field.get_obstacle_distance(pos),
+                    -field.get_obstacle_distance_grad(pos).normalize(),
+                )
+            });
+            force +=
+                10.0 * 0.2 * (-distance / 0.2).exp() * direction + soft_obstacle_force(field, pos);
+        } else {
+            let segments = p
+                .scenario
+                .obstacles
+                .iter()
+                .filter(|obs| obs.level == p.level)
+                .flat_map(|obs| obs.segments().map(move |line| (obs, line)));
+            for (obs, v) in segments {
+                let w = obs.width;
+                let d = v[1] - v[0];
+                let h = d.length();
+                let n = vec2(d.y, -d.x).normalize_or_zero() * w * 0.5;
+                let lines = vec![
+                    [v[0] + n, v[0] - n],
+                    [v[1] + n, v[1] - n],
+                    [v[0] + n, v[1] + n],
+                    [v[0] - n, v[1] - n],
+                ];
+                let diffs: Vec<_> = lines
+                    .into_iter()
+                    .map(|line| util::distance_from_line(pos, line))
+                    .collect();
+                let distances: Vec<_> = diffs.iter().map(|diff| diff.length()).collect();
+                if distances[0] < w && distances[1] < w && distances[2] < h && distances[3] < h {
+                    continue;
+                }
+                let (min_index, min_d) = distances
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+                    .unwrap();
+                let direction = diffs[min_index].normalize();
+
+                let strength = if obs.soft {
+                    SOFT_OBSTACLE_FORCE
+                } else {
+                    10.0 * 0.2
+                };
+                force += strength * (-min_d / 0.2).exp() * direction;
+            }
+        }
+
+        for vehicle in p.vehicles {
+            force += vehicle.repulsion(pos);
+        }
+        force
+    }
+}
+
+/// Calculate repulsive force from another pedestrian at `-difference` moving at `vel_other`.
+///
+/// `e` is the desired direction, which determines whether the other pedestrian is in sight. The range of the
+/// repulsion is widened by `personal_space` of the other pedestrian, keeping its strength at contact.
+fn pedestrian_force(
+    difference: Vec2,
+    vel_other: Vec2,
+    e: Vec2,
+    behavior: &Behavior,
+    personal_space: f32,
+) -> Vec2 {
+    let distance = difference.length();
+    let direction = difference.normalize();
+    let sigma = behavior.repulsion_range;
+
+    let t1 = difference - vel_other * 0.1;
+    let t1_length = t1.length();
+    let t2 = distance + t1_length;
+    let b = (t2.powi(2) - (vel_other.length() * 0.1).powi(2)).sqrt() * 0.5;
+
+    let nabla_b = t2 * (direction + t1 / t1_length) / (4.0 * b);
+    let mut force = 2.1 / sigma * (-b / (sigma * personal_space)).exp() * nabla_b;
+
+    if e.dot(-force) < force.length() * behavior.cos_phi {
+        force *= behavior.out_of_sight_factor;
+    }
+
+    // Pushing by body contact
+    let overlap = 2.0 * BODY_RADIUS - distance;
+    if overlap > 0.0 {
+        force += behavior.body_force * overlap * direction;
+    }
+
+    force
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, Vec2};
+
+    use crate::{
+        field::Field,
+        models::{Behavior, PedestrianModel, SocialForceModel},
+        scenario::Scenario,
+        Simulator, SimulatorOptions,
+    };
+
+    use super::{pedestrian_force, ForceCategory, ForceContext, ForceTerm};
+
+    /// Uniform force like wind
+    struct Wind(Vec2);
+
+    impl ForceTerm for Wind {
+        fn force(&self, _p: &ForceContext) -> Vec2 {
+            self.0
+        }
+    }
+
+    /// Uniform force recorded as a force from other pedestrians, like a pull toward a crowd out of the field
+    struct Crowd(Vec2);
+
+    impl ForceTerm for Crowd {
+        fn category(&self) -> ForceCategory {
+            ForceCategory::Pedestrians
+        }
+
+        fn force(&self, _p: &ForceContext) -> Vec2 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_custom_term() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 1, region = [[1.0, 4.0], [2.0, 4.0], [2.0, 5.0], [1.0, 5.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            record_forces: true,
+            ..Default::default()
+        };
+        let fields = vec![Field::from_scenario(&scenario, 0.25).unwrap()];
+        let run = |wind: Option<Wind>| {
+            let mut model = SocialForceModel::new(&options, &scenario, &fields).unwrap();
+            if let Some(wind) = wind {
+                model = model.with_force_term(wind);
+            }
+            let mut simulator = Simulator::with_model(
+                options.clone(),
+                scenario.clone(),
+                fields.clone(),
+                Box::new(model),
+            )
+            .unwrap();
+            for _ in 0..10 {
+                simulator.tick().unwrap();
+            }
+            (
+                simulator.list_pedestrians()[0].pos,
+                simulator.list_forces().unwrap()[0],
+            )
+        };
+
+        let (pos, forces) = run(None);
+        let (pos_wind, forces_wind) = run(Some(Wind(vec2(0.0, 1.0))));
+        assert_eq!(forces.other, Vec2::ZERO);
+        assert_eq!(forces_wind.other, vec2(0.0, 1.0));
+        assert!(pos_wind.y > pos.y + 0.1, "{pos} {pos_wind}");
+    }
+
+    #[test]
+    fn test_custom_term_of_isolated_pedestrian() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 1, region = [[4.0, 4.0], [5.0, 4.0], [5.0, 5.0], [4.0, 5.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            freeze_idle: true,
+            record_forces: true,
+            ..Default::default()
+        };
+        let fields = vec![Field::from_scenario(&scenario, 0.25).unwrap()];
+        let model = SocialForceModel::new(&options, &scenario, &fields)
+            .unwrap()
+            .with_force_term(Crowd(vec2(0.0, 1.0)));
+        let mut simulator =
+            Simulator::with_model(options, scenario, fields, Box::new(model)).unwrap();
+        simulator.tick().unwrap();
+        let metrics = simulator.tick().unwrap();
+
+        // The built-in terms of the category are skipped for the pedestrian alone, but not the custom one.
+        assert_eq!(metrics.neighbors.unwrap().isolated, 1);
+        assert_eq!(
+            simulator.list_forces().unwrap()[0].pedestrians,
+            vec2(0.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn test_personal_space() {
+        // Repulsion from a pedestrian standing 1 m ahead
+        let behavior = Behavior::new(&Scenario::default(), 0, 1.34);
+        let force = |personal_space| {
+            pedestrian_force(
+                vec2(-1.0, 0.0),
+                Vec2::ZERO,
+                vec2(1.0, 0.0),
+                &behavior,
+                personal_space,
+            )
+        };
+        assert!(force(1.0).x < 0.0);
+        assert!(force(3.0).x < force(1.0).x * 5.0, "{}", force(3.0));
+    }
+
+    #[test]
+    fn test_personal_space_in_simulation() {
+        // A pedestrian walks behind one of the other group 1.5 m ahead.
+        let scenario = |personal_space: f32| {
+            Scenario::from_toml(&format!(
+                r#"
+                field = {{ size = [20.0, 4.0] }}
+                obstacles = []
+
+                [[waypoints]]
+                line = [[19.0, 1.0], [19.0, 3.0]]
+
+                [[pedestrians]]
+                origin = 0
+                destination = 0
+                spawn = {{ kind = "once", count = 1, region = [[2.0, 1.9], [2.1, 1.9], [2.1, 2.1], [2.0, 2.1]] }}
+
+                [[pedestrians]]
+                origin = 0
+                destination = 0
+                personal_space = {personal_space:?}
+                spawn = {{ kind = "once", count = 1, region = [[3.5, 1.9], [3.6, 1.9], [3.6, 2.1], [3.5, 2.1]] }}
+                "#
+            ))
+            .unwrap()
+        };
+        let repulsion = |personal_space| {
+            let options = SimulatorOptions {
+                seed: Some(1),
+                record_forces: true,
+                ..Default::default()
+            };
+            let mut simulator = Simulator::new(options, scenario(personal_space)).unwrap();
+            simulator.tick().unwrap();
+            let follower = simulator
+                .list_pedestrians()
+                .iter()
+                .find(|p| p.group == 0)
+                .unwrap()
+                .id;
+            let forces = simulator.list_forces().unwrap();
+            forces
+                .iter()
+                .find(|f| f.id == follower)
+                .unwrap()
+                .pedestrians
+        };
+
+        // The follower is pushed back harder by the one with a larger personal space.
+        let normal = repulsion(1.0);
+        let wide = repulsion(3.0);
+        assert!(normal.x < 0.0, "{normal}");
+        assert!(wide.x < normal.x * 2.0, "{normal} {wide}");
+    }
+}
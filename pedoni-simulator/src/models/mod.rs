@@ -1,41 +1,559 @@
+mod force_term;
 mod sfm;
 mod sfm_gpu;
 
+use std::time::Duration;
+
 use glam::Vec2;
+use log::{error, warn};
+use serde::Serialize;
 
-use crate::SimulatorOptions;
+use crate::{
+    diagnostic::NeighborStats, error::Result, vehicle::Vehicle, OutOfBoundsPolicy, SimulatorOptions,
+};
 
-use super::{field::Field, scenario::Scenario};
+use super::{field::Field, gate::Gates, routing::Routing, scenario::Scenario};
 
 #[allow(unused)]
-pub use self::{sfm::SocialForceModel, sfm_gpu::SocialForceModelGpu};
+pub use self::{
+    force_term::{ForceCategory, ForceContext, ForceTerm},
+    sfm::SocialForceModel,
+    sfm_gpu::{list_gpu_devices, GpuDevice, SocialForceModelGpu},
+};
 
+/// Interface between the simulator and pedestrian models, which can be implemented outside this crate and driven by
+/// [`Simulator::with_model`](crate::Simulator::with_model).
+///
+/// Models own the states of pedestrians, including ones being calculated for the next step, and expose them only
+/// as [`Pedestrian`]s or [`PedestrianSlices`].
+///
+/// Fields are given for each level of the scenario, indexed by [`Pedestrian::level`].
 pub trait PedestrianModel: Send + Sync {
-    fn new(options: &SimulatorOptions, _scenario: &Scenario, _field: &Field) -> Self
+    fn new(options: &SimulatorOptions, _scenario: &Scenario, _fields: &[Field]) -> Result<Self>
     where
         Self: Sized;
 
-    fn spawn_pedestrians(&mut self, field: &Field, new_pedestrians: Vec<Pedestrian>);
+    /// Add new pedestrians and remove ones which arrived at their destinations.
+    ///
+    /// Returns the removed pedestrians.
+    fn spawn_pedestrians(
+        &mut self,
+        fields: &[Field],
+        routing: &Routing,
+        new_pedestrians: Vec<Pedestrian>,
+    ) -> Vec<Pedestrian>;
+
+    /// Start calculating the next states in the background if the model supports asynchronous execution.
+    ///
+    /// The calculation is completed by [`PedestrianModel::update_states`], and pedestrians must not be spawned or relocated in between.
+    fn dispatch_states(
+        &mut self,
+        _scenario: &Scenario,
+        _fields: &[Field],
+        _routing: &Routing,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Integrate the states of pedestrians over a step.
+    ///
+    /// Returns pedestrians which left the field, handled according to [`SimulatorOptions::out_of_bounds`], or an
+    /// error if the device running the model fails.
+    fn update_states(
+        &mut self,
+        scenario: &Scenario,
+        fields: &[Field],
+        routing: &Routing,
+        gates: &mut Gates,
+    ) -> Result<OutOfBounds>;
+
+    /// Move pedestrians to positions and levels returned by `f`, which is called with the position, level and
+    /// destination of each pedestrian.
+    fn relocate_pedestrians(
+        &mut self,
+        f: &mut dyn FnMut(Vec2, usize, usize) -> Option<(Vec2, usize)>,
+    );
+
+    /// Change destinations of pedestrians to ones returned by `f`, which is called with the ID and group of each pedestrian.
+    fn reroute_pedestrians(&mut self, f: &mut dyn FnMut(usize, usize) -> Option<usize>);
+
+    /// Change states and destinations of pedestrians to ones returned by `f`, which is called with the position,
+    /// level, destination and state of each pedestrian.
+    fn transition_pedestrians(
+        &mut self,
+        f: &mut dyn FnMut(Vec2, usize, usize, AgentState) -> Option<(AgentState, usize)>,
+    );
 
-    fn update_states(&mut self, scenario: &Scenario, field: &Field);
+    /// Replace vehicles which pedestrians avoid from the next update. See [`Vehicle`].
+    fn set_vehicles(&mut self, vehicles: Vec<Vehicle>);
 
-    fn list_pedestrians(&self) -> Vec<Pedestrian>;
+    /// Remove pedestrians for which `f` returns `false`. `f` is called with the ID and position of each pedestrian.
+    ///
+    /// Returns the removed pedestrians. Like [`PedestrianModel::relocate_pedestrians`], this must be followed by
+    /// [`PedestrianModel::spawn_pedestrians`] before the next update, which rebuilds the neighbor search structures.
+    fn retain_pedestrians(&mut self, f: &mut dyn FnMut(usize, Vec2) -> bool) -> Vec<Pedestrian>;
+
+    /// Call `f` with each pedestrian without collecting the whole population.
+    fn for_each_pedestrian(&self, f: &mut dyn FnMut(Pedestrian));
+
+    /// Borrow states of all pedestrians as columns, in the same order as [`PedestrianModel::for_each_pedestrian`].
+    fn slices(&self) -> PedestrianSlices<'_>;
+
+    fn list_pedestrians(&self) -> Vec<Pedestrian> {
+        let mut pedestrians = Vec::with_capacity(self.get_pedestrian_count() as usize);
+        self.for_each_pedestrian(&mut |p| pedestrians.push(p));
+        pedestrians
+    }
+
+    /// List forces acting on each pedestrian in the last step. Pedestrians may have been spawned, removed or reordered
+    /// since, so the forces are matched by [`ForceBreakdown::id`] rather than by position in the list.
+    ///
+    /// Returns `None` unless [`SimulatorOptions::record_forces`] is enabled and the model supports it.
+    fn list_forces(&self) -> Option<Vec<ForceBreakdown>> {
+        None
+    }
 
     fn get_pedestrian_count(&self) -> i32;
+
+    /// Human-readable name of the model, including its backend.
+    fn name(&self) -> &'static str;
+
+    /// Execution time of the GPU kernel in the last step, if the model runs on the GPU.
+    fn time_kernel(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Statistics of the neighbor search in the last step, if the model records them.
+    fn neighbor_stats(&self) -> Option<NeighborStats> {
+        None
+    }
+}
+
+/// Breakdown of forces acting on a pedestrian
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ForceBreakdown {
+    /// ID of the pedestrian the forces act on
+    pub id: usize,
+    /// Driving force toward the destination
+    pub destination: Vec2,
+    /// Repulsive force from other pedestrians
+    pub pedestrians: Vec2,
+    /// Repulsive force from obstacles, including vehicles
+    pub obstacles: Vec2,
+    /// Forces of custom terms. See [`ForceTerm`].
+    pub other: Vec2,
+}
+
+impl ForceBreakdown {
+    pub fn total(&self) -> Vec2 {
+        self.destination + self.pedestrians + self.obstacles + self.other
+    }
 }
 
 /// Pedestrian instance
 #[derive(Debug, Clone)]
 pub struct Pedestrian {
+    /// Unique ID assigned in order of generation
+    pub id: usize,
+    /// Index of the pedestrian group in [`Scenario::pedestrians`] which generated the pedestrian
+    pub group: usize,
     pub pos: Vec2,
+    pub vel: Vec2,
+    /// Level the pedestrian is on. See [`Scenario::levels`].
+    pub level: usize,
     pub destination: usize,
+    /// Local density around the pedestrian in the last step (persons/m^2)
+    pub density: f32,
+    pub comfort: ComfortMetrics,
+    /// Whether the pedestrian does not know the nearest exit. See [`FamiliarityConfig`](crate::scenario::FamiliarityConfig).
+    pub unfamiliar: bool,
+    pub state: AgentState,
+    /// See [`PedestrianConfig::personal_space`](crate::scenario::PedestrianConfig::personal_space).
+    pub personal_space: f32,
+    /// Speed at which the pedestrian walks when unobstructed (m/s)
+    pub desired_speed: f32,
 }
 
 impl Default for Pedestrian {
     fn default() -> Self {
         Pedestrian {
+            id: 0,
+            group: 0,
             pos: Vec2::default(),
+            vel: Vec2::default(),
+            level: 0,
             destination: 0,
+            density: 0.0,
+            comfort: ComfortMetrics::default(),
+            unfamiliar: false,
+            state: AgentState::Walking,
+            personal_space: 1.0,
+            desired_speed: 1.34,
+        }
+    }
+}
+
+/// Stage of a pedestrian on a trip through a holding area. See [`HoldingAreaConfig`](crate::scenario::HoldingAreaConfig).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AgentState {
+    /// Walking to the destination
+    #[default]
+    Walking,
+    /// Standing in a holding area, moved only by other pedestrians and obstacles
+    Waiting,
+    /// Walking to the final destination after released from a holding area, passing through other ones
+    Released,
+}
+
+/// Columns of states of pedestrians stored in a model, which analytics and exporters can process in bulk without
+/// collecting [`Pedestrian`]s. Elements at the same index belong to the same pedestrian.
+#[derive(Debug, Clone, Copy)]
+pub struct PedestrianSlices<'a> {
+    pub ids: &'a [u32],
+    pub groups: &'a [u32],
+    pub positions: &'a [Vec2],
+    pub velocities: &'a [Vec2],
+    /// Destinations as waypoints or waypoint groups
+    pub destinations: &'a [u32],
+}
+
+/// Comfort metrics accumulated over the lifetime of a pedestrian
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ComfortMetrics {
+    /// Cumulative magnitude of acceleration (m/s)
+    pub acceleration: f32,
+    /// Number of times another pedestrian came closer than 0.3 m
+    pub near_collisions: u32,
+    /// Time spent walking slower than 50% of the desired speed (s)
+    pub slow_time: f32,
+}
+
+impl ComfortMetrics {
+    /// Accumulate metrics of a step.
+    fn update(
+        &mut self,
+        vel_prev: Vec2,
+        vel: Vec2,
+        desired_speed: f32,
+        was_near_collision: bool,
+        is_near_collision: bool,
+    ) {
+        self.acceleration += (vel - vel_prev).length();
+        if vel.length() < desired_speed * 0.5 {
+            self.slow_time += 0.1;
+        }
+        if is_near_collision && !was_near_collision {
+            self.near_collisions += 1;
+        }
+    }
+}
+
+/// Radius of bodies of pedestrians, within which they push each other. (meters)
+pub(crate) const BODY_RADIUS: f32 = 0.3;
+/// Distance within which pedestrians interact with each other. (meters)
+pub const INTERACTION_CUTOFF: f32 = 2.0;
+
+/// Unit of the neighbor grid given by [`SimulatorOptions::neighbor_grid_unit`], or the interaction cutoff by default.
+fn neighbor_grid_unit(options: &SimulatorOptions) -> f32 {
+    match options.neighbor_grid_unit {
+        Some(unit) => {
+            if unit < INTERACTION_CUTOFF {
+                warn!(
+                    "The neighbor grid unit of {unit} m is smaller than the interaction cutoff of {INTERACTION_CUTOFF} m; some interactions are missed"
+                );
+            }
+            unit
+        }
+        None => INTERACTION_CUTOFF,
+    }
+}
+
+/// Parameters of a pedestrian's behavior, which change in panic.
+struct Behavior {
+    desired_speed: f32,
+    /// Range of the repulsive potential from other pedestrians (meters)
+    repulsion_range: f32,
+    /// Body force per unit overlap and unit mass (s^-2)
+    body_force: f32,
+    /// See [`BehaviorConfig::cos_half_field_of_view`](crate::scenario::BehaviorConfig::cos_half_field_of_view)
+    cos_phi: f32,
+    /// See [`BehaviorConfig::out_of_sight_factor`](crate::scenario::BehaviorConfig::out_of_sight_factor)
+    out_of_sight_factor: f32,
+}
+
+impl Behavior {
+    fn new(scenario: &Scenario, group: usize, desired_speed: f32) -> Self {
+        let in_panic = scenario.pedestrians.get(group).is_some_and(|g| g.panic);
+        let cos_phi = scenario.behavior.cos_half_field_of_view();
+        let out_of_sight_factor = scenario.behavior.out_of_sight_factor;
+        if in_panic {
+            let panic = &scenario.panic;
+            Behavior {
+                desired_speed: desired_speed.max(panic.desired_speed()),
+                repulsion_range: panic.repulsion_range(),
+                body_force: panic.body_force,
+                cos_phi,
+                out_of_sight_factor,
+            }
+        } else {
+            Behavior {
+                desired_speed,
+                repulsion_range: scenario.behavior.repulsion_range(),
+                body_force: 0.0,
+                cos_phi,
+                out_of_sight_factor,
+            }
+        }
+    }
+}
+
+/// Weight of the mean direction of neighbors in the desired direction of a pedestrian, which is nonzero only for
+/// unfamiliar ones.
+fn herding(scenario: &Scenario, group: usize, unfamiliar: bool) -> f32 {
+    if !unfamiliar {
+        return 0.0;
+    }
+    scenario
+        .pedestrians
+        .get(group)
+        .and_then(|g| g.familiarity.as_ref())
+        .map_or(0.0, |f| f.herding.clamp(0.0, 1.0))
+}
+
+/// Max repulsion of soft obstacles, weaker than the driving force so that pedestrians heading for their destinations
+/// can cross them. (m/s^2)
+const SOFT_OBSTACLE_FORCE: f32 = 1.0;
+
+/// Calculate force from soft obstacles, which are not included in the distance map of hard ones.
+fn soft_obstacle_force(field: &Field, pos: Vec2) -> Vec2 {
+    let Some((distance, grad)) = field.get_soft_obstacle_distance(pos) else {
+        return Vec2::ZERO;
+    };
+    SOFT_OBSTACLE_FORCE * (-distance.max(0.0) / 0.2).exp() * -grad.normalize_or_zero()
+}
+
+/// Distance to the nearest pedestrian regarded as a near-collision
+const NEAR_COLLISION_DISTANCE: f32 = 0.3;
+
+/// Radius of the circle in which local density is measured. (meters)
+const DENSITY_RADIUS: f32 = 1.0;
+
+/// Calculate local density from the number of other pedestrians within [`DENSITY_RADIUS`].
+fn local_density(neighbor_count: u32) -> f32 {
+    (neighbor_count + 1) as f32 / (std::f32::consts::PI * DENSITY_RADIUS.powi(2))
+}
+
+/// Pedestrians which left the field in a step, returned by [`PedestrianModel::update_states`]
+#[derive(Debug, Default, Clone)]
+pub struct OutOfBounds {
+    /// Number of pedestrians which left the field, including ones moved back
+    pub count: i32,
+    /// IDs of pedestrians removed from the field
+    pub despawned: Vec<usize>,
+}
+
+/// Apply `policy` to a pedestrian at `pos` if it is outside the field of `size`.
+///
+/// Returns `None` if the pedestrian is inside the field, or whether to keep the pedestrian otherwise.
+fn check_bounds(policy: OutOfBoundsPolicy, size: Vec2, id: usize, pos: &mut Vec2) -> Option<bool> {
+    if pos.cmpge(Vec2::ZERO).all() && pos.cmple(size).all() {
+        return None;
+    }
+
+    match policy {
+        OutOfBoundsPolicy::Clamp => {
+            *pos = pos.clamp(Vec2::ZERO, size);
+            Some(true)
+        }
+        OutOfBoundsPolicy::Despawn => {
+            warn!("Despawned pedestrian {id} which left the field at {pos}");
+            Some(false)
+        }
+        OutOfBoundsPolicy::Error => {
+            error!("Pedestrian {id} left the field at {pos}; check the geometry of the scenario");
+            Some(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{Simulator, SimulatorOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_check_bounds() {
+        let size = vec2(10.0, 5.0);
+
+        let mut pos = vec2(3.0, 4.0);
+        assert_eq!(
+            check_bounds(OutOfBoundsPolicy::Clamp, size, 0, &mut pos),
+            None
+        );
+
+        let mut pos = vec2(-1.0, 6.0);
+        assert_eq!(
+            check_bounds(OutOfBoundsPolicy::Clamp, size, 0, &mut pos),
+            Some(true)
+        );
+        assert_eq!(pos, vec2(0.0, 5.0));
+
+        let mut pos = vec2(11.0, 1.0);
+        assert_eq!(
+            check_bounds(OutOfBoundsPolicy::Despawn, size, 0, &mut pos),
+            Some(false)
+        );
+        assert_eq!(
+            check_bounds(OutOfBoundsPolicy::Error, size, 0, &mut pos),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_slices() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 20, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        for _ in 0..5 {
+            simulator.tick().unwrap();
+        }
+
+        let pedestrians = simulator.list_pedestrians();
+        let slices = simulator.slices();
+        assert_eq!(slices.positions.len(), 20);
+        for (i, p) in pedestrians.iter().enumerate() {
+            assert_eq!(slices.ids[i] as usize, p.id);
+            assert_eq!(slices.positions[i], p.pos);
+            assert_eq!(slices.velocities[i], p.vel);
+            assert_eq!(slices.destinations[i] as usize, p.destination);
+        }
+    }
+
+    #[test]
+    fn test_neighbor_grid_unit() {
+        // Two pedestrians about 1.8 m apart diagonally
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 1, region = [[2.0, 2.0], [2.1, 2.0], [2.1, 2.1], [2.0, 2.1]] }
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 1, region = [[3.3, 3.3], [3.4, 3.3], [3.4, 3.4], [3.3, 3.4]] }
+            "#,
+        )
+        .unwrap();
+        let repulsion = |neighbor_grid_unit| {
+            let options = SimulatorOptions {
+                seed: Some(1),
+                record_forces: true,
+                neighbor_grid_unit,
+                ..Default::default()
+            };
+            let mut simulator = Simulator::new(options, scenario.clone()).unwrap();
+            simulator.tick().unwrap();
+            simulator.list_forces().unwrap()[0].pedestrians
+        };
+
+        // The default unit covers the cutoff, while the neighbor is out of the adjacent cells of a smaller one.
+        assert_ne!(repulsion(None), Vec2::ZERO);
+        assert_eq!(repulsion(Some(0.5)), Vec2::ZERO);
+    }
+
+    #[test]
+    fn test_isolated_pedestrians() {
+        // Two pedestrians walk toward each other in the middle of the field, and another walks along the wall.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [40.0, 20.0] }
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 12.0]]
+
+            [[waypoints]]
+            line = [[39.0, 1.0], [39.0, 12.0]]
+
+            [[obstacles]]
+            line = [[0.0, 0.0], [40.0, 0.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 1, region = [[3.0, 9.9], [3.1, 9.9], [3.1, 10.1], [3.0, 10.1]] }
+
+            [[pedestrians]]
+            origin = 1
+            destination = 0
+            spawn = { kind = "once", count = 1, region = [[37.0, 10.0], [37.1, 10.0], [37.1, 10.2], [37.0, 10.2]] }
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 1, region = [[3.0, 0.9], [3.1, 0.9], [3.1, 1.1], [3.0, 1.1]] }
+            "#,
+        )
+        .unwrap();
+        let run = |freeze_idle| {
+            let options = SimulatorOptions {
+                seed: Some(1),
+                freeze_idle,
+                ..Default::default()
+            };
+            let mut simulator = Simulator::new(options, scenario.clone()).unwrap();
+            let isolated: Vec<u32> = (0..50)
+                .map(|_| simulator.tick().unwrap().neighbors.unwrap().isolated)
+                .collect();
+            (simulator, isolated)
+        };
+
+        // Forces from pedestrians and obstacles are skipped only for the two far from others and the wall.
+        let (mut simulator, isolated) = run(true);
+        assert_eq!(isolated.iter().max(), Some(&2));
+        let (reference, isolated_reference) = run(false);
+        assert!(isolated_reference.iter().all(|&n| n == 0));
+
+        // They walk as if all forces were calculated.
+        let pedestrians = simulator.list_pedestrians();
+        for (p, q) in pedestrians.iter().zip(reference.list_pedestrians()) {
+            assert_eq!(p.id, q.id);
+            assert!(p.pos.distance(q.pos) < 1e-3, "{} {}", p.pos, q.pos);
+        }
+
+        // They interact in full when they come within the interaction range.
+        let mut met = false;
+        for _ in 0..150 {
+            let metrics = simulator.tick().unwrap();
+            met |= metrics.active_ped_count == 3 && metrics.neighbors.unwrap().isolated == 0;
         }
+        assert!(met);
     }
 }
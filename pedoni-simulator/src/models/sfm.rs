@@ -1,57 +1,140 @@
-use glam::{vec2, IVec2, Vec2};
+use glam::{IVec2, Vec2};
 use rayon::prelude::*;
 use soa_derive::StructOfArray;
 
 use crate::{
-    field::Field,
-    neighbor_grid::NeighborGrid,
-    scenario::Scenario,
-    util::{self, Index},
-    SimulatorOptions,
+    diagnostic::NeighborStats, error::Result, field::Field, gate::Gates,
+    neighbor_grid::NeighborGrid, routing::Routing, scenario::Scenario, util::Index,
+    vehicle::Vehicle, SimulatorOptions,
 };
 
-use super::PedestrianModel;
+use super::{
+    check_bounds,
+    force_term::{builtin_terms, ForceCategory, ForceContext, ForceTerm},
+    local_density, neighbor_grid_unit, AgentState, Behavior, ComfortMetrics, ForceBreakdown,
+    OutOfBounds, PedestrianModel, DENSITY_RADIUS, INTERACTION_CUTOFF, NEAR_COLLISION_DISTANCE,
+};
 
-/// Cosine of phi (2*phi represents the effective angle of sight of pedestrians)
-const COS_PHI: f32 = -0.17364817766693036;
+/// Speed below which pedestrians are regarded as at rest by [`SimulatorOptions::freeze_idle`] (m/s)
+const REST_SPEED: f32 = 0.02;
+/// Magnitude of accelerations below which pedestrians at rest are frozen (m/s^2)
+const REST_ACCELERATION: f32 = 0.05;
 
 #[derive(Default)]
 pub struct SocialForceModel {
     pedestrians: PedestrianVec,
     neighbor_grid: Option<NeighborGrid>,
     neighbor_grid_indices: Vec<u32>,
+    /// Terms summed up into accelerations, starting with the built-in ones
+    terms: Vec<Box<dyn ForceTerm>>,
+    /// Number of the built-in terms at the beginning of [`SocialForceModel::terms`]
+    builtin_term_count: usize,
+    forces: Vec<ForceBreakdown>,
+    neighbor_stats: NeighborStats,
+    vehicles: Vec<Vehicle>,
     options: SimulatorOptions,
 }
 
+/// Result of the neighbor search of a pedestrian
+#[derive(Debug, Clone, Copy)]
+struct Neighbors {
+    /// Distance to the nearest pedestrian
+    min_distance: f32,
+    /// Number of pedestrians within [`DENSITY_RADIUS`]
+    neighbor_count: u32,
+    /// Number of pedestrians whose distances were checked
+    candidates: u32,
+    /// Number of pedestrians within the interaction range, whose forces were calculated
+    interactions: u32,
+    /// Whether any pedestrian within the interaction range is moving, which wakes frozen pedestrians
+    disturbed: bool,
+    /// Whether the pedestrian has nowhere to go and no vehicle around, so that it can be frozen at rest
+    idle: bool,
+    /// Whether forces were skipped since the pedestrian stays frozen
+    frozen: bool,
+    /// Whether forces from pedestrians and obstacles were skipped since none of them is within the interaction range
+    isolated: bool,
+}
+
 #[derive(Debug, Default, Clone, StructOfArray)]
 #[soa_derive(Debug, Default)]
 pub struct Pedestrian {
+    id: u32,
+    group: u32,
     position: Vec2,
+    level: u32,
     destination: u32,
     velocity: Vec2,
     desired_speed: f32,
+    density: f32,
+    comfort: ComfortMetrics,
+    near_collision: bool,
+    unfamiliar: bool,
+    state: AgentState,
+    personal_space: f32,
+    /// Whether the pedestrian is at rest and undisturbed, so that its forces are skipped
+    frozen: bool,
+}
+
+impl From<PedestrianRef<'_>> for super::Pedestrian {
+    fn from(p: PedestrianRef) -> Self {
+        super::Pedestrian {
+            id: *p.id as usize,
+            group: *p.group as usize,
+            pos: *p.position,
+            vel: *p.velocity,
+            level: *p.level as usize,
+            destination: *p.destination as usize,
+            density: *p.density,
+            comfort: *p.comfort,
+            unfamiliar: *p.unfamiliar,
+            state: *p.state,
+            personal_space: *p.personal_space,
+            desired_speed: *p.desired_speed,
+        }
+    }
 }
 
 impl PedestrianModel for SocialForceModel {
-    fn new(options: &SimulatorOptions, scenario: &Scenario, _field: &Field) -> Self {
+    fn new(options: &SimulatorOptions, scenario: &Scenario, _fields: &[Field]) -> Result<Self> {
         let neighbor_grid = options
             .use_neighbor_grid
-            .then(|| NeighborGrid::new(scenario.field.size, options.neighbor_grid_unit));
+            .then(|| NeighborGrid::new(scenario.field.size, neighbor_grid_unit(options)));
 
-        SocialForceModel {
+        let terms = builtin_terms(options, scenario);
+        Ok(SocialForceModel {
             neighbor_grid,
+            builtin_term_count: terms.len(),
+            terms,
             options: options.clone(),
             ..Default::default()
-        }
+        })
     }
 
-    fn spawn_pedestrians(&mut self, field: &Field, spawned_pedestrians: Vec<super::Pedestrian>) {
+    fn spawn_pedestrians(
+        &mut self,
+        _fields: &[Field],
+        routing: &Routing,
+        spawned_pedestrians: Vec<super::Pedestrian>,
+    ) -> Vec<super::Pedestrian> {
+        let mut arrived = Vec::new();
+
         for p in spawned_pedestrians {
             self.pedestrians.push(Pedestrian {
+                id: p.id as u32,
+                group: p.group as u32,
                 position: p.pos,
+                level: p.level as u32,
                 destination: p.destination as u32,
                 velocity: Vec2::ZERO,
-                desired_speed: fastrand_contrib::f32_normal_approx(1.34, 0.26),
+                desired_speed: p.desired_speed,
+                density: 0.0,
+                comfort: p.comfort,
+                near_collision: false,
+                unfamiliar: p.unfamiliar,
+                state: p.state,
+                personal_space: p.personal_space,
+                frozen: false,
             });
         }
 
@@ -65,10 +148,18 @@ impl PedestrianModel for SocialForceModel {
 
             for cell in neighbor_grid.data.iter() {
                 for j in 0..cell.len() {
-                    let p = self.pedestrians.get(cell[j] as usize).unwrap().to_owned();
-                    if field.get_potential(p.destination as usize, p.position) > 0.25 {
-                        sorted_pedestrians.push(p);
+                    let p = self.pedestrians.get(cell[j] as usize).unwrap();
+                    if *p.state == AgentState::Waiting
+                        || !routing.has_arrived(
+                            *p.destination as usize,
+                            *p.level as usize,
+                            *p.position,
+                        )
+                    {
+                        sorted_pedestrians.push(p.to_owned());
                         index += 1;
+                    } else {
+                        arrived.push(p.into());
                     }
                 }
                 self.neighbor_grid_indices.push(index as u32);
@@ -79,192 +170,425 @@ impl PedestrianModel for SocialForceModel {
             let mut pedestrians = PedestrianVec::with_capacity(self.pedestrians.len());
 
             for p in self.pedestrians.iter() {
-                if field.get_potential(*p.destination as usize, *p.position) > 0.25 {
+                if *p.state == AgentState::Waiting
+                    || !routing.has_arrived(*p.destination as usize, *p.level as usize, *p.position)
+                {
                     pedestrians.push(p.to_owned());
+                } else {
+                    arrived.push(p.into());
                 }
             }
 
             self.pedestrians = pedestrians;
         }
+
+        arrived
     }
 
-    fn update_states(&mut self, scenario: &Scenario, field: &Field) {
+    fn update_states(
+        &mut self,
+        scenario: &Scenario,
+        fields: &[Field],
+        routing: &Routing,
+        gates: &mut Gates,
+    ) -> Result<OutOfBounds> {
         let pedestrians = &self.pedestrians;
-        let accelerations: Vec<Vec2> = (0..pedestrians.len())
+        let terms = &self.terms;
+        let builtin_term_count = self.builtin_term_count;
+        let (forces, neighbors): (Vec<ForceBreakdown>, Vec<Neighbors>) = (0..pedestrians.len())
             .into_par_iter()
             .map(|id| {
                 let Pedestrian {
+                    id: pedestrian_id,
+                    group,
                     position: pos,
+                    level,
                     destination,
                     velocity: vel,
                     desired_speed,
+                    unfamiliar,
+                    state,
+                    frozen,
+                    ..
                 } = pedestrians.get(id).unwrap().to_owned();
                 let destination = destination as usize;
+                let field = &fields[level as usize];
+                let behavior = Behavior::new(scenario, group as usize, desired_speed);
+                // Waiting pedestrians only come to rest, moved by others and obstacles.
+                let desired_direction = match routing.target(destination) {
+                    Some(target) if state != AgentState::Waiting => {
+                        field.get_potential_grad(target, pos).normalize_or_zero()
+                    }
+                    _ => Vec2::ZERO,
+                };
+                // Frozen pedestrians wake when they have somewhere to go or a vehicle approaches.
+                let idle = self.options.freeze_idle
+                    && desired_direction == Vec2::ZERO
+                    && self
+                        .vehicles
+                        .iter()
+                        .all(|v| v.repulsion(pos).length() < REST_ACCELERATION);
+                let mut frozen = frozen && idle;
+                let mut context = ForceContext {
+                    id: pedestrian_id as usize,
+                    group: group as usize,
+                    pos,
+                    vel,
+                    level: level as usize,
+                    destination,
+                    desired_speed: behavior.desired_speed,
+                    desired_direction,
+                    neighbor_heading: Vec2::ZERO,
+                    neighbor_personal_space: 1.0,
+                    unfamiliar,
+                    scenario,
+                    field,
+                    vehicles: &self.vehicles,
+                    behavior,
+                };
+
+                let mut forces = ForceBreakdown {
+                    id: pedestrian_id as usize,
+                    ..Default::default()
+                };
+                let mut min_distance_squared = f32::INFINITY;
+                let mut neighbor_count = 0;
+                let mut candidates = 0;
+                let mut interactions = 0;
+                // Sum of walking directions of neighbors within `DENSITY_RADIUS`
+                let mut neighbor_heading = Vec2::ZERO;
+
+                // Calculate forces from other pedestrians, or only check if they are moving if frozen. Returns
+                // whether the pedestrian is moving within the interaction range.
+                let mut interact = |i: usize, frozen: bool| {
+                    if i == id || pedestrians.level[i] != level {
+                        return false;
+                    }
+                    let difference = pos - pedestrians.position[i];
+                    let distance_squared = difference.length_squared();
+                    candidates += 1;
+                    if distance_squared > INTERACTION_CUTOFF.powi(2) {
+                        return false;
+                    }
+                    let moving = pedestrians.velocity[i].length_squared() >= REST_SPEED.powi(2);
+                    if frozen {
+                        return moving;
+                    }
+                    interactions += 1;
+                    min_distance_squared = min_distance_squared.min(distance_squared);
+                    if distance_squared <= DENSITY_RADIUS.powi(2) {
+                        neighbor_count += 1;
+                        neighbor_heading += pedestrians.velocity[i].normalize_or_zero();
+                    }
 
-                let mut acc = Vec2::ZERO;
-
-                // Calculate force from the destination.
-                let grad = field.get_potential_grad(destination, pos);
-                let e = grad.normalize();
-                acc += (e * desired_speed - vel) / 0.5;
-
-                // Calculate force from other pedestrians.
-                if let Some(grid) = &self.neighbor_grid {
-                    let ix = (pos / grid.unit).as_ivec2();
-                    let ix = Index::new(ix.x, ix.y);
-
-                    let shape = IVec2::new(grid.shape.1 as i32, grid.shape.0 as i32);
-                    let y_start = (ix.y - 1).max(0);
-                    let y_end = (ix.y + 1).min(shape.y - 1);
-                    let x_start = (ix.x - 1).max(0);
-                    let x_end = (ix.x + 1).min(shape.x - 1);
-
-                    for y in y_start..=y_end {
-                        let offset = y * shape.x;
-                        let i_start =
-                            self.neighbor_grid_indices[(offset + x_start) as usize] as usize;
-                        let i_end =
-                            self.neighbor_grid_indices[(offset + x_end + 1) as usize] as usize;
-
-                        for i in i_start..i_end {
-                            if i != id {
-                                let difference = pos - self.pedestrians.position[i];
-                                let distance_squared = difference.length_squared();
-                                if distance_squared > 4.0 {
-                                    continue;
-                                }
-
-                                let distance = distance_squared.sqrt();
-                                let direction = difference.normalize();
-
-                                let vel_i = pedestrians.velocity[i];
-                                let t1 = difference - vel_i * 0.1;
-                                let t1_length = t1.length();
-                                let t2 = distance + t1_length;
-                                let b = (t2.powi(2) - (vel_i.length() * 0.1).powi(2)).sqrt() * 0.5;
-
-                                let nabla_b = t2 * (direction + t1 / t1_length) / (4.0 * b);
-                                let mut force = 2.1 / 0.3 * (-b / 0.3).exp() * nabla_b;
-
-                                if e.dot(-force) < force.length() * COS_PHI {
-                                    force *= 0.5;
-                                }
-
-                                acc += force;
-                            }
-                        }
+                    context.neighbor_personal_space = pedestrians.personal_space[i];
+                    for term in terms {
+                        forces.add_interaction(
+                            term.as_ref(),
+                            &context,
+                            difference,
+                            pedestrians.velocity[i],
+                        );
                     }
-                } else {
-                    for i in 0..pedestrians.len() {
-                        if i != id {
-                            let difference = pos - self.pedestrians.position[i];
-                            let distance_squared = difference.length_squared();
-                            if distance_squared > 4.0 {
-                                continue;
-                            }
-
-                            let distance = distance_squared.sqrt();
-                            let direction = difference.normalize();
-
-                            let vel_i = pedestrians.velocity[i];
-                            let t1 = difference - vel_i * 0.1;
-                            let t1_length = t1.length();
-                            let t2 = distance + t1_length;
-                            let b = (t2.powi(2) - (vel_i.length() * 0.1).powi(2)).sqrt() * 0.5;
-
-                            let nabla_b = t2 * (direction + t1 / t1_length) / (4.0 * b);
-                            let mut force = 2.1 / 0.3 * (-b / 0.3).exp() * nabla_b;
-
-                            if e.dot(-force) < force.length() * COS_PHI {
-                                force *= 0.5;
-                            }
-
-                            acc += force;
+                    moving
+                };
+                // Returns whether any neighbor is moving.
+                let mut visit_neighbors = |frozen: bool| {
+                    let mut disturbed = false;
+                    let mut interact = |i| disturbed |= interact(i, frozen);
+                    if let Some(grid) = &self.neighbor_grid {
+                        let ix = (pos / grid.unit).as_ivec2();
+                        let ix = Index::new(ix.x, ix.y);
+
+                        let shape = IVec2::new(grid.shape.1 as i32, grid.shape.0 as i32);
+                        let y_start = (ix.y - 1).max(0);
+                        let y_end = (ix.y + 1).min(shape.y - 1);
+                        let x_start = (ix.x - 1).max(0);
+                        let x_end = (ix.x + 1).min(shape.x - 1);
+
+                        for y in y_start..=y_end {
+                            let offset = y * shape.x;
+                            let i_start =
+                                self.neighbor_grid_indices[(offset + x_start) as usize] as usize;
+                            let i_end =
+                                self.neighbor_grid_indices[(offset + x_end + 1) as usize] as usize;
+                            (i_start..i_end).for_each(&mut interact);
                         }
+                    } else {
+                        (0..pedestrians.len()).for_each(&mut interact);
                     }
-                }
-
-                // Calculate force from obstacles.
-                if self.options.use_distance_map {
-                    let distance = field.get_obstacle_distance(pos);
-                    let direction = -field.get_obstacle_distance_grad(pos).normalize();
-                    let force = 10.0 * 0.2 * (-distance / 0.2).exp() * direction;
-                    acc += force;
-                } else {
-                    for obs in &scenario.obstacles {
-                        let v = obs.line;
-                        let w = obs.width;
-                        let d = v[1] - v[0];
-                        let h = d.length();
-                        let n = vec2(d.y, -d.x).normalize_or_zero() * w * 0.5;
-                        let lines = vec![
-                            [v[0] + n, v[0] - n],
-                            [v[1] + n, v[1] - n],
-                            [v[0] + n, v[1] + n],
-                            [v[0] - n, v[1] - n],
-                        ];
-                        let diffs: Vec<_> = lines
-                            .into_iter()
-                            .map(|line| util::distance_from_line(pos, line))
-                            .collect();
-                        let distances: Vec<_> = diffs.iter().map(|diff| diff.length()).collect();
-                        if distances[0] < w
-                            && distances[1] < w
-                            && distances[2] < h
-                            && distances[3] < h
-                        {
-                            continue;
+                    disturbed
+                };
+                let mut disturbed = frozen && visit_neighbors(true);
+                frozen &= !disturbed;
+                let mut isolated = false;
+                if !frozen {
+                    disturbed = visit_neighbors(false);
+
+                    // Pedestrians with nobody and nothing around them only follow the potential, until others or
+                    // obstacles come within the interaction range.
+                    isolated = self.options.freeze_idle
+                        && interactions == 0
+                        && is_clear_of_obstacles(field, &self.vehicles, pos);
+
+                    // Calculate forces from the destination, obstacles and others.
+                    context.neighbor_heading = neighbor_heading;
+                    for (i, term) in terms.iter().enumerate() {
+                        // Custom terms may act on isolated pedestrians regardless of their categories.
+                        let skipped = i < builtin_term_count
+                            && matches!(
+                                term.category(),
+                                ForceCategory::Pedestrians | ForceCategory::Obstacles
+                            );
+                        if !(isolated && skipped) {
+                            forces.add_force(term.as_ref(), &context);
                         }
-                        let (min_index, min_d) = distances
-                            .iter()
-                            .enumerate()
-                            .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
-                            .unwrap();
-                        let direction = diffs[min_index].normalize();
-
-                        let force = 10.0 * 0.2 * (-min_d / 0.2).exp() * direction;
-                        acc += force;
-
-                        // for line in lines {
-                        //     let diff = util::distance_from_line(pos, line);
-                        //     let distance = diff.length();
-                        //     let direction = diff.normalize();
-
-                        //     let force = 10.0 * 0.2 * (-distance / 0.2).exp() * direction;
-
-                        // }
                     }
                 }
 
-                acc
+                let neighbors = Neighbors {
+                    min_distance: min_distance_squared.sqrt(),
+                    neighbor_count,
+                    candidates,
+                    interactions,
+                    disturbed,
+                    idle,
+                    frozen,
+                    isolated,
+                };
+                (forces, neighbors)
             })
-            .collect();
+            .unzip();
+        let accelerations: Vec<Vec2> = forces.iter().map(ForceBreakdown::total).collect();
+        self.neighbor_stats = self.calc_neighbor_stats(&neighbors);
+        if self.options.record_forces {
+            self.forces = forces;
+        }
 
         let pedestrians = &mut self.pedestrians;
+        let mut out_of_bounds = Vec::new();
 
         for i in 0..pedestrians.len() {
+            let id = pedestrians.id[i] as usize;
             let pos = &mut pedestrians.position[i];
             let vel = &mut pedestrians.velocity[i];
-            let desired_speed = pedestrians.desired_speed[i];
+            let desired_speed = Behavior::new(
+                scenario,
+                pedestrians.group[i] as usize,
+                pedestrians.desired_speed[i],
+            )
+            .desired_speed;
 
             let vel_prev = *vel;
             *vel += accelerations[i] * 0.1;
             *vel = vel.clamp_length_max(desired_speed * 1.3);
-            *pos += (*vel + vel_prev) * 0.05;
+            let next_pos = *pos + (*vel + vel_prev) * 0.05;
+
+            if gates.try_pass(id, *pos, next_pos) {
+                *pos = next_pos;
+            } else {
+                *vel = Vec2::ZERO;
+            }
+
+            if let Some(keep) =
+                check_bounds(self.options.out_of_bounds, scenario.field.size, id, pos)
+            {
+                out_of_bounds.push((i, keep));
+            }
+
+            let Neighbors {
+                min_distance,
+                neighbor_count,
+                disturbed,
+                idle,
+                frozen,
+                ..
+            } = neighbors[i];
+            // Pedestrians at rest among ones at rest stay still until disturbed.
+            pedestrians.frozen[i] = frozen
+                || (idle
+                    && !disturbed
+                    && vel.length() < REST_SPEED
+                    && accelerations[i].length() < REST_ACCELERATION);
+            if pedestrians.frozen[i] {
+                *vel = Vec2::ZERO;
+            }
+
+            // Neighbors of frozen pedestrians are not searched, which are at rest as well.
+            let near_collision = if frozen {
+                pedestrians.near_collision[i]
+            } else {
+                pedestrians.density[i] = local_density(neighbor_count);
+                min_distance < NEAR_COLLISION_DISTANCE
+            };
+            pedestrians.comfort[i].update(
+                vel_prev,
+                *vel,
+                desired_speed,
+                pedestrians.near_collision[i],
+                near_collision,
+            );
+            pedestrians.near_collision[i] = near_collision;
+        }
+
+        // Remove despawned pedestrians in reverse order to keep the remaining indices valid
+        let mut despawned = Vec::new();
+        for &(i, keep) in out_of_bounds.iter().rev() {
+            if !keep {
+                despawned.push(pedestrians.id[i] as usize);
+                pedestrians.swap_remove(i);
+            }
         }
+
+        Ok(OutOfBounds {
+            count: out_of_bounds.len() as i32,
+            despawned,
+        })
     }
 
-    fn list_pedestrians(&self) -> Vec<super::Pedestrian> {
-        self.pedestrians
-            .iter()
-            .map(|p| super::Pedestrian {
-                pos: *p.position,
-                destination: *p.destination as usize,
-            })
-            .collect()
+    fn relocate_pedestrians(
+        &mut self,
+        f: &mut dyn FnMut(Vec2, usize, usize) -> Option<(Vec2, usize)>,
+    ) {
+        let pedestrians = &mut self.pedestrians;
+
+        for i in 0..pedestrians.len() {
+            let level = pedestrians.level[i] as usize;
+            let destination = pedestrians.destination[i] as usize;
+            if let Some((pos, level)) = f(pedestrians.position[i], level, destination) {
+                pedestrians.position[i] = pos;
+                pedestrians.level[i] = level as u32;
+                pedestrians.velocity[i] = Vec2::ZERO;
+            }
+        }
+    }
+
+    fn reroute_pedestrians(&mut self, f: &mut dyn FnMut(usize, usize) -> Option<usize>) {
+        let pedestrians = &mut self.pedestrians;
+
+        for i in 0..pedestrians.len() {
+            let (id, group) = (pedestrians.id[i] as usize, pedestrians.group[i] as usize);
+            if let Some(destination) = f(id, group) {
+                pedestrians.destination[i] = destination as u32;
+            }
+        }
+    }
+
+    fn transition_pedestrians(
+        &mut self,
+        f: &mut dyn FnMut(Vec2, usize, usize, AgentState) -> Option<(AgentState, usize)>,
+    ) {
+        let pedestrians = &mut self.pedestrians;
+
+        for i in 0..pedestrians.len() {
+            let level = pedestrians.level[i] as usize;
+            let destination = pedestrians.destination[i] as usize;
+            if let Some((state, destination)) = f(
+                pedestrians.position[i],
+                level,
+                destination,
+                pedestrians.state[i],
+            ) {
+                pedestrians.state[i] = state;
+                pedestrians.destination[i] = destination as u32;
+            }
+        }
+    }
+
+    fn set_vehicles(&mut self, vehicles: Vec<Vehicle>) {
+        self.vehicles = vehicles;
+    }
+
+    fn retain_pedestrians(
+        &mut self,
+        f: &mut dyn FnMut(usize, Vec2) -> bool,
+    ) -> Vec<super::Pedestrian> {
+        let mut removed = Vec::new();
+        let mut pedestrians = PedestrianVec::with_capacity(self.pedestrians.len());
+
+        for p in self.pedestrians.iter() {
+            if f(*p.id as usize, *p.position) {
+                pedestrians.push(p.to_owned());
+            } else {
+                removed.push(p.into());
+            }
+        }
+
+        self.pedestrians = pedestrians;
+        removed
+    }
+
+    fn for_each_pedestrian(&self, f: &mut dyn FnMut(super::Pedestrian)) {
+        for p in self.pedestrians.iter() {
+            f(p.into());
+        }
+    }
+
+    fn list_forces(&self) -> Option<Vec<ForceBreakdown>> {
+        self.options.record_forces.then(|| self.forces.clone())
+    }
+
+    fn slices(&self) -> super::PedestrianSlices<'_> {
+        let pedestrians = &self.pedestrians;
+        super::PedestrianSlices {
+            ids: &pedestrians.id,
+            groups: &pedestrians.group,
+            positions: &pedestrians.position,
+            velocities: &pedestrians.velocity,
+            destinations: &pedestrians.destination,
+        }
     }
 
     fn get_pedestrian_count(&self) -> i32 {
         self.pedestrians.len() as i32
     }
+
+    fn name(&self) -> &'static str {
+        "Social force model (CPU)"
+    }
+
+    fn neighbor_stats(&self) -> Option<NeighborStats> {
+        Some(self.neighbor_stats.clone())
+    }
+}
+
+impl SocialForceModel {
+    /// Add a custom term to the acceleration of pedestrians, after the built-in ones.
+    pub fn with_force_term(mut self, term: impl ForceTerm + 'static) -> Self {
+        self.terms.push(Box::new(term));
+        self
+    }
+
+    fn calc_neighbor_stats(&self, neighbors: &[Neighbors]) -> NeighborStats {
+        let max_cell_occupancy = self
+            .neighbor_grid_indices
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .max()
+            .unwrap_or(0);
+        let interactions: u64 = neighbors.iter().map(|n| n.interactions as u64).sum();
+        let frozen = neighbors.iter().filter(|n| n.frozen).count();
+        let isolated = neighbors.iter().filter(|n| n.isolated).count();
+
+        NeighborStats {
+            max_cell_occupancy,
+            mean_neighbors: interactions as f64 / (neighbors.len() - frozen).max(1) as f64,
+            candidates: neighbors.iter().map(|n| n.candidates as u64).sum(),
+            interactions,
+            frozen: frozen as u32,
+            isolated: isolated as u32,
+        }
+    }
+}
+
+/// Whether no obstacle, soft obstacle or vehicle is within the interaction range of `pos`, so that their forces are
+/// negligible.
+fn is_clear_of_obstacles(field: &Field, vehicles: &[Vehicle], pos: Vec2) -> bool {
+    field.get_obstacle_distance(pos) > INTERACTION_CUTOFF
+        && field
+            .get_soft_obstacle_distance(pos)
+            .is_none_or(|(distance, _)| distance > INTERACTION_CUTOFF)
+        && vehicles
+            .iter()
+            .all(|v| v.repulsion(pos).length() < REST_ACCELERATION)
 }
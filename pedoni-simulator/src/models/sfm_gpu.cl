@@ -1,5 +1,23 @@
 
-#define COS_PHI -0.17364817766693036f
+#ifndef REPULSION_RANGE
+#define REPULSION_RANGE 0.3f
+#endif
+#ifndef INTERACTION_CUTOFF
+#define INTERACTION_CUTOFF 2.0f
+#endif
+#define DENSITY_RADIUS 1.0f
+
+// Positions and velocities are stored as half floats if HALF_PRECISION is
+// defined, and calculated as floats.
+#ifdef HALF_PRECISION
+#define VEC2_STORAGE half
+#define LOAD_VEC2(p, i) vload_half2(i, p)
+#define STORE_VEC2(v, p, i) vstore_half2(v, i, p)
+#else
+#define VEC2_STORAGE float
+#define LOAD_VEC2(p, i) vload2(i, p)
+#define STORE_VEC2(v, p, i) vstore2(v, i, p)
+#endif
 
 const sampler_t SAMP =
     CLK_NORMALIZED_COORDS_FALSE | CLK_ADDRESS_CLAMP_TO_EDGE | CLK_FILTER_LINEAR;
@@ -40,52 +58,213 @@ inline float2 sobel_array(image2d_array_t grids, float4 coord) {
                     u00 + u01 + u01 + u02 - u20 - u21 - u21 - u22);
 }
 
+// Calculate the neighbor grid cell of each pedestrian and count pedestrians in
+// each cell.
+__kernel void calc_cell_ids(uint ped_count, __global VEC2_STORAGE *positions,
+                            int2 grid_shape, float grid_unit,
+                            __global uint *cell_ids,
+                            __global uint *cell_counts) {
+    int id = get_global_id(0);
+    if (id >= ped_count) {
+        return;
+    }
+
+    int2 cell = convert_int2(LOAD_VEC2(positions, id) / grid_unit);
+    cell = clamp(cell, (int2)(0, 0), grid_shape - (int2)(1, 1));
+    uint cell_id = cell.y * grid_shape.x + cell.x;
+
+    cell_ids[id] = cell_id;
+    atomic_inc(&cell_counts[cell_id]);
+}
+
+// Exclusive prefix sum within each work group. The sum of each work group is
+// written to `block_sums`.
+__kernel void scan_blocks(uint n, __global uint *input, __global uint *output,
+                          __global uint *block_sums, __local uint *temp) {
+    uint gid = get_global_id(0);
+    uint lid = get_local_id(0);
+    uint size = get_local_size(0);
+
+    uint value = gid < n ? input[gid] : 0;
+    temp[lid] = value;
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    for (uint offset = 1; offset < size; offset <<= 1) {
+        uint t = lid >= offset ? temp[lid - offset] : 0;
+        barrier(CLK_LOCAL_MEM_FENCE);
+        temp[lid] += t;
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+
+    if (gid < n) {
+        output[gid] = temp[lid] - value;
+    }
+    if (lid == size - 1) {
+        block_sums[get_group_id(0)] = temp[lid];
+    }
+}
+
+// Exclusive prefix sum of block sums. The total is written at the end.
+__kernel void scan_block_sums(uint block_count, __global uint *block_sums) {
+    uint sum = 0;
+    for (uint i = 0; i < block_count; i++) {
+        uint value = block_sums[i];
+        block_sums[i] = sum;
+        sum += value;
+    }
+    block_sums[block_count] = sum;
+}
+
+__kernel void add_block_offsets(uint n, __global uint *output,
+                                __global uint *block_sums) {
+    uint gid = get_global_id(0);
+    if (gid < n) {
+        output[gid] += block_sums[get_group_id(0)];
+    }
+    if (gid == 0) {
+        output[n] = block_sums[get_num_groups(0)];
+    }
+}
+
+// Sort pedestrians by their cells.
+__kernel void scatter(uint ped_count, __global uint *cell_ids,
+                      __global uint *cell_starts, __global uint *cell_fills,
+                      __global VEC2_STORAGE *positions,
+                      __global VEC2_STORAGE *velocities,
+                      __global float *desired_speeds,
+                      __global uint *destinations,
+                      __global float *personal_spaces,
+                      __global VEC2_STORAGE *sorted_positions,
+                      __global VEC2_STORAGE *sorted_velocities,
+                      __global float *sorted_desired_speeds,
+                      __global uint *sorted_destinations,
+                      __global float *sorted_personal_spaces,
+                      __global uint *original_ids) {
+    int id = get_global_id(0);
+    if (id >= ped_count) {
+        return;
+    }
+
+    uint cell_id = cell_ids[id];
+    uint slot = cell_starts[cell_id] + atomic_inc(&cell_fills[cell_id]);
+
+    STORE_VEC2(LOAD_VEC2(positions, id), sorted_positions, slot);
+    STORE_VEC2(LOAD_VEC2(velocities, id), sorted_velocities, slot);
+    sorted_desired_speeds[slot] = desired_speeds[id];
+    sorted_destinations[slot] = destinations[id];
+    sorted_personal_spaces[slot] = personal_spaces[id];
+    original_ids[slot] = id;
+}
+
+// Sort pedestrians in each cell by their original indices. The order after
+// `scatter` depends on the order of atomic operations, which changes the
+// summation order of forces and thus the results in the last bits.
+__kernel void sort_cells(uint cell_count, __global uint *cell_starts,
+                         __global VEC2_STORAGE *positions,
+                         __global VEC2_STORAGE *velocities,
+                         __global float *desired_speeds,
+                         __global uint *destinations,
+                         __global float *personal_spaces,
+                         __global uint *original_ids) {
+    int cell_id = get_global_id(0);
+    if (cell_id >= cell_count) {
+        return;
+    }
+
+    // Insertion sort, since cells contain only a few pedestrians.
+    uint start = cell_starts[cell_id];
+    uint end = cell_starts[cell_id + 1];
+    for (uint i = start + 1; i < end; i++) {
+        uint id = original_ids[i];
+        float2 pos = LOAD_VEC2(positions, i);
+        float2 vel = LOAD_VEC2(velocities, i);
+        float desired_speed = desired_speeds[i];
+        uint destination = destinations[i];
+        float personal_space = personal_spaces[i];
+
+        uint j = i;
+        for (; j > start && original_ids[j - 1] > id; j--) {
+            STORE_VEC2(LOAD_VEC2(positions, j - 1), positions, j);
+            STORE_VEC2(LOAD_VEC2(velocities, j - 1), velocities, j);
+            desired_speeds[j] = desired_speeds[j - 1];
+            destinations[j] = destinations[j - 1];
+            personal_spaces[j] = personal_spaces[j - 1];
+            original_ids[j] = original_ids[j - 1];
+        }
+
+        STORE_VEC2(pos, positions, j);
+        STORE_VEC2(vel, velocities, j);
+        desired_speeds[j] = desired_speed;
+        destinations[j] = destination;
+        personal_spaces[j] = personal_space;
+        original_ids[j] = id;
+    }
+}
+
+// Calculate accelerations of pedestrians sorted by cells. Results are written
+// in the original order.
 __kernel void
-calc_next_state(uint ped_count, __global float2 *positions,
-                __global float2 *velocities, __global float *desired_speeds,
-                __global uint *destinations,
+calc_next_state(uint ped_count, __global VEC2_STORAGE *positions,
+                __global VEC2_STORAGE *velocities,
+                __global float *desired_speeds,
+                __global uint *destinations, __global float *personal_spaces,
+                __global uint *original_ids,
                 read_only image2d_array_t potential_map,
                 read_only image2d_t distance_map, float field_unit,
-                __global uint *neighbor_grid_indices, int2 neighbor_grid_shape,
-                float neighbor_grid_unit, __global float2 *accelerations) {
+                __global uint *cell_starts, int2 neighbor_grid_shape,
+                float neighbor_grid_unit, float cos_phi,
+                float out_of_sight_factor, __global float2 *accelerations,
+                __global float *min_distances,
+                __global uint *neighbor_counts) {
 
     int id = get_global_id(0);
     if (id >= ped_count) {
         return;
     }
 
-    float2 pos = positions[id];
-    float2 vel = velocities[id];
+    float2 pos = LOAD_VEC2(positions, id);
+    float2 vel = LOAD_VEC2(velocities, id);
     float desired_speed = desired_speeds[id];
-    float dest_id = (float)destinations[id];
+    uint dest_id = destinations[id];
 
     float2 acc = (float2)(0.0f, 0.0f);
 
     // Calculate force toward the destination.
+    // Pedestrians whose destination is UINT_MAX hold their position.
     float2 coord = pos / field_unit - (float2)(0.5f, 0.5f);
-    float2 grad = sobel_array(potential_map, (float4)(coord, dest_id, 0.0f));
-    float2 e = normalize(grad);
+    float2 e = (float2)(0.0f, 0.0f);
+    if (dest_id != UINT_MAX) {
+        float2 grad = sobel_array(potential_map,
+                                  (float4)(coord, (float)dest_id, 0.0f));
+        e = normalize(grad);
+    }
     acc += (e * desired_speed - vel) / 0.5f;
 
     // Calculate force from other pedestrians.
+    float min_distance = INFINITY;
+    uint neighbor_count = 0;
     int2 grid_id = convert_int2((float2)(pos / neighbor_grid_unit));
 
     int y_start = max(grid_id.y - 1, 0);
     int y_end = min(grid_id.y + 1, neighbor_grid_shape.y - 1);
     int x_start = max(grid_id.x - 1, 0);
-    int x_end = min(grid_id.x + 1, neighbor_grid_shape.x);
+    int x_end = min(grid_id.x + 1, neighbor_grid_shape.x - 1);
 
     for (int y = y_start; y <= y_end; y++) {
         int row_id = y * neighbor_grid_shape.x;
-        for (int i = neighbor_grid_indices[row_id + x_start];
-             i < neighbor_grid_indices[row_id + x_end + 1]; i++) {
+        for (int i = cell_starts[row_id + x_start];
+             i < cell_starts[row_id + x_end + 1]; i++) {
             if (i != id) {
-                float2 difference = pos - positions[i];
+                float2 difference = pos - LOAD_VEC2(positions, i);
                 float distance = length(difference);
 
-                if (distance <= 2.0f) {
+                if (distance <= INTERACTION_CUTOFF) {
+                    min_distance = min(min_distance, distance);
+                    if (distance <= DENSITY_RADIUS) {
+                        neighbor_count++;
+                    }
                     float2 direction = normalize(difference);
-                    float2 vel_i = velocities[i];
+                    float2 vel_i = LOAD_VEC2(velocities, i);
                     float2 t1 = difference - vel_i * 0.1f;
                     float t1_length = length(t1);
                     float t2 = distance + t1_length;
@@ -94,10 +273,14 @@ calc_next_state(uint ped_count, __global float2 *positions,
 
                     float2 nabla_b =
                         t2 * (direction + t1 / t1_length) / (4.0f * b);
-                    float2 force = 7.0f * native_exp(-b / 0.3f) * nabla_b;
+                    // The range is widened by the personal space of the other
+                    // pedestrian, keeping the strength at contact.
+                    float range = REPULSION_RANGE * personal_spaces[i];
+                    float2 force = 2.1f / REPULSION_RANGE *
+                                   native_exp(-b / range) * nabla_b;
 
-                    if (dot(e, -force) < length(force) * COS_PHI) {
-                        force *= 0.5f;
+                    if (dot(e, -force) < length(force) * cos_phi) {
+                        force *= out_of_sight_factor;
                     }
 
                     acc += force;
@@ -111,5 +294,8 @@ calc_next_state(uint ped_count, __global float2 *positions,
     float2 direction = -normalize(sobel(distance_map, coord));
     acc += 2.0f * native_exp(-distance / 0.2f) * direction;
 
-    accelerations[id] = acc;
+    uint original_id = original_ids[id];
+    accelerations[original_id] = acc;
+    min_distances[original_id] = min_distance;
+    neighbor_counts[original_id] = neighbor_count;
 }
@@ -0,0 +1,112 @@
+use glam::{vec2, Vec2};
+use ndarray::Array2;
+use thin_vec::ThinVec;
+
+use super::{scenario::ObstacleConfig, util};
+
+/// Range from obstacles within which their distances are evaluated exactly. (meters)
+///
+/// The repulsion decays to less than 1% of its max beyond it, so the distance map is accurate enough there.
+pub const NEAR_RANGE: f32 = 1.0;
+/// Unit length of the grid of candidate obstacles. (meters)
+const UNIT: f32 = 1.0;
+
+/// Grid listing obstacles near each cell, whose distances are evaluated analytically as capsules, i.e. line segments
+/// expanded by half their widths with rounded ends.
+///
+/// Used instead of the distance map near obstacles if
+/// [`SimulatorOptions::exact_obstacle_distance`](crate::SimulatorOptions::exact_obstacle_distance) is enabled, since
+/// the rasterized map rounds corners off and makes forces asymmetric at coarse units.
+pub struct ObstacleIndex {
+    data: Array2<ThinVec<u32>>,
+    /// Line and radius of each obstacle
+    capsules: Vec<([Vec2; 2], f32)>,
+}
+
+impl ObstacleIndex {
+    /// Index hard obstacles in a field of `size`. Soft obstacles are left to their own distance map.
+    pub fn new(size: Vec2, obstacles: &[ObstacleConfig]) -> Self {
+        let shape = (size / UNIT).ceil();
+        let shape = (shape.y as usize, shape.x as usize);
+        let capsules: Vec<_> = obstacles
+            .iter()
+            .filter(|obs| !obs.soft)
+            .flat_map(|obs| obs.segments().map(|line| (line, obs.width * 0.5)))
+            .collect();
+
+        // Cells are listed if any point in them can be within the range.
+        let half_diagonal = UNIT * std::f32::consts::FRAC_1_SQRT_2;
+        let data = Array2::from_shape_fn(shape, |(y, x)| {
+            let center = (vec2(x as f32, y as f32) + 0.5) * UNIT;
+            capsules
+                .iter()
+                .enumerate()
+                .filter(|(_, &(line, radius))| {
+                    util::distance_from_line(center, line).length() - radius
+                        <= NEAR_RANGE + half_diagonal
+                })
+                .map(|(i, _)| i as u32)
+                .collect()
+        });
+
+        ObstacleIndex { data, capsules }
+    }
+
+    /// Find the nearest obstacle within [`NEAR_RANGE`] from `pos`.
+    ///
+    /// Returns the signed distance from its surface and the direction away from it, or `None` if there is no obstacle
+    /// in the range or `pos` is on the center line, where the direction is undefined.
+    pub fn nearest(&self, pos: Vec2) -> Option<(f32, Vec2)> {
+        let ix = (pos / UNIT).as_ivec2();
+        let cell = self.data.get(util::Index::new(ix.x, ix.y))?;
+
+        let (distance, diff) = cell
+            .iter()
+            .map(|&i| {
+                let (line, radius) = self.capsules[i as usize];
+                let diff = util::distance_from_line(pos, line);
+                (diff.length() - radius, diff)
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+        (distance <= NEAR_RANGE)
+            .then(|| diff.try_normalize())
+            .flatten()
+            .map(|direction| (distance, direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+    use glam::vec2;
+
+    use crate::scenario::ObstacleConfig;
+
+    use super::ObstacleIndex;
+
+    #[test]
+    fn test_nearest() {
+        let obstacles = [ObstacleConfig {
+            line: [vec2(2.0, 5.0), vec2(8.0, 5.0)],
+            width: 0.4,
+            soft: false,
+            ..Default::default()
+        }];
+        let index = ObstacleIndex::new(vec2(10.0, 10.0), &obstacles);
+
+        let (distance, direction) = index.nearest(vec2(5.0, 5.5)).unwrap();
+        assert_float_absolute_eq!(distance, 0.3, 1e-6);
+        assert!(direction.abs_diff_eq(vec2(0.0, 1.0), 1e-6));
+
+        // Corners are rounded exactly, so forces are symmetric around the end.
+        let (upper, upper_direction) = index.nearest(vec2(8.3, 5.4)).unwrap();
+        let (lower, lower_direction) = index.nearest(vec2(8.3, 4.6)).unwrap();
+        assert_float_absolute_eq!(upper, 0.3, 1e-6);
+        assert_float_absolute_eq!(lower, upper, 1e-6);
+        assert_float_absolute_eq!(upper_direction.y, -lower_direction.y, 1e-6);
+
+        assert!(index.nearest(vec2(5.0, 7.0)).is_none());
+        assert!(index.nearest(vec2(-1.0, 5.0)).is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use geo::{BoundingRect, Polygon};
+use glam::{vec2, Vec2};
+
+use crate::{models::INTERACTION_CUTOFF, neighbor_grid::NeighborGrid, util, Simulator};
+
+/// Region of the field in which pedestrians are looked up by [`Simulator::query_region`], e.g. measurement areas,
+/// conditions of scripted events or selections in a GUI.
+#[derive(Debug, Clone)]
+pub enum Region {
+    /// Axis-aligned rectangle from the min corner to the max corner
+    Rect([Vec2; 2]),
+    Polygon(Polygon<f32>),
+}
+
+impl Region {
+    /// Rectangle with two opposite corners in any order.
+    pub fn rect(a: Vec2, b: Vec2) -> Self {
+        Region::Rect([a.min(b), a.max(b)])
+    }
+
+    pub fn polygon(vertices: &[Vec2]) -> Self {
+        Region::Polygon(util::polygon(vertices))
+    }
+
+    pub fn contains(&self, pos: Vec2) -> bool {
+        match self {
+            Region::Rect([min, max]) => pos.cmpge(*min).all() && pos.cmple(*max).all(),
+            Region::Polygon(polygon) => util::polygon_contains(polygon, pos),
+        }
+    }
+
+    /// Min and max corners of the bounding box, or `None` for an empty polygon
+    fn bounds(&self) -> Option<[Vec2; 2]> {
+        match self {
+            Region::Rect(corners) => Some(*corners),
+            Region::Polygon(polygon) => polygon.bounding_rect().map(|rect| {
+                [
+                    vec2(rect.min().x, rect.min().y),
+                    vec2(rect.max().x, rect.max().y),
+                ]
+            }),
+        }
+    }
+}
+
+impl Simulator {
+    /// IDs of pedestrians within the region in the current step, in ascending order.
+    ///
+    /// Positions are indexed in a grid on the first query of each step, so that later queries only visit the cells
+    /// overlapping the region. Pedestrians outside the field are not found.
+    pub fn query_region(&self, region: &Region) -> Vec<usize> {
+        let Some([min, max]) = region.bounds() else {
+            return Vec::new();
+        };
+        let slices = self.slices();
+        let grid = self.region_index.get_or_init(|| {
+            let mut grid = NeighborGrid::new(self.scenario.field.size, INTERACTION_CUTOFF);
+            grid.update(slices.positions.iter().copied());
+            grid
+        });
+
+        // Ranges of cells overlapping the bounding box, which are empty if it is outside the grid
+        let cells = |min: f32, max: f32, count: usize| {
+            let first = (min / grid.unit).floor().max(0.0) as usize;
+            let last = ((max / grid.unit).floor() as isize).min(count as isize - 1);
+            first..(last + 1).max(0) as usize
+        };
+        let (rows, columns) = grid.shape;
+        let mut ids = Vec::new();
+        for y in cells(min.y, max.y, rows) {
+            for x in cells(min.x, max.x, columns) {
+                ids.extend(
+                    grid.data[(y, x)]
+                        .iter()
+                        .map(|&i| i as usize)
+                        .filter(|&i| region.contains(slices.positions[i]))
+                        .map(|i| slices.ids[i] as usize),
+                );
+            }
+        }
+        ids.sort_unstable();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{scenario::Scenario, Simulator, SimulatorOptions};
+
+    use super::Region;
+
+    #[test]
+    fn test_query_region() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 40, region = [[1.0, 1.0], [7.0, 1.0], [7.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+
+        let regions = [
+            Region::rect(vec2(5.0, 6.0), vec2(2.5, 3.0)),
+            Region::polygon(&[vec2(0.0, 0.0), vec2(8.0, 0.0), vec2(0.0, 8.0)]),
+            Region::rect(vec2(-3.0, -3.0), vec2(-1.0, 20.0)),
+        ];
+        for _ in 0..2 {
+            for region in &regions {
+                let mut expected: Vec<_> = simulator
+                    .list_pedestrians()
+                    .iter()
+                    .filter(|p| region.contains(p.pos))
+                    .map(|p| p.id)
+                    .collect();
+                expected.sort_unstable();
+                assert_eq!(simulator.query_region(region), expected);
+            }
+            // The index follows pedestrians moving in the next step.
+            for _ in 0..10 {
+                simulator.tick().unwrap();
+            }
+        }
+        assert!(!simulator.query_region(&regions[1]).is_empty());
+        assert!(simulator.query_region(&regions[2]).is_empty());
+    }
+}
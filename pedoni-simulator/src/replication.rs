@@ -0,0 +1,273 @@
+use std::fmt::{self, Display};
+
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    error::Result,
+    events::Event,
+    field::Field,
+    scenario::{PedestrianSpawnConfig, Scenario},
+    Simulator, SimulatorOptions,
+};
+
+/// Outputs of a replication run by [`replicate`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome {
+    pub seed: u64,
+    pub steps: i32,
+    /// Time when the last pedestrian left the field, if all pedestrians are spawned at once and they all left
+    /// (seconds)
+    pub evacuation_time: Option<f64>,
+    /// Number of pedestrians arrived at their destinations after the warm-up
+    pub arrived: usize,
+    /// Mean flow of pedestrians arriving at their destinations after the warm-up (persons/s)
+    pub flow: f64,
+    /// Mean time from spawning to arrival of pedestrians arrived after the warm-up (seconds)
+    pub mean_travel_time: Option<f64>,
+}
+
+/// Mean of an output over replications and its 95% confidence interval
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Estimate {
+    /// Number of replications in which the output was obtained
+    pub n: usize,
+    pub mean: f64,
+    /// Sample standard deviation
+    pub std: f64,
+    /// Half width of the 95% confidence interval of the mean, from Student's t-distribution
+    pub ci95: f64,
+}
+
+impl Estimate {
+    /// Estimate the mean from samples, or `None` if there is none.
+    pub fn new(samples: &[f64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let n = samples.len();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let std = if n > 1 {
+            let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+            variance.sqrt()
+        } else {
+            0.0
+        };
+        let ci95 = if n > 1 {
+            t_quantile_975(n - 1) * std / (n as f64).sqrt()
+        } else {
+            f64::INFINITY
+        };
+
+        Some(Estimate { n, mean, std, ci95 })
+    }
+}
+
+/// 97.5th percentile of Student's t-distribution with `df` degrees of freedom.
+fn t_quantile_975(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+    TABLE.get(df.wrapping_sub(1)).copied().unwrap_or(1.96)
+}
+
+/// Outcomes of replications and estimates of their means
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub outcomes: Vec<Outcome>,
+    pub evacuation_time: Option<Estimate>,
+    pub arrived: Option<Estimate>,
+    pub flow: Option<Estimate>,
+    pub mean_travel_time: Option<Estimate>,
+}
+
+impl Summary {
+    pub fn new(outcomes: Vec<Outcome>) -> Self {
+        let estimate = |f: fn(&Outcome) -> Option<f64>| {
+            Estimate::new(&outcomes.iter().filter_map(f).collect::<Vec<_>>())
+        };
+
+        Summary {
+            evacuation_time: estimate(|o| o.evacuation_time),
+            arrived: estimate(|o| Some(o.arrived as f64)),
+            flow: estimate(|o| Some(o.flow)),
+            mean_travel_time: estimate(|o| o.mean_travel_time),
+            outcomes,
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<20} {:>4} {:>10} {:>10} {:>21}",
+            "Output", "N", "Mean", "Std", "95% CI"
+        )?;
+        let rows = [
+            ("Evacuation time (s)", &self.evacuation_time),
+            ("Arrived", &self.arrived),
+            ("Flow (/s)", &self.flow),
+            ("Travel time (s)", &self.mean_travel_time),
+        ];
+        for (name, estimate) in rows {
+            match estimate {
+                Some(e) => write!(
+                    f,
+                    "\n{:<20} {:>4} {:>10.3} {:>10.3} {:>10.3}-{:<10.3}",
+                    name,
+                    e.n,
+                    e.mean,
+                    e.std,
+                    e.mean - e.ci95,
+                    e.mean + e.ci95
+                )?,
+                None => write!(f, "\n{name:<20} {:>4}", 0)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Simulate the scenario `replications` times with seeds `base_seed`, `base_seed + 1`, ... and summarize the outputs.
+///
+/// Each replication runs until the field is empty if all pedestrians are spawned at once, or for `max_steps` steps.
+/// Arrivals in the warm-up of [`SimulatorOptions::warmup_steps`] are not counted in the outputs.
+pub fn replicate(
+    options: &SimulatorOptions,
+    scenario: &Scenario,
+    fields: &[Field],
+    replications: usize,
+    base_seed: u64,
+    max_steps: i32,
+) -> Result<Summary> {
+    let outcomes = (0..replications as u64)
+        .map(|i| {
+            let seed = base_seed.wrapping_add(i);
+            let outcome = run(options, scenario, fields, seed, max_steps)?;
+            info!(
+                "Replication {}/{replications}: seed: {seed}, steps: {}, arrived: {}",
+                i + 1,
+                outcome.steps,
+                outcome.arrived
+            );
+            Ok(outcome)
+        })
+        .collect::<Result<_>>()?;
+    Ok(Summary::new(outcomes))
+}
+
+fn run(
+    options: &SimulatorOptions,
+    scenario: &Scenario,
+    fields: &[Field],
+    seed: u64,
+    max_steps: i32,
+) -> Result<Outcome> {
+    let options = SimulatorOptions {
+        seed: Some(seed),
+        ..options.clone()
+    };
+    let mut simulator = Simulator::with_fields(options, scenario.clone(), fields.to_vec())?;
+    let events = simulator.subscribe();
+    let evacuates = !scenario
+        .pedestrians
+        .iter()
+        .any(|p| matches!(p.spawn, PedestrianSpawnConfig::Periodic { .. }));
+
+    let mut travel_times = Vec::new();
+    let mut evacuation_time = None;
+    let mut measured_steps = 0;
+    while simulator.step < max_steps {
+        let metrics = simulator.tick()?;
+        let arrivals = events.try_iter().filter_map(|event| match event {
+            Event::PedestrianArrived { travel_time, .. } => Some(travel_time),
+            _ => None,
+        });
+        if simulator.is_warming_up() {
+            arrivals.for_each(drop);
+        } else {
+            travel_times.extend(arrivals);
+            measured_steps += 1;
+        }
+
+        if evacuates && metrics.active_ped_count == 0 && metrics.spawn_queue_length == 0 {
+            evacuation_time = Some(simulator.step as f64 * 0.1);
+            break;
+        }
+    }
+
+    let arrived = travel_times.len();
+    Ok(Outcome {
+        seed,
+        steps: simulator.step,
+        evacuation_time,
+        arrived,
+        flow: arrived as f64 / (measured_steps.max(1) as f64 * 0.1),
+        mean_travel_time: (arrived > 0).then(|| travel_times.iter().sum::<f64>() / arrived as f64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+
+    use super::{replicate, Estimate};
+    use crate::{field::Field, scenario::Scenario, SimulatorOptions};
+
+    #[test]
+    fn test_estimate() {
+        let estimate = Estimate::new(&[1.0, 2.0, 3.0, 4.0]).unwrap();
+        assert_float_absolute_eq!(estimate.mean, 2.5);
+        assert_float_absolute_eq!(estimate.std, 1.2909944, 1e-6);
+        // t(0.975, 3) = 3.182
+        assert_float_absolute_eq!(estimate.ci95, 3.182 * 1.2909944 / 2.0, 1e-6);
+
+        assert!(Estimate::new(&[]).is_none());
+        assert!(Estimate::new(&[1.0]).unwrap().ci95.is_infinite());
+    }
+
+    #[test]
+    fn test_warmup() {
+        let scenario = Scenario::from_toml(
+            r#"
+            version = 2
+            field = { size = [12.0, 4.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 3.0]]
+
+            [[waypoints]]
+            line = [[11.0, 1.0], [11.0, 3.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "periodic", rate = 2.0 }
+            "#,
+        )
+        .unwrap();
+        let fields = [Field::from_scenario(&scenario, 0.25).unwrap()];
+        let outcome = |warmup_steps| {
+            let options = SimulatorOptions {
+                warmup_steps,
+                ..Default::default()
+            };
+            replicate(&options, &scenario, &fields, 1, 1, 600)
+                .unwrap()
+                .outcomes
+                .remove(0)
+        };
+
+        // Few pedestrians arrive in the first 10 s while the corridor fills, which lowers the flow if counted.
+        let (full, warmed) = (outcome(0), outcome(100));
+        assert!(warmed.arrived > 0 && warmed.arrived <= full.arrived);
+        assert!(warmed.flow > full.flow, "{} {}", warmed.flow, full.flow);
+        // Arrivals in the warm-up are not counted at all.
+        assert_eq!(outcome(600).arrived, 0);
+    }
+}
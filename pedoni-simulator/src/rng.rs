@@ -0,0 +1,114 @@
+use fastrand_contrib::RngExt;
+
+/// Source of random numbers used by the simulator, implemented for [`fastrand::Rng`].
+pub trait Rng: Send + Sync {
+    /// Generate a number uniformly in `[0, 1)`.
+    fn f32(&mut self) -> f32;
+    /// Generate a number uniformly in `[0, 1)`.
+    fn f64(&mut self) -> f64;
+    /// Generate a number in the normal distribution, approximately.
+    fn f32_normal(&mut self, mean: f32, std: f32) -> f32;
+}
+
+impl Rng for fastrand::Rng {
+    fn f32(&mut self) -> f32 {
+        fastrand::Rng::f32(self)
+    }
+
+    fn f64(&mut self) -> f64 {
+        fastrand::Rng::f64(self)
+    }
+
+    fn f32_normal(&mut self, mean: f32, std: f32) -> f32 {
+        self.f32_normal_approx(mean, std)
+    }
+}
+
+/// Subsystems which draw random numbers from their own streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    /// Numbers and positions of spawned pedestrians
+    Spawn,
+    /// Destinations chosen by [`crate::scenario::PedestrianConfig::sample_destination`]
+    Destination,
+    /// Desired speeds of pedestrians
+    DesiredSpeed,
+    /// Positions at which pedestrians come out of links
+    Link,
+    /// Whether pedestrians are unfamiliar with the place. See [`crate::scenario::FamiliarityConfig`].
+    Familiarity,
+    /// Noise of readings of [`crate::sensor::Sensors`]
+    Sensor,
+}
+
+const STREAM_COUNT: usize = 6;
+
+/// Independent random streams of subsystems derived from a single seed.
+///
+/// Each subsystem advances only its own stream, so that changing how one of them samples does not perturb
+/// the sequences of the others.
+pub struct RngStreams {
+    streams: [Box<dyn Rng>; STREAM_COUNT],
+}
+
+impl RngStreams {
+    pub fn new(seed: u64) -> Self {
+        let streams = std::array::from_fn(|i| {
+            Box::new(fastrand::Rng::with_seed(stream_seed(seed, i))) as Box<dyn Rng>
+        });
+        RngStreams { streams }
+    }
+
+    /// Seed the streams from [`SimulatorOptions::seed`](crate::SimulatorOptions::seed), or the global generator of
+    /// `fastrand` if it is not given.
+    pub fn from_seed(seed: Option<u64>) -> Self {
+        Self::new(seed.unwrap_or_else(|| fastrand::u64(..)))
+    }
+
+    pub fn get(&mut self, stream: Stream) -> &mut dyn Rng {
+        self.streams[stream as usize].as_mut()
+    }
+
+    /// Replace the generator of a stream, e.g. with one reading recorded numbers.
+    pub fn set(&mut self, stream: Stream, rng: Box<dyn Rng>) {
+        self.streams[stream as usize] = rng;
+    }
+}
+
+/// Derive the seed of the `index`-th stream with SplitMix64, which decorrelates seeds differing by a few bits.
+fn stream_seed(seed: u64, index: usize) -> u64 {
+    let mut z = seed.wrapping_add((index as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RngStreams, Stream};
+
+    #[test]
+    fn test_streams() {
+        let sample = |streams: &mut RngStreams, stream| {
+            (0..10)
+                .map(|_| streams.get(stream).f64())
+                .collect::<Vec<_>>()
+        };
+
+        // Drawing from one stream does not change the sequence of another.
+        let mut a = RngStreams::new(42);
+        let mut b = RngStreams::new(42);
+        sample(&mut a, Stream::Spawn);
+        assert_eq!(
+            sample(&mut a, Stream::DesiredSpeed),
+            sample(&mut b, Stream::DesiredSpeed)
+        );
+
+        let mut c = RngStreams::new(42);
+        assert_ne!(sample(&mut c, Stream::Spawn), sample(&mut c, Stream::Link));
+        assert_ne!(
+            sample(&mut RngStreams::new(1), Stream::Spawn),
+            sample(&mut RngStreams::new(2), Stream::Spawn)
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use glam::Vec2;
+
+use crate::{
+    scenario::{Scenario, Side},
+    util, SimulatorOptions,
+};
+
+/// Effective destinations of waypoints, updated every step according to their schedules.
+#[derive(Debug, Default, Clone)]
+pub struct Routing {
+    open: Vec<bool>,
+    targets: Vec<Option<usize>>,
+    arrival_areas: Vec<ArrivalArea>,
+    /// Members of each waypoint group, whose destination IDs follow waypoints
+    groups: Vec<Vec<usize>>,
+}
+
+/// Area in which pedestrians are regarded as arrived at a waypoint.
+#[derive(Debug, Default, Clone)]
+struct ArrivalArea {
+    line: [Vec2; 2],
+    /// Max distance from `line`. (meters)
+    radius: f32,
+    /// Side of `line` from which pedestrians arrive
+    side: Option<Side>,
+    level: usize,
+}
+
+impl Routing {
+    pub fn new(options: &SimulatorOptions, scenario: &Scenario) -> Self {
+        let arrival_areas = scenario
+            .waypoints
+            .iter()
+            .map(|wp| ArrivalArea {
+                line: wp.line,
+                radius: wp.width * 0.5 + wp.arrival_distance.unwrap_or(options.arrival_distance),
+                side: wp.arrival_side,
+                level: wp.level,
+            })
+            .collect();
+
+        let groups = scenario
+            .waypoint_groups
+            .iter()
+            .map(|group| group.members.clone())
+            .collect();
+
+        let mut routing = Routing {
+            arrival_areas,
+            groups,
+            ..Default::default()
+        };
+        routing.update(scenario, 0.0);
+        routing
+    }
+
+    /// Update states of waypoints at the given time. (seconds)
+    pub fn update(&mut self, scenario: &Scenario, time: f64) {
+        let waypoints = &scenario.waypoints;
+
+        self.open = waypoints.iter().map(|wp| !wp.is_closed_at(time)).collect();
+        self.targets = (0..waypoints.len())
+            .map(|id| {
+                // Follow alternatives until an open waypoint is found.
+                let mut id = id;
+                for _ in 0..waypoints.len() {
+                    if self.open[id] {
+                        return Some(id);
+                    }
+                    id = waypoints[id].alternative?;
+                }
+                None
+            })
+            .collect();
+        // Groups are open while any of their members is open.
+        // Their potentials do not reflect closures, so closed members still attract pedestrians nearby.
+        for (i, members) in self.groups.iter().enumerate() {
+            let is_open = members.iter().any(|&id| self.open[id]);
+            self.targets.push(is_open.then_some(waypoints.len() + i));
+        }
+    }
+
+    /// Check if the waypoint is open.
+    pub fn is_open(&self, waypoint_id: usize) -> bool {
+        self.open.get(waypoint_id).cloned().unwrap_or(true)
+    }
+
+    /// Get the waypoint which pedestrians heading for `destination` should actually walk to.
+    ///
+    /// Returns `None` if they should hold their position.
+    pub fn target(&self, destination: usize) -> Option<usize> {
+        self.targets
+            .get(destination)
+            .cloned()
+            .unwrap_or(Some(destination))
+    }
+
+    /// Check if a pedestrian on `level` heading for `destination` has arrived at its target waypoint.
+    pub fn has_arrived(&self, destination: usize, level: usize, position: Vec2) -> bool {
+        self.arrival_waypoint(destination, level, position)
+            .is_some()
+    }
+
+    /// Waypoint at which a pedestrian on `level` heading for `destination` has arrived, which is one of the members if
+    /// the target is a waypoint group.
+    pub fn arrival_waypoint(
+        &self,
+        destination: usize,
+        level: usize,
+        position: Vec2,
+    ) -> Option<usize> {
+        let target = self.target(destination)?;
+        let arrived = |id| self.is_in_arrival_area(id, level, position);
+        match target.checked_sub(self.arrival_areas.len()) {
+            Some(group) => self
+                .groups
+                .get(group)?
+                .iter()
+                .copied()
+                .find(|&id| self.is_open(id) && arrived(id)),
+            None => arrived(target).then_some(target),
+        }
+    }
+
+    /// Check if the position on `level` is within the arrival area of the waypoint, regardless of whether it is open.
+    pub fn is_in_arrival_area(&self, waypoint_id: usize, level: usize, position: Vec2) -> bool {
+        self.arrival_areas.get(waypoint_id).is_some_and(|area| {
+            area.level == level
+                && util::distance_from_line(position, area.line).length() <= area.radius
+                && area
+                    .side
+                    .is_none_or(|side| side.contains(area.line, position))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{
+        scenario::{Scenario, Side, WaypointConfig, WaypointGroupConfig},
+        SimulatorOptions,
+    };
+
+    use super::Routing;
+
+    #[test]
+    fn test_routing() {
+        let scenario = Scenario {
+            waypoints: vec![
+                WaypointConfig {
+                    closed: vec![[10.0, 20.0]],
+                    alternative: Some(1),
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    closed: vec![[15.0, 30.0]],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mut routing = Routing::new(&SimulatorOptions::default(), &scenario);
+        assert_eq!(routing.target(0), Some(0));
+
+        routing.update(&scenario, 12.0);
+        assert!(!routing.is_open(0));
+        assert_eq!(routing.target(0), Some(1));
+
+        routing.update(&scenario, 16.0);
+        assert_eq!(routing.target(0), None);
+        assert_eq!(routing.target(1), None);
+
+        routing.update(&scenario, 20.0);
+        assert_eq!(routing.target(0), Some(0));
+    }
+
+    #[test]
+    fn test_arrival() {
+        let scenario = Scenario {
+            waypoints: vec![
+                WaypointConfig {
+                    line: [vec2(0.0, 0.0), vec2(0.0, 2.0)],
+                    ..Default::default()
+                },
+                WaypointConfig {
+                    line: [vec2(5.0, 0.0), vec2(5.0, 2.0)],
+                    width: 2.0,
+                    arrival_distance: Some(1.0),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let routing = Routing::new(&SimulatorOptions::default(), &scenario);
+
+        assert!(routing.has_arrived(0, 0, vec2(0.7, 1.0)));
+        assert!(!routing.has_arrived(0, 0, vec2(0.8, 1.0)));
+        assert!(!routing.has_arrived(0, 0, vec2(0.0, 2.8)));
+        // Pedestrians on other levels pass over or under the waypoint.
+        assert!(!routing.has_arrived(0, 1, vec2(0.7, 1.0)));
+        assert!(routing.has_arrived(1, 0, vec2(3.1, 1.0)));
+        assert!(!routing.has_arrived(1, 0, vec2(2.9, 1.0)));
+    }
+
+    #[test]
+    fn test_arrival_side() {
+        // Pedestrians arrive from the right-hand side, i.e. x > 0.
+        let scenario = Scenario {
+            waypoints: vec![WaypointConfig {
+                line: [vec2(0.0, 0.0), vec2(0.0, 2.0)],
+                arrival_side: Some(Side::Right),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let routing = Routing::new(&SimulatorOptions::default(), &scenario);
+
+        assert!(routing.has_arrived(0, 0, vec2(0.7, 1.0)));
+        assert!(routing.has_arrived(0, 0, vec2(0.0, 1.0)));
+        assert!(!routing.has_arrived(0, 0, vec2(-0.5, 1.0)));
+    }
+
+    #[test]
+    fn test_waypoint_group() {
+        let waypoint = |x: f32, closed| WaypointConfig {
+            line: [vec2(x, 0.0), vec2(x, 2.0)],
+            closed,
+            ..Default::default()
+        };
+        let scenario = Scenario {
+            waypoints: vec![waypoint(0.0, vec![]), waypoint(10.0, vec![[10.0, 20.0]])],
+            waypoint_groups: vec![WaypointGroupConfig {
+                members: vec![0, 1],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut routing = Routing::new(&SimulatorOptions::default(), &scenario);
+
+        assert_eq!(routing.target(2), Some(2));
+        assert!(routing.has_arrived(2, 0, vec2(0.5, 1.0)));
+        assert!(routing.has_arrived(2, 0, vec2(9.5, 1.0)));
+        assert!(!routing.has_arrived(2, 0, vec2(5.0, 1.0)));
+        assert_eq!(routing.arrival_waypoint(2, 0, vec2(9.5, 1.0)), Some(1));
+
+        // Closed members are not regarded as arrival areas.
+        routing.update(&scenario, 15.0);
+        assert!(!routing.has_arrived(2, 0, vec2(9.5, 1.0)));
+    }
+}
@@ -1,6 +1,15 @@
+use std::{
+    f32::consts::TAU,
+    fs,
+    path::{Path, PathBuf},
+};
+
 use glam::Vec2;
+use log::warn;
 use serde::Deserialize;
 
+use crate::{migration, rng::Rng};
+
 const fn f_one() -> f32 {
     1.0
 }
@@ -8,29 +17,595 @@ const fn f_one() -> f32 {
 /// Scenario data
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct Scenario {
+    /// Version of the format which the scenario is written in. Files without it are version 1, and older versions are
+    /// upgraded to [`migration::SCENARIO_VERSION`] on loading with warnings of deprecated forms. See [`crate::migration`].
+    #[serde(default)]
+    pub version: u32,
+    /// Unit of lengths in the scenario, which are converted into meters on loading.
+    /// Default widths of waypoints and obstacles, e.g. `width = 1`, are also interpreted in this unit, while the other
+    /// defaults are given in meters and applied after the conversion.
+    #[serde(default)]
+    pub units: Units,
     pub field: FieldConfig,
     pub waypoints: Vec<WaypointConfig>,
+    /// Groups of waypoints which can be destinations, referred to by indices following waypoints.
+    #[serde(default)]
+    pub waypoint_groups: Vec<WaypointGroupConfig>,
     pub obstacles: Vec<ObstacleConfig>,
+    #[serde(default)]
     pub pedestrians: Vec<PedestrianConfig>,
+    /// Demand given as an origin-destination matrix, which is expanded into [`Scenario::pedestrians`] on loading.
+    #[serde(default)]
+    pub od_matrix: Option<OdMatrixConfig>,
+    #[serde(default)]
+    pub gates: Vec<GateConfig>,
+    #[serde(default)]
+    pub links: Vec<LinkConfig>,
+    /// Levels such as floors of a building, connected by [`Scenario::links`]. A scenario without them has a single
+    /// level.
+    #[serde(default)]
+    pub levels: Vec<LevelConfig>,
+    #[serde(default)]
+    pub timeline: Vec<TimelineEntry>,
+    #[serde(default)]
+    pub kill_zones: Vec<KillZoneConfig>,
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>,
+    #[serde(default)]
+    pub holding_areas: Vec<HoldingAreaConfig>,
+    /// Thresholds of design codes which flows through exits are checked against
+    #[serde(default)]
+    pub exit_capacity: ExitCapacityConfig,
+    #[serde(default)]
+    pub panic: PanicConfig,
+    #[serde(default)]
+    pub behavior: BehaviorConfig,
+    /// Controller of periodic spawn rates holding the crowd at a setpoint
+    #[serde(default)]
+    pub density_control: Option<DensityControlConfig>,
 }
 
-#[derive(Debug, Default, Clone, Deserialize)]
+impl Scenario {
+    /// Parse a scenario in TOML, upgrade it from an older format, expand its OD matrix and normalize it with
+    /// [`Scenario::normalize`].
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let mut table: toml::Table = text.parse()?;
+        migration::migrate(&mut table)?;
+        let mut scenario: Scenario = toml::Value::Table(table).try_into()?;
+        scenario.version = scenario.version.max(1);
+        scenario.expand_od_matrix()?;
+        scenario.validate()?;
+        scenario.normalize();
+        Ok(scenario)
+    }
+
+    /// Check references and geometries which cannot be simulated, e.g. links to missing waypoints.
+    ///
+    /// Called by [`Scenario::from_toml`] and [`Simulator::new`](crate::Simulator::new), so scenarios built in code
+    /// are checked as well.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // Indices of waypoints and destinations are used without checks in the simulation.
+        let (waypoints, destinations) = (self.waypoints.len(), self.destination_count());
+        let check_waypoint = |index: usize, owner: &str| {
+            anyhow::ensure!(
+                index < waypoints,
+                "{owner} refers to waypoint {index} of {waypoints} waypoints"
+            );
+            Ok(())
+        };
+        let check_destination = |index: usize, owner: &str| {
+            anyhow::ensure!(
+                index < destinations,
+                "{owner} refers to destination {index} of {destinations} destinations"
+            );
+            Ok(())
+        };
+
+        let levels = self.level_count();
+        for (i, wp) in self.waypoints.iter().enumerate() {
+            anyhow::ensure!(
+                wp.level < levels,
+                "Waypoint {i} is on level {} of {levels} levels",
+                wp.level
+            );
+            if let Some(alternative) = wp.alternative {
+                check_waypoint(alternative, &format!("Alternative of waypoint {i}"))?;
+            }
+        }
+        for (i, group) in self.waypoint_groups.iter().enumerate() {
+            for &member in &group.members {
+                check_waypoint(member, &format!("Waypoint group {i}"))?;
+            }
+        }
+        for (i, link) in self.links.iter().enumerate() {
+            for waypoint in [link.from, link.to] {
+                check_waypoint(waypoint, &format!("Link {i}"))?;
+            }
+        }
+        for (i, area) in self.holding_areas.iter().enumerate() {
+            for destination in [area.waypoint, area.destination] {
+                check_destination(destination, &format!("Holding area {i}"))?;
+            }
+        }
+
+        for (i, obs) in self.obstacles.iter().enumerate() {
+            anyhow::ensure!(
+                obs.level < levels,
+                "Obstacle {i} is on level {} of {levels} levels",
+                obs.level
+            );
+            anyhow::ensure!(
+                obs.polyline.len() != 1,
+                "Polyline of obstacle {i} has only one vertex"
+            );
+            let radius = obs.circle.map(|c| c.radius).or(obs.arc.map(|a| a.radius));
+            anyhow::ensure!(
+                radius.is_none_or(|r| r > 0.0),
+                "Obstacle {i} has a non-positive radius"
+            );
+            anyhow::ensure!(
+                obs.arc.is_none_or(|a| a.angles[0] != a.angles[1]),
+                "Arc of obstacle {i} has the same angles at both ends"
+            );
+            // Curves are replaced with polylines along them by `normalize`, which are not given by users.
+            let polyline = !obs.polyline.is_empty()
+                && obs
+                    .curve()
+                    .is_none_or(|(vertices, _)| vertices != obs.polyline);
+            let shapes = [polyline, obs.circle.is_some(), obs.arc.is_some()];
+            anyhow::ensure!(
+                shapes.iter().filter(|&&given| given).count() <= 1,
+                "Obstacle {i} has more than one of polyline, circle and arc"
+            );
+        }
+
+        for (i, group) in self.pedestrians.iter().enumerate() {
+            anyhow::ensure!(
+                group.destination.is_some() || group.destinations.iter().any(|d| d.weight > 0.0),
+                "Pedestrian group {i} has neither `destination` nor `destinations` with positive weights"
+            );
+            let owner = format!("Pedestrian group {i}");
+            check_waypoint(group.origin, &owner)?;
+            let familiar_exit = group.familiarity.as_ref().and_then(|f| f.familiar_exit);
+            let choices = group.destinations.iter().map(|d| d.id);
+            for destination in group
+                .destination
+                .into_iter()
+                .chain(familiar_exit)
+                .chain(choices)
+            {
+                check_destination(destination, &owner)?;
+            }
+        }
+
+        for (i, entry) in self.timeline.iter().enumerate() {
+            let groups = ("pedestrian group", self.pedestrians.len());
+            let (index, (kind, count)) = match entry.action {
+                TimelineAction::CloseWaypoint { waypoint }
+                | TimelineAction::OpenWaypoint { waypoint } => {
+                    (Some(waypoint), ("waypoint", self.waypoints.len()))
+                }
+                TimelineAction::SetSpawnRate { group, .. } => (Some(group), groups),
+                TimelineAction::StartPanic { group } => (group, groups),
+                TimelineAction::SetDestination { group, destination } => {
+                    let count = self.destination_count();
+                    anyhow::ensure!(
+                        destination < count,
+                        "Timeline entry {i} refers to destination {destination} of {count} destinations"
+                    );
+                    (group, groups)
+                }
+                TimelineAction::ReleaseHoldingArea { area } => {
+                    (Some(area), ("holding area", self.holding_areas.len()))
+                }
+            };
+            if let Some(index) = index {
+                anyhow::ensure!(
+                    index < count,
+                    "Timeline entry {i} refers to {kind} {index} of {count} {kind}s"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the scenario file with [`Scenario::from_toml`], resolving paths in it relative to the file.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let mut scenario = Self::from_toml(&fs::read_to_string(path)?)?;
+        if let Some(plan) = &mut scenario.field.floor_plan {
+            if let Some(dir) = path.parent() {
+                plan.path = dir.join(&plan.path);
+            }
+        }
+        Ok(scenario)
+    }
+
+    /// Append a pedestrian group spawned periodically from each origin of [`Scenario::od_matrix`],
+    /// whose destinations are chosen in proportion to the flows.
+    pub fn expand_od_matrix(&mut self) -> anyhow::Result<()> {
+        let Some(od) = self.od_matrix.take() else {
+            return Ok(());
+        };
+
+        anyhow::ensure!(
+            od.flows.len() == od.origins.len(),
+            "OD matrix has {} rows for {} origins",
+            od.flows.len(),
+            od.origins.len()
+        );
+        let scale = match od.flow_unit {
+            FlowUnit::PerSecond => 1.0,
+            FlowUnit::PerHour => 1.0 / 3600.0,
+        };
+
+        for (&origin, row) in od.origins.iter().zip(&od.flows) {
+            anyhow::ensure!(
+                row.len() == od.destinations.len(),
+                "OD matrix has {} columns for {} destinations",
+                row.len(),
+                od.destinations.len()
+            );
+            anyhow::ensure!(
+                row.iter().all(|&flow| flow >= 0.0),
+                "OD matrix has negative flows from origin {origin}"
+            );
+
+            let rate: f64 = row.iter().sum::<f64>() * scale;
+            if rate <= 0.0 {
+                continue;
+            }
+            let destinations = od
+                .destinations
+                .iter()
+                .zip(row)
+                .filter(|(_, &flow)| flow > 0.0)
+                .map(|(&id, &weight)| DestinationChoice { id, weight })
+                .collect();
+            self.pedestrians.push(PedestrianConfig {
+                origin,
+                destinations,
+                spawn: PedestrianSpawnConfig::Periodic { rate },
+                ..Default::default()
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Convert lengths into meters, approximate curved obstacles by polylines and determine the field size if
+    /// [`FieldConfig::auto_size`] is enabled.
+    ///
+    /// Warns if any objects fall outside the field, where they are truncated.
+    pub fn normalize(&mut self) {
+        let scale = self.units.to_meters();
+        if scale != 1.0 {
+            self.field.size *= scale;
+            self.field.margin = self.field.margin.map(|m| m * scale);
+            if let Some(plan) = &mut self.field.floor_plan {
+                plan.scale *= scale;
+            }
+            for wp in self.waypoints.iter_mut() {
+                wp.line = wp.line.map(|p| p * scale);
+                wp.width *= scale;
+                wp.arrival_distance = wp.arrival_distance.map(|d| d * scale);
+                wp.exit_width = wp.exit_width.map(|w| w * scale);
+            }
+            self.exit_capacity.boundary_layer =
+                self.exit_capacity.boundary_layer.map(|b| b * scale);
+            for obs in self.obstacles.iter_mut() {
+                obs.line = obs.line.map(|p| p * scale);
+                obs.polyline.iter_mut().for_each(|v| *v *= scale);
+                for (center, radius) in obs
+                    .circle
+                    .iter_mut()
+                    .map(|c| (&mut c.center, &mut c.radius))
+                    .chain(obs.arc.iter_mut().map(|a| (&mut a.center, &mut a.radius)))
+                {
+                    *center *= scale;
+                    *radius *= scale;
+                }
+                obs.width *= scale;
+            }
+            for gate in self.gates.iter_mut() {
+                gate.line = gate.line.map(|p| p * scale);
+                if let Some(limit) = &mut gate.occupancy_limit {
+                    limit.region.iter_mut().for_each(|v| *v *= scale);
+                }
+            }
+            for link in self.links.iter_mut() {
+                link.length *= scale;
+            }
+            for choice in self
+                .waypoint_groups
+                .iter_mut()
+                .flat_map(|g| &mut g.exit_choice)
+            {
+                choice.congestion_weight = choice.congestion_weight.map(|w| w * scale);
+                choice.threshold = choice.threshold.map(|t| t * scale);
+                choice.queue_radius = choice.queue_radius.map(|r| r * scale);
+            }
+            self.panic.desired_speed = self.panic.desired_speed.map(|v| v * scale);
+            self.panic.repulsion_range = self.panic.repulsion_range.map(|r| r * scale);
+            self.behavior.repulsion_range = self.behavior.repulsion_range.map(|r| r * scale);
+            for zone in self.kill_zones.iter_mut() {
+                zone.polygon.iter_mut().for_each(|v| *v *= scale);
+            }
+            for sensor in self.sensors.iter_mut() {
+                sensor.polygon.iter_mut().for_each(|v| *v *= scale);
+            }
+            if let Some(region) = self
+                .density_control
+                .as_mut()
+                .and_then(|c| c.region.as_mut())
+            {
+                region.iter_mut().for_each(|v| *v *= scale);
+            }
+            for pedestrian in self.pedestrians.iter_mut() {
+                if let PedestrianSpawnConfig::Once {
+                    region: Some(SpawnRegion::Polygon(vertices)),
+                    ..
+                } = &mut pedestrian.spawn
+                {
+                    vertices.iter_mut().for_each(|v| *v *= scale);
+                }
+            }
+            self.units = Units::M;
+        }
+        self.obstacles
+            .iter_mut()
+            .for_each(ObstacleConfig::tessellate);
+
+        if self.field.auto_size {
+            let max = self.points().fold(Vec2::ZERO, Vec2::max);
+            self.field.size = max + self.field.margin();
+        }
+
+        let size = self.field.size;
+        if let Some(p) = self
+            .points()
+            .find(|p| p.x < 0.0 || p.y < 0.0 || p.x > size.x || p.y > size.y)
+        {
+            warn!(
+                "Point {p} is outside the field of size {size}; objects outside the field are truncated"
+            );
+        }
+    }
+
+    /// Number of levels, each of which has its own field. See [`Scenario::levels`].
+    pub fn level_count(&self) -> usize {
+        self.levels.len().max(1)
+    }
+
+    /// Number of destinations, i.e. waypoints followed by [`Scenario::waypoint_groups`].
+    pub fn destination_count(&self) -> usize {
+        self.waypoints.len() + self.waypoint_groups.len()
+    }
+
+    /// End points of lines of waypoints, obstacles and gates, with vertices of polylines and corners of the bounding
+    /// boxes of circles.
+    fn points(&self) -> impl Iterator<Item = Vec2> + '_ {
+        let waypoints = self.waypoints.iter().flat_map(|wp| wp.line);
+        let obstacles = self
+            .obstacles
+            .iter()
+            .flat_map(|obs| obs.vertices().iter().copied());
+        let circles = self
+            .obstacles
+            .iter()
+            .flat_map(|obs| obs.circle)
+            .flat_map(|c| [c.center - c.radius, c.center + c.radius]);
+        let gates = self.gates.iter().flat_map(|gate| gate.line);
+        waypoints.chain(obstacles).chain(circles).chain(gates)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    #[default]
+    M,
+    Cm,
+    Mm,
+}
+
+impl Units {
+    pub fn to_meters(self) -> f32 {
+        match self {
+            Units::M => 1.0,
+            Units::Cm => 0.01,
+            Units::Mm => 0.001,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct FieldConfig {
+    /// Size of the field. Required unless `auto_size` is enabled.
+    #[serde(default)]
     pub size: Vec2,
+    /// Whether to compute the size from the bounding box of objects.
+    #[serde(default)]
+    pub auto_size: bool,
+    /// Margin added to the bounding box when `auto_size` is enabled. See [`FieldConfig::margin()`] for the default.
+    #[serde(default)]
+    pub margin: Option<f32>,
+    /// Floor plan image whose dark pixels are hard obstacles, applied to the first level in addition to
+    /// [`Scenario::obstacles`]
+    #[serde(default)]
+    pub floor_plan: Option<FloorPlanConfig>,
+}
+
+impl Default for FieldConfig {
+    fn default() -> Self {
+        FieldConfig {
+            size: Vec2::ZERO,
+            auto_size: false,
+            margin: None,
+            floor_plan: None,
+        }
+    }
+}
+
+impl FieldConfig {
+    /// Margin added to the bounding box, which is 1 meter if not given in the scenario
+    pub fn margin(&self) -> f32 {
+        self.margin.unwrap_or(1.0)
+    }
+}
+
+/// Black-and-white floor plan in PNG, whose bottom-left corner is placed at the origin of the field.
+///
+/// The size of the field is not derived from the image, so it must be given unless objects cover the plan.
+///
+/// ```toml
+/// [field]
+/// size = [40, 30]
+/// floor_plan = { path = "plans/office.png", scale = 0.05 }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct FloorPlanConfig {
+    /// Path to the image, relative to the scenario file if loaded by [`Scenario::from_file`]
+    pub path: PathBuf,
+    /// Size of a pixel in the unit of lengths of the scenario
+    pub scale: f32,
+    /// Pixels with luma below it are obstacles, from 0 to 255.
+    #[serde(default = "f_floor_plan_threshold")]
+    pub threshold: u8,
+}
+
+const fn f_floor_plan_threshold() -> u8 {
+    128
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ObstacleConfig {
+    /// End points of a straight obstacle, unless [`ObstacleConfig::polyline`] is given.
+    #[serde(default)]
     pub line: [Vec2; 2],
+    /// Vertices of a wall bending at each of them, used instead of `line` if given. Joints are mitered so that long
+    /// curved walls have no gaps.
+    ///
+    /// ```toml
+    /// [[obstacles]]
+    /// polyline = [[0.0, 0.0], [10.0, 0.0], [12.0, 3.0]]
+    /// width = 0.3
+    /// ```
+    #[serde(default)]
+    pub polyline: Vec<Vec2>,
+    /// Solid circle such as a pillar, used instead of `line` if given, whose `width` is ignored.
+    ///
+    /// ```toml
+    /// [[obstacles]]
+    /// circle = { center = [5.0, 5.0], radius = 0.4 }
+    /// ```
+    #[serde(default)]
+    pub circle: Option<CircleConfig>,
+    /// Curved wall along an arc, used instead of `line` if given.
+    ///
+    /// ```toml
+    /// [[obstacles]]
+    /// arc = { center = [5.0, 5.0], radius = 3.0, angles = [0.0, 90.0] }
+    /// width = 0.3
+    /// ```
+    #[serde(default)]
+    pub arc: Option<ArcConfig>,
     #[serde(default = "f_one")]
     pub width: f32,
+    /// Whether pedestrians can cross the obstacle, e.g. tape barriers or hedges.
+    ///
+    /// Soft obstacles repel pedestrians with a weaker force, which they overcome when pushed toward their
+    /// destinations, and routes through them are as costly as [`SOFT_OBSTACLE_SLOWNESS`] times the distance.
+    #[serde(default)]
+    pub soft: bool,
+    /// Level the obstacle is on. See [`Scenario::levels`].
+    #[serde(default)]
+    pub level: usize,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CircleConfig {
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+/// Arc of a circle from the first angle to the second, counterclockwise if the second is larger.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ArcConfig {
+    pub center: Vec2,
+    pub radius: f32,
+    /// Angles of the ends from the x axis, which must differ (degrees)
+    pub angles: [f32; 2],
 }
 
+/// Largest distance between curved obstacles and the polylines approximating them (meters)
+const CURVE_TOLERANCE: f32 = 0.01;
+
+/// Vertices of a polyline approximating the arc from `angles[0]` to `angles[1]` (radians) within
+/// [`CURVE_TOLERANCE`].
+fn arc_vertices(center: Vec2, radius: f32, [start, end]: [f32; 2]) -> Vec<Vec2> {
+    let step = 2.0 * (1.0 - CURVE_TOLERANCE / radius).max(-1.0).acos();
+    let count = ((end - start).abs() / step).ceil().clamp(1.0, 1024.0) as usize;
+    (0..=count)
+        .map(|i| {
+            center + Vec2::from_angle(start + (end - start) * i as f32 / count as f32) * radius
+        })
+        .collect()
+}
+
+impl ObstacleConfig {
+    /// Polyline along [`ObstacleConfig::circle`] or [`ObstacleConfig::arc`] and its width, if either is given.
+    ///
+    /// A circle becomes a closed polyline at half the radius as wide as the radius, which covers the whole disc.
+    fn curve(&self) -> Option<(Vec<Vec2>, f32)> {
+        if let Some(circle) = self.circle {
+            let mut vertices = arc_vertices(circle.center, circle.radius * 0.5, [0.0, TAU]);
+            vertices.pop();
+            vertices.push(vertices[0]);
+            Some((vertices, circle.radius))
+        } else {
+            self.arc.map(|arc| {
+                let angles = arc.angles.map(f32::to_radians);
+                (arc_vertices(arc.center, arc.radius, angles), self.width)
+            })
+        }
+    }
+
+    /// Replace [`ObstacleConfig::circle`] or [`ObstacleConfig::arc`] with a polyline along it.
+    fn tessellate(&mut self) {
+        if let Some((vertices, width)) = self.curve() {
+            self.polyline = vertices;
+            self.width = width;
+        }
+    }
+
+    /// Vertices along the obstacle, i.e. [`ObstacleConfig::polyline`] if given, or both ends of `line`.
+    pub fn vertices(&self) -> &[Vec2] {
+        if self.polyline.is_empty() {
+            &self.line
+        } else {
+            &self.polyline
+        }
+    }
+
+    /// Straight segments of the obstacle, each of which repels pedestrians as a line with the width.
+    pub fn segments(&self) -> impl Iterator<Item = [Vec2; 2]> + '_ {
+        self.vertices().windows(2).map(|v| [v[0], v[1]])
+    }
+}
+
+/// Cost of walking through soft obstacles relative to free space
+pub const SOFT_OBSTACLE_SLOWNESS: f32 = 10.0;
+
 impl Default for ObstacleConfig {
     fn default() -> Self {
         ObstacleConfig {
             line: Default::default(),
+            polyline: Vec::new(),
+            circle: None,
+            arc: None,
             width: 1.0,
+            soft: false,
+            level: 0,
         }
     }
 }
@@ -40,6 +615,30 @@ pub struct WaypointConfig {
     pub line: [Vec2; 2],
     #[serde(default = "f_one")]
     pub width: f32,
+    /// Time windows `[start, end)` (in seconds) during which the waypoint is closed.
+    #[serde(default)]
+    pub closed: Vec<[f64; 2]>,
+    /// Waypoint to head for while this one is closed. Pedestrians hold their position if not specified.
+    #[serde(default)]
+    pub alternative: Option<usize>,
+    /// Distance from the waypoint area within which pedestrians are regarded as arrived. (meters)
+    /// Defaults to [`SimulatorOptions::arrival_distance`](crate::SimulatorOptions::arrival_distance).
+    #[serde(default)]
+    pub arrival_distance: Option<f32>,
+    /// Side of `line` from which pedestrians arrive, where the right-hand side is that of `(d.y, -d.x)` with
+    /// `d = line[1] - line[0]`, same as [`GateConfig`].
+    ///
+    /// Pedestrians on the other side are not regarded as arrived even within the arrival distance, so that those
+    /// passing behind the waypoint are not absorbed. Both sides count if not specified.
+    #[serde(default)]
+    pub arrival_side: Option<Side>,
+    /// Clear width of the exit which the waypoint is placed at (meters), to check flows through it against
+    /// [`Scenario::exit_capacity`]. See [`crate::exit_capacity`].
+    #[serde(default)]
+    pub exit_width: Option<f32>,
+    /// Level the waypoint is on. See [`Scenario::levels`].
+    #[serde(default)]
+    pub level: usize,
 }
 
 impl Default for WaypointConfig {
@@ -47,22 +646,636 @@ impl Default for WaypointConfig {
         WaypointConfig {
             line: Default::default(),
             width: 1.0,
+            closed: Vec::new(),
+            alternative: None,
+            arrival_distance: None,
+            arrival_side: None,
+            exit_width: None,
+            level: 0,
+        }
+    }
+}
+
+impl WaypointConfig {
+    /// Check if the waypoint is closed at the given time.
+    pub fn is_closed_at(&self, time: f64) -> bool {
+        self.closed
+            .iter()
+            .any(|&[start, end]| start <= time && time < end)
+    }
+}
+
+/// Side of a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Check if `point` is on this side of `line`, or on the line itself.
+    pub fn contains(self, line: [Vec2; 2], point: Vec2) -> bool {
+        let cross = (line[1] - line[0]).perp_dot(point - line[0]);
+        match self {
+            Side::Left => cross >= 0.0,
+            Side::Right => cross <= 0.0,
+        }
+    }
+}
+
+/// One-way gate (or turnstile).
+///
+/// Pedestrians can cross `line` only toward its right-hand side, i.e. the side of
+/// `(d.y, -d.x)` where `d = line[1] - line[0]`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GateConfig {
+    pub line: [Vec2; 2],
+    /// Max number of pedestrians passing per second. Unlimited if not specified.
+    #[serde(default)]
+    pub service_rate: Option<f64>,
+    /// Limit on the number of pedestrians in the region behind the gate, e.g. the capacity of a room.
+    #[serde(default)]
+    pub occupancy_limit: Option<OccupancyLimitConfig>,
+}
+
+/// Control of a gate by the occupancy of a region, which lets pedestrians pass only while the region holds fewer than
+/// `capacity`. Once the region is full, one pedestrian is let in for each one leaving it.
+///
+/// ```toml
+/// [[gates]]
+/// line = [[10.0, 0.0], [10.0, 5.0]]
+/// occupancy_limit = { region = [[10.0, 0.0], [20.0, 0.0], [20.0, 5.0], [10.0, 5.0]], capacity = 50 }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OccupancyLimitConfig {
+    /// Vertices of the region whose occupants are counted
+    pub region: Vec<Vec2>,
+    /// Max number of pedestrians in the region
+    pub capacity: u32,
+}
+
+/// Link between levels, such as stairs or elevators.
+///
+/// Pedestrians within [`WaypointConfig::arrival_distance`] of waypoint `from` are moved onto waypoint `to`, on the
+/// level of `to`, if it brings them closer to their destinations.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkConfig {
+    pub from: usize,
+    pub to: usize,
+    /// Walking distance through the link. (meters)
+    #[serde(default = "f_one")]
+    pub length: f32,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            from: 0,
+            to: 0,
+            length: 1.0,
+        }
+    }
+}
+
+/// Level such as a floor of a building.
+///
+/// Each level has its own field of [`FieldConfig::size`], built from the obstacles and waypoints whose `level` is its
+/// index, so levels share coordinates and may lie on top of each other. Pedestrians are on the level of their origins
+/// until they pass through [`Scenario::links`], and interact only with others on the same level. Gates, sensors, kill
+/// zones and other regions act on pedestrians on every level.
+///
+/// ```toml
+/// levels = [{ name = "ground" }, { name = "first" }]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LevelConfig {
+    /// Label shown in the viewer
+    #[serde(default)]
+    pub name: String,
+}
+
+/// Set of waypoints such as exits, any of which pedestrians heading for the group may walk to.
+///
+/// Its potential is the minimum over the members, so pedestrians head for their nearest member.
+/// Group `i` is referred to as destination `waypoints.len() + i`.
+///
+/// ```toml
+/// [[waypoint_groups]]
+/// members = [1, 2, 3]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WaypointGroupConfig {
+    /// Indices of member waypoints
+    pub members: Vec<usize>,
+    /// Let pedestrians heading for the group choose a member considering congestion, instead of the nearest one.
+    #[serde(default)]
+    pub exit_choice: Option<ExitChoiceConfig>,
+}
+
+/// Adaptive choice among members of a waypoint group, where pedestrians periodically compare
+/// `potential + congestion_weight * queue length` of open members and switch to clearly better ones.
+///
+/// ```toml
+/// [[waypoint_groups]]
+/// members = [1, 2]
+/// exit_choice = { congestion_weight = 1.5, interval = 3.0 }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExitChoiceConfig {
+    /// Cost of a pedestrian queueing at a member, in the same unit as potentials. (meters/person)
+    pub congestion_weight: Option<f32>,
+    /// Interval between comparisons (seconds)
+    pub interval: f64,
+    /// Min improvement of the cost for pedestrians to switch (meters)
+    pub threshold: Option<f32>,
+    /// Distance from a member within which pedestrians are counted as queueing (meters)
+    pub queue_radius: Option<f32>,
+}
+
+impl Default for ExitChoiceConfig {
+    fn default() -> Self {
+        ExitChoiceConfig {
+            congestion_weight: None,
+            interval: 5.0,
+            threshold: None,
+            queue_radius: None,
+        }
+    }
+}
+
+impl ExitChoiceConfig {
+    /// [`ExitChoiceConfig::congestion_weight`], which is 1 m/person if not given in the scenario
+    pub fn congestion_weight(&self) -> f32 {
+        self.congestion_weight.unwrap_or(1.0)
+    }
+
+    /// [`ExitChoiceConfig::threshold`], which is 2 m if not given in the scenario
+    pub fn threshold(&self) -> f32 {
+        self.threshold.unwrap_or(2.0)
+    }
+
+    /// [`ExitChoiceConfig::queue_radius`], which is 3 m if not given in the scenario
+    pub fn queue_radius(&self) -> f32 {
+        self.queue_radius.unwrap_or(3.0)
+    }
+}
+
+/// Controller which scales the rates of periodic spawning to hold the number of pedestrians, or the density in a
+/// region, at a setpoint, e.g. for measuring fundamental diagrams in the steady state.
+///
+/// ```toml
+/// [density_control]
+/// setpoint = 1.5
+/// region = [[10.0, 0.0], [20.0, 0.0], [20.0, 5.0], [10.0, 5.0]]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct DensityControlConfig {
+    /// Target number of pedestrians in the field, or density in `region` (persons/m^2) if it is given
+    pub setpoint: f64,
+    /// Vertices of the region where the density is measured
+    #[serde(default)]
+    pub region: Option<Vec<Vec2>>,
+    /// Proportional gain, by which the relative error is added to the scale of spawn rates
+    #[serde(default = "f_gain")]
+    pub gain: f64,
+    /// Integral time of the controller (seconds)
+    #[serde(default = "f_integral_time")]
+    pub integral_time: f64,
+    /// Max scale of spawn rates
+    #[serde(default = "f_max_scale")]
+    pub max_scale: f64,
+}
+
+impl Default for DensityControlConfig {
+    fn default() -> Self {
+        DensityControlConfig {
+            setpoint: 0.0,
+            region: None,
+            gain: f_gain(),
+            integral_time: f_integral_time(),
+            max_scale: f_max_scale(),
+        }
+    }
+}
+
+const fn f_gain() -> f64 {
+    1.0
+}
+
+const fn f_integral_time() -> f64 {
+    10.0
+}
+
+const fn f_max_scale() -> f64 {
+    5.0
+}
+
+/// Region which removes pedestrians entering it, modeling hazards or vehicles.
+///
+/// ```toml
+/// [[kill_zones]]
+/// polygon = [[10.0, 0.0], [12.0, 0.0], [12.0, 5.0], [10.0, 5.0]]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KillZoneConfig {
+    /// Vertices of the region
+    pub polygon: Vec<Vec2>,
+}
+
+/// Capacity of exits required by a design code, whose defaults are the values of SFPE for doors.
+///
+/// ```toml
+/// [exit_capacity]
+/// max_specific_flow = 1.3
+/// boundary_layer = 0.15
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExitCapacityConfig {
+    /// Max flow per effective width of exits (persons/m/s)
+    pub max_specific_flow: f64,
+    /// Width along each side of exits excluded from the effective width. See
+    /// [`ExitCapacityConfig::boundary_layer()`] for the default.
+    pub boundary_layer: Option<f32>,
+    /// Length of the window over which the peak flow is measured (seconds)
+    pub window: f64,
+}
+
+impl Default for ExitCapacityConfig {
+    fn default() -> Self {
+        ExitCapacityConfig {
+            max_specific_flow: 1.3,
+            boundary_layer: None,
+            window: 10.0,
+        }
+    }
+}
+
+impl ExitCapacityConfig {
+    /// Width along each side of exits excluded from the effective width, which is 0.15 m if not given in the scenario
+    /// (meters)
+    pub fn boundary_layer(&self) -> f32 {
+        self.boundary_layer.unwrap_or(0.15)
+    }
+}
+
+/// Area where pedestrians heading for it wait until released by the timeline, e.g. a platform where passengers
+/// wait for a train.
+///
+/// Pedestrians walk to the waypoint as usual, but stop on arrival instead of leaving the field, and stand moved only
+/// by other pedestrians and obstacles. [`TimelineAction::ReleaseHoldingArea`] sends the waiting ones on to the
+/// destination of the area, through which pedestrians arriving later wait for the next release.
+///
+/// ```toml
+/// [[holding_areas]]
+/// waypoint = 2
+/// destination = 3
+///
+/// [[timeline]]
+/// time = 180.0
+/// action = "release_holding_area"
+/// area = 0
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HoldingAreaConfig {
+    /// Destination where arriving pedestrians wait instead of leaving the field, i.e. a waypoint or a waypoint group
+    pub waypoint: usize,
+    /// Destination of pedestrians released from the area
+    pub destination: usize,
+}
+
+/// Virtual counting camera which reports noisy numbers of pedestrians in a region. See [`crate::sensor`].
+///
+/// ```toml
+/// [[sensors]]
+/// polygon = [[10.0, 0.0], [12.0, 0.0], [12.0, 5.0], [10.0, 5.0]]
+/// interval = 5.0
+/// detection_rate = 0.9
+/// noise = 1.0
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SensorConfig {
+    /// Vertices of the region covered by the sensor
+    pub polygon: Vec<Vec2>,
+    /// Interval between readings (seconds)
+    pub interval: f64,
+    /// Probability that each pedestrian in the region is detected
+    pub detection_rate: f64,
+    /// Standard deviation of the Gaussian noise added to counts (persons)
+    pub noise: f32,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        SensorConfig {
+            polygon: Vec::new(),
+            interval: 1.0,
+            detection_rate: 1.0,
+            noise: 0.0,
         }
     }
 }
 
+/// Action executed at a specified time during the simulation.
+///
+/// ```toml
+/// [[timeline]]
+/// time = 120.0
+/// action = "close_waypoint"
+/// waypoint = 3
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimelineEntry {
+    /// Time to execute the action. (seconds)
+    pub time: f64,
+    #[serde(flatten)]
+    pub action: TimelineAction,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TimelineAction {
+    /// Close the waypoint until it is opened again.
+    CloseWaypoint { waypoint: usize },
+    /// Open the waypoint, ending time windows in [`WaypointConfig::closed`] which contain the time.
+    OpenWaypoint { waypoint: usize },
+    /// Change the spawn rate of the pedestrian group (persons/s). Groups spawned once become periodic.
+    ///
+    /// This was `set_spawn_frequency` with `frequency` before version 2.
+    SetSpawnRate { group: usize, rate: f64 },
+    /// Put the pedestrian group, or all groups if not specified, in panic.
+    StartPanic {
+        #[serde(default)]
+        group: Option<usize>,
+    },
+    /// Send pedestrians of the group, or all groups if not specified, to the destination.
+    ///
+    /// This applies to pedestrians already in the field as well as ones spawned later.
+    SetDestination {
+        #[serde(default)]
+        group: Option<usize>,
+        destination: usize,
+    },
+    /// Send pedestrians waiting in the holding area to its destination. Ones arriving later wait for the next release.
+    ReleaseHoldingArea { area: usize },
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct PedestrianConfig {
     pub origin: usize,
-    pub destination: usize,
+    /// Destination of all pedestrians of the group, which is required unless [`PedestrianConfig::destinations`] is
+    /// given.
+    #[serde(default)]
+    pub destination: Option<usize>,
+    /// Destinations chosen at random for each pedestrian in proportion to their weights.
+    ///
+    /// ```toml
+    /// destinations = [{ id = 1, weight = 0.7 }, { id = 2, weight = 0.3 }]
+    /// ```
+    #[serde(default)]
+    pub destinations: Vec<DestinationChoice>,
     pub spawn: PedestrianSpawnConfig,
+    /// Whether pedestrians of the group are in panic. See [`PanicConfig`].
+    #[serde(default)]
+    pub panic: bool,
+    /// Pedestrians of the group who do not know the nearest exit. See [`FamiliarityConfig`].
+    #[serde(default)]
+    pub familiarity: Option<FamiliarityConfig>,
+    /// Multiplier of the range of repulsion which others feel toward pedestrians of the group, e.g. to model
+    /// stretcher teams or service vehicles which crowds make way for.
+    #[serde(default = "f_one")]
+    pub personal_space: f32,
+}
+
+impl Default for PedestrianConfig {
+    fn default() -> Self {
+        PedestrianConfig {
+            origin: 0,
+            destination: None,
+            destinations: Vec::new(),
+            spawn: PedestrianSpawnConfig::Once {
+                count: 0,
+                region: None,
+            },
+            panic: false,
+            familiarity: None,
+            personal_space: 1.0,
+        }
+    }
+}
+
+impl PedestrianConfig {
+    /// Choose the destination of a new pedestrian. Groups without any destination are rejected by
+    /// [`Scenario::validate`].
+    pub fn sample_destination(&self, rng: &mut dyn Rng) -> usize {
+        let total: f64 = self.destinations.iter().map(|d| d.weight.max(0.0)).sum();
+        if total <= 0.0 {
+            return self.destination.unwrap_or_default();
+        }
+
+        let mut r = rng.f64() * total;
+        for choice in &self.destinations {
+            let weight = choice.weight.max(0.0);
+            if r < weight {
+                return choice.id;
+            }
+            r -= weight;
+        }
+        // Reached only by rounding errors.
+        self.destinations.last().unwrap().id
+    }
+
+    /// Destinations of pedestrians of the group and their expected shares, which sum up to 1.
+    pub fn destination_shares(&self) -> Vec<(usize, f64)> {
+        let total: f64 = self.destinations.iter().map(|d| d.weight.max(0.0)).sum();
+        if total <= 0.0 {
+            return self.destination.map(|d| (d, 1.0)).into_iter().collect();
+        }
+
+        self.destinations
+            .iter()
+            .filter(|d| d.weight > 0.0)
+            .map(|d| (d.id, d.weight / total))
+            .collect()
+    }
+}
+
+/// Demand between origins and destinations, as specified by transport planners.
+///
+/// ```toml
+/// [od_matrix]
+/// origins = [0, 1]
+/// destinations = [2, 3]
+/// flow_unit = "per_hour"
+/// flows = [
+///     [1800.0, 600.0],
+///     [0.0, 1200.0],
+/// ]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct OdMatrixConfig {
+    /// Waypoints where pedestrians enter, corresponding to rows
+    pub origins: Vec<usize>,
+    /// Destinations, corresponding to columns
+    pub destinations: Vec<usize>,
+    /// Flow of pedestrians from each origin to each destination
+    pub flows: Vec<Vec<f64>>,
+    #[serde(default)]
+    pub flow_unit: FlowUnit,
+}
+
+/// Unit of flows in [`OdMatrixConfig`].
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowUnit {
+    /// Persons per second
+    #[default]
+    PerSecond,
+    /// Persons per hour
+    PerHour,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DestinationChoice {
+    /// Index of the destination, i.e. a waypoint or a waypoint group
+    pub id: usize,
+    /// Relative weight, which need not sum up to 1
+    pub weight: f64,
+}
+
+/// Parameters of the normal behavior of pedestrians, which can be fitted to observed flows with
+/// [`crate::calibration`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BehaviorConfig {
+    /// Range of the repulsive potential from other pedestrians. See [`BehaviorConfig::repulsion_range()`] for the
+    /// default.
+    pub repulsion_range: Option<f32>,
+    /// Angle of sight around the desired direction (degrees)
+    pub field_of_view: f32,
+    /// Factor scaling repulsion from pedestrians out of sight, who affect less than ones in sight
+    pub out_of_sight_factor: f32,
+}
+
+impl Default for BehaviorConfig {
+    fn default() -> Self {
+        BehaviorConfig {
+            repulsion_range: None,
+            field_of_view: 200.0,
+            out_of_sight_factor: 0.5,
+        }
+    }
+}
+
+impl BehaviorConfig {
+    /// Range of the repulsive potential from other pedestrians, which is 0.3 m if not given in the scenario (meters)
+    pub fn repulsion_range(&self) -> f32 {
+        self.repulsion_range.unwrap_or(0.3)
+    }
+
+    /// Cosine of half the field of view, below which the cosine of the angle to another pedestrian is out of sight
+    pub fn cos_half_field_of_view(&self) -> f32 {
+        (self.field_of_view * 0.5).to_radians().cos()
+    }
+}
+
+/// Behavior of pedestrians in panic, following the escape panic model of Helbing et al. (2000).
+///
+/// Panicking pedestrians walk faster, keep shorter distances from others and push each other on contact,
+/// which reproduces arching and clogging at narrow exits. Panic is not supported on the GPU backend, which rejects
+/// scenarios with panicking groups or `start_panic` in the timeline.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PanicConfig {
+    /// Desired speed of panicking pedestrians, given in the unit of lengths per second and converted into m/s like
+    /// lengths. See [`PanicConfig::desired_speed()`] for the default.
+    pub desired_speed: Option<f32>,
+    /// Range of the repulsive potential from other pedestrians, which is [`BehaviorConfig::repulsion_range`] normally.
+    /// See [`PanicConfig::repulsion_range()`] for the default.
+    pub repulsion_range: Option<f32>,
+    /// Body force per unit overlap of bodies and unit mass (s^-2). Too large values make the integration unstable.
+    pub body_force: f32,
+}
+
+impl Default for PanicConfig {
+    fn default() -> Self {
+        PanicConfig {
+            desired_speed: None,
+            repulsion_range: None,
+            body_force: 100.0,
+        }
+    }
+}
+
+impl PanicConfig {
+    /// Desired speed of panicking pedestrians, which is 3 m/s if not given in the scenario (m/s)
+    pub fn desired_speed(&self) -> f32 {
+        self.desired_speed.unwrap_or(3.0)
+    }
+
+    /// Range of the repulsive potential in panic, which is 0.15 m if not given in the scenario (meters)
+    pub fn repulsion_range(&self) -> f32 {
+        self.repulsion_range.unwrap_or(0.15)
+    }
+}
+
+/// Share of a pedestrian group unfamiliar with the place, who do not know the nearest exit as in many evacuations.
+///
+/// Unfamiliar pedestrians head for the familiar exit, e.g. the entrance they came in, if it is given. They are also
+/// drawn toward the mean walking direction of their neighbors by [`FamiliarityConfig::herding`], following
+/// Helbing et al. (2000). The GPU backend does not implement herding and refuses scenarios with it.
+///
+/// ```toml
+/// familiarity = { unfamiliar_ratio = 0.3, familiar_exit = 0, herding = 0.4 }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FamiliarityConfig {
+    /// Probability that a generated pedestrian is unfamiliar
+    pub unfamiliar_ratio: f64,
+    /// Waypoint or waypoint group which unfamiliar pedestrians head for instead of their destinations
+    pub familiar_exit: Option<usize>,
+    /// Weight of the mean direction of neighbors in the desired direction of unfamiliar pedestrians, from 0 to 1
+    pub herding: f32,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum PedestrianSpawnConfig {
-    Periodic { frequency: f64 },
-    Once { count: i32 },
+    Periodic {
+        /// Mean number of pedestrians spawned per second, which was `frequency` before version 2
+        rate: f64,
+    },
+    Once {
+        count: i32,
+        /// Region over which pedestrians are scattered at the beginning, instead of entering from the origin.
+        #[serde(default)]
+        region: Option<SpawnRegion>,
+    },
+}
+
+/// Region over which pedestrians are scattered uniformly, avoiding obstacles.
+///
+/// ```toml
+/// spawn = { kind = "once", count = 200, region = "field" }
+/// spawn = { kind = "once", count = 50, region = [[1.0, 1.0], [9.0, 1.0], [9.0, 4.0], [1.0, 4.0]] }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SpawnRegion {
+    /// Whole walkable area of the field (`"field"`)
+    Field(FieldRegion),
+    /// Polygon given by its vertices
+    Polygon(Vec<Vec2>),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldRegion {
+    Field,
 }
 
 #[derive(Debug, Default, Clone, Deserialize)]
@@ -72,3 +1285,252 @@ pub enum PedestrianSpawnKind {
     Periodic,
     Once,
 }
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+    use glam::vec2;
+
+    use super::{
+        BehaviorConfig, DestinationChoice, PedestrianConfig, PedestrianSpawnConfig, Scenario,
+        SpawnRegion,
+    };
+
+    #[test]
+    fn test_normalize() {
+        let scenario = Scenario::from_toml(
+            r#"
+            units = "cm"
+            field = { auto_size = true, margin = 50.0 }
+            panic = { desired_speed = 400.0 }
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 10, region = [[100.0, 0.0], [200.0, 0.0], [200.0, 100.0]] }
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 10, region = "field" }
+
+            [[waypoints]]
+            line = [[100.0, 100.0], [100.0, 300.0]]
+
+            [[obstacles]]
+            line = [[0.0, 0.0], [800.0, 0.0]]
+            width = 20.0
+            "#,
+        )
+        .unwrap();
+
+        assert!(scenario.field.size.abs_diff_eq(vec2(8.5, 3.5), 1e-5));
+        assert!(scenario.waypoints[0].line[1].abs_diff_eq(vec2(1.0, 3.0), 1e-5));
+        assert_float_absolute_eq!(scenario.obstacles[0].width, 0.2);
+        assert_float_absolute_eq!(scenario.panic.desired_speed(), 4.0);
+
+        let PedestrianSpawnConfig::Once {
+            region: Some(SpawnRegion::Polygon(vertices)),
+            ..
+        } = &scenario.pedestrians[0].spawn
+        else {
+            panic!("polygon region is not parsed");
+        };
+        assert!(vertices[2].abs_diff_eq(vec2(2.0, 1.0), 1e-5));
+        assert!(matches!(
+            scenario.pedestrians[1].spawn,
+            PedestrianSpawnConfig::Once {
+                region: Some(SpawnRegion::Field(_)),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_normalize_defaults() {
+        // Defaults are given in meters and must not be converted as lengths in the unit of the scenario.
+        let scenario = Scenario::from_toml(
+            r#"
+            units = "cm"
+            field = { auto_size = true }
+
+            [[waypoints]]
+            line = [[100.0, 100.0], [100.0, 300.0]]
+
+            [[waypoints]]
+            line = [[300.0, 100.0], [300.0, 300.0]]
+
+            [[waypoint_groups]]
+            members = [0, 1]
+            exit_choice = { interval = 3.0 }
+
+            [[obstacles]]
+            line = [[0.0, 0.0], [800.0, 0.0]]
+            "#,
+        )
+        .unwrap();
+
+        assert_float_absolute_eq!(scenario.field.margin(), 1.0);
+        assert_float_absolute_eq!(scenario.behavior.repulsion_range(), 0.3);
+        assert_float_absolute_eq!(scenario.exit_capacity.boundary_layer(), 0.15);
+        assert_float_absolute_eq!(scenario.panic.desired_speed(), 3.0);
+        assert_float_absolute_eq!(scenario.panic.repulsion_range(), 0.15);
+        let choice = scenario.waypoint_groups[0].exit_choice.as_ref().unwrap();
+        assert_float_absolute_eq!(choice.congestion_weight(), 1.0);
+        assert_float_absolute_eq!(choice.threshold(), 2.0);
+        assert_float_absolute_eq!(choice.queue_radius(), 3.0);
+        assert!(scenario.field.size.abs_diff_eq(vec2(9.0, 4.0), 1e-5));
+    }
+
+    #[test]
+    fn test_sample_destination() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = [
+                { line = [[1.0, 1.0], [1.0, 9.0]] },
+                { line = [[9.0, 1.0], [9.0, 9.0]] },
+                { line = [[2.0, 1.0], [8.0, 1.0]] },
+                { line = [[2.0, 9.0], [8.0, 9.0]] },
+            ]
+            obstacles = []
+
+            [[pedestrians]]
+            origin = 0
+            destinations = [{ id = 1, weight = 0.7 }, { id = 2, weight = 0.3 }]
+            spawn = { kind = "periodic", rate = 1.0 }
+            "#,
+        )
+        .unwrap();
+
+        let mut rng = fastrand::Rng::with_seed(1);
+        let config = &scenario.pedestrians[0];
+        let count = (0..10000)
+            .filter(|_| config.sample_destination(&mut rng) == 1)
+            .count();
+        assert!((6800..7200).contains(&count), "{count}");
+
+        // Without weights, the fixed destination is used.
+        let config = PedestrianConfig {
+            destination: Some(3),
+            destinations: vec![DestinationChoice { id: 1, weight: 0.0 }],
+            ..config.clone()
+        };
+        assert_eq!(config.sample_destination(&mut rng), 3);
+
+        // Either of them is required.
+        let text = r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = [
+                { line = [[1.0, 1.0], [1.0, 9.0]] },
+                { line = [[9.0, 1.0], [9.0, 9.0]] },
+                { line = [[2.0, 1.0], [8.0, 1.0]] },
+                { line = [[2.0, 9.0], [8.0, 9.0]] },
+            ]
+            obstacles = []
+
+            [[pedestrians]]
+            origin = 0
+            spawn = { kind = "periodic", rate = 1.0 }
+            "#;
+        assert!(Scenario::from_toml(text).is_err());
+    }
+
+    #[test]
+    fn test_od_matrix() {
+        let text = r#"
+            field = { size = [10.0, 10.0] }
+            waypoints = [
+                { line = [[1.0, 1.0], [1.0, 9.0]] },
+                { line = [[9.0, 1.0], [9.0, 9.0]] },
+                { line = [[2.0, 1.0], [8.0, 1.0]] },
+                { line = [[2.0, 9.0], [8.0, 9.0]] },
+            ]
+            obstacles = []
+
+            [od_matrix]
+            origins = [0, 1]
+            destinations = [2, 3]
+            flow_unit = "per_hour"
+            flows = [[1800.0, 600.0], [0.0, 0.0]]
+            "#;
+        let scenario = Scenario::from_toml(text).unwrap();
+
+        // Origins without demand are skipped.
+        assert_eq!(scenario.pedestrians.len(), 1);
+        let config = &scenario.pedestrians[0];
+        assert_eq!(config.origin, 0);
+        let PedestrianSpawnConfig::Periodic { rate } = config.spawn else {
+            panic!("pedestrians are not spawned periodically");
+        };
+        assert_float_absolute_eq!(rate, 2400.0 / 3600.0);
+        assert_eq!(config.destinations.len(), 2);
+        assert_eq!(config.destinations[1].id, 3);
+
+        let text = text.replace("[0.0, 0.0]", "[0.0]");
+        assert!(Scenario::from_toml(&text).is_err());
+    }
+
+    #[test]
+    fn test_levels() {
+        let text = r#"
+            field = { size = [20.0, 10.0] }
+            levels = [{ name = "ground" }, { name = "first" }]
+            waypoints = [{ line = [[1.0, 1.0], [1.0, 3.0]] }, { line = [[1.0, 1.0], [1.0, 3.0]], level = 1 }]
+            obstacles = [{ line = [[5.0, 0.0], [5.0, 5.0]], level = 1 }]
+            links = [{ from = 1, to = 0 }]
+            "#;
+        assert!(Scenario::from_toml(text).is_ok());
+        // Objects on levels which are not declared, and links to missing waypoints, are rejected.
+        assert!(Scenario::from_toml(&text.replace("level = 1 }]", "level = 2 }]")).is_err());
+        assert!(Scenario::from_toml(&text.replace("levels = ", "# ")).is_err());
+        assert!(Scenario::from_toml(&text.replace("to = 0", "to = 2")).is_err());
+    }
+
+    #[test]
+    fn test_indices() {
+        let text = r#"
+            field = { size = [20.0, 10.0] }
+            obstacles = []
+            waypoints = [{ line = [[1.0, 1.0], [1.0, 9.0]] }, { line = [[19.0, 1.0], [19.0, 9.0]], alternative = 0 }]
+            waypoint_groups = [{ members = [0, 1] }]
+            holding_areas = [{ waypoint = 2, destination = 1 }]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 2
+            destinations = [{ id = 1, weight = 1.0 }]
+            familiarity = { familiar_exit = 1 }
+            spawn = { kind = "once", count = 10 }
+            "#;
+        assert!(Scenario::from_toml(text).is_ok());
+        // Missing waypoints and destinations are rejected rather than panicking in the simulation.
+        for (from, to) in [
+            ("alternative = 0", "alternative = 2"),
+            ("members = [0, 1]", "members = [0, 2]"),
+            ("waypoint = 2,", "waypoint = 3,"),
+            ("destination = 1 }", "destination = 3 }"),
+            ("origin = 0", "origin = 2"),
+            ("destination = 2\n", "destination = 3\n"),
+            ("id = 1", "id = 3"),
+            ("familiar_exit = 1", "familiar_exit = 3"),
+        ] {
+            assert!(text.contains(from));
+            assert!(
+                Scenario::from_toml(&text.replace(from, to)).is_err(),
+                "{to}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_field_of_view() {
+        // The default is the angle of sight of 200 degrees by Helbing and Molnar (1995).
+        let behavior = BehaviorConfig::default();
+        assert_float_absolute_eq!(behavior.cos_half_field_of_view(), -0.173648, 1e-6);
+
+        let behavior: BehaviorConfig = toml::from_str("field_of_view = 180.0").unwrap();
+        assert_float_absolute_eq!(behavior.cos_half_field_of_view(), 0.0, 1e-6);
+        assert_float_absolute_eq!(behavior.out_of_sight_factor, 0.5);
+    }
+}
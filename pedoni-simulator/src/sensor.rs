@@ -0,0 +1,202 @@
+use std::io::{self, Write};
+
+use geo::Polygon;
+
+use crate::{
+    models::PedestrianModel,
+    rng::Rng,
+    scenario::{Scenario, SensorConfig},
+    util, Simulator,
+};
+
+/// Reading of a sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorReading {
+    /// Index of the sensor in [`Scenario::sensors`]
+    pub sensor: usize,
+    pub step: i32,
+    /// Time of the reading (seconds)
+    pub time: f64,
+    /// Actual number of pedestrians in the region
+    pub true_count: u32,
+    /// Number reported by the sensor, with missed detections and noise
+    pub count: u32,
+}
+
+/// Virtual counting cameras given by [`Scenario::sensors`], which report noisy numbers of pedestrians in their regions
+/// for prototyping crowd monitoring against the ground truth of the simulator.
+#[derive(Debug, Default, Clone)]
+pub struct Sensors {
+    regions: Vec<Polygon<f32>>,
+    /// Time of the next reading of each sensor (seconds)
+    next_times: Vec<f64>,
+    /// Readings taken in the last step
+    pub readings: Vec<SensorReading>,
+}
+
+impl Sensors {
+    pub fn new(scenario: &Scenario) -> Self {
+        Sensors {
+            regions: scenario
+                .sensors
+                .iter()
+                .map(|sensor| util::polygon(&sensor.polygon))
+                .collect(),
+            next_times: scenario
+                .sensors
+                .iter()
+                .map(|sensor| sensor.interval)
+                .collect(),
+            readings: Vec::new(),
+        }
+    }
+
+    /// Take readings of sensors whose intervals have elapsed by `time`.
+    pub fn update(
+        &mut self,
+        scenario: &Scenario,
+        model: &dyn PedestrianModel,
+        step: i32,
+        time: f64,
+        rng: &mut dyn Rng,
+    ) {
+        self.readings.clear();
+        // Small margin against rounding errors of the accumulated time
+        let due: Vec<usize> = (0..self.regions.len())
+            .filter(|&i| self.next_times[i] <= time + 1e-6)
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+
+        let mut true_counts = vec![0; due.len()];
+        model.for_each_pedestrian(&mut |p| {
+            for (count, &i) in true_counts.iter_mut().zip(&due) {
+                if util::polygon_contains(&self.regions[i], p.pos) {
+                    *count += 1;
+                }
+            }
+        });
+
+        for (&i, true_count) in due.iter().zip(true_counts) {
+            let config = &scenario.sensors[i];
+            self.readings.push(SensorReading {
+                sensor: i,
+                step,
+                time,
+                true_count,
+                count: observe(config, true_count, rng),
+            });
+            self.next_times[i] += config.interval.max(0.1);
+        }
+    }
+}
+
+/// Count pedestrians as the sensor sees them, missing each with [`SensorConfig::detection_rate`] and adding noise.
+fn observe(config: &SensorConfig, true_count: u32, rng: &mut dyn Rng) -> u32 {
+    let detected = if config.detection_rate >= 1.0 {
+        true_count
+    } else {
+        (0..true_count)
+            .filter(|_| rng.f64() < config.detection_rate)
+            .count() as u32
+    };
+    if config.noise <= 0.0 {
+        return detected;
+    }
+    rng.f32_normal(detected as f32, config.noise)
+        .round()
+        .max(0.0) as u32
+}
+
+/// Writer of readings of sensors as CSV, kept separately from the logs of the simulator.
+pub struct SensorLog<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> SensorLog<W> {
+    /// Write the header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "step,time,sensor,count,true_count")?;
+        Ok(SensorLog { writer })
+    }
+
+    /// Write the readings taken in the current step, flushing them since runs often end by interruption.
+    pub fn record(&mut self, simulator: &Simulator) -> io::Result<()> {
+        let readings = &simulator.sensors.readings;
+        if readings.is_empty() || simulator.is_warming_up() {
+            return Ok(());
+        }
+        for r in readings {
+            writeln!(
+                self.writer,
+                "{},{:.1},{},{},{}",
+                r.step, r.time, r.sensor, r.count, r.true_count
+            )?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{scenario::Scenario, Simulator, SimulatorOptions};
+
+    use super::SensorLog;
+
+    #[test]
+    fn test_sensors() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 20, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+
+            [[sensors]]
+            polygon = [[0.0, 0.0], [4.0, 0.0], [4.0, 10.0], [0.0, 10.0]]
+            interval = 0.5
+
+            [[sensors]]
+            polygon = [[0.0, 0.0], [4.0, 0.0], [4.0, 10.0], [0.0, 10.0]]
+            interval = 0.5
+            detection_rate = 0.5
+            noise = 1.0
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        let mut log = SensorLog::new(Vec::new()).unwrap();
+
+        let mut readings = Vec::new();
+        for _ in 0..10 {
+            simulator.tick().unwrap();
+            log.record(&simulator).unwrap();
+            readings.extend(simulator.sensors.readings.iter().copied());
+        }
+
+        // Each sensor reads every 5 steps.
+        assert_eq!(readings.len(), 4);
+        assert_eq!(readings[0].step, 5);
+        assert_eq!(readings[0].sensor, 0);
+        assert_eq!(readings[0].count, 20);
+        assert_eq!(readings[0].true_count, 20);
+        // The noisy sensor misses some pedestrians.
+        assert_eq!(readings[1].true_count, 20);
+        assert!(readings[1].count < 20, "{}", readings[1].count);
+
+        let csv = String::from_utf8(log.writer).unwrap();
+        assert_eq!(csv.lines().count(), 5);
+        assert!(csv.lines().nth(1).unwrap().starts_with("5,0.5,0,20,20"));
+    }
+}
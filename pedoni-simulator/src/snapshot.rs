@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+
+/// Snapshot of simulation states shared between the simulation thread and readers such as the renderer.
+///
+/// Readers get the latest snapshot without copying or blocking the writer.
+/// The writer is double-buffered: the buffer of the previous snapshot is reused once all readers release it.
+#[derive(Debug, Default)]
+pub struct Snapshot<T> {
+    current: ArcSwap<Vec<T>>,
+    spare: Mutex<Option<Arc<Vec<T>>>>,
+}
+
+impl<T> Snapshot<T> {
+    /// Get the latest snapshot.
+    pub fn load(&self) -> Arc<Vec<T>> {
+        self.current.load_full()
+    }
+
+    /// Get the latest snapshot together with the previous one, e.g. to interpolate between them.
+    ///
+    /// The buffer of the previous snapshot is not reused while it is held. The previous snapshot is `None` while the
+    /// writer fills its buffer for the next one.
+    pub fn load_with_previous(&self) -> (Arc<Vec<T>>, Option<Arc<Vec<T>>>) {
+        let spare = self.spare.lock().unwrap();
+        (self.current.load_full(), spare.clone())
+    }
+
+    /// Replace the snapshot with items pushed by `fill` into a cleared buffer.
+    ///
+    /// The lock shared with [`Snapshot::load_with_previous`] is not held during `fill`, so readers are not blocked.
+    pub fn publish(&self, fill: impl FnOnce(&mut Vec<T>)) {
+        let mut buffer = {
+            let mut spare = self.spare.lock().unwrap();
+            match spare.take().map(Arc::try_unwrap) {
+                Some(Ok(buffer)) => buffer,
+                // Still held by a reader, so keep it as the previous snapshot and fill a new buffer.
+                Some(Err(held)) => {
+                    *spare = Some(held);
+                    Vec::new()
+                }
+                None => Vec::new(),
+            }
+        };
+        buffer.clear();
+        fill(&mut buffer);
+
+        let mut spare = self.spare.lock().unwrap();
+        *spare = Some(self.current.swap(Arc::new(buffer)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshot;
+
+    #[test]
+    fn test_snapshot() {
+        let snapshot = Snapshot::default();
+        snapshot.publish(|buffer| buffer.extend([1, 2, 3]));
+
+        let reader = snapshot.load();
+        snapshot.publish(|buffer| buffer.push(4));
+        assert_eq!(*reader, vec![1, 2, 3]);
+        assert_eq!(*snapshot.load(), vec![4]);
+
+        // The buffer held by the reader is not reused.
+        snapshot.publish(|buffer| buffer.push(5));
+        assert_eq!(*reader, vec![1, 2, 3]);
+        assert_eq!(*snapshot.load(), vec![5]);
+
+        let (current, previous) = snapshot.load_with_previous();
+        assert_eq!(*current, vec![5]);
+        assert_eq!(*previous.unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn test_load_while_publishing() {
+        let snapshot = Snapshot::default();
+        snapshot.publish(|buffer| buffer.push(1));
+        snapshot.publish(|buffer| buffer.push(2));
+
+        // Readers are not blocked while the writer fills the spare buffer.
+        snapshot.publish(|buffer| {
+            let (current, previous) = snapshot.load_with_previous();
+            assert_eq!(*current, vec![2]);
+            assert!(previous.is_none());
+            buffer.push(3);
+        });
+        let (current, previous) = snapshot.load_with_previous();
+        assert_eq!(*current, vec![3]);
+        assert_eq!(*previous.unwrap(), vec![2]);
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::VecDeque;
+
+use glam::{vec2, Vec2};
+use log::warn;
+
+use crate::{
+    field::Field,
+    models::Pedestrian,
+    rng::{Rng, RngStreams, Stream},
+    routing::Routing,
+    scenario::{PedestrianConfig, PedestrianSpawnConfig, Scenario, SpawnRegion},
+    util::{self, Index},
+    SimulatorOptions,
+};
+
+/// Depth of the area around origins where density is measured. (meters)
+const ORIGIN_AREA_DEPTH: f32 = 1.0;
+/// Max number of samples tried for each pedestrian scattered over a region
+const MAX_SCATTER_TRIALS: usize = 100;
+/// Max number of samples tried for each pedestrian spawned on an origin line
+const MAX_LINE_TRIALS: usize = 20;
+/// Min distance of spawn positions on origin lines from obstacles (meters)
+const SPAWN_CLEARANCE: f32 = 0.2;
+
+/// Spawner of pedestrians, which holds back pedestrians waiting to enter the field.
+#[derive(Debug, Default, Clone)]
+pub struct Spawner {
+    /// Pedestrians waiting to be spawned at each origin
+    queues: Vec<VecDeque<Pedestrian>>,
+    /// Pedestrians scattered over regions, which enter the field without waiting
+    scattered: Vec<Pedestrian>,
+    /// ID assigned to the next generated pedestrian
+    next_id: usize,
+    /// Number of pedestrians not spawned since the last [`Spawner::take_unplaced_count`], for lack of positions
+    /// clear of obstacles on their origins
+    unplaced: i32,
+    /// Number of such pedestrians so far
+    unplaced_total: usize,
+}
+
+impl Spawner {
+    pub fn new(scenario: &Scenario) -> Self {
+        Spawner {
+            queues: vec![VecDeque::new(); scenario.waypoints.len()],
+            scattered: Vec::new(),
+            next_id: 0,
+            unplaced: 0,
+            unplaced_total: 0,
+        }
+    }
+
+    fn generate(
+        &mut self,
+        group: usize,
+        config: &PedestrianConfig,
+        pos: Vec2,
+        level: usize,
+        rng: &mut RngStreams,
+    ) -> Pedestrian {
+        let mut destination = config.sample_destination(rng.get(Stream::Destination));
+        let unfamiliar = config
+            .familiarity
+            .as_ref()
+            .is_some_and(|f| rng.get(Stream::Familiarity).f64() < f.unfamiliar_ratio);
+        if unfamiliar {
+            if let Some(exit) = config.familiarity.as_ref().and_then(|f| f.familiar_exit) {
+                destination = exit;
+            }
+        }
+
+        let pedestrian = Pedestrian {
+            id: self.next_id,
+            group,
+            pos,
+            level,
+            destination,
+            unfamiliar,
+            personal_space: config.personal_space,
+            desired_speed: rng.get(Stream::DesiredSpeed).f32_normal(1.34, 0.26),
+            ..Default::default()
+        };
+        self.next_id += 1;
+        pedestrian
+    }
+
+    /// Queue a pedestrian at a position on its origin line clear of obstacles. The pedestrian is dropped and
+    /// counted as unplaced if no such position is found.
+    ///
+    /// `spacing` is the min distance from pedestrians queued at the origin from the index on, if any. It is kept as
+    /// far as possible when the line is too crowded.
+    fn push(
+        &mut self,
+        group: usize,
+        config: &PedestrianConfig,
+        scenario: &Scenario,
+        fields: &[Field],
+        spacing: Option<(f32, usize)>,
+        rng: &mut RngStreams,
+    ) -> Option<&mut Pedestrian> {
+        let origin = &scenario.waypoints[config.origin];
+        let (line, field) = (origin.line, &fields[origin.level]);
+        let (spacing, taken) = match spacing {
+            Some((spacing, first)) => {
+                let queue = &self.queues[config.origin];
+                let taken = queue.range(first.min(queue.len())..).map(|p| p.pos);
+                (spacing, taken.collect())
+            }
+            None => (0.0, Vec::new()),
+        };
+        let Some(pos) = sample_on_line(line, field, &taken, spacing, rng.get(Stream::Spawn)) else {
+            if self.unplaced_total == 0 {
+                warn!(
+                    "No position clear of obstacles was found on origin {} of group {group}; the origin line may overlap obstacles",
+                    config.origin
+                );
+            }
+            self.unplaced += 1;
+            self.unplaced_total += 1;
+            return None;
+        };
+        let pedestrian = self.generate(group, config, pos, origin.level, rng);
+        let queue = &mut self.queues[config.origin];
+        queue.push_back(pedestrian);
+        queue.back_mut()
+    }
+
+    /// Generate pedestrians spawned at the beginning of the simulation.
+    ///
+    /// Pedestrians are placed on the level of their origin, including ones scattered over regions.
+    pub fn spawn_initial(&mut self, scenario: &Scenario, fields: &[Field], rng: &mut RngStreams) {
+        for (group, pedestrian) in scenario.pedestrians.iter().enumerate() {
+            let PedestrianSpawnConfig::Once { count, region } = &pedestrian.spawn else {
+                continue;
+            };
+            let level = scenario.waypoints[pedestrian.origin].level;
+
+            match region {
+                Some(region) => {
+                    let positions = scatter(
+                        region,
+                        *count as usize,
+                        scenario,
+                        &fields[level],
+                        rng.get(Stream::Spawn),
+                    );
+                    if positions.len() < *count as usize {
+                        warn!(
+                            "Only {} of {count} pedestrians of group {group} were placed; the region may be too small or covered by obstacles",
+                            positions.len()
+                        );
+                    }
+                    for pos in positions {
+                        let p = self.generate(group, pedestrian, pos, level, rng);
+                        self.scattered.push(p);
+                    }
+                }
+                None => {
+                    for _ in 0..*count {
+                        self.push(group, pedestrian, scenario, fields, None, rng);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Generate pedestrians spawned in a step.
+    ///
+    /// If [`SimulatorOptions::spawn_spacing`] is set, pedestrians arriving at an origin in the step keep the spacing
+    /// from each other, and their arrivals are spread uniformly over the step.
+    pub fn spawn_periodic(
+        &mut self,
+        options: &SimulatorOptions,
+        scenario: &Scenario,
+        fields: &[Field],
+        routing: &Routing,
+        rate_scale: f64,
+        rng: &mut RngStreams,
+    ) {
+        // Pedestrians queued in earlier steps are not kept apart from new ones.
+        let queued: Vec<usize> = self.queues.iter().map(VecDeque::len).collect();
+
+        for (group, pedestrian) in scenario.pedestrians.iter().enumerate() {
+            if !routing.is_open(pedestrian.origin) {
+                continue;
+            }
+
+            if let PedestrianSpawnConfig::Periodic { rate } = pedestrian.spawn {
+                let count = util::poisson(rng.get(Stream::Spawn), rate * rate_scale / 10.0);
+                let spacing = options
+                    .spawn_spacing
+                    .map(|spacing| (spacing, queued[pedestrian.origin]));
+
+                for k in 0..count {
+                    let Some(p) = self.push(group, pedestrian, scenario, fields, spacing, rng)
+                    else {
+                        continue;
+                    };
+                    if spacing.is_some() && count > 1 {
+                        // Those arriving earlier have walked ahead of the line by the end of the step.
+                        let lag = 1.0 - (k + 1) as f32 / count as f32;
+                        let direction =
+                            routing.target(p.destination).map_or(Vec2::ZERO, |target| {
+                                fields[p.level]
+                                    .get_potential_grad(target, p.pos)
+                                    .normalize_or_zero()
+                            });
+                        p.pos += direction * p.desired_speed * lag * 0.1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take pedestrians which can enter the field.
+    ///
+    /// If [`SimulatorOptions::spawn_density_limit`] is set, pedestrians are held back while the density around their origin exceeds the limit.
+    /// `positions` is used to measure the density and can be empty otherwise.
+    pub fn release(
+        &mut self,
+        options: &SimulatorOptions,
+        scenario: &Scenario,
+        positions: &[Vec2],
+    ) -> Vec<Pedestrian> {
+        let mut new_pedestrians = std::mem::take(&mut self.scattered);
+
+        for (origin, queue) in self.queues.iter_mut().enumerate() {
+            if queue.is_empty() {
+                continue;
+            }
+
+            let capacity = match options.spawn_density_limit {
+                Some(limit) => {
+                    let line = scenario.waypoints[origin].line;
+                    let area = line[0].distance(line[1]) * ORIGIN_AREA_DEPTH * 2.0
+                        + std::f32::consts::PI * ORIGIN_AREA_DEPTH.powi(2);
+                    let count = positions
+                        .iter()
+                        .filter(|&&pos| {
+                            util::distance_from_line(pos, line).length() <= ORIGIN_AREA_DEPTH
+                        })
+                        .count();
+                    ((limit * area) as usize).saturating_sub(count)
+                }
+                None => usize::MAX,
+            };
+
+            let count = capacity.min(queue.len());
+            new_pedestrians.extend(queue.drain(..count));
+        }
+
+        new_pedestrians
+    }
+
+    /// Change destinations of pedestrians waiting to be spawned. See [`crate::models::PedestrianModel::reroute_pedestrians`].
+    pub fn reroute_waiting(&mut self, f: &mut dyn FnMut(usize, usize) -> Option<usize>) {
+        let waiting = self.queues.iter_mut().flatten().chain(&mut self.scattered);
+        for p in waiting {
+            if let Some(destination) = f(p.id, p.group) {
+                p.destination = destination;
+            }
+        }
+    }
+
+    /// Number of pedestrians waiting to be spawned.
+    pub fn queue_length(&self) -> i32 {
+        self.queues.iter().map(|queue| queue.len() as i32).sum()
+    }
+
+    /// Number of pedestrians dropped for lack of positions clear of obstacles since the last call.
+    pub fn take_unplaced_count(&mut self) -> i32 {
+        std::mem::take(&mut self.unplaced)
+    }
+}
+
+/// Sample a position on the line at least [`SPAWN_CLEARANCE`] away from obstacles, or `None` if all trials fail.
+///
+/// The position is also at least `spacing` away from `taken` positions, or as far as possible from them if no trial
+/// is.
+fn sample_on_line(
+    line: [Vec2; 2],
+    field: &Field,
+    taken: &[Vec2],
+    spacing: f32,
+    rng: &mut dyn Rng,
+) -> Option<Vec2> {
+    let is_clear = |pos: Vec2| {
+        let ix = (pos / field.unit).as_ivec2();
+        match field.obstacle_exist.get(Index::new(ix.x, ix.y)) {
+            Some(&obstacle) => !obstacle && field.get_obstacle_distance(pos) >= SPAWN_CLEARANCE,
+            // Lines on the boundary of the field may stick out of the grid.
+            None => true,
+        }
+    };
+
+    let mut best: Option<(f32, Vec2)> = None;
+    for _ in 0..MAX_LINE_TRIALS {
+        let pos = line[0].lerp(line[1], rng.f32());
+        if !is_clear(pos) {
+            continue;
+        }
+        let gap = taken
+            .iter()
+            .map(|p| p.distance(pos))
+            .fold(f32::INFINITY, f32::min);
+        if gap >= spacing {
+            return Some(pos);
+        }
+        if best.is_none_or(|(best_gap, _)| gap > best_gap) {
+            best = Some((gap, pos));
+        }
+    }
+    best.map(|(_, pos)| pos)
+}
+
+/// Sample up to `count` positions uniformly over walkable cells of the region.
+fn scatter(
+    region: &SpawnRegion,
+    count: usize,
+    scenario: &Scenario,
+    field: &Field,
+    rng: &mut dyn Rng,
+) -> Vec<Vec2> {
+    let (min, max, polygon) = match region {
+        SpawnRegion::Field(_) => (Vec2::ZERO, scenario.field.size, None),
+        SpawnRegion::Polygon(vertices) => {
+            let min = vertices.iter().copied().fold(Vec2::INFINITY, Vec2::min);
+            let max = vertices.iter().copied().fold(Vec2::NEG_INFINITY, Vec2::max);
+            (min, max, Some(util::polygon(vertices)))
+        }
+    };
+
+    let is_walkable = |pos: Vec2| {
+        let ix = (pos / field.unit).as_ivec2();
+        let inside = polygon
+            .as_ref()
+            .is_none_or(|polygon| util::polygon_contains(polygon, pos));
+        inside && field.obstacle_exist.get(Index::new(ix.x, ix.y)) == Some(&false)
+    };
+
+    let mut positions = Vec::with_capacity(count);
+    for _ in 0..count * MAX_SCATTER_TRIALS {
+        if positions.len() >= count {
+            break;
+        }
+        let pos = min + (max - min) * vec2(rng.f32(), rng.f32());
+        if is_walkable(pos) {
+            positions.push(pos);
+        }
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec2;
+
+    use crate::{
+        field::Field,
+        rng::RngStreams,
+        routing::Routing,
+        scenario::{
+            FamiliarityConfig, FieldConfig, PedestrianConfig, PedestrianSpawnConfig, Scenario,
+            SpawnRegion, WaypointConfig,
+        },
+        SimulatorOptions,
+    };
+
+    use super::Spawner;
+
+    #[test]
+    fn test_density_limit() {
+        let scenario = Scenario {
+            waypoints: vec![WaypointConfig {
+                line: [vec2(0.0, 0.0), vec2(0.0, 2.0)],
+                ..Default::default()
+            }],
+            pedestrians: vec![PedestrianConfig {
+                origin: 0,
+                destination: Some(0),
+                spawn: PedestrianSpawnConfig::Once {
+                    count: 20,
+                    region: None,
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let options = SimulatorOptions {
+            spawn_density_limit: Some(1.0),
+            ..Default::default()
+        };
+
+        // The origin area is about 7.14 m^2.
+        let mut spawner = Spawner::new(&scenario);
+        spawner.spawn_initial(&scenario, &[Field::default()], &mut RngStreams::new(1));
+        let spawned = spawner.release(&options, &scenario, &[]);
+        assert_eq!(spawned.len(), 7);
+        assert_eq!(spawner.queue_length(), 13);
+
+        let positions: Vec<_> = spawned.iter().map(|p| p.pos).collect();
+        assert!(spawner.release(&options, &scenario, &positions).is_empty());
+    }
+
+    #[test]
+    fn test_scatter() {
+        let region = vec![
+            vec2(1.0, 1.0),
+            vec2(4.0, 1.0),
+            vec2(4.0, 3.0),
+            vec2(1.0, 3.0),
+        ];
+        let scenario = Scenario {
+            field: FieldConfig {
+                size: vec2(10.0, 5.0),
+                ..Default::default()
+            },
+            waypoints: vec![WaypointConfig {
+                line: [vec2(9.0, 1.0), vec2(9.0, 4.0)],
+                width: 1.0,
+                ..Default::default()
+            }],
+            pedestrians: vec![PedestrianConfig {
+                origin: 0,
+                destination: Some(0),
+                spawn: PedestrianSpawnConfig::Once {
+                    count: 50,
+                    region: Some(SpawnRegion::Polygon(region)),
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+
+        let mut spawner = Spawner::new(&scenario);
+        spawner.spawn_initial(&scenario, &[field], &mut RngStreams::new(1));
+        assert_eq!(spawner.queue_length(), 0);
+
+        // Scattered pedestrians are released regardless of the density limit.
+        let options = SimulatorOptions {
+            spawn_density_limit: Some(0.1),
+            ..Default::default()
+        };
+        let spawned = spawner.release(&options, &scenario, &[]);
+        assert_eq!(spawned.len(), 50);
+        assert!(spawned
+            .iter()
+            .all(|p| (1.0..=4.0).contains(&p.pos.x) && (1.0..=3.0).contains(&p.pos.y)));
+    }
+
+    #[test]
+    fn test_clear_of_obstacles() {
+        // The first origin crosses a wall, and the second one lies in it.
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = [{ line = [[0.0, 5.0], [10.0, 5.0]], width = 0.2 }]
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[3.0, 5.0], [7.0, 5.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "once", count = 200 }
+
+            [[pedestrians]]
+            origin = 1
+            destination = 0
+            spawn = { kind = "once", count = 10 }
+            "#,
+        )
+        .unwrap();
+        let field = Field::from_scenario(&scenario, 0.25).unwrap();
+
+        let mut spawner = Spawner::new(&scenario);
+        spawner.spawn_initial(&scenario, &[field], &mut RngStreams::new(1));
+        let spawned = spawner.release(&SimulatorOptions::default(), &scenario, &[]);
+        assert_eq!(spawned.len(), 200);
+        assert!(spawned.iter().all(|p| (p.pos.y - 5.0).abs() > 0.3));
+        assert_eq!(spawner.take_unplaced_count(), 10);
+        assert_eq!(spawner.take_unplaced_count(), 0);
+    }
+
+    #[test]
+    fn test_spacing() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[1.0, 1.0], [1.0, 9.0]]
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 1
+            spawn = { kind = "periodic", rate = 50.0 }
+            "#,
+        )
+        .unwrap();
+        let fields = [Field::from_scenario(&scenario, 0.25).unwrap()];
+        // Smallest distance along the line between pedestrians entering in the same step
+        let min_gap = |options: &SimulatorOptions| {
+            let routing = Routing::new(options, &scenario);
+            let mut spawner = Spawner::new(&scenario);
+            let mut rng = RngStreams::new(1);
+            let mut min_gap = f32::INFINITY;
+            let mut max_lead: f32 = 0.0;
+            let mut max_overshoot = f32::NEG_INFINITY;
+            for _ in 0..20 {
+                spawner.spawn_periodic(options, &scenario, &fields, &routing, 1.0, &mut rng);
+                let spawned = spawner.release(options, &scenario, &[]);
+                for (i, p) in spawned.iter().enumerate() {
+                    max_lead = max_lead.max(p.pos.x - 1.0);
+                    max_overshoot = max_overshoot.max(p.pos.x - 1.0 - p.desired_speed * 0.1);
+                    for q in &spawned[i + 1..] {
+                        min_gap = min_gap.min((p.pos.y - q.pos.y).abs());
+                    }
+                }
+            }
+            (min_gap, max_lead, max_overshoot)
+        };
+
+        let (gap, lead, _) = min_gap(&SimulatorOptions::default());
+        assert!(gap < 0.1, "{gap}");
+        assert_eq!(lead, 0.0);
+
+        let options = SimulatorOptions {
+            spawn_spacing: Some(0.5),
+            ..Default::default()
+        };
+        let (gap, lead, overshoot) = min_gap(&options);
+        // Walking ahead of the line may shift positions along it slightly.
+        assert!(gap > 0.45, "{gap}");
+        // Early arrivals have walked ahead by a step at their own desired speeds at most.
+        assert!(lead > 0.05, "{lead}");
+        assert!(overshoot < 1e-4, "{overshoot}");
+    }
+
+    #[test]
+    fn test_familiarity() {
+        let scenario = Scenario {
+            waypoints: vec![WaypointConfig::default(); 3],
+            pedestrians: vec![PedestrianConfig {
+                origin: 0,
+                destination: Some(1),
+                spawn: PedestrianSpawnConfig::Once {
+                    count: 1000,
+                    region: None,
+                },
+                familiarity: Some(FamiliarityConfig {
+                    unfamiliar_ratio: 0.3,
+                    familiar_exit: Some(2),
+                    herding: 0.0,
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut spawner = Spawner::new(&scenario);
+        spawner.spawn_initial(&scenario, &[Field::default()], &mut RngStreams::new(1));
+        let spawned = spawner.release(&SimulatorOptions::default(), &scenario, &[]);
+        assert_eq!(spawned.len(), 1000);
+
+        // Unfamiliar pedestrians head for the familiar exit instead of the destination.
+        let unfamiliar = spawned.iter().filter(|p| p.unfamiliar).count();
+        assert!((250..350).contains(&unfamiliar), "{unfamiliar}");
+        assert!(spawned
+            .iter()
+            .all(|p| p.destination == if p.unfamiliar { 2 } else { 1 }));
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{self, Write};
+
+use crate::Simulator;
+
+/// Free walking speed of the fundamental diagram by Weidmann (1993) (m/s)
+const WEIDMANN_FREE_SPEED: f32 = 1.34;
+/// Density at which pedestrians stop walking in the fundamental diagram by Weidmann (1993) (persons/m^2)
+const WEIDMANN_JAM_DENSITY: f32 = 5.4;
+/// Fitting parameter of the fundamental diagram by Weidmann (1993) (persons/m^2)
+const WEIDMANN_GAMMA: f32 = 1.913;
+
+/// Walking speed at the density according to the fundamental diagram by Weidmann (1993) (m/s)
+pub fn weidmann_speed(density: f32) -> f32 {
+    if density <= 0.0 {
+        return WEIDMANN_FREE_SPEED;
+    }
+    let speed = WEIDMANN_FREE_SPEED
+        * (1.0 - (-WEIDMANN_GAMMA * (1.0 / density - 1.0 / WEIDMANN_JAM_DENSITY)).exp());
+    speed.max(0.0)
+}
+
+/// Exporter of pairs of local density and walking speed of each pedestrian as CSV, to compare simulated fundamental
+/// diagrams against empirical ones.
+///
+/// Each row also has the speed by [`weidmann_speed`] at the density as a reference.
+pub struct SpeedDensityExporter<W: Write> {
+    writer: W,
+    /// Interval of steps between samples
+    interval: i32,
+}
+
+impl<W: Write> SpeedDensityExporter<W> {
+    /// Write the header and prepare to sample every `interval` steps.
+    pub fn new(mut writer: W, interval: i32) -> io::Result<Self> {
+        writeln!(writer, "step,id,group,density,speed,weidmann_speed")?;
+        Ok(SpeedDensityExporter {
+            writer,
+            interval: interval.max(1),
+        })
+    }
+
+    /// Write a row for each pedestrian if the current step is sampled and out of the warm-up period.
+    ///
+    /// Rows are flushed in each sampled step, since runs often end by interruption.
+    pub fn record(&mut self, simulator: &Simulator) -> io::Result<()> {
+        if simulator.is_warming_up() || simulator.step % self.interval != 0 {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        simulator.for_each_pedestrian(|p| {
+            if result.is_ok() {
+                result = writeln!(
+                    self.writer,
+                    "{},{},{},{:.3},{:.3},{:.3}",
+                    simulator.step,
+                    p.id,
+                    p.group,
+                    p.density,
+                    p.vel.length(),
+                    weidmann_speed(p.density)
+                );
+            }
+        });
+        result?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+
+    use crate::{scenario::Scenario, Simulator, SimulatorOptions};
+
+    use super::{weidmann_speed, SpeedDensityExporter};
+
+    #[test]
+    fn test_weidmann_speed() {
+        assert_float_absolute_eq!(weidmann_speed(0.0), 1.34);
+        assert_float_absolute_eq!(weidmann_speed(1.0), 1.06, 0.01);
+        assert_float_absolute_eq!(weidmann_speed(5.4), 0.0);
+        assert_float_absolute_eq!(weidmann_speed(8.0), 0.0);
+    }
+
+    #[test]
+    fn test_export() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 5, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario).unwrap();
+        let mut exporter = SpeedDensityExporter::new(Vec::new(), 5).unwrap();
+        for _ in 0..10 {
+            simulator.tick().unwrap();
+            exporter.record(&simulator).unwrap();
+        }
+
+        let csv = String::from_utf8(exporter.writer).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "step,id,group,density,speed,weidmann_speed");
+        // Two sampled steps of five pedestrians
+        assert_eq!(lines.len(), 11);
+        assert!(lines[1].starts_with("5,"));
+        assert!(lines[10].starts_with("10,"));
+    }
+}
@@ -0,0 +1,195 @@
+use log::info;
+
+use crate::scenario::{PedestrianSpawnConfig, Scenario, TimelineAction, TimelineEntry};
+
+/// Executor of [`Scenario::timeline`], which modifies the scenario as the time advances.
+#[derive(Debug, Default, Clone)]
+pub struct Timeline {
+    entries: Vec<TimelineEntry>,
+    /// Index of the next entry to execute
+    next: usize,
+}
+
+impl Timeline {
+    pub fn new(scenario: &Scenario) -> Self {
+        let mut entries = scenario.timeline.clone();
+        entries.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        Timeline { entries, next: 0 }
+    }
+
+    /// Execute actions scheduled until the given time. (seconds)
+    ///
+    /// Returns changes of pedestrians, which the simulator applies to ones already generated.
+    pub fn update(&mut self, scenario: &mut Scenario, time: f64) -> Vec<Change> {
+        let mut changes = Vec::new();
+        while let Some(entry) = self.entries.get(self.next).filter(|e| e.time <= time) {
+            info!("Timeline at {:.1} s: {:?}", time, entry.action);
+            changes.extend(execute(scenario, &entry.action, time));
+            self.next += 1;
+        }
+        changes
+    }
+}
+
+/// Change of pedestrians already generated, requested by the timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Reroute(Reroute),
+    /// Release of pedestrians waiting in the holding area requested by [`TimelineAction::ReleaseHoldingArea`]
+    Release {
+        area: usize,
+    },
+}
+
+/// Change of destinations requested by [`TimelineAction::SetDestination`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reroute {
+    /// Pedestrian group to reroute, or all groups if `None`
+    pub group: Option<usize>,
+    pub destination: usize,
+}
+
+impl Reroute {
+    /// New destination of a pedestrian in the group, if it is affected.
+    pub fn apply(&self, group: usize) -> Option<usize> {
+        self.group
+            .is_none_or(|g| g == group)
+            .then_some(self.destination)
+    }
+}
+
+fn execute(scenario: &mut Scenario, action: &TimelineAction, time: f64) -> Option<Change> {
+    match *action {
+        TimelineAction::CloseWaypoint { waypoint } => {
+            scenario.waypoints[waypoint]
+                .closed
+                .push([time, f64::INFINITY]);
+        }
+        TimelineAction::OpenWaypoint { waypoint } => {
+            for window in scenario.waypoints[waypoint].closed.iter_mut() {
+                if window[0] <= time && time < window[1] {
+                    window[1] = time;
+                }
+            }
+        }
+        TimelineAction::SetSpawnRate { group, rate } => {
+            scenario.pedestrians[group].spawn = PedestrianSpawnConfig::Periodic { rate };
+        }
+        TimelineAction::StartPanic { group } => {
+            for (i, pedestrian) in scenario.pedestrians.iter_mut().enumerate() {
+                if group.is_none_or(|group| group == i) {
+                    pedestrian.panic = true;
+                }
+            }
+        }
+        TimelineAction::SetDestination { group, destination } => {
+            for (i, pedestrian) in scenario.pedestrians.iter_mut().enumerate() {
+                if group.is_none_or(|group| group == i) {
+                    pedestrian.destination = Some(destination);
+                    pedestrian.destinations.clear();
+                }
+            }
+            return Some(Change::Reroute(Reroute { group, destination }));
+        }
+        TimelineAction::ReleaseHoldingArea { area } => return Some(Change::Release { area }),
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scenario::Scenario;
+
+    use super::{Change, Reroute, Timeline};
+
+    #[test]
+    fn test_timeline() {
+        let mut scenario: Scenario = toml::from_str(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[0.0, 0.0], [0.0, 1.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "periodic", rate = 1.0 }
+
+            [[timeline]]
+            time = 15.0
+            action = "start_panic"
+
+            [[timeline]]
+            time = 20.0
+            action = "open_waypoint"
+            waypoint = 0
+
+            [[timeline]]
+            time = 10.0
+            action = "close_waypoint"
+            waypoint = 0
+
+            [[timeline]]
+            time = 20.0
+            action = "set_destination"
+            group = 0
+            destination = 1
+            "#,
+        )
+        .unwrap();
+        let mut timeline = Timeline::new(&scenario);
+
+        timeline.update(&mut scenario, 5.0);
+        assert!(!scenario.waypoints[0].is_closed_at(5.0));
+
+        timeline.update(&mut scenario, 10.0);
+        assert!(scenario.waypoints[0].is_closed_at(15.0));
+        assert!(!scenario.pedestrians[0].panic);
+
+        let changes = timeline.update(&mut scenario, 20.0);
+        assert_eq!(
+            changes,
+            [Change::Reroute(Reroute {
+                group: Some(0),
+                destination: 1
+            })]
+        );
+        assert_eq!(scenario.pedestrians[0].destination, Some(1));
+        assert!(scenario.waypoints[0].is_closed_at(15.0));
+        assert!(!scenario.waypoints[0].is_closed_at(20.0));
+        assert!(scenario.pedestrians[0].panic);
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let text = r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[0.0, 0.0], [0.0, 1.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "periodic", rate = 1.0 }
+
+            [[timeline]]
+            time = 10.0
+            action = "close_waypoint"
+            waypoint = 0
+            "#;
+        assert!(Scenario::from_toml(text).is_ok());
+        assert!(Scenario::from_toml(&text.replace("waypoint = 0", "waypoint = 1")).is_err());
+        let text = text.replace(
+            r#"action = "close_waypoint"
+            waypoint = 0"#,
+            r#"action = "start_panic"
+            group = 1"#,
+        );
+        assert!(Scenario::from_toml(&text).is_err());
+    }
+}
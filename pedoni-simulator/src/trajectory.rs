@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use glam::{vec2, Vec2};
+
+use crate::Simulator;
+
+/// Magic bytes at the beginning of compact trajectory files
+const COMPACT_MAGIC: [u8; 4] = *b"PDTR";
+const COMPACT_VERSION: u8 = 1;
+/// Finest step of quantized coordinates (meters)
+const MIN_QUANTUM: f32 = 0.01;
+
+/// Writer of positions and velocities of pedestrians as CSV, one row per pedestrian in each sampled step.
+///
+/// Rows are not flushed in each step, unlike smaller logs, since trajectories are written at a high rate. Call
+/// [`TrajectoryWriter::flush`] periodically to keep them if the run ends by a crash.
+pub struct TrajectoryWriter<W: Write> {
+    writer: W,
+    /// Interval of steps between samples
+    interval: i32,
+}
+
+impl<W: Write> TrajectoryWriter<W> {
+    /// Write the header and prepare to sample every `interval` steps.
+    pub fn new(mut writer: W, interval: i32) -> io::Result<Self> {
+        writeln!(writer, "step,time,id,group,destination,x,y,vx,vy")?;
+        Ok(TrajectoryWriter {
+            writer,
+            interval: interval.max(1),
+        })
+    }
+
+    /// Write a row for each pedestrian if the current step is sampled and out of the warm-up period.
+    pub fn record(&mut self, simulator: &Simulator) -> io::Result<()> {
+        if simulator.is_warming_up() || simulator.step % self.interval != 0 {
+            return Ok(());
+        }
+
+        let time = simulator.step as f64 * 0.1;
+        let mut result = Ok(());
+        simulator.for_each_pedestrian(|p| {
+            if result.is_ok() {
+                result = writeln!(
+                    self.writer,
+                    "{},{:.1},{},{},{},{:.3},{:.3},{:.3},{:.3}",
+                    simulator.step,
+                    time,
+                    p.id,
+                    p.group,
+                    p.destination,
+                    p.pos.x,
+                    p.pos.y,
+                    p.vel.x,
+                    p.vel.y
+                );
+            }
+        });
+        result
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writer of positions of pedestrians in a compact binary format, which is several times smaller than the CSV of
+/// [`TrajectoryWriter`] at the cost of precision. Velocities, groups and destinations are not written.
+///
+/// Coordinates are quantized to `u16` multiples of a quantum. Each sampled step is a record of the step and the
+/// number of pedestrians, followed by the ID and the coordinates of each pedestrian in the order of IDs. IDs are
+/// written as differences from the previous one, and coordinates as differences from the previous record if the
+/// pedestrian is in it, all as LEB128 varints (zigzag-encoded if signed). The header holds the magic `PDTR`, the
+/// version, the quantum as `f32` and the interval of samples as `u32`, in little endian.
+///
+/// Files are read by [`CompactTrajectoryReader`].
+pub struct CompactTrajectoryWriter<W: Write> {
+    writer: W,
+    interval: i32,
+    /// Length of a unit of coordinates (meters)
+    quantum: f32,
+    /// Coordinates of pedestrians in the previous record
+    previous: HashMap<u32, [u16; 2]>,
+}
+
+impl<W: Write> CompactTrajectoryWriter<W> {
+    /// Write the header and prepare to sample every `interval` steps, quantizing coordinates by `quantum`. See
+    /// [`compact_quantum`].
+    pub fn new(mut writer: W, interval: i32, quantum: f32) -> io::Result<Self> {
+        let interval = interval.max(1);
+        writer.write_all(&COMPACT_MAGIC)?;
+        writer.write_all(&[COMPACT_VERSION])?;
+        writer.write_all(&quantum.to_le_bytes())?;
+        writer.write_all(&(interval as u32).to_le_bytes())?;
+        Ok(CompactTrajectoryWriter {
+            writer,
+            interval,
+            quantum,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Write a record if the current step is sampled and out of the warm-up period.
+    pub fn record(&mut self, simulator: &Simulator) -> io::Result<()> {
+        if simulator.is_warming_up() || simulator.step % self.interval != 0 {
+            return Ok(());
+        }
+
+        let mut pedestrians = Vec::new();
+        simulator.for_each_pedestrian(|p| {
+            let quantize = |x: f32| (x / self.quantum).round().clamp(0.0, u16::MAX as f32) as u16;
+            pedestrians.push((p.id as u32, [quantize(p.pos.x), quantize(p.pos.y)]));
+        });
+        pedestrians.sort_unstable_by_key(|&(id, _)| id);
+
+        let mut buffer = Vec::with_capacity(8 + pedestrians.len() * 4);
+        write_varint(&mut buffer, simulator.step as u64);
+        write_varint(&mut buffer, pedestrians.len() as u64);
+        let mut previous_id = 0;
+        for &(id, coords) in &pedestrians {
+            write_varint(&mut buffer, (id - previous_id) as u64);
+            previous_id = id;
+            let base = self.previous.get(&id).copied().unwrap_or_default();
+            for (x, base) in coords.into_iter().zip(base) {
+                write_varint(&mut buffer, zigzag(x as i32 - base as i32));
+            }
+        }
+        self.previous = pedestrians.into_iter().collect();
+        self.writer.write_all(&buffer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Finest quantum of [`CompactTrajectoryWriter`] which covers a field of the size, i.e. 1 cm unless the field is
+/// larger than 655 m.
+pub fn compact_quantum(size: Vec2) -> f32 {
+    (size.max_element() / u16::MAX as f32).max(MIN_QUANTUM)
+}
+
+/// Positions of pedestrians in a step, read from a compact trajectory file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrajectorySample {
+    pub step: i32,
+    /// IDs and positions of pedestrians in the order of IDs
+    pub pedestrians: Vec<(usize, Vec2)>,
+}
+
+/// Reader of files written by [`CompactTrajectoryWriter`], which iterates over the sampled steps.
+pub struct CompactTrajectoryReader<R: Read> {
+    reader: R,
+    /// Length of a unit of coordinates (meters)
+    pub quantum: f32,
+    /// Interval of steps between samples
+    pub interval: i32,
+    previous: HashMap<u32, [u16; 2]>,
+}
+
+impl<R: Read> CompactTrajectoryReader<R> {
+    /// Read the header.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != COMPACT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a compact trajectory file",
+            ));
+        }
+        let [version] = read_bytes(&mut reader)?;
+        if version != COMPACT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported version {version} of compact trajectories"),
+            ));
+        }
+        let quantum = f32::from_le_bytes(read_bytes(&mut reader)?);
+        let interval = u32::from_le_bytes(read_bytes(&mut reader)?) as i32;
+        Ok(CompactTrajectoryReader {
+            reader,
+            quantum,
+            interval,
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Read the next record, or `None` at the end of the file.
+    fn read_sample(&mut self) -> io::Result<Option<TrajectorySample>> {
+        let step = match read_varint(&mut self.reader) {
+            Ok(step) => step as i32,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let count = read_varint(&mut self.reader)?;
+
+        let mut current = HashMap::new();
+        let mut pedestrians = Vec::new();
+        let mut id = 0;
+        for _ in 0..count {
+            id += read_varint(&mut self.reader)? as u32;
+            let base = self.previous.get(&id).copied().unwrap_or_default();
+            let mut coords = [0; 2];
+            for (x, base) in coords.iter_mut().zip(base) {
+                *x = (base as i32 + unzigzag(read_varint(&mut self.reader)?)) as u16;
+            }
+            current.insert(id, coords);
+            let pos = vec2(coords[0] as f32, coords[1] as f32) * self.quantum;
+            pedestrians.push((id as usize, pos));
+        }
+        self.previous = current;
+        Ok(Some(TrajectorySample { step, pedestrians }))
+    }
+}
+
+impl<R: Read> Iterator for CompactTrajectoryReader<R> {
+    type Item = io::Result<TrajectorySample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_sample().transpose()
+    }
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let [byte] = read_bytes(reader)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint is too long",
+    ))
+}
+
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn unzigzag(value: u64) -> i32 {
+    let value = value as u32;
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{scenario::Scenario, Simulator, SimulatorOptions};
+
+    use super::{CompactTrajectoryReader, CompactTrajectoryWriter, TrajectoryWriter};
+
+    fn scenario() -> Scenario {
+        Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 3, region = [[1.0, 1.0], [3.0, 1.0], [3.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_trajectories() {
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario()).unwrap();
+        let mut writer = TrajectoryWriter::new(Vec::new(), 2).unwrap();
+        for _ in 0..4 {
+            simulator.tick().unwrap();
+            writer.record(&simulator).unwrap();
+        }
+
+        let csv = String::from_utf8(writer.writer).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "step,time,id,group,destination,x,y,vx,vy");
+        // Two sampled steps of three pedestrians
+        assert_eq!(lines.len(), 7);
+        assert!(lines[1].starts_with("2,0.2,"));
+        assert!(lines[6].starts_with("4,0.4,"));
+        let fields: Vec<f32> = lines[1].split(',').map(|x| x.parse().unwrap()).collect();
+        assert!((1.0..3.5).contains(&fields[5]), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let options = SimulatorOptions {
+            seed: Some(1),
+            warmup_steps: 3,
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario()).unwrap();
+        let mut writer = TrajectoryWriter::new(Vec::new(), 1).unwrap();
+        for _ in 0..5 {
+            simulator.tick().unwrap();
+            writer.record(&simulator).unwrap();
+        }
+
+        // Only steps 4 and 5 are recorded.
+        let csv = String::from_utf8(writer.writer).unwrap();
+        let steps: Vec<_> = csv.lines().skip(1).map(|line| &line[..2]).collect();
+        assert_eq!(steps, ["4,", "4,", "4,", "5,", "5,", "5,"]);
+    }
+
+    #[test]
+    fn test_compact() {
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let mut simulator = Simulator::new(options, scenario()).unwrap();
+        let mut csv = TrajectoryWriter::new(Vec::new(), 1).unwrap();
+        let mut compact = CompactTrajectoryWriter::new(Vec::new(), 1, 0.01).unwrap();
+        let mut expected = Vec::new();
+        for _ in 0..50 {
+            simulator.tick().unwrap();
+            csv.record(&simulator).unwrap();
+            compact.record(&simulator).unwrap();
+            let mut pedestrians = simulator.list_pedestrians();
+            pedestrians.sort_by_key(|p| p.id);
+            expected.push((simulator.step, pedestrians));
+        }
+        assert!(
+            compact.writer.len() * 8 < csv.writer.len(),
+            "{} {}",
+            compact.writer.len(),
+            csv.writer.len()
+        );
+
+        let reader = CompactTrajectoryReader::new(compact.writer.as_slice()).unwrap();
+        assert_eq!((reader.quantum, reader.interval), (0.01, 1));
+        let samples: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(samples.len(), expected.len());
+        for (sample, (step, pedestrians)) in samples.iter().zip(&expected) {
+            assert_eq!(sample.step, *step);
+            assert_eq!(sample.pedestrians.len(), pedestrians.len());
+            for (&(id, pos), p) in sample.pedestrians.iter().zip(pedestrians) {
+                assert_eq!(id, p.id);
+                assert!(pos.distance(p.pos) < 0.01, "{pos} {}", p.pos);
+            }
+        }
+    }
+}
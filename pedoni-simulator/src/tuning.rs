@@ -0,0 +1,99 @@
+use log::info;
+
+use crate::{
+    error::Result, field::Field, models::INTERACTION_CUTOFF, scenario::Scenario, Backend,
+    Simulator, SimulatorOptions,
+};
+
+/// Candidates of [`SimulatorOptions::neighbor_grid_unit`] relative to the interaction cutoff, which the unit must
+/// cover
+const NEIGHBOR_GRID_SCALES: [f32; 4] = [1.0, 1.25, 1.5, 2.0];
+/// Candidates of [`SimulatorOptions::gpu_work_size`], tried only on the GPU backend
+const GPU_WORK_SIZES: [usize; 4] = [32, 64, 128, 256];
+
+/// Find the fastest combination of the neighbor grid unit and the GPU work size for the scenario.
+///
+/// Each candidate is measured by simulating `steps` steps from the beginning and summing `time_calc_state`, sharing
+/// `fields` built for the scenario in advance since the candidates do not change them.
+/// Returns `options` with the fastest values.
+pub fn auto_tune(
+    options: &SimulatorOptions,
+    scenario: &Scenario,
+    fields: &[Field],
+    steps: i32,
+) -> Result<SimulatorOptions> {
+    let work_sizes: &[usize] = match options.backend {
+        Backend::Cpu => &[options.gpu_work_size],
+        Backend::Gpu => &GPU_WORK_SIZES,
+    };
+
+    let mut best: Option<(f64, SimulatorOptions)> = None;
+    for scale in NEIGHBOR_GRID_SCALES {
+        let neighbor_grid_unit = INTERACTION_CUTOFF * scale;
+        for &gpu_work_size in work_sizes {
+            let candidate = SimulatorOptions {
+                neighbor_grid_unit: Some(neighbor_grid_unit),
+                gpu_work_size,
+                ..options.clone()
+            };
+
+            let mut simulator =
+                Simulator::with_fields(candidate.clone(), scenario.clone(), fields.to_vec())?;
+            let time = (0..steps)
+                .map(|_| simulator.tick().map(|metrics| metrics.time_calc_state))
+                .sum::<Result<f64>>()?;
+            info!(
+                "Auto-tune: neighbor grid unit: {neighbor_grid_unit}, work size: {gpu_work_size}, calc state: {:.2} ms/step",
+                time / steps.max(1) as f64 * 1e3
+            );
+
+            if best.as_ref().is_none_or(|(best_time, _)| time < *best_time) {
+                best = Some((time, candidate));
+            }
+        }
+    }
+
+    let (_, best) = best.unwrap();
+    info!(
+        "Auto-tune: selected neighbor grid unit: {}, work size: {}",
+        best.neighbor_grid_unit.unwrap_or(INTERACTION_CUTOFF),
+        best.gpu_work_size
+    );
+    Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{field::Field, models::INTERACTION_CUTOFF, scenario::Scenario, SimulatorOptions};
+
+    use super::auto_tune;
+
+    #[test]
+    fn test_auto_tune() {
+        let scenario = Scenario::from_toml(
+            r#"
+            field = { size = [10.0, 10.0] }
+            obstacles = []
+
+            [[waypoints]]
+            line = [[9.0, 1.0], [9.0, 9.0]]
+
+            [[pedestrians]]
+            origin = 0
+            destination = 0
+            spawn = { kind = "once", count = 50, region = [[1.0, 1.0], [5.0, 1.0], [5.0, 9.0], [1.0, 9.0]] }
+            "#,
+        )
+        .unwrap();
+        let fields = [Field::from_scenario(&scenario, 0.25).unwrap()];
+        let options = SimulatorOptions {
+            seed: Some(1),
+            ..Default::default()
+        };
+
+        let tuned = auto_tune(&options, &scenario, &fields, 5).unwrap();
+        let unit = tuned.neighbor_grid_unit.unwrap();
+        assert!(unit >= INTERACTION_CUTOFF, "{unit}");
+        assert_eq!(tuned.gpu_work_size, options.gpu_work_size);
+    }
+}
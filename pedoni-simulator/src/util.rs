@@ -1,8 +1,13 @@
+use std::ops::{Add, Mul};
+
+use geo::{Contains, Coord, LineString, Polygon};
 use glam::{vec2, Vec2};
-use ndarray::Array2;
+use ndarray::{ArrayBase, Data, Ix2};
 use num_traits::PrimInt;
 use ocl::prm::Float2;
 
+use crate::rng::Rng;
+
 /// Index struct for [`ndarray::Array2`]
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Index {
@@ -40,33 +45,56 @@ unsafe impl ndarray::NdIndex<ndarray::Ix2> for Index {
     }
 }
 
+/// Build a polygon from its vertices.
+pub fn polygon(vertices: &[Vec2]) -> Polygon<f32> {
+    let exterior: LineString<f32> = vertices.iter().map(|v| Coord { x: v.x, y: v.y }).collect();
+    Polygon::new(exterior, vec![])
+}
+
+/// Check if the polygon contains the point.
+pub fn polygon_contains(polygon: &Polygon<f32>, pos: Vec2) -> bool {
+    polygon.contains(&Coord { x: pos.x, y: pos.y })
+}
+
 /// Interpolate grid using bilinear interpolation.
-pub fn bilinear(grid: &Array2<f32>, pos: Vec2) -> f32 {
+pub fn bilinear<S: Data<Elem = f32>>(grid: &ArrayBase<S, Ix2>, pos: Vec2) -> f32 {
     const FMAX: f32 = 1e12;
 
+    interpolate(grid, pos, FMAX)
+}
+
+/// Interpolate grid of vectors using bilinear interpolation. Cells outside the grid are regarded as zero.
+pub fn bilinear_vec2<S: Data<Elem = Vec2>>(grid: &ArrayBase<S, Ix2>, pos: Vec2) -> Vec2 {
+    interpolate(grid, pos, Vec2::ZERO)
+}
+
+fn interpolate<S, T>(grid: &ArrayBase<S, Ix2>, pos: Vec2, outside: T) -> T
+where
+    S: Data<Elem = T>,
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
     let base = pos.floor();
     let t = pos - base;
     let s = Vec2::ONE - t;
     let ix = Index::new(base.x as i32, base.y as i32);
+    let get = |ix: Index| grid.get(ix).cloned().unwrap_or(outside);
 
-    let mut y = 0.0;
-    y += s.y * s.x * grid.get(ix).cloned().unwrap_or(FMAX);
-    y += s.y * t.x * grid.get(ix.add(1, 0)).cloned().unwrap_or(FMAX);
-    y += t.y * s.x * grid.get(ix.add(0, 1)).cloned().unwrap_or(FMAX);
-    y += t.y * t.x * grid.get(ix.add(1, 1)).cloned().unwrap_or(FMAX);
-    y
+    get(ix) * (s.y * s.x)
+        + get(ix.add(1, 0)) * (s.y * t.x)
+        + get(ix.add(0, 1)) * (t.y * s.x)
+        + get(ix.add(1, 1)) * (t.y * t.x)
 }
 
 /// Apply Sobel operator on grid at given position.
-pub fn sobel_filter(grid: &Array2<f32>, pos: Vec2) -> Vec2 {
-    let u00 = bilinear(&grid, pos + vec2(-1.0, -1.0));
-    let u01 = bilinear(&grid, pos + vec2(0.0, -1.0));
-    let u02 = bilinear(&grid, pos + vec2(1.0, -1.0));
-    let u10 = bilinear(&grid, pos + vec2(-1.0, 0.0));
-    let u12 = bilinear(&grid, pos + vec2(1.0, 0.0));
-    let u20 = bilinear(&grid, pos + vec2(-1.0, 1.0));
-    let u21 = bilinear(&grid, pos + vec2(0.0, 1.0));
-    let u22 = bilinear(&grid, pos + vec2(1.0, 1.0));
+pub fn sobel_filter<S: Data<Elem = f32>>(grid: &ArrayBase<S, Ix2>, pos: Vec2) -> Vec2 {
+    let u00 = bilinear(grid, pos + vec2(-1.0, -1.0));
+    let u01 = bilinear(grid, pos + vec2(0.0, -1.0));
+    let u02 = bilinear(grid, pos + vec2(1.0, -1.0));
+    let u10 = bilinear(grid, pos + vec2(-1.0, 0.0));
+    let u12 = bilinear(grid, pos + vec2(1.0, 0.0));
+    let u20 = bilinear(grid, pos + vec2(-1.0, 1.0));
+    let u21 = bilinear(grid, pos + vec2(0.0, 1.0));
+    let u22 = bilinear(grid, pos + vec2(1.0, 1.0));
 
     vec2(
         u00 + u10 + u10 + u20 - u02 - u12 - u12 - u22,
@@ -75,13 +103,13 @@ pub fn sobel_filter(grid: &Array2<f32>, pos: Vec2) -> Vec2 {
 }
 
 /// Spawn a random integer based on Poisson distribution.
-pub fn poisson(lambda: f64) -> i32 {
+pub fn poisson(rng: &mut dyn Rng, lambda: f64) -> i32 {
     let mut y = 0;
-    let mut x = fastrand::f64();
+    let mut x = rng.f64();
     let exp_lambda = (-lambda).exp();
 
     while x >= exp_lambda {
-        x *= fastrand::f64();
+        x *= rng.f64();
         y += 1;
     }
 
@@ -110,6 +138,62 @@ pub fn line_with_width(line: [Vec2; 2], width: f32) -> Vec<Vec2> {
     vec![line[0] - b, line[0] + b, line[1] + b, line[1] - b]
 }
 
+/// Joints of polylines turning further than this are beveled instead of mitered, since their miters grow too long.
+/// (cosine of half the turning angle)
+const MITER_LIMIT: f32 = 0.5;
+
+/// Calculate polygons covering a polyline with given width, which is a quadrilateral per segment and a triangle per
+/// beveled joint.
+///
+/// Segments meet at mitered joints without gaps, including the first and the last ones of a closed polyline whose
+/// ends are the same. A polyline of two vertices is the same as [`line_with_width`].
+pub fn polyline_with_width(vertices: &[Vec2], width: f32) -> Vec<Vec<Vec2>> {
+    let mut vertices = vertices.to_vec();
+    vertices.dedup();
+    if vertices.len() < 2 {
+        return Vec::new();
+    }
+
+    let half = 0.5 * width;
+    let normals: Vec<Vec2> = vertices
+        .windows(2)
+        .map(|v| {
+            let a = (v[1] - v[0]).normalize();
+            vec2(a.y, -a.x)
+        })
+        .collect();
+
+    // Offsets of the corners at the start and the end of each segment from its vertices
+    let mut starts: Vec<Vec2> = normals.iter().map(|&n| n * half).collect();
+    let mut ends = starts.clone();
+    let mut polygons = Vec::new();
+    let last = normals.len() - 1;
+    let closed = last > 1 && vertices[0] == vertices[last + 1];
+    // Pairs of segments meeting at each joint
+    let joints = (1..=last)
+        .map(|i| (i - 1, i))
+        .chain(closed.then_some((last, 0)));
+    for (i, j) in joints {
+        let (n_1, n_2) = (normals[i], normals[j]);
+        let miter = (n_1 + n_2).normalize_or_zero();
+        let cos = miter.dot(n_1);
+        if cos >= MITER_LIMIT {
+            ends[i] = miter * half / cos;
+            starts[j] = ends[i];
+        } else {
+            let v = vertices[j];
+            polygons.push(vec![v, v + n_1 * half, v + n_2 * half]);
+            polygons.push(vec![v, v - n_1 * half, v - n_2 * half]);
+        }
+    }
+
+    for (i, v) in vertices.windows(2).enumerate() {
+        let (b_1, b_2) = (starts[i], ends[i]);
+        polygons.push(vec![v[0] - b_1, v[0] + b_1, v[1] + b_2, v[1] - b_2]);
+    }
+    polygons
+}
+
 pub trait ToGlam {
     type T;
     fn to_glam(self) -> Self::T;
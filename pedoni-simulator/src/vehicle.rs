@@ -0,0 +1,94 @@
+use glam::{vec2, Vec2};
+
+/// Time for which pedestrians keep clear of the path ahead of a moving vehicle. (seconds)
+pub const LOOKAHEAD_TIME: f32 = 1.0;
+
+/// Strength and range of the repulsion, same as obstacles (m/s^2, meters)
+const REPULSION_STRENGTH: f32 = 10.0 * 0.2;
+const REPULSION_RANGE: f32 = 0.2;
+/// Max depth inside footprints taken into account, which bounds the repulsion.
+const MAX_PENETRATION: f32 = 0.5;
+
+/// Vehicle injected from an external source such as a traffic simulator, which pedestrians avoid as a moving obstacle.
+///
+/// The footprint is a rectangle centered at `pos`, swept forward by the distance the vehicle moves in
+/// [`LOOKAHEAD_TIME`] so that pedestrians also keep out of its way.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Vehicle {
+    /// ID in the external source
+    pub id: usize,
+    /// Center of the footprint
+    pub pos: Vec2,
+    pub vel: Vec2,
+    /// Unit vector toward the front
+    pub heading: Vec2,
+    /// Length and width of the footprint (meters)
+    pub size: Vec2,
+}
+
+impl Vehicle {
+    /// Signed distance from the swept footprint to `pos`, negative inside it, and the outward direction.
+    pub fn distance(&self, pos: Vec2) -> (f32, Vec2) {
+        let heading = self.heading.normalize_or(Vec2::X);
+        let sweep = self.vel.dot(heading).max(0.0) * LOOKAHEAD_TIME;
+        let center = self.pos + heading * sweep * 0.5;
+        let half_size = self.size * 0.5 + vec2(sweep * 0.5, 0.0);
+
+        // Position in the frame of the vehicle
+        let local = vec2(heading.dot(pos - center), heading.perp_dot(pos - center));
+        let excess = local.abs() - half_size;
+        let (distance, normal) = if excess.cmpgt(Vec2::ZERO).any() {
+            let outside = excess.max(Vec2::ZERO) * local.signum();
+            (outside.length(), outside.normalize())
+        } else if excess.x > excess.y {
+            (excess.x, vec2(local.x.signum(), 0.0))
+        } else {
+            (excess.y, vec2(0.0, local.y.signum()))
+        };
+
+        (distance, heading * normal.x + heading.perp() * normal.y)
+    }
+
+    /// Acceleration of a pedestrian at `pos` repelled by the vehicle.
+    pub fn repulsion(&self, pos: Vec2) -> Vec2 {
+        let (distance, direction) = self.distance(pos);
+        REPULSION_STRENGTH * (-distance.max(-MAX_PENETRATION) / REPULSION_RANGE).exp() * direction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_float_eq::*;
+    use glam::vec2;
+
+    use super::Vehicle;
+
+    #[test]
+    fn test_vehicle_distance() {
+        // 4 m x 2 m, heading up
+        let vehicle = Vehicle {
+            pos: vec2(10.0, 10.0),
+            heading: vec2(0.0, 1.0),
+            size: vec2(4.0, 2.0),
+            ..Default::default()
+        };
+
+        let (distance, direction) = vehicle.distance(vec2(12.0, 10.0));
+        assert_float_absolute_eq!(distance, 1.0);
+        assert!(direction.abs_diff_eq(vec2(1.0, 0.0), 1e-6));
+
+        let (distance, direction) = vehicle.distance(vec2(10.0, 11.5));
+        assert_float_absolute_eq!(distance, -0.5);
+        assert!(direction.abs_diff_eq(vec2(0.0, 1.0), 1e-6));
+
+        // Moving at 5 m/s, the path ahead is avoided as well.
+        let moving = Vehicle {
+            vel: vec2(0.0, 5.0),
+            ..vehicle
+        };
+        assert_float_absolute_eq!(vehicle.distance(vec2(10.0, 15.0)).0, 3.0);
+        assert!(moving.distance(vec2(10.0, 15.0)).0 < 0.0);
+
+        assert!(vehicle.repulsion(vec2(8.5, 10.0)).x < 0.0);
+    }
+}
@@ -0,0 +1,66 @@
+//! Tests of the GPU backend, which need an OpenCL device and run with `--features gpu-tests`.
+#![cfg(feature = "gpu-tests")]
+
+use std::{collections::BTreeMap, fs};
+
+use glam::Vec2;
+use pedoni_simulator::{scenario::Scenario, Backend, GpuPrecision, Simulator, SimulatorOptions};
+
+/// Positions of pedestrians by ID after the steps.
+fn run(name: &str, options: SimulatorOptions, steps: usize) -> BTreeMap<usize, Vec2> {
+    fastrand::seed(1);
+    let path = format!("{}/tests/scenarios/{name}.toml", env!("CARGO_MANIFEST_DIR"));
+    let scenario = Scenario::from_toml(&fs::read_to_string(path).unwrap()).unwrap();
+    let mut simulator = Simulator::new(options, scenario).unwrap();
+    for _ in 0..steps {
+        simulator.tick().unwrap();
+    }
+    simulator
+        .list_pedestrians()
+        .into_iter()
+        .map(|p| (p.id, p.pos))
+        .collect()
+}
+
+fn gpu_options(precision: GpuPrecision) -> SimulatorOptions {
+    SimulatorOptions {
+        backend: Backend::Gpu,
+        gpu_precision: precision,
+        seed: Some(1),
+        ..Default::default()
+    }
+}
+
+/// States stored in half precision should only perturb forces, so trajectories stay close to single precision.
+#[test]
+fn test_half_precision_parity() {
+    let single = run("corridor", gpu_options(GpuPrecision::Single), 300);
+    let half = run("corridor", gpu_options(GpuPrecision::Half), 300);
+    assert_eq!(
+        single.keys().collect::<Vec<_>>(),
+        half.keys().collect::<Vec<_>>()
+    );
+
+    let deviations: Vec<f32> = single
+        .iter()
+        .map(|(id, pos)| pos.distance(half[id]))
+        .collect();
+    let max = deviations.iter().copied().fold(0.0, f32::max);
+    let mean = deviations.iter().sum::<f32>() / deviations.len() as f32;
+    assert!(max < 0.2, "max deviation: {max}");
+    assert!(mean < 0.05, "mean deviation: {mean}");
+}
+
+/// Without `strict_determinism`, forces from neighbors are summed in an order depending on thread scheduling, which
+/// shows up in a crowded bottleneck within a few hundred steps.
+#[test]
+fn test_strict_determinism() {
+    let options = SimulatorOptions {
+        strict_determinism: true,
+        ..gpu_options(GpuPrecision::Single)
+    };
+    let expected = run("bottleneck", options.clone(), 300);
+    for _ in 0..3 {
+        assert_eq!(run("bottleneck", options.clone(), 300), expected);
+    }
+}
@@ -0,0 +1,87 @@
+//! Validation of the social force model against empirical ranges of canonical cases.
+
+use std::fs;
+
+use pedoni_simulator::{diagnostic::lane_order, scenario::Scenario, Simulator, SimulatorOptions};
+
+fn load_simulator(name: &str) -> Simulator {
+    fastrand::seed(1);
+    let path = format!("{}/tests/scenarios/{name}.toml", env!("CARGO_MANIFEST_DIR"));
+    let scenario = Scenario::from_toml(&fs::read_to_string(path).unwrap()).unwrap();
+    Simulator::new(SimulatorOptions::default(), scenario).unwrap()
+}
+
+/// Mean speed in free flow should match the walking speed observed in the field, 1.34 m/s (Weidmann, 1993).
+#[test]
+fn test_free_flow_speed() {
+    let mut simulator = load_simulator("corridor");
+
+    let mut speeds = Vec::new();
+    for step in 0..600 {
+        simulator.tick().unwrap();
+        if step >= 200 {
+            simulator.for_each_pedestrian(|p| {
+                if (8.0..22.0).contains(&p.pos.x) {
+                    speeds.push(p.vel.length());
+                }
+            });
+        }
+    }
+
+    let mean = speeds.iter().sum::<f32>() / speeds.len() as f32;
+    assert!((1.1..1.6).contains(&mean), "mean speed: {mean}");
+}
+
+/// Flow through a bottleneck 1 m wide is 1.5-2.5 persons/s in experiments (Seyfried et al., 2009; Kretz et al., 2006).
+#[test]
+fn test_bottleneck_flow() {
+    let mut simulator = load_simulator("bottleneck");
+
+    // Record times when the 20th and 120th pedestrians passed the exit, skipping the initial transient.
+    let (mut start, mut end) = (None, None);
+    for step in 0..3000 {
+        simulator.tick().unwrap();
+
+        let mut passed = 0;
+        simulator.for_each_pedestrian(|p| passed += (p.pos.x > 12.5) as i32);
+        // Pedestrians who arrived at the destination are removed.
+        let passed = passed + 150 - simulator.model.get_pedestrian_count();
+        if passed >= 20 && start.is_none() {
+            start = Some(step);
+        }
+        if passed >= 120 {
+            end = Some(step);
+            break;
+        }
+    }
+
+    let duration = (end.unwrap() - start.unwrap()) as f64 * 0.1;
+    let flow = 100.0 / duration;
+    assert!((1.5..2.5).contains(&flow), "flow: {flow} persons/s");
+}
+
+/// Pedestrians walking in opposite directions segregate into lanes (Helbing & Molnár, 1995).
+#[test]
+fn test_counterflow_lanes() {
+    let mut simulator = load_simulator("counterflow");
+
+    let mut order = Vec::new();
+    for step in 0..1200 {
+        simulator.tick().unwrap();
+        if step >= 600 && step % 10 == 0 {
+            // Directions are mixed near the waypoints where pedestrians spawn, so only the middle is measured.
+            let mut pedestrians = Vec::new();
+            simulator.for_each_pedestrian(|p| {
+                if (10.0..30.0).contains(&p.pos.x) {
+                    pedestrians.push((p.pos, p.vel));
+                }
+            });
+            order.extend(lane_order(pedestrians));
+        }
+    }
+
+    let mean = order.iter().sum::<f64>() / order.len() as f64;
+    // Lanes in which 80% of pedestrians walk in one direction, i.e. 0.6 of |n_+ - n_-| / (n_+ + n_-), give 0.36 of
+    // the squared parameter. Randomly mixed directions give about (number of lanes) / (number of pedestrians).
+    assert!(mean > 0.36, "lane order: {mean}");
+}
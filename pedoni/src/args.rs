@@ -1,27 +1,174 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
-use pedoni_simulator::SimulatorOptions;
+use pedoni_simulator::{field::FmmOptions, generator::Geometry, SimulatorOptions};
 
-#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+use crate::clock::ClockSource;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
 pub enum Backend {
     Cpu,
     Gpu,
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum GpuPrecision {
+    Single,
+    Half,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum OutOfBoundsPolicy {
+    Clamp,
+    Despawn,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum RunMode {
+    /// Advance at the playback speed times real time
+    RealTime,
+    /// Advance as fast as possible
+    MaxSpeed,
+    /// Advance --steps-per-frame steps each frame
+    StepsPerFrame,
+    /// Advance to times received from --clock, for co-simulation
+    ExternalClock,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum LogFormat {
+    Plain,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum TrajectoryFormat {
+    /// Positions and velocities in CSV
+    Csv,
+    /// Positions quantized to 1 cm in a binary format several times smaller than CSV
+    Compact,
+    /// Positions and velocities in Apache Parquet
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Generate a scenario of a standard geometry in TOML, then exit
+    Gen {
+        #[command(subcommand)]
+        geometry: GenGeometry,
+        /// Path to write the scenario to [default: stdout]
+        #[arg(short, long, global = true)]
+        output: Option<PathBuf>,
+    },
+    /// Build the field and report the width of the narrowest passage on each route from origins to destinations,
+    /// then exit
+    AnalyzeScenario,
+}
+
+/// Geometries generated by `gen`, whose lengths are in meters
+#[derive(Debug, Clone, Copy, clap::Subcommand)]
+pub enum GenGeometry {
+    /// Straight corridor
+    Corridor {
+        #[arg(long, default_value_t = 50.0)]
+        length: f32,
+        #[arg(long, default_value_t = 4.0)]
+        width: f32,
+        /// Pedestrians spawned per second in each direction
+        #[arg(long, default_value_t = 1.0)]
+        rate: f64,
+        /// Spawn pedestrians at both ends
+        #[arg(long)]
+        bidirectional: bool,
+    },
+    /// Square room evacuated through a door
+    Bottleneck {
+        /// Width of the door
+        #[arg(long, default_value_t = 1.2)]
+        door: f32,
+        /// Length of the sides of the room
+        #[arg(long, default_value_t = 8.0)]
+        room: f32,
+        /// Number of pedestrians in the room
+        #[arg(long, default_value_t = 100)]
+        count: i32,
+    },
+    /// Two branches merging into a stem
+    TJunction {
+        /// Length of each branch and the stem
+        #[arg(long, default_value_t = 10.0)]
+        length: f32,
+        #[arg(long, default_value_t = 4.0)]
+        width: f32,
+        /// Pedestrians spawned per second in each branch
+        #[arg(long, default_value_t = 1.0)]
+        rate: f64,
+    },
+}
+
+impl From<GenGeometry> for Geometry {
+    fn from(geometry: GenGeometry) -> Self {
+        match geometry {
+            GenGeometry::Corridor {
+                length,
+                width,
+                rate,
+                bidirectional,
+            } => Geometry::Corridor {
+                length,
+                width,
+                rate,
+                bidirectional,
+            },
+            GenGeometry::Bottleneck { door, room, count } => {
+                Geometry::Bottleneck { door, room, count }
+            }
+            GenGeometry::TJunction {
+                length,
+                width,
+                rate,
+            } => Geometry::TJunction {
+                length,
+                width,
+                rate,
+            },
+        }
+    }
+}
+
 #[derive(Debug, clap::Parser)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Path to scenario file
     #[arg(default_value = "scenarios/default.toml")]
     pub scenario: PathBuf,
+    /// Directory to save settings [default: pedoni in the platform config directory]
+    #[arg(long)]
+    pub config_dir: Option<PathBuf>,
     /// Runs in headless mode
     #[arg(short = 'H', long)]
     pub headless: bool,
-    /// Backend
-    #[arg(value_enum, short, long, default_value_t=Backend::Cpu)]
-    pub backend: Backend,
-    /// Max playback speed
-    #[arg(short, long, default_value_t = 100.0)]
-    pub speed: f32,
+    /// Backend [default: the last one used for the scenario, or cpu]
+    #[arg(value_enum, short, long)]
+    pub backend: Option<Backend>,
+    /// How fast the simulation advances
+    #[arg(value_enum, long, default_value_t = RunMode::RealTime)]
+    pub run_mode: RunMode,
+    /// Steps simulated each frame in --run-mode steps-per-frame
+    #[arg(long, default_value_t = 1)]
+    pub steps_per_frame: u32,
+    /// Source of ticks in --run-mode external-clock: `stdin` or an address to accept a TCP connection on
+    #[arg(long, default_value = "stdin")]
+    pub clock: ClockSource,
+    /// Max playback speed [default: the last one used for the scenario, or 100]
+    #[arg(short, long)]
+    pub speed: Option<f32>,
+    /// Initial size of the window in logical pixels [default: the last one, or 800x600]
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_window_size)]
+    pub window_size: Option<[u32; 2]>,
 
     /// Do not use grid for acceleration
     #[arg(long)]
@@ -29,29 +176,204 @@ pub struct Args {
     /// Do not use distance map
     #[arg(long)]
     pub no_distance_map: bool,
+    /// Evaluate distances to nearby obstacles exactly, which removes artifacts at corners (CPU backend only)
+    #[arg(long)]
+    pub exact_obstacle_distance: bool,
     /// Unit length of field navigation grid
     #[arg(long)]
     pub field_unit: Option<f32>,
-    /// Unit length of neighbor search grid
+    /// Unit length of neighbor search grid (the interaction cutoff of 2 m if not given)
     #[arg(long)]
     pub neighbor_unit: Option<f32>,
     /// Local work size of GPU kernel
     #[arg(long)]
     pub work_size: Option<usize>,
-    /// Max steps to simulate (this affects only in headless mode)
+    /// Index of OpenCL platform for GPU backend
+    #[arg(long, default_value_t = 0)]
+    pub gpu_platform: usize,
+    /// Index of OpenCL device in the platform for GPU backend
+    #[arg(long, default_value_t = 0)]
+    pub gpu_device: usize,
+    /// Precision of pedestrian states stored in GPU memory
+    #[arg(value_enum, long, default_value_t = GpuPrecision::Single)]
+    pub gpu_precision: GpuPrecision,
+    /// Path to OpenCL source overriding the embedded kernels
+    #[arg(long)]
+    pub kernel_path: Option<PathBuf>,
+    /// Options passed to OpenCL compiler (e.g. "-cl-fast-relaxed-math")
+    #[arg(long, default_value = "", allow_hyphen_values = true)]
+    pub kernel_options: String,
+    /// Print available OpenCL platforms and devices, then exit
+    #[arg(long)]
+    pub list_devices: bool,
+    /// Default distance from waypoints within which pedestrians are regarded as arrived
+    #[arg(long)]
+    pub arrival_distance: Option<f32>,
+    /// Use second-order fast marching for potentials, which reduces zigzag walking at a modest build cost
+    #[arg(long)]
+    pub fmm_second_order: bool,
+    /// Update potentials from diagonal neighbors too, which reduces artifacts along the grid axes
+    #[arg(long)]
+    pub fmm_diagonal: bool,
+    /// Cache built fields in the directory and reuse them when the geometry is unchanged
+    #[arg(long, num_args = 0..=1, default_missing_value = ".pedoni/cache")]
+    pub field_cache: Option<PathBuf>,
+    /// Max density around origins (persons/m^2); spawning is deferred while exceeded
+    #[arg(long)]
+    pub spawn_density_limit: Option<f32>,
+    /// Min distance between pedestrians entering from an origin in a step (meters); arrivals are spread over the step
+    #[arg(long)]
+    pub spawn_spacing: Option<f32>,
+    /// Max number of pedestrians in the field; pedestrians spawned beyond it are refused
+    #[arg(long)]
+    pub max_pedestrians: Option<usize>,
+    /// Number of threads for the CPU work of the simulator (all cores if not given)
+    #[arg(long)]
+    pub cpu_threads: Option<usize>,
+    /// Directory to write outputs of the run to [default: runs/<timestamp>-<scenario name>]
+    ///
+    /// Relative paths of other outputs, e.g. `--speed-density`, are placed in it.
+    #[arg(long)]
+    pub out_dir: Option<PathBuf>,
+    /// Export pairs of local density and speed of each pedestrian to the CSV file
+    #[arg(long)]
+    pub speed_density: Option<PathBuf>,
+    /// Interval of steps to sample pairs exported by `--speed-density`
+    #[arg(long, default_value_t = 10)]
+    pub speed_density_every: i32,
+    /// Write readings of the sensors of the scenario to the CSV file
+    #[arg(long)]
+    pub sensor_log: Option<PathBuf>,
+    /// Write positions and velocities of pedestrians to the file
+    #[arg(long)]
+    pub trajectories: Option<PathBuf>,
+    /// Format of trajectories written by `--trajectories`
+    #[arg(value_enum, long, default_value_t = TrajectoryFormat::Csv)]
+    pub trajectories_format: TrajectoryFormat,
+    /// Interval of steps to sample trajectories written by `--trajectories`
+    #[arg(long, default_value_t = 1)]
+    pub trajectories_every: i32,
+    /// Interval of seconds to save the log of GUI runs, which is also saved on exit (0 to save only on exit)
+    #[arg(long, value_name = "SECS", default_value_t = 60.0)]
+    pub autosave_every: f32,
+    /// Interval of steps to print metrics (0 to disable)
+    #[arg(long, default_value_t = 100)]
+    pub log_every: i32,
+    /// Also write the metrics of each step to `log.parquet` at the end of headless runs
+    #[cfg(feature = "parquet")]
+    #[arg(long)]
+    pub log_parquet: bool,
+    /// Format of metrics printed every `--log-every` steps
+    #[arg(value_enum, long, default_value_t = LogFormat::Plain)]
+    pub log_format: LogFormat,
+    /// Serve step metrics in the Prometheus format on http://ADDR/metrics (e.g. 0.0.0.0:9184)
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Max steps to simulate (this affects only in headless mode and replications)
     #[arg(long)]
     pub max_steps: Option<usize>,
+    /// Build the field and report spawn rates, reachability of destinations and potentials without simulating,
+    /// then exit
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Simulate the scenario N times with seeds from --seed (or 0) and print means of the outputs, then exit
+    #[arg(long, value_name = "N")]
+    pub replications: Option<usize>,
+    /// Steps of warm-up at the beginning, which are not recorded in the log, trajectories and other exports so that
+    /// statistics are of the steady state
+    #[arg(long, value_name = "STEPS", default_value_t = 0)]
+    pub warmup_steps: i32,
+    /// Skip forces of pedestrians at rest with nowhere to go while nobody around moves, e.g. in holding areas, and
+    /// forces from pedestrians and obstacles on ones with none of them within the interaction range (CPU backend
+    /// only)
+    #[arg(long)]
+    pub freeze_idle: bool,
+    /// Record forces acting on pedestrians separately (CPU backend only)
+    #[arg(long)]
+    pub record_forces: bool,
+    /// Record the lane order parameter of bidirectional flow in step metrics
+    #[arg(long)]
+    pub record_lane_order: bool,
+    /// Calculate the crowd pressure each step, which is logged and drawn as an overlay
+    #[arg(long)]
+    pub record_crowd_pressure: bool,
+    /// Accumulate forces in a fixed order so that runs are bit-identical (slower on the GPU backend)
+    #[arg(long)]
+    pub strict_determinism: bool,
+    /// Seed of random numbers, which makes runs reproducible [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// How to handle pedestrians leaving the field: move them back, despawn them with a warning, or stop the run with an error
+    #[arg(value_enum, long, default_value_t = OutOfBoundsPolicy::Clamp)]
+    pub out_of_bounds: OutOfBoundsPolicy,
+    /// Select the fastest neighbor grid unit and GPU work size by simulating the scenario before the run
+    #[arg(long)]
+    pub auto_tune: bool,
+    /// Steps simulated for each configuration tried by --auto-tune
+    #[arg(long, default_value_t = 200)]
+    pub auto_tune_steps: i32,
+    /// Measure specific flows through a standard bottleneck with doors of the widths (e.g. 0.8,1.0,1.2), then exit
+    #[arg(long, value_delimiter = ',', value_name = "WIDTHS")]
+    pub calibrate: Vec<f32>,
+    /// Fit the repulsion range of pedestrians so that the mean specific flow of --calibrate matches it (persons/m/s)
+    #[arg(long, value_name = "FLOW", requires = "calibrate")]
+    pub calibrate_target: Option<f64>,
 }
 
 impl Args {
+    pub fn log_format(&self) -> pedoni_simulator::diagnostic::LogFormat {
+        match self.log_format {
+            LogFormat::Plain => pedoni_simulator::diagnostic::LogFormat::Plain,
+            LogFormat::Json => pedoni_simulator::diagnostic::LogFormat::Json,
+        }
+    }
+
+    pub fn run_mode(&self) -> crate::runner::RunMode {
+        match self.run_mode {
+            RunMode::RealTime => crate::runner::RunMode::RealTime,
+            RunMode::MaxSpeed => crate::runner::RunMode::MaxSpeed,
+            RunMode::StepsPerFrame => crate::runner::RunMode::StepsPerFrame(self.steps_per_frame),
+            RunMode::ExternalClock => crate::runner::RunMode::ExternalClock,
+        }
+    }
+
     pub fn to_simulator_options(&self) -> SimulatorOptions {
         let mut options = SimulatorOptions {
-            backend: match self.backend {
+            backend: match self.backend.unwrap_or(Backend::Cpu) {
                 Backend::Cpu => pedoni_simulator::Backend::Cpu,
                 Backend::Gpu => pedoni_simulator::Backend::Gpu,
             },
             use_neighbor_grid: !self.no_neighbor_grid,
             use_distance_map: !self.no_distance_map,
+            exact_obstacle_distance: self.exact_obstacle_distance,
+            freeze_idle: self.freeze_idle,
+            warmup_steps: self.warmup_steps,
+            record_forces: self.record_forces,
+            fmm: FmmOptions {
+                second_order: self.fmm_second_order,
+                diagonal: self.fmm_diagonal,
+            },
+            record_lane_order: self.record_lane_order,
+            record_crowd_pressure: self.record_crowd_pressure,
+            strict_determinism: self.strict_determinism,
+            seed: self.seed,
+            spawn_density_limit: self.spawn_density_limit,
+            spawn_spacing: self.spawn_spacing,
+            max_pedestrians: self.max_pedestrians,
+            cpu_threads: self.cpu_threads,
+            gpu_platform: self.gpu_platform,
+            gpu_device: self.gpu_device,
+            gpu_precision: match self.gpu_precision {
+                GpuPrecision::Single => pedoni_simulator::GpuPrecision::Single,
+                GpuPrecision::Half => pedoni_simulator::GpuPrecision::Half,
+            },
+            gpu_kernel_path: self.kernel_path.clone(),
+            gpu_build_options: self.kernel_options.clone(),
+            out_of_bounds: match self.out_of_bounds {
+                OutOfBoundsPolicy::Clamp => pedoni_simulator::OutOfBoundsPolicy::Clamp,
+                OutOfBoundsPolicy::Despawn => pedoni_simulator::OutOfBoundsPolicy::Despawn,
+                OutOfBoundsPolicy::Error => pedoni_simulator::OutOfBoundsPolicy::Error,
+            },
             ..Default::default()
         };
 
@@ -59,9 +381,70 @@ impl Args {
             options.field_grid_unit = field_unit;
         }
         if let Some(neighbor_unit) = self.neighbor_unit {
-            options.neighbor_grid_unit = neighbor_unit;
+            options.neighbor_grid_unit = Some(neighbor_unit);
+        }
+        if let Some(work_size) = self.work_size {
+            options.gpu_work_size = work_size;
+        }
+        if let Some(arrival_distance) = self.arrival_distance {
+            options.arrival_distance = arrival_distance;
         }
 
         options
     }
 }
+
+fn parse_window_size(s: &str) -> Result<[u32; 2], String> {
+    s.split_once('x')
+        .and_then(|(width, height)| Some([width.parse().ok()?, height.parse().ok()?]))
+        .filter(|size| size.iter().all(|&x| x > 0))
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT (e.g. 1280x720), got `{s}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{parse_window_size, Args};
+
+    #[test]
+    fn test_simulator_options() {
+        let args = Args::try_parse_from([
+            "pedoni",
+            "scenarios/bottleneck.toml",
+            "--backend",
+            "gpu",
+            "--seed",
+            "7",
+            "--field-unit",
+            "0.5",
+            "--out-of-bounds",
+            "despawn",
+        ])
+        .unwrap();
+        assert_eq!(args.scenario.to_str(), Some("scenarios/bottleneck.toml"));
+
+        let options = args.to_simulator_options();
+        assert!(matches!(options.backend, pedoni_simulator::Backend::Gpu));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.field_grid_unit, 0.5);
+        assert!(matches!(
+            options.out_of_bounds,
+            pedoni_simulator::OutOfBoundsPolicy::Despawn
+        ));
+
+        // Options not given keep the defaults of the simulator.
+        let options = Args::try_parse_from(["pedoni"])
+            .unwrap()
+            .to_simulator_options();
+        assert_eq!(options.seed, None);
+        assert_eq!(options.field_grid_unit, 0.25);
+    }
+
+    #[test]
+    fn test_window_size() {
+        assert_eq!(parse_window_size("1280x720"), Ok([1280, 720]));
+        assert!(parse_window_size("1280").is_err());
+        assert!(parse_window_size("0x720").is_err());
+    }
+}
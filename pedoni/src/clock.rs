@@ -0,0 +1,97 @@
+//! External clock for co-simulation with other simulators, e.g. SUMO, which exchange states at fixed intervals.
+//!
+//! The other side sends a line with the simulation time to advance to in seconds (e.g. `12.5`) for each tick,
+//! and the runner replies with a line of the step and time it reached (e.g. `125 12.5`) once the steps are done.
+//! Empty lines are ignored, and `quit` or closing the stream ends the run.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener},
+    str::FromStr,
+};
+
+use log::{info, warn};
+
+/// Where ticks of the external clock come from.
+#[derive(Debug, Clone, Copy)]
+pub enum ClockSource {
+    /// Lines on stdin, answered on stdout
+    Stdin,
+    /// A TCP connection accepted on the address
+    Tcp(SocketAddr),
+}
+
+impl FromStr for ClockSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdin" => Ok(ClockSource::Stdin),
+            _ => s
+                .parse()
+                .map(ClockSource::Tcp)
+                .map_err(|_| format!("expected `stdin` or a socket address, got `{s}`")),
+        }
+    }
+}
+
+pub struct ExternalClock {
+    reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl ExternalClock {
+    /// Open the source, waiting for the other side to connect if it is a socket.
+    pub fn connect(source: ClockSource) -> io::Result<Self> {
+        match source {
+            ClockSource::Stdin => Ok(ExternalClock {
+                reader: Box::new(BufReader::new(io::stdin())),
+                writer: Box::new(io::stdout()),
+            }),
+            ClockSource::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                info!("Waiting for the external clock to connect to {addr}");
+                let (stream, peer) = listener.accept()?;
+                info!("External clock connected from {peer}");
+                stream.set_nodelay(true)?;
+                Ok(ExternalClock {
+                    reader: Box::new(BufReader::new(stream.try_clone()?)),
+                    writer: Box::new(stream),
+                })
+            }
+        }
+    }
+
+    /// Wait for the next time to advance to. (seconds)
+    ///
+    /// Returns `None` when the other side quits or disconnects.
+    pub fn next_target(&mut self) -> Option<f64> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to read the external clock: {e}");
+                    return None;
+                }
+            }
+
+            match line.trim() {
+                "" => continue,
+                "quit" => return None,
+                text => match text.parse() {
+                    Ok(time) => return Some(time),
+                    Err(_) => warn!("Ignored invalid time from the external clock: {text}"),
+                },
+            }
+        }
+    }
+
+    /// Notify the other side that the simulation reached the step.
+    pub fn acknowledge(&mut self, step: i32, time: f64) -> io::Result<()> {
+        writeln!(self.writer, "{step} {time}")?;
+        self.writer.flush()
+    }
+}
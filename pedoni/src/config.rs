@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use crate::args::Backend;
+
+/// Directory overriding the platform config directory, set by `--config-dir`.
+static CONFIG_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Persistent state of the application, which is saved in `state.json` in the config directory.
+///
+/// The config directory is `pedoni` in the platform config directory, e.g. `~/.config/pedoni` on Linux,
+/// `~/Library/Application Support/pedoni` on macOS and `%APPDATA%\pedoni` on Windows.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Profiles keyed by absolute paths of scenario files
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    /// Geometry of the window when it was last closed
+    #[serde(default)]
+    pub window: Option<WindowGeometry>,
+}
+
+/// Settings restored when the same scenario is opened again.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub camera: Option<Camera>,
+    pub playback_speed: Option<f32>,
+    pub backend: Option<Backend>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Camera {
+    pub target: [f32; 2],
+    pub scale: f32,
+}
+
+/// Size and position of the window in logical pixels, which are independent of the DPI scaling.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    pub width: u32,
+    pub height: u32,
+    /// Position of the top-left corner, which is not available on macOS
+    #[serde(default)]
+    pub position: Option<[u32; 2]>,
+}
+
+impl Default for WindowGeometry {
+    fn default() -> Self {
+        WindowGeometry {
+            width: 800,
+            height: 600,
+            position: None,
+        }
+    }
+}
+
+impl State {
+    /// Load the state file. Returns the default state if the file is missing or corrupt.
+    pub fn load() -> Self {
+        let Some(path) = state_path() else {
+            warn!("Config directory is not found; the state is not restored");
+            return State::default();
+        };
+        migrate_legacy_state(&path);
+
+        match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|err| {
+                warn!("Ignored corrupt state file {}: {err}", path.display());
+                State::default()
+            }),
+            Err(_) => State::default(),
+        }
+    }
+
+    /// Save the state file atomically, so that it is never left half-written.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = state_path().context("config directory is not found")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temp_path, &path)?;
+
+        Ok(())
+    }
+
+    pub fn profile(&self, scenario: &Path) -> Profile {
+        self.profiles
+            .get(&profile_key(scenario))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Modify the profile of the scenario and save the state.
+    pub fn update_profile(scenario: &Path, f: impl FnOnce(&mut Profile)) {
+        State::update(|state| f(state.profiles.entry(profile_key(scenario)).or_default()));
+    }
+
+    /// Modify the state and save it.
+    ///
+    /// The state is loaded again so that changes made by other instances in the meantime are kept.
+    pub fn update(f: impl FnOnce(&mut State)) {
+        let mut state = State::load();
+        f(&mut state);
+
+        if let Err(err) = state.save() {
+            warn!("Failed to save the state: {err}");
+        }
+    }
+}
+
+/// Override the config directory. This should be called before loading the state.
+pub fn set_config_dir(dir: PathBuf) {
+    CONFIG_DIR.set(dir).ok();
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    match CONFIG_DIR.get() {
+        Some(dir) => Some(dir.clone()),
+        None => Some(dirs::config_dir()?.join("pedoni")),
+    }
+}
+
+fn state_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("state.json"))
+}
+
+/// Move the state file from `~/.pedoni` used by older versions.
+fn migrate_legacy_state(path: &Path) {
+    let Some(legacy_path) = dirs::home_dir().map(|dir| dir.join(".pedoni").join("state.json"))
+    else {
+        return;
+    };
+    if path.exists() || !legacy_path.exists() {
+        return;
+    }
+
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::rename(&legacy_path, path));
+    match result {
+        Ok(_) => info!(
+            "Moved state file from {} to {}",
+            legacy_path.display(),
+            path.display()
+        ),
+        Err(err) => warn!("Failed to move legacy state file: {err}"),
+    }
+}
+
+fn profile_key(scenario: &Path) -> String {
+    scenario
+        .canonicalize()
+        .unwrap_or_else(|_| scenario.to_path_buf())
+        .display()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::Backend;
+
+    use super::State;
+
+    #[test]
+    fn test_state() {
+        // States saved by older versions have no window.
+        let state: State = serde_json::from_str(
+            r#"{
+                "profiles": {
+                    "/scenarios/bottleneck.toml": {
+                        "camera": { "target": [10.0, 5.0], "scale": 0.1 },
+                        "playback_speed": 8.0,
+                        "backend": "Gpu"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(state.window.is_none());
+
+        // The state is saved and loaded back as it is.
+        let state: State = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let profile = &state.profiles["/scenarios/bottleneck.toml"];
+        let camera = profile.camera.unwrap();
+        assert_eq!((camera.target, camera.scale), ([10.0, 5.0], 0.1));
+        assert_eq!(profile.playback_speed, Some(8.0));
+        assert!(matches!(profile.backend, Some(Backend::Gpu)));
+    }
+}
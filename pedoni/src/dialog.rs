@@ -0,0 +1,62 @@
+use std::{io, path::PathBuf, process::Command};
+
+use log::warn;
+
+/// Script showing the file dialog of Windows Forms, which prints the chosen path.
+const POWERSHELL_SCRIPT: &str = "Add-Type -AssemblyName System.Windows.Forms; \
+    $dialog = New-Object System.Windows.Forms.OpenFileDialog; \
+    $dialog.Title = 'Open scenario'; \
+    $dialog.Filter = 'Scenario (*.toml)|*.toml|All files (*.*)|*.*'; \
+    if ($dialog.ShowDialog() -eq 'OK') { $dialog.FileName }";
+
+/// Ask the user to choose a scenario file with a dialog of the platform, blocking until it is closed.
+///
+/// The dialog is shown by an external command so that no GUI toolkit is linked: zenity or kdialog on Linux,
+/// AppleScript on macOS and PowerShell on Windows. Returns `None` if it is cancelled or no command is available.
+pub fn pick_scenario() -> Option<PathBuf> {
+    let commands: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[(
+            "osascript",
+            &[
+                "-e",
+                "POSIX path of (choose file with prompt \"Open scenario\")",
+            ],
+        )]
+    } else if cfg!(windows) {
+        &[(
+            "powershell",
+            &["-NoProfile", "-STA", "-Command", POWERSHELL_SCRIPT],
+        )]
+    } else {
+        &[
+            (
+                "zenity",
+                &[
+                    "--file-selection",
+                    "--title=Open scenario",
+                    "--file-filter=Scenario | *.toml",
+                    "--file-filter=All files | *",
+                ],
+            ),
+            ("kdialog", &["--getopenfilename", ".", "*.toml"]),
+        ]
+    };
+
+    for (program, args) in commands {
+        match Command::new(program).args(*args).output() {
+            Ok(output) => {
+                // Dialogs exit with an error status when cancelled.
+                let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                return (output.status.success() && !path.is_empty()).then(|| PathBuf::from(path));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                warn!("Failed to show the file dialog with {program}: {err}");
+                return None;
+            }
+        }
+    }
+
+    warn!("No file dialog is available; install zenity or kdialog, or pass the scenario as an argument");
+    None
+}
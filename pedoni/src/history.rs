@@ -0,0 +1,68 @@
+use pedoni_simulator::diagnostic::StepMetrics;
+
+/// Max number of points kept, beyond which adjacent points are merged.
+const CAPACITY: usize = 512;
+
+/// Metrics of a range of steps, plotted over the run by the renderer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HistoryPoint {
+    /// First step of the range
+    pub start: usize,
+    /// Last step of the range
+    pub step: usize,
+    /// Number of active pedestrians at the last step
+    pub active_ped_count: i32,
+    /// Max computation time of a step in the range (milliseconds)
+    pub step_time: f64,
+}
+
+/// Metrics over the whole run kept in a bounded buffer, merging points of twice as many steps each time it fills up.
+#[derive(Debug)]
+pub struct History {
+    pub points: Vec<HistoryPoint>,
+    /// Steps covered by each point
+    stride: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            points: Vec::with_capacity(CAPACITY + 1),
+            stride: 1,
+        }
+    }
+}
+
+impl History {
+    pub fn push(&mut self, step: usize, metrics: &StepMetrics) {
+        let point = HistoryPoint {
+            start: step,
+            step,
+            active_ped_count: metrics.active_ped_count,
+            step_time: metrics.time_total() * 1e3,
+        };
+
+        match self.points.last_mut() {
+            Some(last) if step < last.start + self.stride => *last = merge(*last, point),
+            _ => self.points.push(point),
+        }
+
+        if self.points.len() > CAPACITY {
+            self.points = self
+                .points
+                .chunks(2)
+                .map(|pair| pair.iter().copied().reduce(merge).unwrap())
+                .collect();
+            self.stride *= 2;
+        }
+    }
+}
+
+fn merge(a: HistoryPoint, b: HistoryPoint) -> HistoryPoint {
+    HistoryPoint {
+        start: a.start,
+        step: b.step,
+        active_ped_count: b.active_ped_count,
+        step_time: a.step_time.max(b.step_time),
+    }
+}
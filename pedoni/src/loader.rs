@@ -0,0 +1,71 @@
+use std::{
+    path::{Path, PathBuf},
+    thread,
+};
+
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use pedoni_simulator::{
+    error,
+    field::{Field, FieldBuilder, FieldProgress},
+    scenario::Scenario,
+    Simulator, SimulatorOptions,
+};
+
+use crate::{runner, Scene};
+
+/// Loader installed at startup, with which other scenarios are opened while the app runs.
+static LOADER: OnceCell<Loader> = OnceCell::new();
+
+/// Settings to build simulators of scenarios.
+#[derive(Debug, Clone)]
+pub struct Loader {
+    pub options: SimulatorOptions,
+    /// Directory to cache built fields in
+    pub field_cache: Option<PathBuf>,
+}
+
+impl Loader {
+    pub fn build_fields(&self, scenario: &Scenario) -> error::Result<Vec<Field>> {
+        let options = &self.options;
+        let mut builder = FieldBuilder::new(scenario.field.size, options.field_grid_unit)
+            .fmm_options(options.fmm)
+            .on_progress(|FieldProgress { done, total }| info!("Built field maps: {done}/{total}"));
+        if let Some(dir) = &self.field_cache {
+            builder = builder.cache_dir(dir);
+        }
+        builder.build_scenario(scenario)
+    }
+
+    /// Read the scenario file and build its simulator and scene.
+    pub fn load(&self, path: &Path) -> anyhow::Result<(Simulator, Scene)> {
+        let scenario = Scenario::from_file(path)?;
+        let fields = self.build_fields(&scenario)?;
+        let simulator = Simulator::with_fields(self.options.clone(), scenario, fields)?;
+        let scene = Scene::new(path, &simulator);
+        Ok((simulator, scene))
+    }
+
+    /// Keep the loader to open other scenarios with [`open`].
+    pub fn install(self) {
+        LOADER.set(self).ok();
+    }
+}
+
+/// Load the scenario in the background and switch the runner to it.
+///
+/// The current scenario keeps running while the field is built, and if the file turns out to be invalid.
+pub fn open(path: PathBuf) {
+    let Some(loader) = LOADER.get() else {
+        warn!("Scenarios cannot be opened before the loader is installed");
+        return;
+    };
+
+    thread::spawn(move || {
+        info!("Open scenario {}", path.display());
+        match loader.load(&path) {
+            Ok((simulator, scene)) => runner::replace(simulator, scene),
+            Err(err) => warn!("Failed to open {}: {err:#}", path.display()),
+        }
+    });
+}
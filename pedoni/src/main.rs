@@ -1,45 +1,183 @@
 mod args;
+mod clock;
+mod config;
+mod dialog;
+mod history;
+mod loader;
+mod metrics;
 pub mod renderer;
+mod run_dir;
+mod runner;
 
 use std::{
     fs::{self, File},
-    path::PathBuf,
-    sync::{atomic::AtomicBool, Mutex},
+    io::BufWriter,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
     thread,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use args::Args;
+use args::{Args, Command, OutOfBoundsPolicy, TrajectoryFormat};
 use clap::Parser;
+use clock::ExternalClock;
+use glam::{vec2, Vec2};
+use history::HistoryPoint;
+use loader::Loader;
 use log::{info, warn};
 use once_cell::sync::Lazy;
+#[cfg(feature = "parquet")]
+use pedoni_simulator::arrow_export::{self, ParquetTrajectoryWriter};
 use pedoni_simulator::{
-    diagnostic::DiagnositcLog, models::Pedestrian, scenario::Scenario, Simulator,
+    calibration, connectivity,
+    diagnostic::DiagnositcLog,
+    dry_run::{self, Route},
+    exit_capacity,
+    generator::Geometry,
+    models::{ForceBreakdown, Pedestrian},
+    replication,
+    scenario::Scenario,
+    sensor::SensorLog,
+    snapshot::Snapshot,
+    speed_density::SpeedDensityExporter,
+    trajectory::{self, CompactTrajectoryWriter, TrajectoryWriter},
+    Simulator, SimulatorOptions,
 };
+use run_dir::RunDir;
+use runner::{Autosave, RunMode, Runner};
 
-static SIMULATOR_STATE: Lazy<Mutex<SimulatorState>> =
-    Lazy::new(|| Mutex::new(SimulatorState::default()));
+/// Parts of the simulation which do not change while it runs, set before the runner starts and replaced when another
+/// scenario is opened.
+static SCENE: RwLock<Option<Arc<Scene>>> = RwLock::new(None);
+/// Pedestrians in the latest step, shared with the renderer without locking.
+static PEDESTRIANS: Lazy<Snapshot<Pedestrian>> = Lazy::new(Snapshot::default);
+/// Forces acting on pedestrians in the latest step, empty unless forces are recorded.
+static FORCES: Lazy<Snapshot<ForceBreakdown>> = Lazy::new(Snapshot::default);
+/// Centers of cells with crowd pressure and their values in the latest step, empty unless the pressure is recorded.
+static PRESSURE: Lazy<Snapshot<(Vec2, f32)>> = Lazy::new(Snapshot::default);
+/// Metrics over the run, plotted by the renderer.
+static HISTORY: Lazy<Snapshot<HistoryPoint>> = Lazy::new(Snapshot::default);
+/// Number of steps simulated so far
+static TOTAL_STEPS: AtomicUsize = AtomicUsize::new(0);
+/// Metrics of every step. Only the runner and the exporter lock it, so the renderer never waits for a step.
+static DIAGNOSTIC_LOG: Lazy<Mutex<DiagnositcLog>> = Lazy::new(Default::default);
 static CONTROL_STATE: Mutex<ControlState> = Mutex::new(ControlState {
-    paused: true,
     playback_speed: 4.0,
 });
 static SIG_INT: AtomicBool = AtomicBool::new(false);
 
 pub const DELTA_TIME: f32 = 0.1;
+/// Steps of each replication without --max-steps, i.e. an hour
+const REPLICATION_MAX_STEPS: usize = 36000;
 
-#[derive(Default)]
-pub struct SimulatorState {
-    pub pedestrians: Vec<Pedestrian>,
+pub struct Scene {
     pub scenario: Scenario,
-    pub diagnostic_log: DiagnositcLog,
+    /// Path to the scenario file
+    pub scenario_name: String,
+    pub model: &'static str,
+    /// Centers of obstacle cells in the rasterized field of each level, and their size
+    pub obstacle_cells: (Vec<Vec<Vec2>>, f32),
+    /// Centers of cells in spawn areas of each level from which destinations are unreachable, highlighted as warnings
+    pub blocked_cells: Vec<Vec<Vec2>>,
+    /// Desired directions sampled on each level toward each destination, i.e. waypoints and waypoint groups. See [`pedoni_simulator::field::Field::sample_directions`].
+    pub directions: Vec<Vec<Vec<(Vec2, Vec2)>>>,
+    /// Spacing of the samples in [`Scene::directions`] (meters)
+    pub direction_spacing: f32,
+}
+
+impl Scene {
+    pub fn new(path: &Path, simulator: &Simulator) -> Self {
+        let unit = simulator.fields[0].unit;
+        let cells = simulator
+            .fields
+            .iter()
+            .map(|field| {
+                field
+                    .obstacle_exist
+                    .indexed_iter()
+                    .filter(|(_, &exist)| exist)
+                    .map(|((y, x), _)| (vec2(x as f32, y as f32) + 0.5) * unit)
+                    .collect()
+            })
+            .collect();
+        // Keep the samples within about 100 x 100 per waypoint.
+        let size = simulator.scenario.field.size;
+        let direction_spacing = (size.max_element() / 100.0).max(1.0);
+        let directions = simulator
+            .fields
+            .iter()
+            .map(|field| {
+                (0..simulator.scenario.destination_count())
+                    .map(|i| field.sample_directions(i, direction_spacing))
+                    .collect()
+            })
+            .collect();
+        let mut blocked_cells = vec![Vec::new(); simulator.fields.len()];
+        for route in &simulator.unreachable {
+            blocked_cells[route.level].extend_from_slice(&route.blocked_cells);
+        }
+
+        Scene {
+            scenario: simulator.scenario.clone(),
+            scenario_name: path.display().to_string(),
+            model: simulator.model.name(),
+            obstacle_cells: (cells, unit),
+            blocked_cells,
+            directions,
+            direction_spacing,
+        }
+    }
+}
+
+/// Get the current scene.
+pub fn scene() -> Arc<Scene> {
+    SCENE
+        .read()
+        .unwrap()
+        .clone()
+        .expect("the scene is set before the runner starts")
+}
+
+fn set_scene(scene: Scene) {
+    *SCENE.write().unwrap() = Some(Arc::new(scene));
 }
 
 #[derive(Clone)]
 pub struct ControlState {
-    pub paused: bool,
     pub playback_speed: f32,
 }
 
+/// Print specific flows through the standard bottleneck, fitting the behavior first if a target is given.
+fn calibrate(args: &Args, options: &SimulatorOptions, scenario: &Scenario) -> anyhow::Result<()> {
+    let (behavior, samples) = match args.calibrate_target {
+        Some(target) => {
+            calibration::fit_repulsion_range(options, &scenario.behavior, &args.calibrate, target)?
+        }
+        None => {
+            let samples = calibration::measure(options, &scenario.behavior, &args.calibrate)?;
+            (scenario.behavior.clone(), samples)
+        }
+    };
+
+    println!("width (m)  specific flow (/m/s)");
+    for sample in samples {
+        match sample.specific_flow {
+            Some(flow) => println!("{:>9.2}  {flow:>20.3}", sample.width),
+            None => println!("{:>9.2}  {:>20}", sample.width, "-"),
+        }
+    }
+    if args.calibrate_target.is_some() {
+        println!(
+            "Fitted behavior:\n[behavior]\nrepulsion_range = {:.3}",
+            behavior.repulsion_range()
+        );
+    }
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     env_logger::builder()
         .filter_module("pedoni", log::LevelFilter::Info)
@@ -49,11 +187,43 @@ fn main() -> anyhow::Result<()> {
         warn!("Debug build");
     }
 
-    let args = Args::parse();
-    CONTROL_STATE.lock().unwrap().playback_speed = args.speed;
+    let mut args = Args::parse();
+
+    if let Some(Command::Gen { geometry, output }) = &args.command {
+        let toml = Geometry::from(*geometry).to_toml();
+        match output {
+            Some(path) => {
+                fs::write(path, toml)?;
+                info!("Wrote the scenario to {}", path.display());
+            }
+            None => print!("{toml}"),
+        }
+        return Ok(());
+    }
+
+    if args.list_devices {
+        for device in pedoni_simulator::models::list_gpu_devices()? {
+            println!(
+                "{}:{} {} ({})",
+                device.platform, device.device, device.name, device.platform_name
+            );
+        }
+        return Ok(());
+    }
+
+    // Restore settings used for the scenario last time.
+    if let Some(dir) = &args.config_dir {
+        config::set_config_dir(dir.clone());
+    }
+    let state = config::State::load();
+    let profile = state.profile(&args.scenario);
+    args.backend = args.backend.or(profile.backend);
+    args.speed = args.speed.or(profile.playback_speed);
+    config::State::update_profile(&args.scenario, |p| p.backend = args.backend);
+
+    CONTROL_STATE.lock().unwrap().playback_speed = args.speed.unwrap_or(100.0);
 
-    let scenario: Scenario = toml::from_str(&fs::read_to_string(&args.scenario)?)?;
-    SIMULATOR_STATE.lock().unwrap().scenario = scenario.clone();
+    let scenario = Scenario::from_file(&args.scenario)?;
 
     // {
     //     let ts: Vec<i32> = (0..20)
@@ -76,59 +246,211 @@ fn main() -> anyhow::Result<()> {
     //     return Ok(());
     // }
 
-    let mut simulator = Simulator::new(args.to_simulator_options(), scenario);
-
-    thread::spawn(move || loop {
-        let start = Instant::now();
-        let state = CONTROL_STATE.lock().unwrap().clone();
-
-        if !state.paused {
-            let step_metrics = simulator.tick();
-            if simulator.step % 100 == 0 {
-                info!(
-                    "Step: {:6}, Active pedestrians: {:6}",
-                    simulator.step, step_metrics.active_ped_count
-                );
-            }
-
-            let mut state = SIMULATOR_STATE.lock().unwrap();
-            state.pedestrians = simulator.list_pedestrians();
-            state.diagnostic_log.push(step_metrics);
+    let mut options = args.to_simulator_options();
+    if !args.calibrate.is_empty() {
+        return calibrate(&args, &options, &scenario);
+    }
+    let mut loader = Loader {
+        options: options.clone(),
+        field_cache: args.field_cache.clone(),
+    };
+    let fields = loader.build_fields(&scenario)?;
+    if args.auto_tune {
+        options = pedoni_simulator::tuning::auto_tune(
+            &options,
+            &scenario,
+            &fields,
+            args.auto_tune_steps,
+        )?;
+        loader.options = options.clone();
+    }
+    if args.dry_run {
+        let report = dry_run::inspect(&scenario, &fields);
+        for warning in report.unreachable_routes().filter_map(Route::warning) {
+            warn!("{warning}");
+        }
+        println!("{report}");
+        return Ok(());
+    }
+    if let Some(Command::AnalyzeScenario) = args.command {
+        let graph = connectivity::analyze(&scenario, &fields);
+        for edge in graph.edges.iter().filter(|edge| edge.is_narrow()) {
+            warn!(
+                "The route from waypoint {} to destination {} passes a gap narrower than a person",
+                edge.from, edge.to
+            );
         }
+        println!("{graph}");
+        return Ok(());
+    }
+    if let Some(replications) = args.replications {
+        let max_steps = args.max_steps.unwrap_or(REPLICATION_MAX_STEPS) as i32;
+        let summary = replication::replicate(
+            &options,
+            &scenario,
+            &fields,
+            replications,
+            args.seed.unwrap_or(0),
+            max_steps,
+        )?;
+        println!("{summary}");
+        return Ok(());
+    }
+    // Headless runs can be reproduced from the seed in the manifest even if no seed is given.
+    if args.headless && options.seed.is_none() {
+        let seed = fastrand::u64(..);
+        info!("Seed: {seed}");
+        options.seed = Some(seed);
+    }
+    let seed = options.seed;
+    let simulator = Simulator::with_fields(options, scenario, fields)?;
+    {
+        let log = &mut DIAGNOSTIC_LOG.lock().unwrap();
+        log.model = simulator.model.name().to_string();
+        log.scenario = args.scenario.display().to_string();
+        log.warmup_steps = simulator.options.warmup_steps.max(0) as usize;
+    }
+    set_scene(Scene::new(&args.scenario, &simulator));
+    loader.install();
 
-        let step_time = Instant::now() - start;
-        let min_interval = Duration::from_secs_f32(DELTA_TIME / state.playback_speed);
-        if step_time < min_interval {
-            thread::sleep(min_interval - step_time);
+    let mut mode = args.run_mode();
+    if args.headless && matches!(mode, RunMode::StepsPerFrame(_)) {
+        warn!("No frames are drawn in headless mode; run at max speed instead");
+        mode = RunMode::MaxSpeed;
+    }
+    if let Some(addr) = args.metrics_addr {
+        metrics::serve(addr)?;
+    }
+    let run_dir = RunDir::new(args.out_dir.clone(), &args.scenario);
+    let clock = matches!(mode, RunMode::ExternalClock)
+        .then(|| ExternalClock::connect(args.clock))
+        .transpose()?;
+    let speed_density = match &args.speed_density {
+        Some(path) => {
+            let path = run_dir.file(path)?;
+            info!("Export speeds and densities to {}", path.display());
+            let writer = BufWriter::new(File::create(path)?);
+            Some(SpeedDensityExporter::new(writer, args.speed_density_every)?)
+        }
+        None => None,
+    };
+    let sensor_log = match &args.sensor_log {
+        Some(path) => {
+            let path = run_dir.file(path)?;
+            info!("Write sensor readings to {}", path.display());
+            Some(SensorLog::new(BufWriter::new(File::create(path)?))?)
+        }
+        None => None,
+    };
+    let (mut trajectories, mut compact_trajectories) = (None, None);
+    #[cfg(feature = "parquet")]
+    let mut parquet_trajectories = None;
+    if let Some(path) = &args.trajectories {
+        let path = run_dir.file(path)?;
+        info!("Write trajectories to {}", path.display());
+        let writer = BufWriter::new(File::create(path)?);
+        match args.trajectories_format {
+            TrajectoryFormat::Csv => {
+                trajectories = Some(TrajectoryWriter::new(writer, args.trajectories_every)?);
+            }
+            TrajectoryFormat::Compact => {
+                let quantum = trajectory::compact_quantum(simulator.scenario.field.size);
+                compact_trajectories = Some(CompactTrajectoryWriter::new(
+                    writer,
+                    args.trajectories_every,
+                    quantum,
+                )?);
+            }
+            #[cfg(feature = "parquet")]
+            TrajectoryFormat::Parquet => {
+                parquet_trajectories = Some(ParquetTrajectoryWriter::new(
+                    writer,
+                    args.trajectories_every,
+                )?);
+            }
         }
-    });
+    }
+    // Headless runs write the log at the end by themselves.
+    let autosave = if args.headless {
+        None
+    } else {
+        let path = run_dir.file("log.json")?;
+        info!("Save the log to {} on exit", path.display());
+        let interval =
+            (args.autosave_every > 0.0).then(|| Duration::from_secs_f32(args.autosave_every));
+        Some(Autosave::new(path, interval))
+    };
+    let model = simulator.model.name();
+    Runner {
+        mode,
+        clock,
+        log_every: args.log_every,
+        log_format: args.log_format(),
+        history: Default::default(),
+        speed_density,
+        sensor_log,
+        trajectories,
+        compact_trajectories,
+        #[cfg(feature = "parquet")]
+        parquet_trajectories,
+        autosave,
+    }
+    .spawn(simulator);
 
     if args.headless {
         info!("Run as headless mode");
-        ctrlc::set_handler(|| SIG_INT.store(true, std::sync::atomic::Ordering::SeqCst))?;
+        ctrlc::set_handler(|| SIG_INT.store(true, Ordering::SeqCst))?;
 
-        CONTROL_STATE.lock().unwrap().paused = false;
+        runner::handle().start();
 
         loop {
-            if SIG_INT.load(std::sync::atomic::Ordering::SeqCst)
-                || args.max_steps.is_some_and(|limit| {
-                    SIMULATOR_STATE.lock().unwrap().diagnostic_log.total_steps > limit
-                })
+            // Runs are stopped as soon as a pedestrian leaves the field with `--out-of-bounds error`.
+            let left_field = matches!(args.out_of_bounds, OutOfBoundsPolicy::Error)
+                && metrics::out_of_bounds_total() > 0;
+            // The simulation thread stops by itself when a step fails, e.g. because the GPU device was lost.
+            let failure = runner::handle().take_error();
+            if SIG_INT.load(Ordering::SeqCst)
+                || left_field
+                || failure.is_some()
+                || args
+                    .max_steps
+                    .is_some_and(|limit| TOTAL_STEPS.load(Ordering::Relaxed) > limit)
             {
-                let current_time = chrono::Local::now();
-                fs::create_dir("logs").ok();
-                let log_path: PathBuf = [
-                    "logs",
-                    &current_time.format("%Y-%m-%d_%H%M%S_log.json").to_string(),
-                ]
-                .iter()
-                .collect();
+                // Stop stepping so that outputs end at the same step as the log.
+                runner::handle().stop();
+                runner::save_outputs();
+                let log_path = run_dir.file("log.json")?;
                 let mut log_file = File::create(&log_path)?;
-                let state = SIMULATOR_STATE.lock().unwrap();
+                let log = DIAGNOSTIC_LOG.lock().unwrap();
 
-                serde_json::to_writer(&mut log_file, &state.diagnostic_log)?;
+                serde_json::to_writer(&mut log_file, &*log)?;
                 info!("Exported log file: {}", log_path.display());
+                #[cfg(feature = "parquet")]
+                if args.log_parquet {
+                    let path = run_dir.file("log.parquet")?;
+                    arrow_export::write_step_metrics(&log, BufWriter::new(File::create(&path)?))?;
+                    info!("Exported metrics of steps to {}", path.display());
+                }
+                run_dir.write_manifest(&args.scenario, model, seed)?;
+                info!("Outputs of the run are in {}", run_dir.path.display());
+                info!("Summary of the run\n{}", log.summary());
 
+                let report =
+                    exit_capacity::report(&scene().scenario, &log.step_metrics.exit_counts);
+                if !report.exits.is_empty() {
+                    info!("Flows through exits against the design code\n{report}");
+                    if !report.passed() {
+                        warn!("Flows through some exits exceed the capacity of the design code");
+                    }
+                }
+
+                if let Some(e) = failure {
+                    return Err(e.into());
+                }
+                anyhow::ensure!(
+                    !left_field,
+                    "Pedestrians left the field; check the geometry of the scenario"
+                );
                 break;
             }
 
@@ -139,10 +461,30 @@ fn main() -> anyhow::Result<()> {
             r#"
 How to use
 - Press SPACE to pause/resume simulation
+- Press F to show/hide forces acting on pedestrians (requires --record-forces)
+- Press O to show/hide obstacle cells of the rasterized field
+- Press P to show/hide crowd pressure (requires --record-crowd-pressure)
+- Press H to show/hide the HUD
+- Press L to show/hide indices of waypoints
+- Press K to show/hide the legend of colors of pedestrians
+- Press T to show/hide the plot of metrics over the run, and click it to read the metrics of a step (it does not seek)
+- Press V to show desired directions toward each destination in turn
+- Press G to cycle through levels
+- Press C to switch coloring of pedestrians (destination, speed, density, group, ID)
+- Press M to toggle the ruler, and click two points to measure the distance with grid cells and potentials along it
+- Press Ctrl+O or click the button below the HUD to open another scenario
+- Press Ctrl+R to reload the scenario from the file
+- Drag with Shift and left mouse button to select pedestrians, which stay highlighted
+- Press 0-9 to send the selected pedestrians to the destination, Delete to despawn them and Escape to deselect them
 - Drag with middle mouse button to pan
 - Scroll to zoom"#
         );
-        renderer::run();
+        let mut window = state.window.unwrap_or_default();
+        if let Some([width, height]) = args.window_size {
+            window.width = width;
+            window.height = height;
+        }
+        renderer::run(args.scenario.clone(), profile.camera, window);
     }
 
     Ok(())
@@ -0,0 +1,199 @@
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use pedoni_simulator::diagnostic::StepMetrics;
+
+/// Totals and latest values of step metrics, exposed on `/metrics` by [`serve`].
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    steps: 0,
+    active_pedestrians: 0,
+    spawn_queue_length: 0,
+    spawned: 0,
+    refused: 0,
+    arrived: 0,
+    despawned: 0,
+    out_of_bounds: 0,
+    last_times: [None; 3],
+    total_times: [0.0; 3],
+});
+
+/// Phases of a step whose times are exported, in the order of [`Metrics::last_times`].
+const PHASES: [&str; 3] = ["spawn", "calc_state", "kernel"];
+/// Time to wait for a client to send its request or receive the response, so that a stalled client does not block
+/// the others
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Metrics {
+    steps: u64,
+    active_pedestrians: i32,
+    spawn_queue_length: i32,
+    spawned: u64,
+    refused: u64,
+    arrived: u64,
+    despawned: u64,
+    out_of_bounds: u64,
+    /// Time of each phase in the latest step (seconds)
+    last_times: [Option<f64>; 3],
+    /// Cumulative time of each phase (seconds)
+    total_times: [f64; 3],
+}
+
+/// Accumulate metrics of a step.
+pub fn record(step_metrics: &StepMetrics) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.steps += 1;
+    metrics.active_pedestrians = step_metrics.active_ped_count;
+    metrics.spawn_queue_length = step_metrics.spawn_queue_length;
+    metrics.spawned += step_metrics.spawned_count as u64;
+    metrics.refused += step_metrics.refused_count as u64;
+    metrics.arrived += step_metrics.arrived_count as u64;
+    metrics.despawned += step_metrics.despawned_count as u64;
+    metrics.out_of_bounds += step_metrics.out_of_bounds_count as u64;
+
+    let times = [
+        Some(step_metrics.time_spawn),
+        Some(step_metrics.time_calc_state),
+        step_metrics.time_calc_state_kernel,
+    ];
+    for (i, time) in times.into_iter().enumerate() {
+        metrics.last_times[i] = time;
+        metrics.total_times[i] += time.unwrap_or(0.0);
+    }
+}
+
+/// Number of pedestrians which left the field so far
+pub fn out_of_bounds_total() -> u64 {
+    METRICS.lock().unwrap().out_of_bounds
+}
+
+/// Serve metrics in the Prometheus text format on `http://{addr}/metrics` from a background thread.
+pub fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on http://{addr}/metrics");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(respond);
+            if let Err(e) = result {
+                warn!("Failed to serve metrics: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", render()),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Format the current metrics in the Prometheus text exposition format.
+fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+        writeln!(text, "# HELP pedoni_{name} {help}").unwrap();
+        writeln!(text, "# TYPE pedoni_{name} {kind}").unwrap();
+        for (labels, value) in samples {
+            writeln!(text, "pedoni_{name}{labels} {value}").unwrap();
+        }
+    };
+
+    let value = |v: f64| [("", v)];
+    metric(
+        "steps_total",
+        "counter",
+        "Number of simulated steps",
+        &value(metrics.steps as f64),
+    );
+    metric(
+        "active_pedestrians",
+        "gauge",
+        "Number of pedestrians in the field",
+        &value(metrics.active_pedestrians as f64),
+    );
+    metric(
+        "spawn_queue_length",
+        "gauge",
+        "Number of pedestrians waiting to be spawned",
+        &value(metrics.spawn_queue_length as f64),
+    );
+    metric(
+        "spawned_total",
+        "counter",
+        "Number of pedestrians which entered the field",
+        &value(metrics.spawned as f64),
+    );
+    metric(
+        "refused_total",
+        "counter",
+        "Number of pedestrians refused since the field was full",
+        &value(metrics.refused as f64),
+    );
+    metric(
+        "arrived_total",
+        "counter",
+        "Number of pedestrians which arrived at their destinations",
+        &value(metrics.arrived as f64),
+    );
+    metric(
+        "despawned_total",
+        "counter",
+        "Number of pedestrians removed by kill zones or requests",
+        &value(metrics.despawned as f64),
+    );
+    metric(
+        "out_of_bounds_total",
+        "counter",
+        "Number of times pedestrians were found outside the field",
+        &value(metrics.out_of_bounds as f64),
+    );
+
+    let labels: Vec<String> = PHASES
+        .iter()
+        .map(|phase| format!("{{phase=\"{phase}\"}}"))
+        .collect();
+    let last: Vec<(&str, f64)> = labels
+        .iter()
+        .zip(metrics.last_times)
+        .filter_map(|(labels, time)| Some((labels.as_str(), time?)))
+        .collect();
+    metric(
+        "step_time_seconds",
+        "gauge",
+        "Time spent on each phase of the latest step",
+        &last,
+    );
+    let total: Vec<(&str, f64)> = labels
+        .iter()
+        .zip(metrics.total_times)
+        .map(|(labels, time)| (labels.as_str(), time))
+        .collect();
+    metric(
+        "step_time_seconds_total",
+        "counter",
+        "Cumulative time spent on each phase of steps",
+        &total,
+    );
+
+    text
+}
@@ -0,0 +1,78 @@
+use std::{sync::atomic::Ordering, time::Instant};
+
+use crate::{Scene, DELTA_TIME, TOTAL_STEPS};
+
+/// Heads-up display showing the scenario, the model and performance.
+pub struct Hud {
+    pub visible: bool,
+    last_frame: Instant,
+    fps: f32,
+    last_sample: Instant,
+    last_step: usize,
+    steps_per_sec: f32,
+    /// Key numbers of the run sampled every second, shown in the status bar even while the HUD is hidden
+    pub status: String,
+}
+
+impl Hud {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Hud {
+            visible: true,
+            last_frame: now,
+            fps: 0.0,
+            last_sample: now,
+            last_step: 0,
+            steps_per_sec: 0.0,
+            status: String::new(),
+        }
+    }
+
+    /// Update frame rate, step rate and the status with the number of active pedestrians. This should be called
+    /// every frame.
+    pub fn update(&mut self, total_steps: usize, active_ped_count: usize) {
+        let now = Instant::now();
+
+        let frame_time = (now - self.last_frame).as_secs_f32();
+        self.last_frame = now;
+        if frame_time > 0.0 {
+            self.fps = self.fps * 0.9 + frame_time.recip() * 0.1;
+        }
+
+        // Steps per second is sampled every second since steps do not advance every frame.
+        let elapsed = (now - self.last_sample).as_secs_f32();
+        if elapsed >= 1.0 {
+            self.steps_per_sec = total_steps.saturating_sub(self.last_step) as f32 / elapsed;
+            self.last_sample = now;
+            self.last_step = total_steps;
+            self.status = format!(
+                "Active: {active_ped_count}  Time: {:.0} s  Steps/s: {:.1}",
+                total_steps as f32 * DELTA_TIME,
+                self.steps_per_sec
+            );
+        }
+    }
+
+    /// Lines of the HUD, showing `level` as the level in view.
+    pub fn lines(&self, scene: &Scene, level: usize) -> Vec<String> {
+        let size = scene.scenario.field.size;
+        let total_steps = TOTAL_STEPS.load(Ordering::Relaxed);
+
+        vec![
+            format!("Scenario: {}", scene.scenario_name),
+            format!("Field: {:.1} x {:.1} m", size.x, size.y),
+            format!("Model: {}", scene.model),
+            match scene.scenario.levels.get(level) {
+                Some(config) => format!("Level: {level} ({})", config.name),
+                None => format!("Level: {level}"),
+            },
+            format!("dt: {DELTA_TIME} s"),
+            format!(
+                "Step: {}  Time: {:.1} s",
+                total_steps,
+                total_steps as f32 * DELTA_TIME
+            ),
+            format!("FPS: {:.1}  Steps/s: {:.1}", self.fps, self.steps_per_sec),
+        ]
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use glam::{vec2, Affine2, Mat2, Vec2};
+
+use super::{
+    font,
+    state::{Color, Instance, RenderState},
+    ColorMode, COLORS, MAX_COLOR_DENSITY, MAX_COLOR_SPEED,
+};
+use crate::Scene;
+
+/// Most entries listed, beyond which the rest are summarized in a line
+const MAX_ENTRIES: usize = 12;
+/// Number of bands in which the heatmap swatch is drawn
+const GRADIENT_STEPS: usize = 16;
+
+/// Sample of colors drawn before the text of an entry
+#[derive(Clone, Copy)]
+enum Swatch {
+    Solid(Color),
+    /// Heatmap from the lowest value at the left to the highest at the right, drawn wider than solid ones
+    Heatmap,
+}
+
+/// Panel at the top-right corner naming the scenario and what the colors of pedestrians stand for, so that
+/// screenshots of scenarios with multiple destinations can be read without the scenario file.
+pub struct Legend {
+    pub visible: bool,
+}
+
+impl Legend {
+    pub fn new() -> Self {
+        Legend { visible: false }
+    }
+
+    /// Draw the panel below the status bar of `top` pixels in the screen view of `state`.
+    pub fn draw(
+        &self,
+        state: &mut RenderState,
+        scene: &Scene,
+        color_mode: ColorMode,
+        screen: Vec2,
+        top: f32,
+        dpi_scale: f32,
+    ) {
+        if !self.visible {
+            return;
+        }
+
+        let scenario = &scene.scenario;
+        let entries: Vec<(Option<Swatch>, String)> = match color_mode {
+            ColorMode::Destination => (0..scenario.destination_count())
+                .map(|i| {
+                    let label = match i.checked_sub(scenario.waypoints.len()) {
+                        None => format!("Waypoint {i}"),
+                        Some(j) => {
+                            let members = &scenario.waypoint_groups[j].members;
+                            let members: Vec<_> = members.iter().map(usize::to_string).collect();
+                            format!("Waypoints {}", members.join(", "))
+                        }
+                    };
+                    (Some(Swatch::Solid(COLORS[i % COLORS.len()])), label)
+                })
+                .collect(),
+            ColorMode::Group => scenario
+                .pedestrians
+                .iter()
+                .enumerate()
+                .map(|(i, group)| {
+                    let destinations: Vec<_> = group
+                        .destination_shares()
+                        .iter()
+                        .map(|(id, _)| id.to_string())
+                        .collect();
+                    (
+                        Some(Swatch::Solid(COLORS[i % COLORS.len()])),
+                        format!("Group {i}: {} -> {}", group.origin, destinations.join(", ")),
+                    )
+                })
+                .collect(),
+            ColorMode::Speed => vec![(
+                Some(Swatch::Heatmap),
+                format!("Speed: 0 - {MAX_COLOR_SPEED} m/s"),
+            )],
+            ColorMode::Density => vec![(
+                Some(Swatch::Heatmap),
+                format!("Density: 0 - {MAX_COLOR_DENSITY} /m2"),
+            )],
+            ColorMode::Id => vec![(None, "Colored by ID".into())],
+        };
+
+        let name = Path::new(&scene.scenario_name)
+            .file_name()
+            .map_or(scene.scenario_name.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        let mut lines: Vec<(Option<Swatch>, String)> = vec![(None, name)];
+        let hidden = entries.len().saturating_sub(MAX_ENTRIES);
+        lines.extend(entries.into_iter().take(MAX_ENTRIES));
+        if hidden > 0 {
+            lines.push((None, format!("... and {hidden} more")));
+        }
+
+        let pixel = 2.0 * dpi_scale;
+        let margin = 8.0 * dpi_scale;
+        let line_height = (font::GLYPH_HEIGHT + 3) as f32 * pixel;
+        let swatch = font::GLYPH_HEIGHT as f32 * pixel;
+        let swatch_width = |kind: &Swatch| match kind {
+            Swatch::Solid(_) => swatch,
+            Swatch::Heatmap => swatch * 4.0,
+        };
+        let text_width = lines
+            .iter()
+            .map(|(kind, text)| {
+                let indent = kind
+                    .as_ref()
+                    .map_or(0.0, |kind| swatch_width(kind) + margin);
+                font::text_size(text, pixel).x + indent
+            })
+            .fold(0.0, f32::max);
+        let box_size = vec2(text_width, lines.len() as f32 * line_height) + margin * 2.0;
+        let origin = vec2(screen.x - box_size.x, screen.y - top - margin);
+
+        state.draw_rectangles(&[Instance::new(
+            Affine2::from_mat2_translation(
+                Mat2::from_diagonal(box_size),
+                origin + vec2(box_size.x, -box_size.y) * 0.5,
+            ),
+            Color::rgb(0.9, 0.9, 0.9),
+        )]);
+
+        let mut swatches = Vec::new();
+        let mut texts = Vec::new();
+        for (i, (kind, text)) in lines.iter().enumerate() {
+            let mut pos = origin + vec2(margin, -margin - i as f32 * line_height);
+            // Rectangle of the swatch height from its top-left corner
+            let rect = |pos: Vec2, width: f32, color| {
+                Instance::new(
+                    Affine2::from_mat2_translation(
+                        Mat2::from_diagonal(vec2(width, swatch)),
+                        pos + vec2(width, -swatch) * 0.5,
+                    ),
+                    color,
+                )
+            };
+            if let Some(kind) = kind {
+                match kind {
+                    Swatch::Solid(color) => swatches.push(rect(pos, swatch, *color)),
+                    Swatch::Heatmap => {
+                        let band = swatch_width(kind) / GRADIENT_STEPS as f32;
+                        swatches.extend((0..GRADIENT_STEPS).map(|j| {
+                            let t = (j as f32 + 0.5) / GRADIENT_STEPS as f32;
+                            rect(pos + vec2(band * j as f32, 0.0), band, Color::heatmap(t))
+                        }));
+                    }
+                }
+                pos.x += swatch_width(kind) + margin;
+            }
+            texts.extend(font::text_instances(text, pos, pixel, Color::BLACK));
+        }
+        state.draw_rectangles(&swatches);
+        state.draw_rectangles(&texts);
+    }
+}
@@ -1,10 +1,34 @@
+mod font;
+mod hud;
+mod legend;
+mod plot;
+mod ruler;
+mod selection;
 mod state;
 
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    thread,
+};
+
 use glam::{vec2, Affine2, Mat2, Vec2};
+use hud::Hud;
+use legend::Legend;
+use log::info;
 use miniquad::{EventHandler, KeyCode};
+use pedoni_simulator::{diagnostic::PRESSURE_GRID_UNIT, models::Pedestrian, util};
+use plot::Plot;
+use ruler::Ruler;
+use selection::Selection;
 use state::{Color, Instance, RenderState};
 
-use crate::{CONTROL_STATE, SIMULATOR_STATE};
+use crate::{
+    config::{self, Camera, WindowGeometry},
+    dialog, loader, runner, Scene, CONTROL_STATE, FORCES, HISTORY, PEDESTRIANS, PRESSURE,
+    TOTAL_STEPS,
+};
 
 const COLORS: &[Color] = &[
     Color::RED,
@@ -15,6 +39,68 @@ const COLORS: &[Color] = &[
     Color::YELLOW,
 ];
 
+/// Crowd pressure drawn in the hottest color, above which crowd disasters happen. (s^-2)
+const CRITICAL_PRESSURE: f32 = 0.02;
+
+/// Speed drawn in the hottest color when pedestrians are colored by speed. (m/s)
+const MAX_COLOR_SPEED: f32 = 1.8;
+/// Density drawn in the hottest color when pedestrians are colored by density. (m^-2)
+const MAX_COLOR_DENSITY: f32 = 4.0;
+
+/// Radius of pedestrians drawn as circles. (meters)
+const PEDESTRIAN_RADIUS: f32 = 0.2;
+/// Pedestrians smaller than this on screen are drawn as point sprites instead of circles. (pixels)
+const LOD_RADIUS: f32 = 2.0;
+
+/// Label of the button below the HUD
+const OPEN_BUTTON_LABEL: &str = "Open scenario (Ctrl+O)";
+
+/// Ratio of framebuffer pixels to the logical pixels in which the window size is given.
+///
+/// Windows are not scaled on X11, where the DPI scale only enlarges texts.
+fn window_scale() -> f32 {
+    if cfg!(target_os = "linux") {
+        1.0
+    } else {
+        miniquad::window::dpi_scale()
+    }
+}
+
+/// Property of pedestrians represented by their colors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorMode {
+    Destination,
+    Speed,
+    Density,
+    Group,
+    Id,
+}
+
+impl ColorMode {
+    fn next(self) -> Self {
+        match self {
+            ColorMode::Destination => ColorMode::Speed,
+            ColorMode::Speed => ColorMode::Density,
+            ColorMode::Density => ColorMode::Group,
+            ColorMode::Group => ColorMode::Id,
+            ColorMode::Id => ColorMode::Destination,
+        }
+    }
+
+    fn color(self, ped: &Pedestrian) -> Color {
+        match self {
+            ColorMode::Destination => COLORS[ped.destination % COLORS.len()],
+            ColorMode::Speed => Color::heatmap(ped.vel.length() / MAX_COLOR_SPEED),
+            ColorMode::Density => Color::heatmap(ped.density / MAX_COLOR_DENSITY),
+            ColorMode::Group => COLORS[ped.group % COLORS.len()],
+            ColorMode::Id => {
+                let hash = (ped.id as u32).wrapping_mul(2654435761);
+                Color::heatmap(hash as f32 / u32::MAX as f32)
+            }
+        }
+    }
+}
+
 pub struct Renderer {
     state: RenderState,
     view_target: Vec2,
@@ -23,14 +109,38 @@ pub struct Renderer {
     cursor_pos: Vec2,
     mouse_left_down: bool,
     mouse_center_down: bool,
+    shift_down: bool,
     wheel_delta: f32,
+    show_forces: bool,
+    show_obstacle_cells: bool,
+    show_pressure: bool,
+    show_labels: bool,
+    /// Level of [`Scenario::levels`](pedoni_simulator::scenario::Scenario::levels) being drawn
+    level: usize,
+    /// Destination whose desired directions are drawn as arrows
+    quiver_waypoint: Option<usize>,
+    color_mode: ColorMode,
+    hud: Hud,
+    legend: Legend,
+    plot: Plot,
+    ruler: Ruler,
+    selection: Selection,
+    /// Rectangle of the button to open another scenario in screen pixels from the top-left corner, if drawn
+    open_button: Option<(Vec2, Vec2)>,
+    /// Scene being drawn, compared with the current one to notice that another scenario is opened
+    scene: Arc<Scene>,
+    /// Path to the scenario file, whose profile saves the camera on exit
+    scenario_path: PathBuf,
 }
 
 impl Renderer {
-    pub fn new() -> Self {
-        let size = SIMULATOR_STATE.lock().unwrap().scenario.field.size;
-        let view_target = size * 0.5;
-        let view_scale = size.x.max(size.y).recip();
+    pub fn new(scenario_path: PathBuf, camera: Option<Camera>, window: WindowGeometry) -> Self {
+        if let Some([x, y]) = window.position {
+            miniquad::window::set_window_position(x, y);
+        }
+
+        let scene = crate::scene();
+        let (view_target, view_scale) = initial_view(&scene, camera);
 
         Renderer {
             state: RenderState::new(),
@@ -40,16 +150,94 @@ impl Renderer {
             cursor_pos: Vec2::ZERO,
             mouse_left_down: false,
             mouse_center_down: false,
+            shift_down: false,
             wheel_delta: 0.0,
+            show_forces: false,
+            show_obstacle_cells: false,
+            show_pressure: false,
+            show_labels: true,
+            level: 0,
+            quiver_waypoint: None,
+            color_mode: ColorMode::Destination,
+            hud: Hud::new(),
+            legend: Legend::new(),
+            plot: Plot::new(),
+            ruler: Ruler::new(),
+            selection: Selection::new(),
+            open_button: None,
+            scene,
+            scenario_path,
+        }
+    }
+
+    /// Save the camera and the playback speed in the profile of the scenario.
+    fn save_profile(&self) {
+        let camera = Camera {
+            target: self.view_target.to_array(),
+            scale: self.view_scale,
+        };
+        let playback_speed = CONTROL_STATE.lock().unwrap().playback_speed;
+
+        config::State::update_profile(&self.scenario_path, |p| {
+            p.camera = Some(camera);
+            p.playback_speed = Some(playback_speed);
+        });
+    }
+
+    /// Reset the view when another scenario is opened, restoring the camera saved for it if any.
+    fn follow_scene(&mut self) {
+        let scene = crate::scene();
+        if Arc::ptr_eq(&scene, &self.scene) {
+            return;
         }
+
+        self.save_profile();
+        self.scenario_path = PathBuf::from(&scene.scenario_name);
+        let camera = config::State::load().profile(&self.scenario_path).camera;
+        (self.view_target, self.view_scale) = initial_view(&scene, camera);
+        self.level = 0;
+        self.quiver_waypoint = None;
+        self.plot.reset();
+        self.ruler.clear();
+        self.selection.clear();
+        self.scene = scene;
     }
+
+    /// Convert a position on the screen in pixels from the top-left corner to the field in meters.
+    fn screen_to_world(&self, pos: Vec2) -> Vec2 {
+        let (width, height) = miniquad::window::screen_size();
+        let pixels_per_meter = self.view_scale * width * 0.5;
+        let offset = pos - vec2(width, height) * 0.5;
+        self.view_target + vec2(offset.x, -offset.y) / pixels_per_meter
+    }
+}
+
+/// Target and scale of the view, which fits the field if no camera is saved.
+fn initial_view(scene: &Scene, camera: Option<Camera>) -> (Vec2, f32) {
+    let size = scene.scenario.field.size;
+    match camera {
+        Some(camera) => (Vec2::from(camera.target), camera.scale),
+        None => (size * 0.5, size.x.max(size.y).recip()),
+    }
+}
+
+/// Show the file dialog on another thread so that rendering continues, and open the chosen scenario.
+fn open_with_dialog() {
+    thread::spawn(|| {
+        if let Some(path) = dialog::pick_scenario() {
+            loader::open(path);
+        }
+    });
 }
 
 impl EventHandler for Renderer {
     fn update(&mut self) {}
 
     fn draw(&mut self) {
+        self.follow_scene();
         let (width, height) = miniquad::window::screen_size();
+        // Texts are enlarged on HiDPI screens so that they stay legible.
+        let dpi_scale = miniquad::window::dpi_scale();
 
         // Handle camera movement.
         self.view_scale *= 2.0_f32.powf(self.wheel_delta / 512.0);
@@ -64,6 +252,7 @@ impl EventHandler for Renderer {
         }
 
         // Render.
+        let cursor = self.screen_to_world(self.cursor_pos);
         let state = &mut self.state;
 
         state.begin_pass();
@@ -72,69 +261,518 @@ impl EventHandler for Renderer {
             vec2(1.0, width / height) * self.view_scale,
         );
 
+        let pixels_per_meter = self.view_scale * width * 0.5;
+
         {
-            let simulator = SIMULATOR_STATE.lock().unwrap();
+            let scene = &*self.scene;
+            let scenario = &scene.scenario;
+            // Interpolate positions between the last two steps for smooth motion.
+            let t = runner::interpolation_factor();
+            let (pedestrians, previous) = PEDESTRIANS.load_with_previous();
+            let previous_positions: HashMap<usize, Vec2> = match previous.filter(|_| t < 1.0) {
+                Some(previous) => previous.iter().map(|p| (p.id, p.pos)).collect(),
+                None => HashMap::new(),
+            };
+            let positions: Vec<Vec2> = pedestrians
+                .iter()
+                .map(|p| match previous_positions.get(&p.id) {
+                    Some(prev) => prev.lerp(p.pos, t),
+                    None => p.pos,
+                })
+                .collect();
+
+            // Draw obstacle cells which the simulation actually uses.
+            if self.show_obstacle_cells {
+                let (cells, unit) = &scene.obstacle_cells;
+                state.draw_rectangles(
+                    &cells[self.level]
+                        .iter()
+                        .map(|&pos| {
+                            Instance::new(
+                                Affine2::from_mat2_translation(
+                                    Mat2::from_diagonal(Vec2::splat(*unit)),
+                                    pos,
+                                ),
+                                Color::rgb(1.0, 0.75, 0.75),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                );
+            }
+
+            // Draw crowd pressure.
+            if self.show_pressure {
+                state.draw_rectangles(
+                    &PRESSURE
+                        .load()
+                        .iter()
+                        .map(|&(pos, value)| {
+                            Instance::new(
+                                Affine2::from_mat2_translation(
+                                    Mat2::from_diagonal(Vec2::splat(PRESSURE_GRID_UNIT)),
+                                    pos,
+                                ),
+                                Color::heatmap(value / CRITICAL_PRESSURE),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                );
+            }
+
+            // Draw obstacles as the polygons rasterized into the field, so that joints of polylines look the same
+            // as pedestrians see them.
+            let mut triangles = Vec::new();
+            let mut discs = Vec::new();
+            for obs in scenario
+                .obstacles
+                .iter()
+                .filter(|obs| obs.level == self.level)
+            {
+                // Soft obstacles are drawn lighter, since pedestrians can cross them.
+                let color = if obs.soft {
+                    Color::rgb(0.8, 0.8, 0.8)
+                } else {
+                    Color::GRAY
+                };
+                // Circles are drawn as discs rather than the polylines approximating them.
+                if let Some(circle) = obs.circle {
+                    discs.push(Instance::new(
+                        Affine2::from_mat2_translation(
+                            Mat2::from_diagonal(Vec2::splat(circle.radius)),
+                            circle.center,
+                        ),
+                        color,
+                    ));
+                    continue;
+                }
+                // Polygons are convex, so they are split into fans of triangles.
+                for polygon in util::polyline_with_width(obs.vertices(), obs.width) {
+                    triangles.extend((1..polygon.len() - 1).map(|i| {
+                        Instance::from_triangle([polygon[0], polygon[i], polygon[i + 1]], color)
+                    }));
+                }
+            }
+            state.draw_triangles(&triangles);
+            state.draw_circles(&discs);
+
+            // Highlight spawn areas from which destinations are unreachable.
+            let blocked_cells = &scene.blocked_cells[self.level];
+            if !blocked_cells.is_empty() {
+                let (_, unit) = scene.obstacle_cells;
+                state.draw_rectangles(
+                    &blocked_cells
+                        .iter()
+                        .map(|&pos| {
+                            Instance::new(
+                                Affine2::from_mat2_translation(
+                                    Mat2::from_diagonal(Vec2::splat(unit)),
+                                    pos,
+                                ),
+                                Color::rgb(1.0, 0.2, 0.2),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                );
+            }
+
+            // Draw waypoints.
+            state.draw_rectangles(
+                &scenario
+                    .waypoints
+                    .iter()
+                    .filter(|wp| wp.level == self.level)
+                    .map(|wp| Instance::from_line(wp.line[0], wp.line[1], 0.25, Color::ORANGE))
+                    .collect::<Vec<_>>(),
+            );
 
-            // Draw obstacles.
+            // Draw links from and to the level.
+            let waypoints = &scenario.waypoints;
             state.draw_rectangles(
-                &simulator
-                    .scenario
-                    .obstacles
+                &scenario
+                    .links
                     .iter()
-                    .map(|obs| {
-                        Instance::from_line(obs.line[0], obs.line[1], obs.width, Color::GRAY)
+                    .filter(|link| {
+                        waypoints[link.from].level == self.level
+                            || waypoints[link.to].level == self.level
+                    })
+                    .map(|link| {
+                        let [p_1, p_2] = waypoints[link.from].line;
+                        let [p_3, p_4] = waypoints[link.to].line;
+                        Instance::from_line(
+                            p_1.lerp(p_2, 0.5),
+                            p_3.lerp(p_4, 0.5),
+                            0.1,
+                            Color::MAGENTA,
+                        )
                     })
                     .collect::<Vec<_>>(),
             );
 
-            // Draw waypoints.
+            // Draw gates.
             state.draw_rectangles(
-                &simulator
-                    .scenario
-                    .waypoints
+                &scenario
+                    .gates
                     .iter()
-                    .map(|wp| Instance::from_line(wp.line[0], wp.line[1], 0.25, Color::ORANGE))
+                    .map(|gate| Instance::from_line(gate.line[0], gate.line[1], 0.15, Color::BLACK))
                     .collect::<Vec<_>>(),
             );
 
+            // Draw desired directions toward the selected destination.
+            if let Some(directions) = self
+                .quiver_waypoint
+                .and_then(|i| scene.directions[self.level].get(i))
+            {
+                let length = scene.direction_spacing * 0.8;
+                let mut instances = Vec::with_capacity(directions.len() * 3);
+                for &(pos, direction) in directions {
+                    let start = pos - direction * length * 0.5;
+                    let end = pos + direction * length * 0.5;
+                    let color = Color::rgb(0.2, 0.5, 0.5);
+                    instances.push(Instance::from_line(start, end, 0.05, color));
+                    for angle in [2.6, -2.6] {
+                        let head = end + Vec2::from_angle(angle).rotate(direction) * length * 0.3;
+                        instances.push(Instance::from_line(end, head, 0.05, color));
+                    }
+                }
+                state.draw_rectangles(&instances);
+            }
+
             // Draw pedestrians.
-            state.draw_circles(
-                &simulator
-                    .pedestrians
+            // When zoomed out, they are drawn as quads at least one pixel wide to reduce vertices.
+            let is_point = PEDESTRIAN_RADIUS * pixels_per_meter < LOD_RADIUS;
+            let size = if is_point {
+                (PEDESTRIAN_RADIUS * 2.0).max(pixels_per_meter.recip())
+            } else {
+                PEDESTRIAN_RADIUS
+            };
+            // Selected pedestrians are outlined by larger shapes behind them.
+            let outlines = pedestrians
+                .iter()
+                .zip(&positions)
+                .filter(|(ped, _)| ped.level == self.level && self.selection.contains(ped.id))
+                .map(|(_, &pos)| {
+                    Instance::new(
+                        Affine2::from_mat2_translation(
+                            Mat2::from_diagonal(Vec2::splat(size * 1.8)),
+                            pos,
+                        ),
+                        Color::BLACK,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let instances = pedestrians
+                .iter()
+                .zip(&positions)
+                .filter(|(ped, _)| ped.level == self.level)
+                .map(|(ped, &pos)| {
+                    Instance::new(
+                        Affine2::from_mat2_translation(Mat2::from_diagonal(Vec2::splat(size)), pos),
+                        self.color_mode.color(ped),
+                    )
+                })
+                .collect::<Vec<_>>();
+            if is_point {
+                state.draw_rectangles(&outlines);
+                state.draw_rectangles(&instances);
+            } else {
+                state.draw_circles(&outlines);
+                state.draw_circles(&instances);
+            }
+
+            // Draw forces acting on pedestrians.
+            if self.show_forces {
+                // Forces are published right before pedestrians, so they may be a step ahead for a moment. They are
+                // matched by IDs since pedestrians are reordered while spawned and removed.
+                let forces = FORCES.load();
+                let forces: HashMap<usize, _> = forces.iter().map(|f| (f.id, f)).collect();
+                let mut instances = Vec::new();
+                for (ped, &pos) in pedestrians.iter().zip(&positions) {
+                    let Some(force) = forces.get(&ped.id).filter(|_| ped.level == self.level)
+                    else {
+                        continue;
+                    };
+                    for (f, color) in [
+                        (force.destination, Color::GREEN),
+                        (force.pedestrians, Color::BLUE),
+                        (force.obstacles, Color::RED),
+                    ] {
+                        if f != Vec2::ZERO {
+                            instances.push(Instance::from_line(pos, pos + f * 0.2, 0.05, color));
+                        }
+                    }
+                }
+                state.draw_rectangles(&instances);
+            }
+
+            // Draw indices of waypoints.
+            if self.show_labels {
+                let pixel = 2.0 * dpi_scale / pixels_per_meter;
+                let mut backgrounds = Vec::new();
+                let mut labels = Vec::new();
+
+                for (i, wp) in scenario.waypoints.iter().enumerate() {
+                    if wp.level != self.level {
+                        continue;
+                    }
+                    let text = i.to_string();
+                    let size = font::text_size(&text, pixel);
+                    let center = wp.line[0].lerp(wp.line[1], 0.5);
+                    backgrounds.push(Instance::new(
+                        Affine2::from_mat2_translation(
+                            Mat2::from_diagonal(size + pixel * 2.0),
+                            center,
+                        ),
+                        Color::WHITE,
+                    ));
+                    labels.extend(font::text_instances(
+                        &text,
+                        center + vec2(-size.x, size.y) * 0.5,
+                        pixel,
+                        Color::BLACK,
+                    ));
+                }
+
+                state.draw_rectangles(&backgrounds);
+                state.draw_rectangles(&labels);
+            }
+
+            self.selection
+                .draw_box(state, cursor, 2.0 * dpi_scale / pixels_per_meter);
+
+            if self.ruler.active {
+                self.ruler
+                    .draw(state, cursor, 2.0 * dpi_scale / pixels_per_meter);
+            }
+
+            // Draw the HUD in screen coordinates.
+            self.hud
+                .update(TOTAL_STEPS.load(Ordering::Relaxed), pedestrians.len());
+            state.set_view(vec2(width, height) * 0.5, vec2(width, height).recip() * 2.0);
+            let pixel = 2.0 * dpi_scale;
+            let margin = 8.0 * dpi_scale;
+
+            // The status bar at the top-right corner stays visible while overlays are toggled off.
+            let status_size = font::text_size(&self.hud.status, pixel) + margin * 2.0;
+            state.draw_rectangles(&[Instance::new(
+                Affine2::from_mat2_translation(
+                    Mat2::from_diagonal(status_size),
+                    vec2(width, height) - status_size * 0.5,
+                ),
+                Color::rgb(0.9, 0.9, 0.9),
+            )]);
+            state.draw_rectangles(&font::text_instances(
+                &self.hud.status,
+                vec2(width - status_size.x + margin, height - margin),
+                pixel,
+                Color::BLACK,
+            ));
+
+            self.legend.draw(
+                state,
+                scene,
+                self.color_mode,
+                vec2(width, height),
+                status_size.y,
+                dpi_scale,
+            );
+
+            if self.hud.visible {
+                let line_height = (font::GLYPH_HEIGHT + 3) as f32 * pixel;
+                let lines = self.hud.lines(scene, self.level);
+                let text_width = lines
+                    .iter()
+                    .map(|line| font::text_size(line, pixel).x)
+                    .fold(0.0, f32::max);
+                let box_size = vec2(text_width, lines.len() as f32 * line_height) + margin * 2.0;
+
+                state.draw_rectangles(&[Instance::new(
+                    Affine2::from_mat2_translation(
+                        Mat2::from_diagonal(box_size),
+                        vec2(box_size.x * 0.5, height - box_size.y * 0.5),
+                    ),
+                    Color::rgb(0.9, 0.9, 0.9),
+                )]);
+                let instances: Vec<_> = lines
                     .iter()
-                    .map(|ped| {
-                        Instance::new(
-                            Affine2::from_mat2_translation(
-                                Mat2::from_diagonal(Vec2::splat(0.2)),
-                                ped.pos,
-                            ),
-                            COLORS[ped.destination as usize % COLORS.len()],
+                    .enumerate()
+                    .flat_map(|(i, line)| {
+                        font::text_instances(
+                            line,
+                            vec2(margin, height - margin - i as f32 * line_height),
+                            pixel,
+                            Color::BLACK,
                         )
                     })
-                    .collect::<Vec<_>>(),
+                    .collect();
+                state.draw_rectangles(&instances);
+
+                // Draw the button to open another scenario below the HUD.
+                let button_size = font::text_size(OPEN_BUTTON_LABEL, pixel) + margin * 2.0;
+                let button_top = box_size.y + margin;
+                state.draw_rectangles(&[Instance::new(
+                    Affine2::from_mat2_translation(
+                        Mat2::from_diagonal(button_size),
+                        vec2(
+                            button_size.x * 0.5,
+                            height - button_top - button_size.y * 0.5,
+                        ),
+                    ),
+                    Color::rgb(0.75, 0.75, 0.75),
+                )]);
+                state.draw_rectangles(&font::text_instances(
+                    OPEN_BUTTON_LABEL,
+                    vec2(margin, height - button_top - margin),
+                    pixel,
+                    Color::BLACK,
+                ));
+                self.open_button = Some((
+                    vec2(0.0, button_top),
+                    vec2(button_size.x, button_top + button_size.y),
+                ));
+            } else {
+                self.open_button = None;
+            }
+
+            // Draw the plot of metrics over the run along the bottom.
+            self.plot.draw(
+                state,
+                &HISTORY.load(),
+                vec2(width, height),
+                dpi_scale,
+                vec2(self.cursor_pos.x, height - self.cursor_pos.y),
             );
         }
 
         state.end_pass();
+        runner::notify_frame();
     }
 
     fn key_down_event(
         &mut self,
         keycode: miniquad::KeyCode,
-        _keymods: miniquad::KeyMods,
+        keymods: miniquad::KeyMods,
         repeat: bool,
     ) {
         if !repeat {
             match keycode {
+                KeyCode::O if keymods.ctrl => {
+                    open_with_dialog();
+                }
+                KeyCode::R if keymods.ctrl => {
+                    loader::open(self.scenario_path.clone());
+                }
                 KeyCode::Space => {
-                    let mut state = CONTROL_STATE.lock().unwrap();
-                    state.paused ^= true;
+                    let handle = runner::handle();
+                    if handle.is_running() {
+                        handle.stop();
+                    } else {
+                        handle.start();
+                    }
+                }
+                KeyCode::F => {
+                    self.show_forces ^= true;
+                }
+                KeyCode::H => {
+                    self.hud.visible ^= true;
+                }
+                KeyCode::L => {
+                    self.show_labels ^= true;
+                }
+                KeyCode::K => {
+                    self.legend.visible ^= true;
+                }
+                KeyCode::O => {
+                    self.show_obstacle_cells ^= true;
+                }
+                KeyCode::P => {
+                    self.show_pressure ^= true;
+                }
+                KeyCode::T => {
+                    self.plot.visible ^= true;
+                }
+                KeyCode::V => {
+                    // Cycle through destinations, then hide arrows.
+                    let count = self.scene.directions[self.level].len();
+                    self.quiver_waypoint = match self.quiver_waypoint {
+                        None if count > 0 => Some(0),
+                        Some(i) if i + 1 < count => Some(i + 1),
+                        _ => None,
+                    };
+                    match self.quiver_waypoint {
+                        Some(i) => info!("Show desired directions toward destination {i}"),
+                        None => info!("Hide desired directions"),
+                    }
+                }
+                KeyCode::G => {
+                    let scenario = &self.scene.scenario;
+                    self.level = (self.level + 1) % scenario.level_count();
+                    match scenario.levels.get(self.level) {
+                        Some(level) => info!("Show level {} ({})", self.level, level.name),
+                        None => info!("Show level {}", self.level),
+                    }
+                }
+                KeyCode::M => {
+                    self.ruler.active ^= true;
+                    self.ruler.clear();
+                    if self.ruler.active {
+                        info!("Click two points to measure");
+                    }
+                }
+                KeyCode::LeftShift | KeyCode::RightShift => {
+                    self.shift_down = true;
+                }
+                KeyCode::Escape => {
+                    self.selection.clear();
+                }
+                KeyCode::Delete | KeyCode::Backspace => {
+                    self.selection.despawn();
+                }
+                KeyCode::Key0
+                | KeyCode::Key1
+                | KeyCode::Key2
+                | KeyCode::Key3
+                | KeyCode::Key4
+                | KeyCode::Key5
+                | KeyCode::Key6
+                | KeyCode::Key7
+                | KeyCode::Key8
+                | KeyCode::Key9 => {
+                    let destination = keycode as usize - KeyCode::Key0 as usize;
+                    self.selection.set_destination(destination);
+                }
+                KeyCode::C => {
+                    self.color_mode = self.color_mode.next();
+                    info!("Color pedestrians by {:?}", self.color_mode);
                 }
                 _ => {}
             }
         }
     }
 
+    fn key_up_event(&mut self, keycode: miniquad::KeyCode, _keymods: miniquad::KeyMods) {
+        if matches!(keycode, KeyCode::LeftShift | KeyCode::RightShift) {
+            self.shift_down = false;
+        }
+    }
+
+    fn quit_requested_event(&mut self) {
+        self.save_profile();
+        runner::save_outputs();
+
+        let (width, height) = miniquad::window::screen_size();
+        let scale = window_scale();
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        let position = Some(miniquad::window::get_window_position().into());
+        #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+        let position = None;
+        let window = WindowGeometry {
+            width: (width / scale).round() as u32,
+            height: (height / scale).round() as u32,
+            position,
+        };
+        config::State::update(|state| state.window = Some(window));
+    }
+
     fn mouse_wheel_event(&mut self, _x: f32, y: f32) {
         self.wheel_delta += y;
     }
@@ -143,8 +781,26 @@ impl EventHandler for Renderer {
         self.cursor_pos = vec2(x, y);
     }
 
-    fn mouse_button_down_event(&mut self, button: miniquad::MouseButton, _x: f32, _y: f32) {
+    fn mouse_button_down_event(&mut self, button: miniquad::MouseButton, x: f32, y: f32) {
+        let (_, height) = miniquad::window::screen_size();
         match button {
+            miniquad::MouseButton::Left if self.plot.click(vec2(x, height - y)) => {}
+            miniquad::MouseButton::Left
+                if self.open_button.is_some_and(|(min, max)| {
+                    (min.x..max.x).contains(&x) && (min.y..max.y).contains(&y)
+                }) =>
+            {
+                open_with_dialog();
+            }
+            miniquad::MouseButton::Left if self.shift_down => {
+                self.selection.begin(self.screen_to_world(vec2(x, y)));
+            }
+            miniquad::MouseButton::Left if self.ruler.active => {
+                // Potentials are sampled toward the destination whose directions are shown, if any.
+                let pos = self.screen_to_world(vec2(x, y));
+                self.ruler
+                    .click(pos, self.level, self.quiver_waypoint.unwrap_or(0));
+            }
             miniquad::MouseButton::Left => {
                 self.mouse_left_down = true;
             }
@@ -155,8 +811,12 @@ impl EventHandler for Renderer {
         }
     }
 
-    fn mouse_button_up_event(&mut self, button: miniquad::MouseButton, _x: f32, _y: f32) {
+    fn mouse_button_up_event(&mut self, button: miniquad::MouseButton, x: f32, y: f32) {
         match button {
+            miniquad::MouseButton::Left if self.selection.is_dragging() => {
+                self.selection
+                    .finish(self.screen_to_world(vec2(x, y)), self.level);
+            }
             miniquad::MouseButton::Left => {
                 self.mouse_left_down = false;
             }
@@ -168,15 +828,18 @@ impl EventHandler for Renderer {
     }
 }
 
-pub fn run() {
+pub fn run(scenario_path: PathBuf, camera: Option<Camera>, window: WindowGeometry) {
     let conf = miniquad::conf::Conf {
         window_title: "Pedoni".into(),
-        window_width: 800,
-        window_height: 600,
+        window_width: window.width as i32,
+        window_height: window.height as i32,
+        high_dpi: true,
         icon: None,
         sample_count: 4,
         ..Default::default()
     };
 
-    miniquad::start(conf, move || Box::new(Renderer::new()));
+    miniquad::start(conf, move || {
+        Box::new(Renderer::new(scenario_path, camera, window))
+    });
 }
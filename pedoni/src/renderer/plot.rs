@@ -0,0 +1,180 @@
+use glam::{vec2, Affine2, Mat2, Vec2};
+
+use super::{
+    font,
+    state::{Color, Instance, RenderState},
+};
+use crate::history::HistoryPoint;
+
+/// Height of the panel in pixels at the DPI scale of 1
+const HEIGHT: f32 = 120.0;
+
+/// Panel plotting the number of active pedestrians and step times over the run, with a cursor reading their values at
+/// a step. The cursor only reads the history; the simulation is not rewound to the step.
+pub struct Plot {
+    pub visible: bool,
+    /// Step pinned by clicking, at which the cursor stays
+    pinned: Option<usize>,
+    /// Range of steps and the rectangle of the plot area in screen pixels from the bottom-left corner, if drawn
+    layout: Option<([usize; 2], Vec2, Vec2)>,
+}
+
+impl Plot {
+    pub fn new() -> Self {
+        Plot {
+            visible: false,
+            pinned: None,
+            layout: None,
+        }
+    }
+
+    /// Draw the panel along the bottom of the screen, which must be the view of `state`.
+    ///
+    /// `cursor` is the position of the mouse in the same coordinates, i.e. from the bottom-left corner.
+    pub fn draw(
+        &mut self,
+        state: &mut RenderState,
+        points: &[HistoryPoint],
+        screen: Vec2,
+        dpi_scale: f32,
+        cursor: Vec2,
+    ) {
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            self.layout = None;
+            return;
+        };
+        if !self.visible {
+            self.layout = None;
+            return;
+        }
+
+        let pixel = 2.0 * dpi_scale;
+        let margin = 8.0 * dpi_scale;
+        let line_height = (font::GLYPH_HEIGHT + 3) as f32 * pixel;
+        let panel_size = vec2(screen.x - margin * 2.0, HEIGHT * dpi_scale);
+        state.draw_rectangles(&[Instance::new(
+            Affine2::from_mat2_translation(
+                Mat2::from_diagonal(panel_size),
+                vec2(screen.x * 0.5, margin + panel_size.y * 0.5),
+            ),
+            Color::rgb(0.9, 0.9, 0.9),
+        )]);
+
+        // The plot area is below the label line.
+        let min = Vec2::splat(margin * 2.0);
+        let max = vec2(screen.x - margin * 2.0, panel_size.y - line_height);
+        let range = [first.start, last.step];
+        self.layout = Some((range, min, max));
+
+        let max_count = points
+            .iter()
+            .map(|p| p.active_ped_count)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let max_time = points
+            .iter()
+            .map(|p| p.step_time)
+            .fold(0.0, f64::max)
+            .max(1e-3);
+        let x = |step: usize| {
+            let t = (step - range[0]) as f32 / (range[1] - range[0]).max(1) as f32;
+            min.x + (max.x - min.x) * t
+        };
+        let y = |value: f32| min.y + (max.y - min.y) * value;
+
+        let series = [
+            (
+                points
+                    .iter()
+                    .map(|p| p.active_ped_count as f32 / max_count as f32)
+                    .collect::<Vec<_>>(),
+                Color::BLUE,
+            ),
+            (
+                points
+                    .iter()
+                    .map(|p| (p.step_time / max_time) as f32)
+                    .collect(),
+                Color::RED,
+            ),
+        ];
+        let mut instances = Vec::with_capacity(points.len() * series.len());
+        for (values, color) in series {
+            for i in 1..points.len() {
+                instances.push(Instance::from_line(
+                    vec2(x(points[i - 1].step), y(values[i - 1])),
+                    vec2(x(points[i].step), y(values[i])),
+                    dpi_scale * 1.5,
+                    color,
+                ));
+            }
+        }
+        state.draw_rectangles(&instances);
+
+        // The cursor follows the mouse over the plot unless a step is pinned.
+        let hovered = self.step_at(cursor);
+        let label = match self
+            .pinned
+            .or(hovered)
+            .and_then(|step| nearest(points, step))
+        {
+            Some(point) => {
+                let cursor_x = x(point.step);
+                state.draw_rectangles(&[Instance::from_line(
+                    vec2(cursor_x, min.y),
+                    vec2(cursor_x, max.y),
+                    dpi_scale,
+                    Color::BLACK,
+                )]);
+                format!(
+                    "Step {}: {} active, {:.2} ms/step",
+                    point.step, point.active_ped_count, point.step_time
+                )
+            }
+            None => {
+                format!("Active (blue): max {max_count}  Step time (red): max {max_time:.2} ms")
+            }
+        };
+        state.draw_rectangles(&font::text_instances(
+            &label,
+            vec2(margin * 2.0, panel_size.y + margin * 0.5),
+            pixel,
+            Color::BLACK,
+        ));
+    }
+
+    /// Pin the cursor at the clicked step, or release it if already pinned.
+    ///
+    /// Returns whether the click is on the plot.
+    pub fn click(&mut self, pos: Vec2) -> bool {
+        let Some(step) = self.step_at(pos) else {
+            return false;
+        };
+        self.pinned = match self.pinned {
+            Some(_) => None,
+            None => Some(step),
+        };
+        true
+    }
+
+    /// Forget the pinned step, e.g. when another scenario is opened.
+    pub fn reset(&mut self) {
+        self.pinned = None;
+    }
+
+    fn step_at(&self, pos: Vec2) -> Option<usize> {
+        let (range, min, max) = self.layout?;
+        if !(min.x..=max.x).contains(&pos.x) || !(min.y..=max.y).contains(&pos.y) {
+            return None;
+        }
+        let t = (pos.x - min.x) / (max.x - min.x).max(1.0);
+        Some(range[0] + ((range[1] - range[0]) as f32 * t).round() as usize)
+    }
+}
+
+/// Point covering the step, or the nearest one.
+fn nearest(points: &[HistoryPoint], step: usize) -> Option<&HistoryPoint> {
+    let i = points.partition_point(|p| p.step < step);
+    points.get(i.min(points.len().saturating_sub(1)))
+}
@@ -0,0 +1,159 @@
+use glam::{vec2, Affine2, Mat2, Vec2};
+use log::info;
+use pedoni_simulator::field::Field;
+
+use super::{
+    font,
+    state::{Color, Instance, RenderState},
+};
+use crate::runner;
+
+/// Most points sampled along the segment, including both ends
+const MAX_SAMPLES: usize = 11;
+
+/// Tool measuring the distance between two clicked points, with the grid cells and potentials along the segment, to
+/// check the scale of geometries in scenarios.
+pub struct Ruler {
+    pub active: bool,
+    /// Start of the segment in meters, and its end once clicked
+    points: Option<(Vec2, Option<Vec2>)>,
+    samples: Vec<Sample>,
+}
+
+/// Point sampled along the segment of the ruler
+struct Sample {
+    pos: Vec2,
+    /// Column and row of the cell in the field, if inside it
+    cell: Option<(usize, usize)>,
+    /// Potential toward the destination, if reachable
+    potential: Option<f32>,
+}
+
+impl Ruler {
+    pub fn new() -> Self {
+        Ruler {
+            active: false,
+            points: None,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.points = None;
+        self.samples.clear();
+    }
+
+    /// Set the start of a new segment, or the end of the current one at `pos` in meters. The potentials are sampled
+    /// toward `destination` on `level`.
+    pub fn click(&mut self, pos: Vec2, level: usize, destination: usize) {
+        let start = match self.points {
+            Some((start, None)) => start,
+            _ => {
+                self.points = Some((pos, None));
+                self.samples.clear();
+                return;
+            }
+        };
+        self.points = Some((start, Some(pos)));
+
+        let distance = start.distance(pos);
+        let count = ((distance / 0.5).ceil() as usize + 1).clamp(2, MAX_SAMPLES);
+        self.samples = runner::handle().with_simulator(|simulator| {
+            (0..count)
+                .map(|i| {
+                    let pos = start.lerp(pos, i as f32 / (count - 1) as f32);
+                    sample(&simulator.fields[level], destination, pos)
+                })
+                .collect()
+        });
+
+        info!(
+            "Ruler: {distance:.3} m from ({:.3}, {:.3}) to ({:.3}, {:.3})",
+            start.x, start.y, pos.x, pos.y
+        );
+        for sample in &self.samples {
+            info!(
+                "  ({:.3}, {:.3}) cell {} potential toward {destination}: {}",
+                sample.pos.x,
+                sample.pos.y,
+                sample
+                    .cell
+                    .map_or("outside".into(), |(x, y)| format!("[{x}, {y}]")),
+                sample
+                    .potential
+                    .map_or("unreachable".into(), |p| format!("{p:.3}")),
+            );
+        }
+    }
+
+    /// Draw the segment and its labels in the view of the field, where `cursor` is the mouse position and `pixel` is
+    /// the size of a font pixel in meters. A segment without its end is drawn to the cursor.
+    pub fn draw(&self, state: &mut RenderState, cursor: Vec2, pixel: f32) {
+        let Some((start, end)) = self.points else {
+            return;
+        };
+        let end = end.unwrap_or(cursor);
+        let width = pixel * 1.5;
+
+        let mut instances = vec![Instance::from_line(start, end, width, Color::BLACK)];
+        for &point in [start, end]
+            .iter()
+            .chain(self.samples.iter().map(|s| &s.pos))
+        {
+            instances.push(Instance::new(
+                Affine2::from_mat2_translation(
+                    Mat2::from_diagonal(Vec2::splat(width * 3.0)),
+                    point,
+                ),
+                Color::BLACK,
+            ));
+        }
+        state.draw_rectangles(&instances);
+
+        // The distance is labeled above the end, and samples at their lower right.
+        let offset = vec2(pixel * 3.0, -pixel * 3.0);
+        let mut labels = vec![(
+            format!("{:.2} m", start.distance(end)),
+            end + vec2(offset.x, pixel * (font::GLYPH_HEIGHT + 3) as f32),
+        )];
+        labels.extend(self.samples.iter().map(|sample| {
+            let cell = sample
+                .cell
+                .map_or("-".into(), |(x, y)| format!("[{x},{y}]"));
+            let potential = sample.potential.map_or("-".into(), |p| format!("{p:.2}"));
+            (format!("{cell} {potential}"), sample.pos + offset)
+        }));
+
+        let mut backgrounds = Vec::new();
+        let mut texts = Vec::new();
+        for &(ref text, origin) in &labels {
+            let size = font::text_size(text, pixel);
+            backgrounds.push(Instance::new(
+                Affine2::from_mat2_translation(
+                    Mat2::from_diagonal(size + pixel * 2.0),
+                    origin + vec2(size.x, -size.y) * 0.5,
+                ),
+                Color::rgb(1.0, 1.0, 0.85),
+            ));
+            texts.extend(font::text_instances(text, origin, pixel, Color::BLACK));
+        }
+        state.draw_rectangles(&backgrounds);
+        state.draw_rectangles(&texts);
+    }
+}
+
+fn sample(field: &Field, destination: usize, pos: Vec2) -> Sample {
+    let (rows, columns) = field.shape;
+    let cell = (pos / field.unit).floor();
+    let cell =
+        (cell.cmpge(Vec2::ZERO).all() && (cell.x as usize) < columns && (cell.y as usize) < rows)
+            .then_some((cell.x as usize, cell.y as usize));
+    let potential = (cell.is_some() && destination < field.potentials.dim().0)
+        .then(|| field.get_potential(destination, pos))
+        .filter(|&p| field.is_reachable(p));
+    Sample {
+        pos,
+        cell,
+        potential,
+    }
+}
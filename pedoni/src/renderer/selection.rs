@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use glam::{vec2, Vec2};
+use log::{info, warn};
+use pedoni_simulator::region::Region;
+
+use super::state::{Color, Instance, RenderState};
+use crate::runner;
+
+/// Pedestrians selected by dragging a box, which stay highlighted while they move so that they can be tracked and
+/// operated on together.
+pub struct Selection {
+    ids: HashSet<usize>,
+    /// Corner where the box being dragged started, in meters
+    start: Option<Vec2>,
+}
+
+impl Selection {
+    pub fn new() -> Self {
+        Selection {
+            ids: HashSet::new(),
+            start: None,
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.ids.contains(&id)
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.start.is_some()
+    }
+
+    pub fn begin(&mut self, pos: Vec2) {
+        self.start = Some(pos);
+    }
+
+    /// Replace the selection with pedestrians on `level` in the box dragged to `pos`.
+    pub fn finish(&mut self, pos: Vec2, level: usize) {
+        let Some(start) = self.start.take() else {
+            return;
+        };
+        let region = Region::rect(start, pos);
+        self.ids = runner::handle().with_simulator(|simulator| {
+            let mut ids: HashSet<usize> = simulator.query_region(&region).into_iter().collect();
+            simulator.model.for_each_pedestrian(&mut |p| {
+                if p.level != level {
+                    ids.remove(&p.id);
+                }
+            });
+            ids
+        });
+        info!("Selected {} pedestrians", self.ids.len());
+    }
+
+    pub fn clear(&mut self) {
+        self.ids.clear();
+        self.start = None;
+    }
+
+    /// Send the selected pedestrians to the destination, if it exists.
+    pub fn set_destination(&self, destination: usize) {
+        if self.ids.is_empty() {
+            return;
+        }
+        let count = crate::scene().scenario.destination_count();
+        if destination >= count {
+            warn!("Destination {destination} does not exist in {count} destinations");
+            return;
+        }
+        info!(
+            "Send {} pedestrians to destination {destination}",
+            self.ids.len()
+        );
+        runner::handle().with_simulator(|simulator| {
+            simulator.set_destination(self.ids.iter().copied(), destination)
+        });
+    }
+
+    /// Remove the selected pedestrians from the simulation in the next step.
+    pub fn despawn(&mut self) {
+        if self.ids.is_empty() {
+            return;
+        }
+        info!("Despawn {} pedestrians", self.ids.len());
+        runner::handle().with_simulator(|simulator| simulator.despawn(self.ids.drain()));
+    }
+
+    /// Draw the outline of the box being dragged to `cursor` in the view of the field, with lines of `width` meters.
+    pub fn draw_box(&self, state: &mut RenderState, cursor: Vec2, width: f32) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let (min, max) = (start.min(cursor), start.max(cursor));
+        let corners = [min, vec2(max.x, min.y), max, vec2(min.x, max.y)];
+        let color = Color::rgb(0.2, 0.4, 1.0);
+        state.draw_rectangles(
+            &(0..4)
+                .map(|i| Instance::from_line(corners[i], corners[(i + 1) % 4], width, color))
+                .collect::<Vec<_>>(),
+        );
+    }
+}
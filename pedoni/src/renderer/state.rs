@@ -10,6 +10,7 @@ pub struct RenderState {
     pipeline: Pipeline,
     mesh_rectangle: Mesh,
     mesh_circle: Mesh,
+    mesh_triangle: Mesh,
 
     commands: Vec<Command>,
 }
@@ -27,6 +28,14 @@ impl RenderState {
                 Vertex::new(-0.5, 0.5),
             ],
         );
+        let mesh_triangle = Mesh::triangle_fan(
+            &mut ctx,
+            &[
+                Vertex::new(0.0, 0.0),
+                Vertex::new(1.0, 0.0),
+                Vertex::new(0.0, 1.0),
+            ],
+        );
         let mesh_circle = Mesh::triangle_fan(
             &mut ctx,
             &(0..20)
@@ -78,6 +87,7 @@ impl RenderState {
             pipeline,
             mesh_rectangle,
             mesh_circle,
+            mesh_triangle,
 
             commands: Vec::new(),
         }
@@ -145,6 +155,21 @@ impl RenderState {
         });
     }
 
+    /// Draw triangles made by [`Instance::from_triangle`].
+    pub fn draw_triangles(&mut self, instances: &[Instance]) {
+        let instance_buffer = self.ctx.new_buffer(
+            BufferType::VertexBuffer,
+            BufferUsage::Immutable,
+            BufferSource::slice(instances),
+        );
+
+        self.commands.push(Command::Draw {
+            mesh: self.mesh_triangle.clone(),
+            instance_buffer,
+            num_instances: instances.len() as _,
+        });
+    }
+
     pub fn draw_circles(&mut self, instances: &[Instance]) {
         let instance_buffer = self.ctx.new_buffer(
             BufferType::VertexBuffer,
@@ -208,6 +233,15 @@ impl Instance {
         };
         Instance::new(affine, color)
     }
+
+    /// Instance of the triangle mesh with the given vertices.
+    pub fn from_triangle(vertices: [Vec2; 3], color: Color) -> Self {
+        let affine = Affine2 {
+            matrix2: Mat2::from_cols(vertices[1] - vertices[0], vertices[2] - vertices[0]),
+            translation: vertices[0],
+        };
+        Instance::new(affine, color)
+    }
 }
 
 #[repr(C)]
@@ -282,6 +316,16 @@ impl Color {
     pub const fn rgb(r: f32, g: f32, b: f32) -> Self {
         Color([r, g, b, 1.0])
     }
+
+    /// Map a value in [0, 1] to a color from blue (low) through green to red (high).
+    pub fn heatmap(t: f32) -> Self {
+        let t = t.clamp(0.0, 1.0) * 4.0;
+        Color::rgb(
+            (t - 2.0).clamp(0.0, 1.0),
+            (t.min(4.0 - t)).clamp(0.0, 1.0),
+            (2.0 - t).clamp(0.0, 1.0),
+        )
+    }
 }
 
 const VERTEX_SHADER: &str = r#"
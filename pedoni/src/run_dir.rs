@@ -0,0 +1,124 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+/// Directory collecting the outputs of a run, i.e. `runs/<timestamp>-<scenario name>/` unless `--out-dir` is given,
+/// which keeps outputs of sweeps apart. The directory is created when the first output is written.
+pub struct RunDir {
+    pub path: PathBuf,
+    started_at: DateTime<Local>,
+}
+
+/// Description of a run written as `manifest.json`, to tell which scenario and settings produced the outputs.
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    scenario: &'a Path,
+    model: &'a str,
+    /// Seed given by `--seed`, or picked at random in headless mode
+    seed: Option<u64>,
+    started_at: String,
+    /// Command line of the run
+    command: Vec<String>,
+    /// Files in the directory other than the manifest
+    outputs: Vec<PathBuf>,
+}
+
+impl RunDir {
+    pub fn new(out_dir: Option<PathBuf>, scenario: &Path) -> Self {
+        let started_at = Local::now();
+        let path = out_dir.unwrap_or_else(|| {
+            let name = scenario
+                .file_stem()
+                .map_or("scenario".into(), |stem| stem.to_string_lossy());
+            Path::new("runs").join(format!("{}-{name}", started_at.format("%Y-%m-%d_%H%M%S")))
+        });
+        RunDir { path, started_at }
+    }
+
+    /// Path of an output file in the directory, creating the directory. Absolute paths are returned as they are.
+    pub fn file(&self, name: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let name = name.as_ref();
+        if name.is_absolute() {
+            return Ok(name.to_owned());
+        }
+        fs::create_dir_all(&self.path)?;
+        Ok(self.path.join(name))
+    }
+
+    /// Write `manifest.json` listing the outputs written so far.
+    pub fn write_manifest(
+        &self,
+        scenario: &Path,
+        model: &str,
+        seed: Option<u64>,
+    ) -> anyhow::Result<PathBuf> {
+        let path = self.file("manifest.json")?;
+        let mut outputs: Vec<PathBuf> = fs::read_dir(&self.path)?
+            .filter_map(|entry| Some(PathBuf::from(entry.ok()?.file_name())))
+            .filter(|name| name.as_os_str() != "manifest.json")
+            .collect();
+        outputs.sort();
+        let manifest = Manifest {
+            scenario,
+            model,
+            seed,
+            started_at: self.started_at.to_rfc3339(),
+            command: std::env::args().collect(),
+            outputs,
+        };
+        serde_json::to_writer_pretty(File::create(&path)?, &manifest)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::RunDir;
+
+    #[test]
+    fn test_manifest() {
+        let dir = std::env::temp_dir().join(format!("pedoni-test-run-{}", std::process::id()));
+        let scenario = Path::new("scenarios/bottleneck.toml");
+        let run_dir = RunDir::new(Some(dir.clone()), scenario);
+        fs::write(run_dir.file("log.json").unwrap(), "{}").unwrap();
+        fs::write(run_dir.file("trajectories.csv").unwrap(), "").unwrap();
+
+        let path = run_dir
+            .write_manifest(scenario, "Social force model", Some(7))
+            .unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(manifest["scenario"], "scenarios/bottleneck.toml");
+        assert_eq!(manifest["model"], "Social force model");
+        assert_eq!(manifest["seed"], 7);
+        assert_eq!(
+            manifest["outputs"],
+            serde_json::json!(["log.json", "trajectories.csv"])
+        );
+        assert!(manifest["started_at"]
+            .as_str()
+            .is_some_and(|time| chrono::DateTime::parse_from_rfc3339(time).is_ok()));
+    }
+
+    #[test]
+    fn test_default_path() {
+        let run_dir = RunDir::new(None, Path::new("scenarios/bottleneck.toml"));
+        assert!(run_dir.path.starts_with("runs"));
+        let name = run_dir.path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-bottleneck"), "{name}");
+
+        // Absolute paths are kept outside the directory, which is not created for them.
+        let absolute = std::env::temp_dir().join("log.json");
+        assert_eq!(run_dir.file(&absolute).unwrap(), absolute);
+        assert!(!run_dir.path.exists());
+    }
+}
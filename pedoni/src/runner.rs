@@ -0,0 +1,363 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+#[cfg(feature = "parquet")]
+use pedoni_simulator::arrow_export::ParquetTrajectoryWriter;
+use pedoni_simulator::{
+    diagnostic::{DiagnositcLog, LogFormat, StepMetrics},
+    handle::{Pace, SimulationHandle, StepObserver},
+    sensor::SensorLog,
+    speed_density::SpeedDensityExporter,
+    trajectory::{CompactTrajectoryWriter, TrajectoryWriter},
+    Simulator,
+};
+
+use crate::{
+    clock::ExternalClock, history::History, metrics, Scene, CONTROL_STATE, DELTA_TIME,
+    DIAGNOSTIC_LOG, FORCES, HISTORY, PEDESTRIANS, PRESSURE, SIG_INT, TOTAL_STEPS,
+};
+
+/// Handle of the simulation thread, set by [`Runner::spawn`]
+static HANDLE: OnceLock<SimulationHandle> = OnceLock::new();
+/// Steps taken each time the renderer draws a frame in [`RunMode::StepsPerFrame`], or 0 in other modes
+static STEPS_PER_FRAME: AtomicU32 = AtomicU32::new(0);
+/// Time when the latest step was published and the interval from the previous one
+static LAST_STEP: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+/// Simulator of a newly opened scenario and its scene, swapped in by the runner before the next step
+static PENDING: Mutex<Option<(Simulator, Scene)>> = Mutex::new(None);
+/// Whether saving outputs is requested, served by the runner between steps. See [`save_outputs`].
+static SAVE: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+/// How fast the simulation advances.
+#[derive(Debug, Clone, Copy)]
+pub enum RunMode {
+    /// Advance at the playback speed times real time.
+    RealTime,
+    /// Advance as fast as possible.
+    MaxSpeed,
+    /// Advance a fixed number of steps each time the renderer draws a frame.
+    StepsPerFrame(u32),
+    /// Advance to times received from [`Runner::clock`], regardless of the playback controls.
+    ExternalClock,
+}
+
+/// Outputs of the simulation advanced on its own thread by a [`SimulationHandle`], independently of the frame rate of
+/// the renderer.
+pub struct Runner {
+    pub mode: RunMode,
+    /// Clock driving the simulation in [`RunMode::ExternalClock`]
+    pub clock: Option<ExternalClock>,
+    /// Interval of steps to print metrics (0 to disable)
+    pub log_every: i32,
+    pub log_format: LogFormat,
+    /// Metrics over the run, published to [`HISTORY`]
+    pub history: History,
+    /// Exporter of speeds and densities given by `--speed-density`
+    pub speed_density: Option<SpeedDensityExporter<BufWriter<File>>>,
+    /// Log of sensor readings given by `--sensor-log`
+    pub sensor_log: Option<SensorLog<BufWriter<File>>>,
+    /// Writer of trajectories given by `--trajectories`
+    pub trajectories: Option<TrajectoryWriter<BufWriter<File>>>,
+    /// Writer of trajectories given by `--trajectories` in the compact format
+    pub compact_trajectories: Option<CompactTrajectoryWriter<BufWriter<File>>>,
+    /// Writer of trajectories given by `--trajectories` in Parquet, closed when outputs are saved on request
+    #[cfg(feature = "parquet")]
+    pub parquet_trajectories: Option<ParquetTrajectoryWriter<BufWriter<File>>>,
+    /// Periodic save of the diagnostic log, enabled in GUI mode
+    pub autosave: Option<Autosave>,
+}
+
+/// Periodic save of the diagnostic log, so that metrics of GUI runs survive crashes. Trajectories in text formats
+/// are flushed at the same time.
+pub struct Autosave {
+    pub path: PathBuf,
+    /// Interval between saves, or `None` to save only on request, e.g. on exit
+    pub interval: Option<Duration>,
+    pub last: Instant,
+}
+
+impl Autosave {
+    pub fn new(path: PathBuf, interval: Option<Duration>) -> Self {
+        Autosave {
+            path,
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.interval
+            .is_some_and(|interval| self.last.elapsed() >= interval)
+    }
+
+    /// Write the log to a temporary file first, so that a crash while writing keeps the previous save.
+    fn save(&mut self, log: &DiagnositcLog) -> anyhow::Result<()> {
+        self.last = Instant::now();
+        let temp = self.path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        serde_json::to_writer(&mut writer, log)?;
+        writer.flush()?;
+        fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Runner {
+    /// Start the simulation thread, stopped until [`handle`] starts it unless driven by the external clock.
+    pub fn spawn(mut self, simulator: Simulator) {
+        let clock = self.clock.take();
+        let pace = match self.mode {
+            RunMode::RealTime => {
+                let playback_speed = CONTROL_STATE.lock().unwrap().playback_speed;
+                Pace::Interval(Duration::from_secs_f32(DELTA_TIME / playback_speed))
+            }
+            RunMode::MaxSpeed => Pace::MaxSpeed,
+            RunMode::StepsPerFrame(steps) => {
+                STEPS_PER_FRAME.store(steps, Ordering::Relaxed);
+                Pace::Manual
+            }
+            RunMode::ExternalClock => Pace::Manual,
+        };
+
+        let handle = SimulationHandle::spawn(simulator, self);
+        handle.set_pace(pace);
+        if HANDLE.set(handle).is_err() {
+            panic!("The runner is already spawned");
+        }
+        if let Some(clock) = clock {
+            thread::spawn(move || run_with_clock(clock));
+        }
+    }
+
+    /// Save outputs if requested by [`save_outputs`] or if the autosave is due.
+    fn serve_save(&mut self) {
+        let requested = *SAVE.0.lock().unwrap();
+        if requested || self.autosave.as_ref().is_some_and(Autosave::is_due) {
+            self.save();
+        }
+        if requested {
+            // Parquet files are unreadable until closed, and requests come before exit.
+            #[cfg(feature = "parquet")]
+            if let Some(writer) = self.parquet_trajectories.take() {
+                if let Err(e) = writer.close() {
+                    warn!("Failed to write trajectories: {e}");
+                }
+            }
+            *SAVE.0.lock().unwrap() = false;
+            SAVE.1.notify_all();
+        }
+    }
+
+    /// Save the diagnostic log if autosave is enabled, and flush trajectories.
+    ///
+    /// Parquet trajectories are not flushed, since each flush writes a row group and autosaves would split them
+    /// into many small ones. They are written as row groups fill and finished when closed.
+    fn save(&mut self) {
+        if let Some(autosave) = &mut self.autosave {
+            match autosave.save(&DIAGNOSTIC_LOG.lock().unwrap()) {
+                Ok(()) => debug!("Saved the log to {}", autosave.path.display()),
+                Err(e) => warn!("Failed to save the log: {e}"),
+            }
+        }
+        if let Some(writer) = &mut self.trajectories {
+            if let Err(e) = writer.flush() {
+                warn!("Failed to write trajectories: {e}");
+                self.trajectories = None;
+            }
+        }
+        if let Some(writer) = &mut self.compact_trajectories {
+            if let Err(e) = writer.flush() {
+                warn!("Failed to write trajectories: {e}");
+                self.compact_trajectories = None;
+            }
+        }
+    }
+
+    /// Switch to another simulator, discarding the states and the log of the current one.
+    fn reset(&mut self, simulator: &mut Simulator, replacement: Simulator, scene: Scene) {
+        *DIAGNOSTIC_LOG.lock().unwrap() = DiagnositcLog {
+            model: replacement.model.name().to_string(),
+            scenario: scene.scenario_name.clone(),
+            warmup_steps: replacement.options.warmup_steps.max(0) as usize,
+            ..Default::default()
+        };
+        TOTAL_STEPS.store(0, Ordering::Relaxed);
+        *LAST_STEP.lock().unwrap() = None;
+
+        // Published twice so that positions are not interpolated from the previous scenario.
+        for _ in 0..2 {
+            PEDESTRIANS.publish(|_| {});
+        }
+        FORCES.publish(|_| {});
+        PRESSURE.publish(|_| {});
+        self.history = History::default();
+        HISTORY.publish(|_| {});
+
+        info!("Opened scenario {}", scene.scenario_name);
+        crate::set_scene(scene);
+        *simulator = replacement;
+    }
+}
+
+impl StepObserver for Runner {
+    fn between_steps(&mut self, simulator: &mut Simulator) {
+        if let Some((replacement, scene)) = PENDING.lock().unwrap().take() {
+            self.reset(simulator, replacement, scene);
+        }
+        self.serve_save();
+    }
+
+    fn on_step(&mut self, simulator: &Simulator, step_metrics: &StepMetrics) {
+        if self.log_every > 0 && simulator.step % self.log_every == 0 {
+            let line = step_metrics.to_log_line(simulator.step, self.log_format);
+            match self.log_format {
+                // JSON lines are printed to stdout as is so that they can be piped to other tools.
+                LogFormat::Json => println!("{line}"),
+                LogFormat::Plain => info!("{line}"),
+            }
+        }
+
+        if let Some(exporter) = &mut self.speed_density {
+            if let Err(e) = exporter.record(simulator) {
+                warn!("Failed to export speeds and densities: {e}");
+                self.speed_density = None;
+            }
+        }
+        if let Some(log) = &mut self.sensor_log {
+            if let Err(e) = log.record(simulator) {
+                warn!("Failed to write sensor readings: {e}");
+                self.sensor_log = None;
+            }
+        }
+        if let Some(writer) = &mut self.trajectories {
+            if let Err(e) = writer.record(simulator) {
+                warn!("Failed to write trajectories: {e}");
+                self.trajectories = None;
+            }
+        }
+        if let Some(writer) = &mut self.compact_trajectories {
+            if let Err(e) = writer.record(simulator) {
+                warn!("Failed to write trajectories: {e}");
+                self.compact_trajectories = None;
+            }
+        }
+        #[cfg(feature = "parquet")]
+        if let Some(writer) = &mut self.parquet_trajectories {
+            if let Err(e) = writer.record(simulator) {
+                warn!("Failed to write trajectories: {e}");
+                self.parquet_trajectories = None;
+            }
+        }
+
+        FORCES.publish(|buffer| buffer.extend(simulator.list_forces().unwrap_or_default()));
+        if let Some(pressure) = &simulator.crowd_pressure {
+            PRESSURE.publish(|buffer| {
+                buffer.extend(
+                    pressure
+                        .values
+                        .indexed_iter()
+                        .filter(|(_, &value)| value > 0.0)
+                        .map(|(index, &value)| (pressure.cell_center(index), value)),
+                )
+            });
+        }
+        PEDESTRIANS.publish(|buffer| simulator.for_each_pedestrian(|p| buffer.push(p)));
+        {
+            let mut last_step = LAST_STEP.lock().unwrap();
+            let now = Instant::now();
+            let interval = last_step.map_or(Duration::ZERO, |(published, _)| now - published);
+            *last_step = Some((now, interval));
+        }
+
+        metrics::record(step_metrics);
+        self.history.push(simulator.step as usize, step_metrics);
+        HISTORY.publish(|buffer| buffer.extend_from_slice(&self.history.points));
+
+        if !simulator.is_warming_up() {
+            DIAGNOSTIC_LOG.lock().unwrap().push(step_metrics.clone());
+        }
+        TOTAL_STEPS.store(simulator.step as usize, Ordering::Relaxed);
+    }
+}
+
+/// Advance to times received from the clock, regardless of the playback controls.
+fn run_with_clock(mut clock: ExternalClock) {
+    let handle = handle();
+    // The nearest f64 to the written step length, as casting the f32 would report times like 1.0000000149 s.
+    let delta_time: f64 = DELTA_TIME.to_string().parse().unwrap();
+    while let Some(target) = clock.next_target() {
+        // Compare steps rather than accumulated times, which drift by rounding errors.
+        let target_step = (target / delta_time - 1e-6).ceil() as i32;
+        let step = handle.with_simulator(|simulator| simulator.step);
+        handle.step(target_step.saturating_sub(step).max(0) as u64);
+        handle.wait();
+
+        let step = handle.with_simulator(|simulator| simulator.step);
+        if let Err(e) = clock.acknowledge(step, step as f64 * delta_time) {
+            warn!("Failed to reply to the external clock: {e}");
+            break;
+        }
+    }
+
+    info!("The external clock stopped");
+    save_outputs();
+    // End headless runs as if interrupted, exporting the log.
+    SIG_INT.store(true, Ordering::SeqCst);
+}
+
+/// Handle of the simulation thread, e.g. to start and stop it.
+pub fn handle() -> &'static SimulationHandle {
+    HANDLE.get().expect("the runner is not spawned")
+}
+
+/// Switch the runner to another scenario. The simulation restarts from the beginning, stopped.
+pub fn replace(simulator: Simulator, scene: Scene) {
+    handle().stop();
+    *PENDING.lock().unwrap() = Some((simulator, scene));
+}
+
+/// Have the runner save its outputs, e.g. before exit, waiting a few seconds at most.
+pub fn save_outputs() {
+    let (requested, condvar) = &SAVE;
+    *requested.lock().unwrap() = true;
+    let (_requested, result) = condvar
+        .wait_timeout_while(requested.lock().unwrap(), Duration::from_secs(5), |r| *r)
+        .unwrap();
+    if result.timed_out() {
+        warn!("Outputs were not saved in time");
+    }
+}
+
+/// Notify the runner that the renderer drew a frame, which takes the steps of [`RunMode::StepsPerFrame`].
+pub fn notify_frame() {
+    let steps = STEPS_PER_FRAME.load(Ordering::Relaxed);
+    let Some(handle) = HANDLE.get() else {
+        return;
+    };
+    // Frames drawn while the steps of the previous one are in progress are skipped.
+    if steps > 0 && handle.is_running() && handle.pending_steps() == 0 {
+        handle.step(steps.into());
+    }
+}
+
+/// Progress from the previous published step to the latest one in `[0, 1]`, assuming steps keep the same interval.
+///
+/// The renderer draws pedestrians interpolated by this factor, which keeps motion smooth at any playback speed.
+pub fn interpolation_factor() -> f32 {
+    match *LAST_STEP.lock().unwrap() {
+        Some((published, interval)) if !interval.is_zero() => {
+            (published.elapsed().as_secs_f32() / interval.as_secs_f32()).min(1.0)
+        }
+        _ => 1.0,
+    }
+}